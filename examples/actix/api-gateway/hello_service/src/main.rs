@@ -5,24 +5,33 @@ use std::{
 };
 use actix_http::header::{HeaderName, HeaderValue};
 use actix_web::{
-    middleware::Logger,
     web,
     get,
     App, HttpServer,
     HttpResponse, Responder, HttpRequest
 };
 
+use telemetry::RequestTracing;
+
+mod telemetry;
+
 #[actix_web::main]
 async fn main() -> Result<(), impl Error> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    HttpServer::new(move || {
+    let tracer_provider = telemetry::init("hello_service");
+    let result = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(RequestTracing)
             .configure(configure())
     })
     .bind((Ipv4Addr::UNSPECIFIED, 8080))?
     .run()
-    .await
+    .await;
+
+    // Flush the spans not exported yet
+    if let Some(provider) = tracer_provider {
+        let _ = provider.shutdown();
+    }
+    result
 }
 
 
@@ -0,0 +1,174 @@
+use std::future::{ready, Ready};
+use std::time::Instant;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    Error,
+};
+use futures::future::LocalBoxFuture;
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Install the global tracing subscriber.
+///
+/// Logs are written to stdout, filtered by `RUST_LOG` (`info` by default), as JSON lines when
+/// `LOG_FORMAT=json`. Spans are also exported over OTLP/gRPC when `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set, e.g. `http://localhost:4317` for a local collector.
+///
+/// Returns the tracer provider to shut down on exit, so buffered spans get flushed.
+pub fn init(service_name: &'static str) -> Option<TracerProvider> {
+    // Incoming and outgoing trace context use the W3C `traceparent` header
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let fmt_layer = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    let provider = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().map(|_| {
+        // The exporter reads the endpoint from the environment itself
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .build()
+            .expect("Could not build the OTLP exporter.");
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::TokioCurrentThread)
+            .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+            .build()
+    });
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+    provider
+}
+
+// Read-only view of the request headers for the trace context propagator
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Middleware opening a span per request, and logging its outcome.
+///
+/// The span continues the trace of the caller when a `traceparent` header is present, and carries
+/// the request id, taken from the `X-Request-Id` header or generated when missing. The id is
+/// echoed back in the `X-Request-Id` response header.
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTracingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware { service }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Only trust reasonably sized ids, they end up in every log line
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= 128)
+            .map(str::to_owned)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let span = tracing::info_span!(
+            "HTTP request",
+            otel.name = format!("{} {}", req.method(), route),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.method = %req.method(),
+            http.route = %route,
+            http.target = %req.uri(),
+            http.status_code = Empty,
+            request_id = %request_id,
+        );
+        let parent_context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        span.set_parent(parent_context);
+
+        let start = Instant::now();
+        let fut = span.in_scope(|| self.service.call(req));
+
+        Box::pin(
+            async move {
+                let res = fut.await;
+                let status = match &res {
+                    Ok(response) => response.status(),
+                    Err(err) => err.as_response_error().status_code(),
+                };
+                let span = tracing::Span::current();
+                span.record("http.status_code", status.as_u16());
+                if status.is_server_error() {
+                    span.record("otel.status_code", "ERROR");
+                }
+                tracing::info!(
+                    status = status.as_u16(),
+                    elapsed_ms = start.elapsed().as_millis() as u64,
+                    "request completed"
+                );
+
+                let mut response = res?;
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
@@ -0,0 +1,392 @@
+//! Accounts of the users: registration and login with a password hashed with argon2, password
+//! changes, logouts, and logins through an OpenID Connect provider.
+//!
+//! Every proxied response refreshes the session cookie, so that revoking only the token sent
+//! along a logout would leave the ones handed out since valid: sessions carry the session version
+//! of their user instead, and revoking them increments it.
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use jwt_simple::prelude::{JWTClaims, MACLike};
+use lazy_static::lazy_static;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::oidc::Identity;
+use crate::schemas::{Credentials, SessionClaims, User};
+use crate::store_interface::{AccountError, UserRepository};
+use crate::stores::config::get_key;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+// Hashing takes as long whatever the length, but the input is still bounded
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+lazy_static! {
+    // Verified against when the username is unknown, for logins to take as long either way
+    static ref DUMMY_HASH: String = hash("not the password of anyone");
+}
+
+/// Body of the registration and login requests.
+#[derive(Deserialize)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+/// Body of the password change requests.
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Why an account request is refused.
+#[derive(Debug)]
+pub struct Refused {
+    pub status: StatusCode,
+    pub detail: String,
+}
+
+impl Refused {
+    pub(crate) fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            detail: detail.into(),
+        }
+    }
+
+    pub(crate) fn storage() -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "Accounts unavailable")
+    }
+}
+
+/// Usernames are compared case-insensitively, and kept to characters safe to show.
+fn normalize_username(username: &str) -> Result<String, Refused> {
+    let username = username.trim().to_lowercase();
+    let allowed = |c: char| c.is_alphanumeric() || "._-@+".contains(c);
+    if !(3..=64).contains(&username.chars().count()) || !username.chars().all(allowed) {
+        return Err(Refused::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Usernames have 3 to 64 letters, digits or characters among ._-@+",
+        ));
+    }
+    Ok(username)
+}
+
+fn check_strength(password: &str) -> Result<(), Refused> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        let detail = format!("Passwords need at least {MIN_PASSWORD_LENGTH} characters");
+        return Err(Refused::new(StatusCode::UNPROCESSABLE_ENTITY, detail));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        let detail = format!("Passwords have at most {MAX_PASSWORD_LENGTH} characters");
+        return Err(Refused::new(StatusCode::UNPROCESSABLE_ENTITY, detail));
+    }
+    Ok(())
+}
+
+fn hash(password: &str) -> String {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).unwrap();
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// PHC string of `password`, e.g. `$argon2id$v=19$...`, hashed on the blocking threads as it is
+/// made slow on purpose.
+pub async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || hash(&password))
+        .await
+        .unwrap()
+}
+
+/// Whether `password` is the one of the stored `hash`, checked against a dummy hash when the
+/// account is unknown.
+pub async fn verify_password(password: String, hash: Option<String>) -> bool {
+    let known = hash.is_some();
+    tokio::task::spawn_blocking(move || {
+        let hash = hash.unwrap_or_else(|| DUMMY_HASH.clone());
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        }) && known
+    })
+    .await
+    .unwrap()
+}
+
+/// User id and claims of a valid session token, whether or not the session was revoked since.
+pub fn session_claims(token: &str) -> Option<(Uuid, SessionClaims)> {
+    let claims: JWTClaims<SessionClaims> = get_key().verify_token(token, None).ok()?;
+    let user_id = Uuid::parse_str(&claims.subject?).ok()?;
+    Some((user_id, claims.custom))
+}
+
+/// Create an account logging in with a password, answering its user.
+pub async fn register<R>(repository: &R, login: Login) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let username = normalize_username(&login.username)?;
+    check_strength(&login.password)?;
+    let user = User {
+        id: Uuid::new_v4(),
+        admin: false,
+        session_version: 0,
+        roles: Vec::new(),
+    };
+    let credentials = Credentials {
+        user_id: user.id,
+        username,
+        password_hash: hash_password(login.password).await,
+    };
+    match repository.create_account(&user, &credentials).await {
+        Ok(()) => Ok(user),
+        Err(AccountError::UsernameTaken) => {
+            Err(Refused::new(StatusCode::CONFLICT, "Username already taken"))
+        }
+        Err(AccountError::Storage) => Err(Refused::storage()),
+    }
+}
+
+/// User of the account `login` is the password of.
+pub async fn login<R>(repository: &R, login: Login) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let credentials = match normalize_username(&login.username) {
+        Ok(username) => repository.find_credentials(&username).await,
+        Err(_) => None,
+    };
+    let user_id = credentials.as_ref().map(|credentials| credentials.user_id);
+    let hash = credentials.map(|credentials| credentials.password_hash);
+    if !verify_password(login.password, hash).await {
+        return Err(Refused::new(
+            StatusCode::UNAUTHORIZED,
+            "Wrong username or password",
+        ));
+    }
+    let user_id = user_id.ok_or_else(Refused::storage)?;
+    repository
+        .get_user(user_id)
+        .await
+        .ok_or_else(Refused::storage)
+}
+
+/// Revoke every session of `user`, answering it with its new session version.
+pub async fn logout<R>(repository: &R, user: &User) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    repository
+        .revoke_sessions(user.id)
+        .await
+        .map_err(|()| Refused::storage())
+}
+
+/// Change the password of `user`, revoking its other sessions, and answering it with its new
+/// session version.
+pub async fn change_password<R>(
+    repository: &R,
+    user: &User,
+    change: PasswordChange,
+) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let Some(credentials) = repository.get_credentials(user.id).await else {
+        return Err(Refused::new(
+            StatusCode::CONFLICT,
+            "This account logs in without a password",
+        ));
+    };
+    if !verify_password(change.current_password, Some(credentials.password_hash)).await {
+        return Err(Refused::new(
+            StatusCode::FORBIDDEN,
+            "Wrong current password",
+        ));
+    }
+    check_strength(&change.new_password)?;
+    let password_hash = hash_password(change.new_password).await;
+    repository
+        .set_password_hash(user.id, &password_hash)
+        .await
+        .map_err(|()| Refused::storage())?;
+    logout(repository, user).await
+}
+
+/// User logging in with `identity`, its account being created on its first login.
+pub async fn identity_user<R>(repository: &R, identity: &Identity) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    if let Some(user_id) = repository
+        .find_identity(&identity.issuer, &identity.subject)
+        .await
+    {
+        return repository
+            .get_user(user_id)
+            .await
+            .ok_or_else(Refused::storage);
+    }
+    let user = User {
+        id: Uuid::new_v4(),
+        admin: false,
+        session_version: 0,
+        roles: Vec::new(),
+    };
+    repository
+        .create_identity(&user, &identity.issuer, &identity.subject)
+        .await
+        .map_err(|()| Refused::storage())?;
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::memory::InMemoryUsers;
+
+    fn login_of(username: &str, password: &str) -> Login {
+        Login {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_normalize_username() {
+        assert_eq!(normalize_username(" Alice ").unwrap(), "alice");
+        assert_eq!(normalize_username("a.b_c-d@e+f").unwrap(), "a.b_c-d@e+f");
+        assert_eq!(normalize_username("Élodie").unwrap(), "élodie");
+        for refused in ["ab", "with space", "semi;colon", &"a".repeat(65)] {
+            let refused = normalize_username(refused).err().unwrap();
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[test]
+    fn test_check_strength() {
+        assert!(check_strength("12345678").is_ok());
+        assert!(check_strength(&"a".repeat(MAX_PASSWORD_LENGTH)).is_ok());
+        // Characters are counted, not bytes
+        for refused in ["1234567", "ééééééé", &"a".repeat(MAX_PASSWORD_LENGTH + 1)] {
+            let refused = check_strength(refused).err().unwrap();
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_password() {
+        let password_hash = hash_password("correct horse".to_owned()).await;
+        assert!(password_hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse".to_owned(), Some(password_hash.clone())).await);
+        assert!(!verify_password("wrong horse".to_owned(), Some(password_hash)).await);
+        // Even the password of the dummy hash fails for unknown users
+        assert!(!verify_password("correct horse".to_owned(), None).await);
+        assert!(!verify_password("not the password of anyone".to_owned(), None).await);
+    }
+
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let repository = InMemoryUsers::default();
+        let user = register(&repository, login_of("Alice", "correct horse"))
+            .await
+            .unwrap();
+        let refused = register(&repository, login_of("alice", "another horse"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::CONFLICT);
+        let refused = register(&repository, login_of("bob", "short"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let logged_in = login(&repository, login_of(" ALICE", "correct horse"))
+            .await
+            .unwrap();
+        assert_eq!(logged_in.id, user.id);
+        for (username, password) in [("alice", "wrong horse"), ("bob", "correct horse")] {
+            let refused = login(&repository, login_of(username, password))
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(refused.status, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_revoked() {
+        let repository = InMemoryUsers::default();
+        let user = register(&repository, login_of("alice", "correct horse"))
+            .await
+            .unwrap();
+        assert_eq!(user.session_version, 0);
+        let user = logout(&repository, &user).await.unwrap();
+        assert_eq!(user.session_version, 1);
+
+        let change = |current: &str, new: &str| PasswordChange {
+            current_password: current.to_owned(),
+            new_password: new.to_owned(),
+        };
+        let refused = change_password(&repository, &user, change("wrong horse", "battery staple"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::FORBIDDEN);
+        let refused = change_password(&repository, &user, change("correct horse", "short"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            repository.get_user(user.id).await.unwrap().session_version,
+            1
+        );
+
+        let user = change_password(
+            &repository,
+            &user,
+            change("correct horse", "battery staple"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(user.session_version, 2);
+        assert!(login(&repository, login_of("alice", "correct horse"))
+            .await
+            .is_err());
+        assert!(login(&repository, login_of("alice", "battery staple"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_identity_user() {
+        let repository = InMemoryUsers::default();
+        let identity = Identity {
+            issuer: "http://mock_idp:8080".to_owned(),
+            subject: "alice".to_owned(),
+        };
+        let user = identity_user(&repository, &identity).await.unwrap();
+        assert_eq!(
+            identity_user(&repository, &identity).await.unwrap().id,
+            user.id
+        );
+        // Accounts logging in through a provider have no password to change
+        let change = PasswordChange {
+            current_password: String::new(),
+            new_password: "battery staple".to_owned(),
+        };
+        let refused = change_password(&repository, &user, change)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::CONFLICT);
+    }
+}
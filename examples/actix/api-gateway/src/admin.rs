@@ -0,0 +1,322 @@
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder, Scope};
+use coi_actix_web::inject;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::authn::{self, credential, NewApiKey, NewClient};
+use crate::authz::valid_role;
+use crate::gateway::authenticate;
+use crate::problem::error_response;
+use crate::store_interface::UserRepository;
+use crate::stores::config::{get_config, reload_config};
+
+/// Endpoints administrating the gateway, reserved to admin sessions.
+pub fn scope() -> Scope {
+    web::scope("/admin")
+        .route("/config", web::get().to(active_config))
+        .route("/config/reload", web::post().to(reload))
+        .route("/users/{id}/roles", web::get().to(user_roles))
+        .route("/users/{id}/roles/{role}", web::put().to(grant_role))
+        .route("/users/{id}/roles/{role}", web::delete().to(revoke_role))
+        .route("/users/{id}/api-keys", web::get().to(api_keys))
+        .route("/users/{id}/api-keys", web::post().to(create_api_key))
+        .route("/users/{id}/api-keys/{key}", web::delete().to(delete_api_key))
+        .route("/clients", web::post().to(create_client))
+        .route("/clients/{client_id}", web::delete().to(delete_client))
+}
+
+/// Error response unless the request is made by an admin, with a session, a bearer token or an
+/// API key scoped to `admin`.
+async fn require_admin(
+    req: &HttpRequest,
+    repository: Arc<dyn UserRepository>,
+    cache: Arc<dyn UserRepository>,
+) -> Result<(), HttpResponse> {
+    let cookie = req.cookie("session");
+    let credential = credential(req.headers(), cookie.as_ref().map(|cookie| cookie.value()));
+    match authenticate(repository, cache, credential).await {
+        Ok((user, _)) if user.is_admin() => Ok(()),
+        Ok(_) => Err(error_response(req, StatusCode::FORBIDDEN, "Insuficient permissions")),
+        Err(_) => Err(error_response(req, StatusCode::UNAUTHORIZED, "Need authentication")),
+    }
+}
+
+/// Configuration in use, along with its version.
+///
+/// ```text
+/// curl --cookie session=... localhost:8000/admin/config
+/// ```
+#[inject]
+async fn active_config(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository, cache).await {
+        return response;
+    }
+    HttpResponse::Ok().json(&*get_config())
+}
+
+/// Reload the configuration file, answering the version in use afterwards, or why the file was
+/// rejected.
+#[inject]
+async fn reload(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository, cache).await {
+        return response;
+    }
+    match reload_config("admin") {
+        Ok(version) => HttpResponse::Ok().json(version),
+        Err(err) => error_response(&req, StatusCode::UNPROCESSABLE_ENTITY, &err.to_string()),
+    }
+}
+
+/// Roles of a user, the `admin` flag being set apart from them.
+#[derive(Serialize)]
+struct UserRoles {
+    id: String,
+    admin: bool,
+    roles: Vec<String>,
+}
+
+/// Roles of the user of `id`, along with the users repository, read again rather than from the
+/// cache for the roles to be up to date.
+async fn roles_response(
+    req: &HttpRequest,
+    repository: &Arc<dyn UserRepository>,
+    cache: &Arc<dyn UserRepository>,
+    id: Uuid,
+) -> HttpResponse {
+    match repository.get_user(id).await {
+        Some(user) => {
+            // Other gateway instances see the change once their cache entry expires
+            let _res = cache.create_user(&user).await;
+            HttpResponse::Ok().json(UserRoles {
+                id: user.id.to_string(),
+                admin: user.admin,
+                roles: user.roles,
+            })
+        }
+        None => error_response(req, StatusCode::NOT_FOUND, "Unknown user"),
+    }
+}
+
+/// ```text
+/// curl --cookie session=... localhost:8000/admin/users/{id}/roles
+/// ```
+#[inject]
+async fn user_roles(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache.clone()).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    };
+    roles_response(&req, &repository, &cache, id).await
+}
+
+/// Grant a role to a user, answering its roles.
+///
+/// ```text
+/// curl -X PUT --cookie session=... localhost:8000/admin/users/{id}/roles/editor
+/// ```
+#[inject]
+async fn grant_role(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache.clone()).await {
+        return response;
+    }
+    let (id, role) = path.into_inner();
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    };
+    if !valid_role(&role) {
+        let detail = "Roles are 1 to 64 letters, digits or `_-.:`";
+        return error_response(&req, StatusCode::BAD_REQUEST, detail);
+    }
+    if repository.get_user(id).await.is_none() {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    }
+    if repository.grant_role(id, &role).await.is_err() {
+        let detail = "Could not grant the role";
+        return error_response(&req, StatusCode::SERVICE_UNAVAILABLE, detail);
+    }
+    tracing::info!(user = %id, role, "role granted");
+    roles_response(&req, &repository, &cache, id).await
+}
+
+/// Revoke a role of a user, answering its roles.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8000/admin/users/{id}/roles/editor
+/// ```
+#[inject]
+async fn revoke_role(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache.clone()).await {
+        return response;
+    }
+    let (id, role) = path.into_inner();
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    };
+    if repository.revoke_role(id, &role).await.is_err() {
+        let detail = "Could not revoke the role";
+        return error_response(&req, StatusCode::SERVICE_UNAVAILABLE, detail);
+    }
+    tracing::info!(user = %id, role, "role revoked");
+    roles_response(&req, &repository, &cache, id).await
+}
+
+/// API keys of a user, without the keys.
+///
+/// ```text
+/// curl --cookie session=... localhost:8000/admin/users/{id}/api-keys
+/// ```
+#[inject]
+async fn api_keys(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    };
+    match authn::list_api_keys(repository.as_ref(), id).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Create an API key for a user, answering the key, which is not shown again.
+///
+/// ```text
+/// curl --cookie session=... localhost:8000/admin/users/{id}/api-keys \
+///     -H 'Content-Type: application/json' \
+///     -d '{"name": "backup script", "scopes": ["editor"], "expires_in_days": 90}'
+/// ```
+#[inject]
+async fn create_api_key(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    id: web::Path<String>,
+    request: web::Json<NewApiKey>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown user");
+    };
+    match authn::create_api_key(repository.as_ref(), id, request.into_inner()).await {
+        Ok(key) => {
+            tracing::info!(user = %id, key = key.info.id, "API key created");
+            HttpResponse::Created().json(key)
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Delete an API key of a user, which stops working at once.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8000/admin/users/{id}/api-keys/{key}
+/// ```
+#[inject]
+async fn delete_api_key(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache).await {
+        return response;
+    }
+    let (id, key) = path.into_inner();
+    let (Ok(id), Ok(key)) = (Uuid::parse_str(&id), Uuid::parse_str(&key)) else {
+        return error_response(&req, StatusCode::NOT_FOUND, "Unknown API key");
+    };
+    match authn::delete_api_key(repository.as_ref(), id, key).await {
+        Ok(()) => {
+            tracing::info!(user = %id, %key, "API key deleted");
+            HttpResponse::NoContent().finish()
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Create a machine client and its service principal, answering the client secret, which is not
+/// shown again.
+///
+/// ```text
+/// curl --cookie session=... localhost:8000/admin/clients -H 'Content-Type: application/json' \
+///     -d '{"name": "billing", "roles": ["invoicer"]}'
+/// ```
+#[inject]
+async fn create_client(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    request: web::Json<NewClient>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache).await {
+        return response;
+    }
+    match authn::create_client(repository.as_ref(), request.into_inner()).await {
+        Ok(client) => {
+            tracing::info!(client = client.client_id, user = client.user_id, "client created");
+            HttpResponse::Created().json(client)
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Delete a machine client, revoking the tokens it was issued.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8000/admin/clients/{client_id}
+/// ```
+#[inject]
+async fn delete_client(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    client_id: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) = require_admin(&req, repository.clone(), cache.clone()).await {
+        return response;
+    }
+    match authn::delete_client(repository.as_ref(), &client_id).await {
+        Ok(principal) => {
+            // Other gateway instances see the revocation once their cache entry expires
+            let _res = cache.create_user(&principal).await;
+            tracing::info!(client = %client_id, "client deleted");
+            HttpResponse::NoContent().finish()
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
@@ -0,0 +1,681 @@
+//! Authentication of the requests besides the session cookie: bearer tokens, long-lived API keys
+//! of the users, and machine clients getting tokens with the client credentials grant.
+//!
+//! API keys and client secrets are random and long, so they are stored as their SHA-256, a slow
+//! hash like the one of the passwords bringing nothing but latency to every request.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jwt_simple::prelude::{Claims, Duration, MACLike};
+use jwt_simple::reexports::ct_codecs::{Base64, Decoder};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::accounts::Refused;
+use crate::authz::valid_role;
+use crate::routing::Headers;
+use crate::schemas::{ApiKey, Client, SessionClaims, User};
+use crate::store_interface::UserRepository;
+use crate::stores::config::get_key;
+
+/// Start of the API keys, telling them apart from the bearer tokens.
+pub const API_KEY_PREFIX: &str = "gwk_";
+/// Start of the ids of the machine clients.
+pub const CLIENT_ID_PREFIX: &str = "gwc_";
+pub const CLIENT_TOKEN_TTL_SECS: u64 = 3600;
+// API keys expire after 10 years at most
+const MAX_API_KEY_DAYS: u32 = 3650;
+
+/// How the user of a request authenticated, told to the services in `X-Auth-Method`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthMethod {
+    /// Session cookie of a browser.
+    Session,
+    /// Session token sent in `Authorization: Bearer`.
+    Bearer,
+    ApiKey,
+    /// Token of a machine client, sent in `Authorization: Bearer`.
+    ClientCredentials,
+}
+
+impl AuthMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Session => "session",
+            Self::Bearer => "bearer",
+            Self::ApiKey => "api_key",
+            Self::ClientCredentials => "client_credentials",
+        }
+    }
+}
+
+/// Credential a request authenticates with.
+#[derive(Debug, PartialEq)]
+pub enum Credential {
+    Session(String),
+    Bearer(String),
+    ApiKey(String),
+}
+
+/// Token of an `Authorization: Bearer` header.
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    Some(token.trim()).filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+/// Credential of a request: its `Authorization: Bearer` token or API key, else its `X-Api-Key`,
+/// else its session cookie.
+pub fn credential(headers: &dyn Headers, session_cookie: Option<&str>) -> Option<Credential> {
+    if let Some(token) = headers.get_str("authorization").and_then(bearer_token) {
+        return Some(match token.starts_with(API_KEY_PREFIX) {
+            true => Credential::ApiKey(token.to_owned()),
+            false => Credential::Bearer(token.to_owned()),
+        });
+    }
+    if let Some(key) = headers.get_str("x-api-key").filter(|key| !key.is_empty()) {
+        return Some(Credential::ApiKey(key.to_owned()));
+    }
+    session_cookie
+        .filter(|cookie| !cookie.is_empty())
+        .map(|cookie| Credential::Session(cookie.to_owned()))
+}
+
+/// SHA-256 of a secret, hex encoded.
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn check_name(name: &str) -> Result<String, Refused> {
+    let name = name.trim();
+    if !(1..=64).contains(&name.chars().count()) || name.chars().any(char::is_control) {
+        let detail = "Names have 1 to 64 characters";
+        return Err(Refused::new(StatusCode::UNPROCESSABLE_ENTITY, detail));
+    }
+    Ok(name.to_owned())
+}
+
+fn check_roles(roles: &[String]) -> Result<(), Refused> {
+    if let Some(role) = roles.iter().find(|role| !valid_role(role)) {
+        let detail = format!("`{role}` is not a valid role");
+        return Err(Refused::new(StatusCode::UNPROCESSABLE_ENTITY, detail));
+    }
+    Ok(())
+}
+
+/// Body of the API key creation requests.
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    /// Roles of the user the key may use, none by default.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Never expiring when unset.
+    #[serde(default)]
+    pub expires_in_days: Option<u32>,
+}
+
+/// API key as listed to the admins, without the key.
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        Self {
+            id: key.id.to_string(),
+            name: key.name.clone(),
+            prefix: key.prefix.clone(),
+            scopes: key.scopes.clone(),
+            expires_at: key.expires_at,
+            created_at: key.created_at,
+        }
+    }
+}
+
+/// API key just created, the only time the key is shown.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    pub key: String,
+}
+
+/// Create an API key for the user of `user_id`.
+pub async fn create_api_key<R>(
+    repository: &R,
+    user_id: Uuid,
+    request: NewApiKey,
+) -> Result<CreatedApiKey, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let name = check_name(&request.name)?;
+    check_roles(&request.scopes)?;
+    if let Some(days) = request.expires_in_days {
+        if !(1..=MAX_API_KEY_DAYS).contains(&days) {
+            let detail = format!("Keys expire in 1 to {MAX_API_KEY_DAYS} days");
+            return Err(Refused::new(StatusCode::UNPROCESSABLE_ENTITY, detail));
+        }
+    }
+    if repository.get_user(user_id).await.is_none() {
+        return Err(Refused::new(StatusCode::NOT_FOUND, "Unknown user"));
+    }
+    let key = format!("{API_KEY_PREFIX}{}", random_string(40));
+    let created_at = now_secs();
+    let api_key = ApiKey {
+        id: Uuid::new_v4(),
+        user_id,
+        name,
+        prefix: key[..API_KEY_PREFIX.len() + 6].to_owned(),
+        key_hash: hash_secret(&key),
+        scopes: request.scopes,
+        expires_at: request
+            .expires_in_days
+            .map(|days| created_at + i64::from(days) * 86400),
+        created_at,
+    };
+    repository
+        .create_api_key(&api_key)
+        .await
+        .map_err(|_| Refused::storage())?;
+    Ok(CreatedApiKey {
+        info: ApiKeyInfo::from(&api_key),
+        key,
+    })
+}
+
+pub async fn list_api_keys<R>(repository: &R, user_id: Uuid) -> Result<Vec<ApiKeyInfo>, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let keys = repository
+        .list_api_keys(user_id)
+        .await
+        .map_err(|_| Refused::storage())?;
+    Ok(keys.iter().map(ApiKeyInfo::from).collect())
+}
+
+pub async fn delete_api_key<R>(repository: &R, user_id: Uuid, id: Uuid) -> Result<(), Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    match repository.delete_api_key(user_id, id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Refused::new(StatusCode::NOT_FOUND, "Unknown API key")),
+        Err(()) => Err(Refused::storage()),
+    }
+}
+
+/// User of an API key, unless expired, holding the roles of the key scopes only.
+pub async fn api_key_user<R>(repository: &R, key: &str) -> Option<User>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let key = repository.find_api_key(&hash_secret(key)).await?;
+    if key
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now_secs())
+    {
+        return None;
+    }
+    let mut user = repository.get_user(key.user_id).await?;
+    // Admins keep their rights through the `admin` scope only
+    user.admin = user.is_admin() && key.scopes.iter().any(|scope| scope == "admin");
+    user.roles.retain(|role| key.scopes.contains(role));
+    Some(user)
+}
+
+/// Body of the machine client creation requests.
+#[derive(Deserialize)]
+pub struct NewClient {
+    pub name: String,
+    /// Roles of the service principal of the client.
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// Machine client just created, the only time its secret is shown.
+#[derive(Serialize)]
+pub struct CreatedClient {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Service principal the requests of the client are made as.
+    pub user_id: String,
+    pub name: String,
+    pub roles: Vec<String>,
+}
+
+/// Create a machine client along with its service principal.
+pub async fn create_client<R>(repository: &R, request: NewClient) -> Result<CreatedClient, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    let name = check_name(&request.name)?;
+    check_roles(&request.roles)?;
+    let principal = User {
+        id: Uuid::new_v4(),
+        admin: false,
+        session_version: 0,
+        roles: Vec::new(),
+    };
+    let client_secret = random_string(48);
+    let client = Client {
+        client_id: format!("{CLIENT_ID_PREFIX}{}", random_string(20)),
+        user_id: principal.id,
+        name,
+        secret_hash: hash_secret(&client_secret),
+    };
+    repository
+        .create_client(&principal, &client)
+        .await
+        .map_err(|_| Refused::storage())?;
+    for role in &request.roles {
+        repository
+            .grant_role(principal.id, role)
+            .await
+            .map_err(|_| Refused::storage())?;
+    }
+    Ok(CreatedClient {
+        client_id: client.client_id,
+        client_secret,
+        user_id: principal.id.to_string(),
+        name: client.name,
+        roles: request.roles,
+    })
+}
+
+/// Delete a machine client, revoking the tokens it was issued, answering its service principal.
+pub async fn delete_client<R>(repository: &R, client_id: &str) -> Result<User, Refused>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    match repository.delete_client(client_id).await {
+        Ok(Some(user_id)) => repository
+            .revoke_sessions(user_id)
+            .await
+            .map_err(|_| Refused::storage()),
+        Ok(None) => Err(Refused::new(StatusCode::NOT_FOUND, "Unknown client")),
+        Err(()) => Err(Refused::storage()),
+    }
+}
+
+/// Body of the token requests, form encoded. Clients authenticate with HTTP basic or with
+/// `client_id` and `client_secret`.
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+}
+
+/// Why a token request is refused, answered as `{"error": code}` (RFC 6749, section 5.2).
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    InvalidRequest,
+    InvalidClient,
+    UnsupportedGrantType,
+}
+
+impl TokenError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest | Self::UnsupportedGrantType => StatusCode::BAD_REQUEST,
+            Self::InvalidClient => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest => "invalid_request",
+            Self::InvalidClient => "invalid_client",
+            Self::UnsupportedGrantType => "unsupported_grant_type",
+        }
+    }
+}
+
+/// `client_id` and `client_secret` of an `Authorization: Basic` header.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(Base64::decode_to_vec(encoded.trim(), None).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((id.to_owned(), secret.to_owned()))
+}
+
+/// Issue a token to a machine client, `authorization` being the `Authorization` header of the
+/// request.
+pub async fn client_credentials<R>(
+    repository: &R,
+    authorization: Option<&str>,
+    request: TokenRequest,
+) -> Result<TokenResponse, TokenError>
+where
+    R: UserRepository + Sync + ?Sized,
+{
+    if request.grant_type != "client_credentials" {
+        return Err(TokenError::UnsupportedGrantType);
+    }
+    let (client_id, secret) = match authorization.and_then(basic_credentials) {
+        Some(credentials) => credentials,
+        None => request
+            .client_id
+            .zip(request.client_secret)
+            .ok_or(TokenError::InvalidRequest)?,
+    };
+    let client = repository
+        .find_client(&client_id)
+        .await
+        .filter(|client| client.secret_hash == hash_secret(&secret))
+        .ok_or(TokenError::InvalidClient)?;
+    let principal = repository
+        .get_user(client.user_id)
+        .await
+        .ok_or(TokenError::InvalidClient)?;
+    let session = SessionClaims {
+        session_version: principal.session_version,
+        client_id: Some(client.client_id),
+    };
+    let mut claims =
+        Claims::with_custom_claims(session, Duration::from_secs(CLIENT_TOKEN_TTL_SECS));
+    claims.subject = Some(principal.id.to_string());
+    Ok(TokenResponse {
+        access_token: get_key().authenticate(claims).unwrap(),
+        token_type: "Bearer",
+        expires_in: CLIENT_TOKEN_TTL_SECS,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::reexports::ct_codecs::Encoder;
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::*;
+    use crate::accounts::session_claims;
+    use crate::stores::memory::InMemoryUsers;
+
+    fn user(admin: bool, roles: &[&str]) -> User {
+        User {
+            id: Uuid::new_v4(),
+            admin,
+            session_version: 0,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                let value = HeaderValue::from_str(value).unwrap();
+                (HeaderName::from_static(name), value)
+            })
+            .collect()
+    }
+
+    fn new_key(scopes: &[&str], expires_in_days: Option<u32>) -> NewApiKey {
+        NewApiKey {
+            name: "script".to_owned(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            expires_in_days,
+        }
+    }
+
+    fn token_request(client_id: Option<&str>, client_secret: Option<&str>) -> TokenRequest {
+        TokenRequest {
+            grant_type: "client_credentials".to_owned(),
+            client_id: client_id.map(str::to_owned),
+            client_secret: client_secret.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("Bearer"), None);
+        assert_eq!(bearer_token("Basic abc"), None);
+    }
+
+    #[test]
+    fn test_credential() {
+        // API keys are told apart from the tokens by their prefix
+        let sent = headers(&[("authorization", "Bearer gwk_key")]);
+        let expected = Credential::ApiKey("gwk_key".to_owned());
+        assert_eq!(credential(&sent, Some("cookie")), Some(expected));
+
+        let sent = headers(&[("authorization", "Bearer token"), ("x-api-key", "gwk_key")]);
+        let expected = Credential::Bearer("token".to_owned());
+        assert_eq!(credential(&sent, Some("cookie")), Some(expected));
+
+        let sent = headers(&[("authorization", "Basic abc"), ("x-api-key", "gwk_key")]);
+        let expected = Credential::ApiKey("gwk_key".to_owned());
+        assert_eq!(credential(&sent, Some("cookie")), Some(expected));
+
+        let sent = headers(&[("x-api-key", "")]);
+        let expected = Credential::Session("cookie".to_owned());
+        assert_eq!(credential(&sent, Some("cookie")), Some(expected));
+        assert_eq!(credential(&sent, Some("")), None);
+        assert_eq!(credential(&HeaderMap::new(), None), None);
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        let encoded = Base64::encode_to_string("gwc_id:se:cret").unwrap();
+        let expected = Some(("gwc_id".to_owned(), "se:cret".to_owned()));
+        assert_eq!(basic_credentials(&format!("Basic {encoded}")), expected);
+        assert_eq!(basic_credentials(&format!("basic  {encoded}")), expected);
+
+        let encoded = Base64::encode_to_string("no separator").unwrap();
+        assert_eq!(basic_credentials(&format!("Basic {encoded}")), None);
+        assert_eq!(basic_credentials("Basic not base64!"), None);
+        assert_eq!(basic_credentials("Bearer token"), None);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_scopes() {
+        let editor = user(false, &["editor", "viewer"]);
+        let repository = InMemoryUsers::with_users([editor.clone()]);
+
+        let created = create_api_key(&repository, editor.id, new_key(&["editor"], None))
+            .await
+            .unwrap();
+        assert!(created.key.starts_with(API_KEY_PREFIX));
+        assert!(created.key.starts_with(&created.info.prefix));
+        // Only the hash of the key is kept
+        let stored = repository.api_keys.lock().unwrap()[0].clone();
+        assert_eq!(stored.key_hash, hash_secret(&created.key));
+
+        let key_user = api_key_user(&repository, &created.key).await.unwrap();
+        assert_eq!(key_user.id, editor.id);
+        assert_eq!(key_user.roles, vec!["editor"]);
+        assert!(api_key_user(&repository, "gwk_unknown").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_admin_scope() {
+        let flagged = user(true, &["editor"]);
+        let granted = user(false, &["admin", "editor"]);
+        let repository = InMemoryUsers::with_users([flagged.clone(), granted.clone()]);
+
+        for admin in [&flagged, &granted] {
+            let created = create_api_key(&repository, admin.id, new_key(&["editor"], None))
+                .await
+                .unwrap();
+            let key_user = api_key_user(&repository, &created.key).await.unwrap();
+            assert!(!key_user.is_admin());
+            assert_eq!(key_user.roles, vec!["editor"]);
+
+            let scopes = new_key(&["admin", "editor"], None);
+            let created = create_api_key(&repository, admin.id, scopes).await.unwrap();
+            let key_user = api_key_user(&repository, &created.key).await.unwrap();
+            assert!(key_user.is_admin());
+        }
+
+        // The scope alone makes no admin
+        let editor = user(false, &["editor"]);
+        repository.create_user(&editor).await.unwrap();
+        let scopes = new_key(&["admin", "editor"], None);
+        let created = create_api_key(&repository, editor.id, scopes)
+            .await
+            .unwrap();
+        let key_user = api_key_user(&repository, &created.key).await.unwrap();
+        assert!(!key_user.is_admin());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_expiry() {
+        let editor = user(false, &["editor"]);
+        let repository = InMemoryUsers::with_users([editor.clone()]);
+
+        let created = create_api_key(&repository, editor.id, new_key(&[], Some(30)))
+            .await
+            .unwrap();
+        let expires_at = created.info.expires_at.unwrap();
+        assert_eq!(expires_at, created.info.created_at + 30 * 86400);
+        assert!(api_key_user(&repository, &created.key).await.is_some());
+
+        repository.api_keys.lock().unwrap()[0].expires_at = Some(now_secs() - 1);
+        assert!(api_key_user(&repository, &created.key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_refused() {
+        let editor = user(false, &["editor"]);
+        let repository = InMemoryUsers::with_users([editor.clone()]);
+
+        for days in [0, MAX_API_KEY_DAYS + 1] {
+            let refused = create_api_key(&repository, editor.id, new_key(&[], Some(days)))
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let refused = create_api_key(&repository, editor.id, new_key(&["not a role"], None))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let refused = create_api_key(&repository, Uuid::new_v4(), new_key(&[], None))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused.status, StatusCode::NOT_FOUND);
+        assert!(repository.api_keys.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials() {
+        let repository = InMemoryUsers::default();
+        let request = NewClient {
+            name: "billing".to_owned(),
+            roles: vec!["invoicer".to_owned()],
+        };
+        let client = create_client(&repository, request).await.unwrap();
+        assert!(client.client_id.starts_with(CLIENT_ID_PREFIX));
+        let principal = Uuid::parse_str(&client.user_id).unwrap();
+        let roles = repository.get_user(principal).await.unwrap().roles;
+        assert_eq!(roles, vec!["invoicer"]);
+
+        let basic = format!("{}:{}", client.client_id, client.client_secret);
+        let basic = format!("Basic {}", Base64::encode_to_string(basic).unwrap());
+        let token = client_credentials(&repository, Some(&basic), token_request(None, None))
+            .await
+            .unwrap();
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.expires_in, CLIENT_TOKEN_TTL_SECS);
+        let (subject, claims) = session_claims(&token.access_token).unwrap();
+        assert_eq!(subject, principal);
+        assert_eq!(claims.client_id.as_deref(), Some(client.client_id.as_str()));
+
+        // Or with the credentials in the form
+        let request = token_request(Some(&client.client_id), Some(&client.client_secret));
+        assert!(client_credentials(&repository, None, request).await.is_ok());
+
+        // Deleting the client revokes its tokens
+        let revoked = delete_client(&repository, &client.client_id).await.unwrap();
+        assert!(revoked.session_version > claims.session_version);
+        let request = token_request(Some(&client.client_id), Some(&client.client_secret));
+        let refused = client_credentials(&repository, None, request).await;
+        assert_eq!(refused.err(), Some(TokenError::InvalidClient));
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials_refused() {
+        let repository = InMemoryUsers::default();
+        let request = NewClient {
+            name: "billing".to_owned(),
+            roles: Vec::new(),
+        };
+        let client = create_client(&repository, request).await.unwrap();
+
+        let mut request = token_request(Some(&client.client_id), Some(&client.client_secret));
+        request.grant_type = "password".to_owned();
+        let refused = client_credentials(&repository, None, request)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused, TokenError::UnsupportedGrantType);
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(refused.code(), "unsupported_grant_type");
+
+        let request = token_request(Some(&client.client_id), None);
+        let refused = client_credentials(&repository, None, request)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(refused, TokenError::InvalidRequest);
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(refused.code(), "invalid_request");
+
+        for (client_id, secret) in [
+            (client.client_id.as_str(), "wrong secret"),
+            ("gwc_unknown", client.client_secret.as_str()),
+        ] {
+            let request = token_request(Some(client_id), Some(secret));
+            let refused = client_credentials(&repository, None, request)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(refused, TokenError::InvalidClient);
+            assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(refused.code(), "invalid_client");
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Upstream pools: routes naming an `upstream` are balanced across its endpoints, the failing
+//! ones being set aside by active health checks and passive outlier detection.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::sleep;
+use arc_swap::ArcSwap;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::DiscoveryConfig;
+use crate::metrics::{UPSTREAM_EJECTIONS_TOTAL, UPSTREAM_ENDPOINT_HEALTHY};
+use crate::resilience::CircuitBreakerConfig;
+use crate::stores::config::get_config;
+
+/// How a pool picks the endpoint of a request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// Endpoint with the fewest requests waiting for their response head.
+    LeastOutstanding,
+    /// Same endpoint for the requests of a user, as long as it is available.
+    ConsistentHash,
+}
+
+/// Requests sent to every endpoint on their own, those failing `unhealthy_threshold` times in a
+/// row being set aside until they succeed `healthy_threshold` times in a row.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// Path answering a 2xx when the endpoint is healthy.
+    pub path: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            path: "/health".to_owned(),
+            interval_secs: 10,
+            timeout_secs: 2,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+/// Endpoints failing `consecutive_failures` proxied requests in a row, with a 5xx or no response,
+/// are ejected for `ejection_secs`. At most `max_ejection_percent` of a pool is ejected at once.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OutlierDetectionConfig {
+    /// 0 disables the detection.
+    pub consecutive_failures: u32,
+    pub ejection_secs: u64,
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetectionConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            ejection_secs: 30,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+/// Named pool of instances of a service, in the `upstreams` section of `routes.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpstreamConfig {
+    /// Where the instances are found, the `endpoints` listed by default.
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    /// `host:port` of every instance, when listed statically.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub outlier_detection: OutlierDetectionConfig,
+    /// Replaces the `resilience` default for this upstream.
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl UpstreamConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.discovery {
+            DiscoveryConfig::Static if self.endpoints.is_empty() => {
+                return Err("no endpoint is listed".to_owned());
+            }
+            DiscoveryConfig::DnsSrv { name } if name.is_empty() => {
+                return Err("no SRV record name is set".to_owned());
+            }
+            _ => {}
+        }
+        if let Some(endpoint) = self
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.is_empty() || endpoint.contains('/'))
+        {
+            return Err(format!("endpoint `{endpoint}` must be a `host:port`"));
+        }
+        if let Some(check) = &self.health_check {
+            if !check.path.starts_with('/') {
+                return Err(format!(
+                    "health check path `{}` must start with `/`",
+                    check.path
+                ));
+            }
+            if check.interval_secs == 0 || check.timeout_secs == 0 {
+                return Err("health check interval and timeout must be positive".to_owned());
+            }
+            if check.healthy_threshold == 0 || check.unhealthy_threshold == 0 {
+                return Err("health check thresholds must be positive".to_owned());
+            }
+        }
+        if self.outlier_detection.max_ejection_percent > 100 {
+            return Err("`max_ejection_percent` must be at most 100".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct EndpointState {
+    /// Failing its health checks.
+    unhealthy: bool,
+    check_successes: u32,
+    check_failures: u32,
+    last_check: Option<Instant>,
+    /// Proxied requests failed in a row.
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl EndpointState {
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Instance of a service. Its state is kept across configuration reloads listing it again.
+#[derive(Debug)]
+pub struct Endpoint {
+    pub address: String,
+    outstanding: AtomicUsize,
+    state: Mutex<EndpointState>,
+}
+
+impl Endpoint {
+    fn new(address: &str) -> Self {
+        Self {
+            address: address.to_owned(),
+            outstanding: AtomicUsize::new(0),
+            state: Mutex::default(),
+        }
+    }
+
+    fn available(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.unhealthy && !state.ejected(now)
+    }
+
+    fn check_due(&self, interval: Duration, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .last_check
+            .is_none_or(|last| now.duration_since(last) >= interval);
+        if due {
+            state.last_check = Some(now);
+        }
+        due
+    }
+}
+
+/// Endpoints of an upstream, listed in its configuration or found by discovery.
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub config: UpstreamConfig,
+    endpoints: ArcSwap<Vec<Arc<Endpoint>>>,
+    next: AtomicUsize,
+    refreshed: Mutex<Option<Instant>>,
+}
+
+/// Endpoints at `addresses`, the `current` ones being kept along with their state.
+fn endpoints_at(addresses: &[String], current: &[Arc<Endpoint>]) -> Vec<Arc<Endpoint>> {
+    addresses
+        .iter()
+        .map(|address| {
+            current
+                .iter()
+                .find(|endpoint| &endpoint.address == address)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Endpoint::new(address)))
+        })
+        .collect()
+}
+
+impl Pool {
+    /// Pool of `config`, reusing the endpoints of `previous` still listed along with their state.
+    /// Discovered pools start with the endpoints `previous` found, until discovered again.
+    pub fn new(name: &str, config: &UpstreamConfig, previous: Option<&Pool>) -> Self {
+        let current = previous.map(Pool::endpoints).unwrap_or_default();
+        let endpoints = match config.discovery {
+            DiscoveryConfig::Static => endpoints_at(&config.endpoints, &current),
+            _ => current.to_vec(),
+        };
+        Self {
+            name: name.to_owned(),
+            config: config.clone(),
+            endpoints: ArcSwap::from_pointee(endpoints),
+            next: AtomicUsize::new(0),
+            refreshed: Mutex::default(),
+        }
+    }
+
+    pub fn endpoints(&self) -> Arc<Vec<Arc<Endpoint>>> {
+        self.endpoints.load_full()
+    }
+
+    /// Replace the endpoints by the ones at `addresses`, telling whether they changed.
+    pub fn set_endpoints(&self, addresses: &[String]) -> bool {
+        let current = self.endpoints();
+        let unchanged = current.len() == addresses.len()
+            && current
+                .iter()
+                .all(|endpoint| addresses.contains(&endpoint.address));
+        if !unchanged {
+            self.endpoints
+                .store(Arc::new(endpoints_at(addresses, &current)));
+        }
+        !unchanged
+    }
+
+    /// Whether the endpoints are to be discovered again, `interval` after the last time.
+    pub fn refresh_due(&self, interval: Duration, now: Instant) -> bool {
+        let mut refreshed = self.refreshed.lock().unwrap();
+        let due = refreshed.is_none_or(|last| now.duration_since(last) >= interval);
+        if due {
+            *refreshed = Some(now);
+        }
+        due
+    }
+
+    /// Endpoint to send a request of `user` to, `None` when none is available.
+    pub fn pick(self: &Arc<Self>, user: &str) -> Option<Lease> {
+        let now = Instant::now();
+        let endpoints = self.endpoints();
+        let available: Vec<&Arc<Endpoint>> = endpoints
+            .iter()
+            .filter(|endpoint| endpoint.available(now))
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let endpoint = match self.config.strategy {
+            Strategy::RoundRobin => available[start % available.len()],
+            // Ties are broken in turn, for idle pools to be balanced as well
+            Strategy::LeastOutstanding => (0..available.len())
+                .map(|offset| available[(start + offset) % available.len()])
+                .min_by_key(|endpoint| endpoint.outstanding.load(Ordering::Relaxed))?,
+            // Rendezvous hashing: only the users of an endpoint leaving the pool move
+            Strategy::ConsistentHash => available.iter().copied().max_by_key(|endpoint| {
+                let mut hasher = DefaultHasher::new();
+                (&endpoint.address, user).hash(&mut hasher);
+                hasher.finish()
+            })?,
+        };
+        endpoint.outstanding.fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            pool: self.clone(),
+            endpoint: endpoint.clone(),
+        })
+    }
+
+    /// Eject `endpoint` unless the pool already has its share of ejected endpoints.
+    fn eject(&self, endpoint: &Endpoint, now: Instant) {
+        let outlier = &self.config.outlier_detection;
+        // Locked one at a time, for concurrent ejections not to wait on each other
+        let endpoints = self.endpoints();
+        let ejected = endpoints
+            .iter()
+            .filter(|other| other.state.lock().unwrap().ejected(now))
+            .count();
+        if (ejected + 1) * 100 > outlier.max_ejection_percent as usize * endpoints.len() {
+            return;
+        }
+        let mut state = endpoint.state.lock().unwrap();
+        state.ejected_until = Some(now + Duration::from_secs(outlier.ejection_secs));
+        state.failures = 0;
+        drop(state);
+        UPSTREAM_EJECTIONS_TOTAL
+            .with_label_values(&[&self.name, &endpoint.address])
+            .inc();
+        tracing::warn!(
+            upstream = self.name,
+            endpoint = endpoint.address,
+            seconds = outlier.ejection_secs,
+            "endpoint ejected after failing requests in a row"
+        );
+    }
+}
+
+/// Endpoint picked for a request, counted as outstanding until dropped.
+pub struct Lease {
+    pool: Arc<Pool>,
+    endpoint: Arc<Endpoint>,
+}
+
+impl Lease {
+    pub fn address(&self) -> &str {
+        &self.endpoint.address
+    }
+
+    /// Outcome of the request, failures being a 5xx or no response at all.
+    pub fn report(&self, success: bool) {
+        let threshold = self.pool.config.outlier_detection.consecutive_failures;
+        let now = Instant::now();
+        let mut state = self.endpoint.state.lock().unwrap();
+        if success {
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        // Requests sent before an ejection may still fail afterwards
+        let outlier = threshold > 0 && state.failures >= threshold && !state.ejected(now);
+        drop(state);
+        if outlier {
+            self.pool.eject(&self.endpoint, now);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.endpoint.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Pools of the upstreams of `config`, reusing the endpoints of the `previous` ones.
+pub fn build_pools(
+    upstreams: &HashMap<String, UpstreamConfig>,
+    previous: &HashMap<String, Arc<Pool>>,
+) -> HashMap<String, Arc<Pool>> {
+    upstreams
+        .iter()
+        .map(|(name, config)| {
+            let pool = Pool::new(name, config, previous.get(name).map(Arc::as_ref));
+            (name.clone(), Arc::new(pool))
+        })
+        .collect()
+}
+
+async fn check(
+    client: &reqwest::Client,
+    pool: &Pool,
+    check: &HealthCheckConfig,
+    endpoint: Arc<Endpoint>,
+) {
+    let url = format!("http://{}{}", endpoint.address, check.path);
+    let healthy = client
+        .get(url)
+        .timeout(Duration::from_secs(check.timeout_secs))
+        .send()
+        .await
+        .is_ok_and(|response| response.status().is_success());
+    let mut state = endpoint.state.lock().unwrap();
+    if healthy {
+        state.check_failures = 0;
+        state.check_successes += 1;
+    } else {
+        state.check_successes = 0;
+        state.check_failures += 1;
+    }
+    let changed = if state.unhealthy && state.check_successes >= check.healthy_threshold {
+        state.unhealthy = false;
+        true
+    } else if !state.unhealthy && state.check_failures >= check.unhealthy_threshold {
+        state.unhealthy = true;
+        true
+    } else {
+        false
+    };
+    UPSTREAM_ENDPOINT_HEALTHY
+        .with_label_values(&[&pool.name, &endpoint.address])
+        .set(i64::from(!state.unhealthy));
+    if changed && state.unhealthy {
+        tracing::warn!(
+            upstream = pool.name,
+            endpoint = endpoint.address,
+            "endpoint failing its health checks, set aside"
+        );
+    } else if changed {
+        tracing::info!(
+            upstream = pool.name,
+            endpoint = endpoint.address,
+            "endpoint healthy again, back in the pool"
+        );
+    }
+}
+
+// How often the endpoints due for a check are looked for
+const HEALTH_CHECK_TICK: Duration = Duration::from_secs(1);
+
+/// Run the health checks of the upstreams of the configuration in use, forever.
+pub async fn run_health_checks() {
+    let client = reqwest::Client::new();
+    let client = &client;
+    loop {
+        let config = get_config();
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for pool in config.pools.values() {
+            let Some(health_check) = &pool.config.health_check else {
+                continue;
+            };
+            let interval = Duration::from_secs(health_check.interval_secs);
+            for endpoint in pool.endpoints().iter() {
+                if endpoint.check_due(interval, now) {
+                    due.push(check(client, pool, health_check, endpoint.clone()));
+                }
+            }
+        }
+        join_all(due).await;
+        sleep(HEALTH_CHECK_TICK).await;
+    }
+}
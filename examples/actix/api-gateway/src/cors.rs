@@ -0,0 +1,216 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{
+            HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        Method, StatusCode,
+    },
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::problem::error_response;
+use crate::routing::RequestInfo;
+use crate::stores::config::get_config;
+
+/// Cross-origin requests of browsers, in the `cors` section of `routes.yml`. No origin is allowed
+/// by default.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the gateway, e.g. `https://app.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Request headers allowed on top of the CORS-safelisted ones, or `*` for any.
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the scripts on top of the CORS-safelisted ones.
+    pub exposed_headers: Vec<String>,
+    /// Whether the session cookie is sent along with the requests.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.allowed_headers
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// Headers granting `origin` access to a response.
+fn set_headers(headers: &mut HeaderMap, config: &CorsConfig, origin: &HeaderValue) {
+    // Origins are echoed rather than `*`, which browsers refuse along with credentials
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+    if config.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if !config.exposed_headers.is_empty() {
+        headers.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            header_value(&config.exposed_headers.join(", ")),
+        );
+    }
+}
+
+/// Middleware answering the CORS preflight requests of the allowed origins, and granting them
+/// access to the responses of the gateway and of the proxied services.
+///
+/// Preflights are answered without authentication, browsers sending them without cookies, with
+/// the methods of the routes matching their path. Requests from other origins go through
+/// untouched, browsers refusing their responses to the scripts.
+#[derive(Clone)]
+pub struct Cors;
+
+impl<S, B> Transform<S, ServiceRequest> for Cors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Kept along the request, for the preflight to see the origins it was allowed by
+        let config = get_config();
+        let origin = req
+            .headers()
+            .get(ORIGIN)
+            .filter(|origin| config.cors.allows_origin(origin.to_str().unwrap_or_default()))
+            .cloned();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let Some(origin) = origin else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+            let requested_method = req
+                .headers()
+                .get(ACCESS_CONTROL_REQUEST_METHOD)
+                .and_then(|method| method.to_str().ok())
+                .map(str::to_owned);
+            let Some(requested_method) =
+                requested_method.filter(|_| req.method() == Method::OPTIONS)
+            else {
+                let mut res = service.call(req).await?;
+                set_headers(res.headers_mut(), &config.cors, &origin);
+                return Ok(res.map_into_left_body());
+            };
+
+            let (req, _) = req.into_parts();
+            let methods = config.allowed_methods(&RequestInfo::new(
+                req.method().as_str(),
+                req.path(),
+                req.query_string(),
+                req.headers(),
+            ));
+            let requested_headers = req
+                .headers()
+                .get(ACCESS_CONTROL_REQUEST_HEADERS)
+                .and_then(|headers| headers.to_str().ok())
+                .unwrap_or_default();
+            let mut response = if !methods.contains(&requested_method) {
+                let detail = format!("{requested_method} is not allowed on this path");
+                error_response(&req, StatusCode::FORBIDDEN, &detail)
+            } else if !config.cors.allows_headers(requested_headers) {
+                error_response(&req, StatusCode::FORBIDDEN, "Request headers not allowed")
+            } else {
+                let mut response = HttpResponse::NoContent().finish();
+                let headers = response.headers_mut();
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_METHODS,
+                    header_value(&methods.join(", ")),
+                );
+                if !requested_headers.is_empty() {
+                    headers.insert(
+                        ACCESS_CONTROL_ALLOW_HEADERS,
+                        header_value(requested_headers),
+                    );
+                }
+                headers.insert(
+                    ACCESS_CONTROL_MAX_AGE,
+                    HeaderValue::from(config.cors.max_age_secs),
+                );
+                response
+            };
+            set_headers(response.headers_mut(), &config.cors, &origin);
+            for vary in [
+                "Access-Control-Request-Method",
+                "Access-Control-Request-Headers",
+            ] {
+                response
+                    .headers_mut()
+                    .append(VARY, HeaderValue::from_static(vary));
+            }
+            Ok(ServiceResponse::new(req, response).map_into_right_body())
+        })
+    }
+}
@@ -0,0 +1,242 @@
+//! Endpoints of the upstreams found by service discovery, refreshed in the background.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hickory_resolver::{error::ResolveError, TokioAsyncResolver};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::balancer::UpstreamConfig;
+use crate::metrics::UPSTREAM_DISCOVERED_ENDPOINTS;
+use crate::stores::config::get_config;
+
+/// Where the endpoints of an upstream are found, its `discovery` entry in `routes.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoveryConfig {
+    /// The `endpoints` listed along with the upstream.
+    #[default]
+    Static,
+    /// The targets of the SRV records of `name`, e.g. `_http._tcp.todos.service.consul`, with
+    /// the lowest priority.
+    DnsSrv { name: String },
+    /// The instances registered under `service` in the file registry, the name of the upstream
+    /// by default.
+    File {
+        #[serde(default)]
+        service: Option<String>,
+    },
+}
+
+/// Settings of the discovery, in the `discovery` section of `routes.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    /// How often the endpoints of the discovered upstreams are looked up again.
+    pub refresh_secs: u64,
+    /// Directory of the file registry.
+    pub registry_dir: PathBuf,
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            refresh_secs: 30,
+            registry_dir: PathBuf::from("registry"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    Io(io::Error),
+    Dns(ResolveError),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read the registry: {err}"),
+            Self::Dns(err) => write!(f, "could not look up the SRV records: {err}"),
+        }
+    }
+}
+
+/// Source of the `host:port` of the instances of a service.
+#[async_trait]
+pub trait ServiceDiscovery: Send + Sync {
+    /// Endpoints of `service`, none when no instance is up.
+    async fn resolve(&self, service: &str) -> Result<Vec<String>, DiscoveryError>;
+}
+
+/// Endpoints listed in the configuration, by upstream.
+pub struct StaticDiscovery {
+    endpoints: HashMap<String, Vec<String>>,
+}
+
+impl StaticDiscovery {
+    pub fn new(upstreams: &HashMap<String, UpstreamConfig>) -> Self {
+        let endpoints = upstreams
+            .iter()
+            .map(|(name, config)| (name.clone(), config.endpoints.clone()))
+            .collect();
+        Self { endpoints }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for StaticDiscovery {
+    async fn resolve(&self, service: &str) -> Result<Vec<String>, DiscoveryError> {
+        Ok(self.endpoints.get(service).cloned().unwrap_or_default())
+    }
+}
+
+/// SRV records, looked up with the resolvers of the system.
+pub struct DnsSrvDiscovery {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsSrvDiscovery {
+    pub fn from_system_conf() -> Result<Self, DiscoveryError> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(DiscoveryError::Dns)?;
+        Ok(Self { resolver })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsSrvDiscovery {
+    async fn resolve(&self, service: &str) -> Result<Vec<String>, DiscoveryError> {
+        let lookup = self
+            .resolver
+            .srv_lookup(service)
+            .await
+            .map_err(DiscoveryError::Dns)?;
+        // Lower priorities are only used when no target of a higher one is
+        let Some(priority) = lookup.iter().map(|srv| srv.priority()).min() else {
+            return Ok(Vec::new());
+        };
+        let mut endpoints: Vec<String> = lookup
+            .iter()
+            .filter(|srv| srv.priority() == priority)
+            .map(|srv| {
+                let target = srv.target().to_utf8();
+                format!("{}:{}", target.trim_end_matches('.'), srv.port())
+            })
+            .collect();
+        endpoints.sort();
+        endpoints.dedup();
+        Ok(endpoints)
+    }
+}
+
+/// Registry in a directory, e.g. shared by the containers of a host.
+///
+/// Instances register in a file of `{registry_dir}/{service}/` holding their `host:port`, named
+/// as they like, and deregister by removing it. Files starting with a dot are skipped, for
+/// instances to write a hidden file then rename it.
+pub struct FileRegistry {
+    dir: PathBuf,
+}
+
+impl FileRegistry {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileRegistry {
+    async fn resolve(&self, service: &str) -> Result<Vec<String>, DiscoveryError> {
+        let entries = match std::fs::read_dir(self.dir.join(service)) {
+            Ok(entries) => entries,
+            // No instance registered yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(DiscoveryError::Io(err)),
+        };
+        let mut endpoints = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(DiscoveryError::Io)?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let address = match std::fs::read_to_string(entry.path()) {
+                Ok(address) => address.trim().to_owned(),
+                // Removed since listed
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(DiscoveryError::Io(err)),
+            };
+            if !address.is_empty() {
+                endpoints.push(address);
+            }
+        }
+        endpoints.sort();
+        endpoints.dedup();
+        Ok(endpoints)
+    }
+}
+
+// How often the upstreams due for a refresh are looked for
+const DISCOVERY_TICK: Duration = Duration::from_secs(1);
+
+/// Refresh the endpoints of the upstreams of the configuration in use, forever. Upstreams keep
+/// the endpoints found last when their discovery fails.
+pub async fn run_discovery() {
+    let dns = match DnsSrvDiscovery::from_system_conf() {
+        Ok(dns) => Some(dns),
+        Err(err) => {
+            tracing::error!(%err, "DNS SRV discovery unavailable");
+            None
+        }
+    };
+    loop {
+        let config = get_config();
+        let settings = &config.discovery;
+        let interval = Duration::from_secs(settings.refresh_secs);
+        let listed = StaticDiscovery::new(&config.upstreams);
+        let registry = FileRegistry::new(&settings.registry_dir);
+        let now = Instant::now();
+        for pool in config.pools.values() {
+            if !pool.refresh_due(interval, now) {
+                continue;
+            }
+            let (discovery, service): (&dyn ServiceDiscovery, &str) = match &pool.config.discovery {
+                DiscoveryConfig::Static => (&listed, &pool.name),
+                DiscoveryConfig::DnsSrv { name } => match &dns {
+                    Some(dns) => (dns, name),
+                    None => continue,
+                },
+                DiscoveryConfig::File { service } => {
+                    (&registry, service.as_deref().unwrap_or(&pool.name))
+                }
+            };
+            match discovery.resolve(service).await {
+                Ok(endpoints) => {
+                    UPSTREAM_DISCOVERED_ENDPOINTS
+                        .with_label_values(&[&pool.name])
+                        .set(endpoints.len() as i64);
+                    if pool.set_endpoints(&endpoints) {
+                        tracing::info!(
+                            upstream = pool.name,
+                            ?endpoints,
+                            "upstream endpoints changed"
+                        );
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        upstream = pool.name,
+                        service,
+                        %err,
+                        "could not discover the upstream endpoints"
+                    );
+                }
+            }
+        }
+        sleep(DISCOVERY_TICK).await;
+    }
+}
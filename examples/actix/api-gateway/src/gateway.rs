@@ -1,21 +1,33 @@
-use crate::store_interface::Proxy;
+use crate::accounts::session_claims;
+use crate::authn::{api_key_user, AuthMethod, Credential};
+use crate::authz::identity_headers;
+use crate::resilience::{call_service, ServiceCall};
+use crate::routing::{NoRoute, RequestInfo};
+use crate::schemas::SessionClaims;
 use crate::store_interface::UserRepository;
+use crate::store_interface::{Proxy, UpstreamError, UpstreamResponse};
 use crate::stores::cache::User;
 use crate::stores::config::{self, get_key};
-use bytes::Bytes;
-use jwt_simple::prelude::{Claims, Duration, JWTClaims, MACLike, NoCustomClaims};
-use std::{str::FromStr, sync::Arc};
+use reqwest::header::HeaderMap;
+use jwt_simple::prelude::{Claims, Duration, MACLike};
+use std::sync::Arc;
 use uuid::Uuid;
 
-// Implements proxying any method towards authenticated microservices.
-pub async fn proxy(
+/// Client request to proxy, its headers already filtered for forwarding.
+pub struct ClientRequest<'a> {
+    pub method: &'a reqwest::Method,
+    /// What the route is chosen on, the headers being the ones received.
+    pub target: RequestInfo<'a>,
+    pub headers: HeaderMap,
+    pub body: reqwest::Body,
+}
+
+/// User of a session cookie, as long as it exists.
+pub async fn session_user(
     repository: Arc<dyn UserRepository>,
     cache: Arc<dyn UserRepository>,
-    client: Arc<dyn Proxy>,
-    method: &str,
-    path: &str,
     cookie: Option<String>,
-) -> Result<(Bytes, u16, String), u16> {
+) -> Result<User, u16> {
     // For the purpose of browser testing, we use a session cookie.
     // Not authenticated - Could redirect to a front signup page
     if cookie.is_none() {
@@ -23,23 +35,15 @@ pub async fn proxy(
     }
     // Check token signature
     // In real life, keys should be taken from env, and injected in your production via CI/CD tools
-    let key = get_key();
-
-    let try_claims: Result<JWTClaims<NoCustomClaims>, jwt_simple::Error> =
-        key.verify_token(cookie.unwrap().as_str(), None);
-    if try_claims.is_err() {
+    let Some((user_id, claims)) = session_claims(cookie.unwrap().as_str()) else {
         return Err(401);
-    }
-    let claims = try_claims.unwrap();
-    let try_user_id = claims.subject;
-    if try_user_id.is_none() {
-        return Err(401);
-    }
-    let user_id_str = try_user_id.unwrap();
-    let user_id = Uuid::from_str(&user_id_str).unwrap();
+    };
     // We also verify the user exists. This enable for permission check
-
-    let mut try_user: Option<User> = cache.get_user(user_id.clone()).await;
+    // Users cached before their sessions were revoked are read again
+    let mut try_user: Option<User> = cache
+        .get_user(user_id)
+        .await
+        .filter(|user| user.session_version == claims.session_version);
     if try_user.is_none() {
         let db_user = repository.get_user(user_id.clone()).await;
         if db_user.is_none() {
@@ -48,52 +52,177 @@ pub async fn proxy(
         try_user = db_user;
     }
     let user: User = try_user.unwrap();
+    // Sessions opened before a logout or a password change are revoked
+    if user.session_version != claims.session_version {
+        return Err(401);
+    }
     let _res = cache.create_user(&user).await;
-    // User is authenticated. Cookie jwt could be refreshed starting from here
-
-    // Lazy load of the dynamic routing config file
-    let my_config = config::get_config();
+    Ok(user)
+}
 
-    for route in &my_config.routes {
-        // See config for more details
-        if route.methods.contains(&method.to_owned()) && path.starts_with(&route.prefix) {
-            if !user.admin && route.restrict_admin {
-                return Err(403);
+/// User of the credential of a request, and how it authenticated.
+pub async fn authenticate(
+    repository: Arc<dyn UserRepository>,
+    cache: Arc<dyn UserRepository>,
+    credential: Option<Credential>,
+) -> Result<(User, AuthMethod), u16> {
+    match credential {
+        None => Err(401),
+        Some(Credential::Session(token)) => {
+            // Machine tokens are only bearer tokens, never refreshed as sessions are
+            if session_claims(&token).is_some_and(|(_, claims)| claims.client_id.is_some()) {
+                return Err(401);
             }
-            // Prepare proxy request
+            let user = session_user(repository, cache, Some(token)).await?;
+            Ok((user, AuthMethod::Session))
+        }
+        Some(Credential::Bearer(token)) => {
+            let method = match session_claims(&token) {
+                Some((_, claims)) if claims.client_id.is_some() => AuthMethod::ClientCredentials,
+                _ => AuthMethod::Bearer,
+            };
+            let user = session_user(repository, cache, Some(token)).await?;
+            Ok((user, method))
+        }
+        // Looked up every time, for revoked keys to stop working at once
+        Some(Credential::ApiKey(key)) => api_key_user(repository.as_ref(), &key)
+            .await
+            .map(|user| (user, AuthMethod::ApiKey))
+            .ok_or(401),
+    }
+}
 
-            let stripped_path = path.strip_prefix(&route.prefix).unwrap();
+/// Why a request could not be proxied.
+pub enum ProxyError {
+    /// Refused by the gateway, with this status.
+    Rejected(u16),
+    /// No response from the service.
+    Upstream(UpstreamError),
+}
 
-            // We support proxying variable path this way
-            let (status, resp_bytes) = client
-                .make_request(
-                    method,
-                    format!("http://{}{}", route.service, stripped_path).as_str(),
-                    &user.id,
-                )
-                .await;
-            // Refresh token, to avoid cutting session during browsing
-            let refresh_token = gen_session_token(user_id).await;
-            return Ok((resp_bytes, status, refresh_token));
-        }
+impl From<u16> for ProxyError {
+    fn from(status: u16) -> Self {
+        Self::Rejected(status)
     }
+}
+
+// Implements proxying any method towards authenticated microservices.
+pub async fn proxy(
+    repository: Arc<dyn UserRepository>,
+    cache: Arc<dyn UserRepository>,
+    client: Arc<dyn Proxy>,
+    request: ClientRequest<'_>,
+    credential: Option<Credential>,
+    request_id: &str,
+) -> Result<(UpstreamResponse, Option<String>), ProxyError> {
+    let (user, method) = authenticate(repository, cache, credential).await?;
+    // User is authenticated. Cookie jwt could be refreshed starting from here
+
+    // Lazy load of the dynamic routing config file
+    let my_config = config::get_config();
 
-    Err(404)
+    let found = my_config.find_route(&request.target).map_err(|err| match err {
+        NoRoute::NotFound => 404_u16,
+        NoRoute::MethodNotAllowed => 405_u16,
+    })?;
+    // See config for more details
+    let route = found.route;
+    if !route.authorizes(
+        &user,
+        request.method.as_str(),
+        &my_config.roles,
+        &found.captures,
+    ) {
+        return Err(ProxyError::Rejected(403));
+    }
+    // Services may authorize in turn on the roles of the user
+    let mut headers = request.headers;
+    headers.extend(identity_headers(&user, method));
+    // Prepare proxy request
+    let call = ServiceCall {
+        method: request.method,
+        query: request.target.query,
+        headers,
+        body: request.body,
+        user_id: &user.id,
+        request_id,
+    };
+    let response = call_service(client.as_ref(), &my_config, &found, call)
+        .await
+        .map_err(ProxyError::Upstream)?;
+    // Refresh token, to avoid cutting session during browsing
+    let refresh_token = match method {
+        AuthMethod::Session => Some(gen_session_token(&user).await),
+        _ => None,
+    };
+    Ok((response, refresh_token))
 }
 
-pub async fn gen_user(repository: Arc<dyn UserRepository>) -> Result<Uuid, ()> {
+pub async fn gen_user(repository: Arc<dyn UserRepository>) -> Result<User, ()> {
     let user = User {
         id: Uuid::new_v4(),
         admin: false,
+        session_version: 0,
+        roles: Vec::new(),
     };
     repository.create_user(&user).await?;
-    Ok(user.id)
+    Ok(user)
 }
 
-pub async fn gen_session_token(user_id: Uuid) -> String {
+pub async fn gen_session_token(user: &User) -> String {
     // Exact duration might vary based on business security requirements.
-    let mut claims = Claims::create(Duration::from_days(1));
-    claims.subject = Some(user_id.to_string());
+    let session = SessionClaims {
+        session_version: user.session_version,
+        client_id: None,
+    };
+    let mut claims = Claims::with_custom_claims(session, Duration::from_days(1));
+    claims.subject = Some(user.id.to_string());
     let key = get_key();
     key.authenticate(claims).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authn::{client_credentials, create_client, NewClient, TokenRequest};
+    use crate::stores::memory::InMemoryUsers;
+
+    #[tokio::test]
+    async fn test_authenticate_methods() {
+        let user = User { id: Uuid::new_v4(), admin: false, session_version: 0, roles: Vec::new() };
+        let repository: Arc<dyn UserRepository> = Arc::new(InMemoryUsers::with_users([user.clone()]));
+        let cache: Arc<dyn UserRepository> = Arc::new(InMemoryUsers::default());
+        let token = gen_session_token(&user).await;
+
+        let session = Some(Credential::Session(token.clone()));
+        let (_, method) = authenticate(repository.clone(), cache.clone(), session).await.unwrap();
+        assert_eq!(method, AuthMethod::Session);
+        let bearer = Some(Credential::Bearer(token));
+        let (_, method) = authenticate(repository.clone(), cache.clone(), bearer).await.unwrap();
+        assert_eq!(method, AuthMethod::Bearer);
+        assert_eq!(authenticate(repository, cache, None).await.err(), Some(401));
+    }
+
+    #[tokio::test]
+    async fn test_client_tokens_are_no_sessions() {
+        let repository = Arc::new(InMemoryUsers::default());
+        let request = NewClient { name: "billing".to_owned(), roles: Vec::new() };
+        let client = create_client(repository.as_ref(), request).await.unwrap();
+        let request = TokenRequest {
+            grant_type: "client_credentials".to_owned(),
+            client_id: Some(client.client_id),
+            client_secret: Some(client.client_secret),
+        };
+        let token = client_credentials(repository.as_ref(), None, request).await.unwrap().access_token;
+        let repository: Arc<dyn UserRepository> = repository;
+        let cache: Arc<dyn UserRepository> = Arc::new(InMemoryUsers::default());
+
+        let bearer = Some(Credential::Bearer(token.clone()));
+        let (principal, method) = authenticate(repository.clone(), cache.clone(), bearer).await.unwrap();
+        assert_eq!(principal.id.to_string(), client.user_id);
+        assert_eq!(method, AuthMethod::ClientCredentials);
+        // Sessions get refreshed, which would keep machine tokens alive for good
+        let session = Some(Credential::Session(token));
+        assert_eq!(authenticate(repository, cache, session).await.err(), Some(401));
+    }
+}
@@ -0,0 +1,313 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    rt,
+    web::{Bytes, BytesMut},
+    Error, HttpMessage, HttpResponse,
+};
+use futures::{future::LocalBoxFuture, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::authn::{credential, hash_secret, Credential};
+use crate::problem::error_response;
+use crate::ratelimit::client_key;
+use crate::stores::config::get_config;
+use crate::stores::idempotency::{Claim, IdempotencyStore, StoredResponse};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Header flagging the responses replayed from a previous request.
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Where the idempotency keys are kept.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdempotencyBackend {
+    /// In the memory of the instance, retries must reach the same instance to be replayed.
+    Memory,
+    /// In the users database, shared by every instance.
+    Postgres,
+}
+
+/// Handling of the `Idempotency-Key` header, in the `idempotency` section of `routes.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdempotencyConfig {
+    /// Methods the header applies to.
+    pub methods: Vec<String>,
+    /// How long the response to a key is replayed.
+    pub ttl_secs: i64,
+    /// How long a request holds its key before a retry may take it over, should its instance die.
+    pub lock_secs: i64,
+    /// Largest body of a request sent with a key, as it is read whole to be fingerprinted.
+    pub max_body_size: usize,
+    pub store: IdempotencyBackend,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            methods: vec!["POST".to_owned()],
+            ttl_secs: 24 * 3600,
+            lock_secs: 60,
+            max_body_size: 1024 * 1024,
+            store: IdempotencyBackend::Postgres,
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+// How often the expired keys are deleted from the store
+const PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Delete the expired keys of `store` periodically, rather than on the claims of the requests.
+pub async fn purge_expired(store: Arc<dyn IdempotencyStore>) {
+    loop {
+        rt::time::sleep(PURGE_INTERVAL).await;
+        store.purge(now()).await;
+    }
+}
+
+/// Hash of what makes a request: its method, uri and body as sent, still encoded.
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b" ");
+    hasher.update(req.uri().to_string());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Read the body, `None` when it is larger than `limit`.
+async fn read_body(payload: &mut Payload, limit: usize) -> Result<Option<Bytes>, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body.freeze()))
+}
+
+fn replay(response: StoredResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+    let mut replayed = HttpResponse::build(status);
+    for (name, value) in response.headers {
+        replayed.append_header((name, value));
+    }
+    replayed
+        .insert_header((IDEMPOTENT_REPLAYED, "true"))
+        .body(response.body)
+}
+
+/// Whose keys the `Idempotency-Key` of a request is among. API keys are unverified at this point,
+/// but their responses are only ever replayed to the requests sending them again.
+fn key_scope(req: &ServiceRequest) -> String {
+    let cookie = req.cookie("session");
+    match credential(req.headers(), cookie.as_ref().map(|cookie| cookie.value())) {
+        // Keys are secrets, kept out of the idempotency store
+        Some(Credential::ApiKey(api_key)) => format!("api_key:{}", hash_secret(&api_key)),
+        _ => client_key(req),
+    }
+}
+
+/// Middleware making the requests with an `Idempotency-Key` header safe to retry.
+///
+/// The first response to a key is stored, then replayed verbatim to the requests sent again with
+/// it, flagged with `Idempotent-Replayed`. A request made while another one holds its key gets a
+/// 409 problem, one reusing a key with another method, uri or body gets a 422 problem. Keys are
+/// scoped to the client, as the rate limits are, or to the API key. Server errors, the proxied services' included,
+/// are not stored, so that the request can be retried.
+#[derive(Clone)]
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore>,
+}
+
+impl Idempotency {
+    pub fn new(store: Arc<dyn IdempotencyStore>) -> Self {
+        Self { store }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn IdempotencyStore>,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let config = get_config();
+        let applies = config
+            .idempotency
+            .methods
+            .iter()
+            .any(|method| method == req.method().as_str());
+        let key = req.headers().get(IDEMPOTENCY_KEY).cloned();
+        let service = self.service.clone();
+        let store = self.store.clone();
+
+        Box::pin(async move {
+            let Some(key) = key.filter(|_| applies) else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+            let key = match key.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_owned(),
+                _ => {
+                    let (req, _) = req.into_parts();
+                    let detail = format!(
+                        "Idempotency-Key must be between 1 and {MAX_KEY_LENGTH} visible ASCII characters"
+                    );
+                    let response = error_response(&req, StatusCode::BAD_REQUEST, &detail);
+                    return Ok(ServiceResponse::new(req, response).map_into_right_body());
+                }
+            };
+            let key = format!("{}:{key}", key_scope(&req));
+            let Some(body) = read_body(&mut req.take_payload(), config.idempotency.max_body_size).await? else {
+                let (req, _) = req.into_parts();
+                let detail = format!(
+                    "bodies sent with an Idempotency-Key are limited to {} bytes",
+                    config.idempotency.max_body_size
+                );
+                let response = error_response(&req, StatusCode::PAYLOAD_TOO_LARGE, &detail);
+                return Ok(ServiceResponse::new(req, response).map_into_right_body());
+            };
+            let fingerprint = fingerprint(&req, &body);
+            req.set_payload(Payload::from(body));
+
+            let now = now();
+            let claim = store
+                .claim(
+                    &key,
+                    &fingerprint,
+                    now,
+                    now + config.idempotency.lock_secs,
+                    now + config.idempotency.ttl_secs,
+                )
+                .await;
+            let error = match claim {
+                Claim::Acquired => None,
+                Claim::Completed(response) => {
+                    let (req, _) = req.into_parts();
+                    return Ok(ServiceResponse::new(req, replay(response)).map_into_right_body());
+                }
+                Claim::InProgress => Some((
+                    StatusCode::CONFLICT,
+                    "a request with the same Idempotency-Key is being processed",
+                )),
+                Claim::Mismatch => Some((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "the Idempotency-Key was used for another request",
+                )),
+            };
+            if let Some((status, detail)) = error {
+                let (req, _) = req.into_parts();
+                let response = error_response(&req, status, detail);
+                return Ok(ServiceResponse::new(req, response).map_into_right_body());
+            }
+
+            let res = match service.call(req).await {
+                Ok(res) if !res.status().is_server_error() => res,
+                res => {
+                    store.release(&key).await;
+                    return res.map(|res| res.map_into_left_body());
+                }
+            };
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = match body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    store.release(&key).await;
+                    let err: Box<dyn std::error::Error> = err.into();
+                    return Err(actix_web::error::ErrorInternalServerError(err));
+                }
+            };
+            let headers = res
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect();
+            store
+                .complete(
+                    &key,
+                    &StoredResponse {
+                        status: res.status().as_u16(),
+                        headers,
+                        body: body.to_vec(),
+                    },
+                )
+                .await;
+            let res = res.set_body(BoxBody::new(body));
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_key_scope() {
+        let peer = "203.0.113.7:4000".parse().unwrap();
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("x-user", "alice"))
+            .to_srv_request();
+        assert_eq!(key_scope(&req), "ip:203.0.113.7");
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("x-api-key", "gwk_key"))
+            .to_srv_request();
+        assert_eq!(key_scope(&req), format!("api_key:{}", hash_secret("gwk_key")));
+    }
+}
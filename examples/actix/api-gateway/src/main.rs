@@ -1,31 +1,77 @@
-use std::{error::Error, net::Ipv4Addr};
+use std::{error::Error, net::Ipv4Addr, sync::Arc};
 
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{rt, web, App, HttpServer};
 use coi::container;
 use stores::postgres::UserPostgresProvider;
 
+use crate::cors::Cors;
+use crate::idempotency::{purge_expired, Idempotency, IdempotencyBackend};
+use crate::metrics::{PoolMetrics, RequestMetrics};
+use crate::ratelimit::{InMemoryBackend, RateLimit};
+use crate::shutdown::{Readiness, ShutdownConfig};
 use crate::stores::cache::UserMemoryProvider;
+use crate::stores::config::{get_config, watch_config};
 use crate::stores::http::RqClientProvider;
+use crate::stores::idempotency::{
+    IdempotencyStore, MemoryIdempotencyStore, PostgresIdempotencyStore,
+};
+use crate::telemetry::RequestTracing;
 
+mod accounts;
+mod admin;
+mod authn;
+mod authz;
+mod balancer;
+mod cors;
+mod discovery;
+mod forwarding;
 mod gateway;
+mod idempotency;
+mod metrics;
+mod oidc;
+mod problem;
+mod ratelimit;
+mod resilience;
 mod rest;
+mod routing;
 mod schemas;
+mod shutdown;
 mod store_interface;
+mod telemetry;
 mod stores {
     pub mod cache;
     pub mod config;
     pub mod http;
+    pub mod idempotency;
+    #[cfg(test)]
+    pub mod memory;
     pub mod postgres;
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), impl Error> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let tracer_provider = telemetry::init("api-gateway");
     // Local access to a dockerized postgres
     let provider =
         UserPostgresProvider::new("some_postgres", "postgres", "replacethisplease", "postgres")
             .await;
     let _res = provider.migrate().await;
+    prometheus::register(Box::new(PoolMetrics::new("users", provider.pool.clone())))
+        .expect("Could not register pool metrics.");
+    let pool = provider.pool.clone();
+    // Reloaded as long as the watcher lives
+    let _watcher = watch_config()
+        .inspect_err(|err| tracing::warn!(%err, "could not watch the configuration file"))
+        .ok();
+    #[cfg(unix)]
+    rt::spawn(stores::config::reload_on_hangup());
+    rt::spawn(balancer::run_health_checks());
+    rt::spawn(discovery::run_discovery());
+    let idempotency_store: Arc<dyn IdempotencyStore> = match get_config().idempotency.store {
+        IdempotencyBackend::Memory => Arc::new(MemoryIdempotencyStore::default()),
+        IdempotencyBackend::Postgres => Arc::new(PostgresIdempotencyStore { pool: pool.clone() }),
+    };
+    rt::spawn(purge_expired(idempotency_store.clone()));
 
     let containers = container! {
         repository => provider; singleton,
@@ -33,14 +79,46 @@ async fn main() -> Result<(), impl Error> {
         client => RqClientProvider; singleton,
     };
 
-    HttpServer::new(move || {
+    let readiness = Readiness::default();
+    let shutdown_config = ShutdownConfig::from_env();
+    let app_readiness = readiness.clone();
+    // Buckets are shared by every worker thread
+    let rate_limit = RateLimit::new(Arc::new(InMemoryBackend::default()));
+    let idempotency = Idempotency::new(idempotency_store);
+
+    let server = HttpServer::new(move || {
         // This factory closure is called on each worker thread independently.
         App::new()
-            .wrap(Logger::default())
+            .wrap(idempotency.clone())
+            .wrap(rate_limit.clone())
+            // Outside of the rate limits, for their rejections to be readable by the scripts
+            .wrap(Cors)
+            .wrap(RequestMetrics)
+            .wrap(RequestTracing)
             .app_data(containers.clone())
+            .app_data(web::Data::new(app_readiness.clone()))
             .configure(rest::configure())
     })
+    // Signals are handled below, to flag the gateway as not ready before refusing connections.
+    // Proxied calls in flight are drained like any other request.
+    .disable_signals()
+    .shutdown_timeout(shutdown_config.drain_timeout.as_secs())
     .bind((Ipv4Addr::UNSPECIFIED, 8000))?
-    .run()
-    .await
+    .run();
+    rt::spawn(shutdown::graceful_shutdown(
+        server.handle(),
+        readiness,
+        shutdown_config,
+        shutdown::termination_signal(),
+    ));
+    let result = server.await;
+
+    // Nothing uses the database past this point
+    pool.close();
+
+    // Flush the spans not exported yet
+    if let Some(provider) = tracer_provider {
+        let _ = provider.shutdown();
+    }
+    result
 }
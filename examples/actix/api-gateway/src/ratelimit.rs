@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    Error,
+};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use jwt_simple::prelude::{JWTClaims, MACLike, NoCustomClaims};
+use serde::{Deserialize, Serialize};
+
+use crate::authn::{credential, Credential};
+use crate::problem::error_response;
+use crate::routing::RequestInfo;
+use crate::stores::config::{get_config, get_key};
+
+/// Token bucket parameters: `per_second` tokens are given back every second, up to `burst`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Rate limits of the gateway, in the `rate_limit` section of `routes.yml`. Routes may set
+/// their own limits, in a `rate_limit` entry.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Limits shared by every route without its own.
+    pub default: Quota,
+    /// Paths never limited, such as the probes.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: Quota {
+                per_second: 50.0,
+                burst: 100,
+            },
+            exempt: vec![
+                "/public/health".to_owned(),
+                "/public/ready".to_owned(),
+                "/metrics".to_owned(),
+            ],
+        }
+    }
+}
+
+/// Name and limits of the bucket a request goes to, `None` when the path is exempt.
+fn quota_for(request: &RequestInfo) -> Option<(String, Quota)> {
+    let config = get_config();
+    if config.rate_limit.exempt.iter().any(|exempt| exempt == request.path) {
+        return None;
+    }
+    // Routes are matched like the proxy does
+    let quota = config
+        .find_route(request)
+        .ok()
+        .and_then(|found| {
+            let name = format!("{} {}", request.method, found.route.label());
+            Some((name, found.route.rate_limit?))
+        })
+        .unwrap_or_else(|| ("default".to_owned(), config.rate_limit.default));
+    Some(quota)
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until a token is available, zero when the request was allowed.
+    pub retry_after: Duration,
+}
+
+/// Storage of the token buckets.
+///
+/// The in-memory backend limits each instance on its own; implement this trait over a shared
+/// store, e.g. redis, to enforce the limits across instances.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Take a token from the bucket of `key`, created full on first use.
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+// Past this number of buckets, the full ones are dropped as they hold no information
+const MAX_BUCKETS: usize = 100_000;
+
+/// Token buckets kept in the memory of the instance.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+fn secs(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBackend {
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision {
+        let now = Instant::now();
+        let burst = f64::from(quota.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * quota.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = secs((burst - bucket.tokens) / quota.per_second);
+        bucket.full_at = now + reset;
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: bucket.tokens as u32,
+            reset,
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                secs((1.0 - bucket.tokens) / quota.per_second)
+            },
+        }
+    }
+}
+
+/// Id of the user of a valid session token, sent as a cookie or a bearer token.
+fn token_user(credential: &Credential) -> Option<String> {
+    let (Credential::Session(token) | Credential::Bearer(token)) = credential else {
+        return None;
+    };
+    let claims: JWTClaims<NoCustomClaims> = get_key().verify_token(token, None).ok()?;
+    claims.subject
+}
+
+/// Who the request is counted against: the user of its session or bearer token, verified, or its
+/// peer address. `X-User` and API keys are unverified at this point, and left to the address.
+pub(crate) fn client_key(req: &ServiceRequest) -> String {
+    let cookie = req.cookie("session");
+    let credential = credential(req.headers(), cookie.as_ref().map(|cookie| cookie.value()));
+    if let Some(user) = credential.as_ref().and_then(token_user) {
+        format!("user:{user}")
+    } else {
+        let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+        format!("ip:{}", peer.unwrap_or_default())
+    }
+}
+
+// Round up, so that clients waiting the advertised time do get a token
+fn header_secs(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs_f64().ceil() as u64)
+}
+
+fn set_headers(headers: &mut actix_web::http::header::HeaderMap, decision: &Decision) {
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        header_secs(decision.reset),
+    );
+}
+
+/// Middleware limiting the request rate of each client with token buckets.
+///
+/// Requests over the limit are answered with a 429 problem and a `Retry-After` header; every
+/// limited response carries the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers.
+#[derive(Clone)]
+pub struct RateLimit {
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimit {
+    pub fn new(backend: Arc<dyn RateLimitBackend>) -> Self {
+        Self { backend }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            backend: self.backend.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let quota = quota_for(&RequestInfo::new(
+            req.method().as_str(),
+            req.path(),
+            req.query_string(),
+            req.headers(),
+        ));
+        let service = self.service.clone();
+        let backend = self.backend.clone();
+
+        Box::pin(async move {
+            let Some((name, quota)) = quota else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+            let decision = backend
+                .acquire(&format!("{name}:{}", client_key(&req)), quota)
+                .await;
+            if !decision.allowed {
+                let (req, _) = req.into_parts();
+                let detail = format!(
+                    "{} requests allowed per second, with bursts of {}",
+                    quota.per_second, quota.burst
+                );
+                let mut response = error_response(&req, StatusCode::TOO_MANY_REQUESTS, &detail);
+                set_headers(response.headers_mut(), &decision);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, header_secs(decision.retry_after));
+                return Ok(ServiceResponse::new(req, response).map_into_right_body());
+            }
+            let mut res = service.call(req).await?;
+            set_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::cookie::Cookie;
+    use actix_web::test::TestRequest;
+    use jwt_simple::prelude::Claims;
+
+    use super::*;
+
+    #[test]
+    fn test_client_key() {
+        let peer = "203.0.113.7:4000".parse().unwrap();
+        // Whatever the client claims, unverified
+        for header in [
+            ("x-user", "alice"),
+            ("x-api-key", "gwk_random"),
+            ("authorization", "Bearer gwk_random"),
+            ("authorization", "Bearer forged"),
+        ] {
+            let req = TestRequest::default()
+                .peer_addr(peer)
+                .insert_header(header)
+                .to_srv_request();
+            assert_eq!(client_key(&req), "ip:203.0.113.7");
+        }
+
+        let claims =
+            Claims::create(jwt_simple::prelude::Duration::from_hours(1)).with_subject("alice");
+        let token = get_key().authenticate(claims).unwrap();
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("x-user", "bob"))
+            .cookie(Cookie::new("session", token.clone()))
+            .to_srv_request();
+        assert_eq!(client_key(&req), "user:alice");
+        let req = TestRequest::default()
+            .peer_addr(peer)
+            .insert_header(("authorization", format!("Bearer {token}")))
+            .to_srv_request();
+        assert_eq!(client_key(&req), "user:alice");
+    }
+}
@@ -0,0 +1,471 @@
+//! Timeouts, retries and circuit breaking of the calls to the services.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use rand::Rng;
+use reqwest::{header::HeaderMap, Method};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+use crate::metrics::{
+    UPSTREAM_CIRCUIT_STATE, UPSTREAM_REQUEST_DURATION_SECONDS, UPSTREAM_RETRIES_TOTAL,
+};
+use crate::routing::RouteMatch;
+use crate::store_interface::{Proxy, UpstreamError, UpstreamRequest, UpstreamResponse};
+use crate::stores::config::Config;
+
+/// Time given to the services, in milliseconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Timeouts {
+    pub connect_ms: u64,
+    /// Until the response head, the body being streamed afterwards.
+    pub request_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect_ms: 2_000,
+            request_ms: 30_000,
+        }
+    }
+}
+
+/// Retries allowed on top of the requests, per upstream: `ratio` of the requests, and at least
+/// `min_per_sec`. Retries past the budget are not made, for a failing service not to get the
+/// load multiplied.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct RetryBudget {
+    pub ratio: f64,
+    pub min_per_sec: f64,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self {
+            ratio: 0.2,
+            min_per_sec: 10.0,
+        }
+    }
+}
+
+/// Requests sent again after no response, or a `retry_on` status, waiting a random time up to
+/// an exponential backoff. Only the `methods` listed are retried, and only without a streamed
+/// body, which can be sent once.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub methods: Vec<String>,
+    pub retry_on: Vec<u16>,
+    pub base_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            methods: ["GET", "HEAD", "OPTIONS", "PUT", "DELETE"]
+                .map(str::to_owned)
+                .to_vec(),
+            retry_on: vec![502, 503, 504],
+            base_backoff_ms: 25,
+            max_backoff_ms: 250,
+            budget: RetryBudget::default(),
+        }
+    }
+}
+
+/// Calls failing `failure_threshold` times in a row, with a 5xx or no response, open the circuit
+/// of their upstream: its requests are answered with a 503 for `open_secs`, then
+/// `half_open_requests` trial requests close it again on success.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// 0 disables the breaker.
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 30,
+            half_open_requests: 1,
+        }
+    }
+}
+
+/// Defaults of every route, in the `resilience` section of `routes.yml`. Routes may set their
+/// own `timeouts` and `retry`, upstreams their own `circuit_breaker`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ResilienceConfig {
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trials: u32 },
+}
+
+struct UpstreamState {
+    circuit: Circuit,
+    retry_tokens: f64,
+    refilled: Instant,
+}
+
+lazy_static! {
+    // Kept across configuration reloads, by upstream
+    static ref UPSTREAMS: Mutex<HashMap<String, UpstreamState>> = Mutex::default();
+}
+
+fn with_state<T>(upstream: &str, f: impl FnOnce(&mut UpstreamState) -> T) -> T {
+    let mut upstreams = UPSTREAMS.lock().unwrap();
+    let state = upstreams
+        .entry(upstream.to_owned())
+        .or_insert_with(|| UpstreamState {
+            circuit: Circuit::Closed { failures: 0 },
+            // As many retries saved up as the smallest budget holds
+            retry_tokens: 10.0,
+            refilled: Instant::now(),
+        });
+    f(state)
+}
+
+fn set_circuit(upstream: &str, state: &mut UpstreamState, circuit: Circuit) {
+    let (value, name) = match circuit {
+        Circuit::Closed { .. } => (0, "closed"),
+        Circuit::HalfOpen { .. } => (1, "half-open"),
+        Circuit::Open { .. } => (2, "open"),
+    };
+    UPSTREAM_CIRCUIT_STATE
+        .with_label_values(&[upstream])
+        .set(value);
+    tracing::warn!(upstream, circuit = name, "upstream circuit changed");
+    state.circuit = circuit;
+}
+
+/// Call let through the circuit of an upstream, its outcome being recorded once known.
+///
+/// Trial calls of a half-open circuit dropped before, e.g. when the client disconnects, reopen it,
+/// for the circuit not to wait on them for good.
+struct Permit<'a> {
+    upstream: &'a str,
+    breaker: &'a CircuitBreakerConfig,
+    trial: bool,
+}
+
+impl Permit<'_> {
+    fn record(mut self, success: bool) {
+        self.trial = false;
+        record(self.upstream, self.breaker, success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.trial {
+            return;
+        }
+        let open = Circuit::Open {
+            until: Instant::now() + Duration::from_secs(self.breaker.open_secs),
+        };
+        with_state(self.upstream, |state| {
+            // Unless another trial settled it meanwhile
+            if let Circuit::HalfOpen { .. } = state.circuit {
+                set_circuit(self.upstream, state, open);
+            }
+        })
+    }
+}
+
+/// Let a call through the circuit of `upstream`, or tell how long it stays open.
+fn acquire<'a>(
+    upstream: &'a str,
+    breaker: &'a CircuitBreakerConfig,
+) -> Result<Permit<'a>, Duration> {
+    let permit = |trial| Permit {
+        upstream,
+        breaker,
+        trial,
+    };
+    if breaker.failure_threshold == 0 {
+        return Ok(permit(false));
+    }
+    let now = Instant::now();
+    with_state(upstream, |state| match state.circuit {
+        Circuit::Closed { .. } => Ok(permit(false)),
+        Circuit::Open { until } if until > now => Err(until - now),
+        Circuit::Open { .. } => {
+            set_circuit(upstream, state, Circuit::HalfOpen { trials: 1 });
+            Ok(permit(true))
+        }
+        Circuit::HalfOpen { ref mut trials } if *trials < breaker.half_open_requests => {
+            *trials += 1;
+            Ok(permit(true))
+        }
+        // Until the trials are over
+        Circuit::HalfOpen { .. } => Err(Duration::from_secs(1)),
+    })
+}
+
+fn record(upstream: &str, breaker: &CircuitBreakerConfig, success: bool) {
+    if breaker.failure_threshold == 0 {
+        return;
+    }
+    let open = Circuit::Open {
+        until: Instant::now() + Duration::from_secs(breaker.open_secs),
+    };
+    with_state(upstream, |state| match (&mut state.circuit, success) {
+        (Circuit::Closed { failures }, true) => *failures = 0,
+        (Circuit::Closed { failures }, false) => {
+            *failures += 1;
+            if *failures >= breaker.failure_threshold {
+                set_circuit(upstream, state, open);
+            }
+        }
+        (Circuit::HalfOpen { .. }, true) => {
+            set_circuit(upstream, state, Circuit::Closed { failures: 0 })
+        }
+        (Circuit::HalfOpen { .. }, false) => set_circuit(upstream, state, open),
+        // Calls made before the circuit opened
+        (Circuit::Open { .. }, _) => {}
+    })
+}
+
+/// Add the share of a request to the retry budget of `upstream`.
+fn deposit(upstream: &str, budget: &RetryBudget) {
+    with_state(upstream, |state| {
+        state.retry_tokens = (state.retry_tokens + budget.ratio).min(budget_cap(budget));
+    })
+}
+
+/// Take a retry from the budget of `upstream`, if any is left.
+fn withdraw(upstream: &str, budget: &RetryBudget) -> bool {
+    let now = Instant::now();
+    with_state(upstream, |state| {
+        let refill = now.duration_since(state.refilled).as_secs_f64() * budget.min_per_sec;
+        state.retry_tokens = (state.retry_tokens + refill).min(budget_cap(budget));
+        state.refilled = now;
+        if state.retry_tokens < 1.0 {
+            return false;
+        }
+        state.retry_tokens -= 1.0;
+        true
+    })
+}
+
+// Retries saved up while all is well, for a burst of failures
+fn budget_cap(budget: &RetryBudget) -> f64 {
+    (budget.min_per_sec * 10.0).max(10.0)
+}
+
+/// Random wait before the `retry`-th retry, up to an exponential backoff ("full jitter").
+fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let ceiling = policy
+        .base_backoff_ms
+        .saturating_mul(1 << retry.min(16))
+        .min(policy.max_backoff_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+}
+
+/// Client request to send to the service of a route.
+pub struct ServiceCall<'a> {
+    pub method: &'a Method,
+    pub query: &'a str,
+    pub headers: HeaderMap,
+    pub body: reqwest::Body,
+    pub user_id: &'a Uuid,
+    pub request_id: &'a str,
+}
+
+/// Send `call` to the service of `found`, within the timeouts of the route, retrying as its
+/// policy allows, an endpoint of its upstream being picked for every attempt.
+pub async fn call_service<P: Proxy + ?Sized>(
+    client: &P,
+    config: &Config,
+    found: &RouteMatch<'_>,
+    call: ServiceCall<'_>,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let route = found.route;
+    let timeouts = route.timeouts.unwrap_or(config.resilience.timeouts);
+    let policy = route.retry.as_ref().unwrap_or(&config.resilience.retry);
+    let breaker = route
+        .upstream
+        .as_ref()
+        .and_then(|name| config.upstreams.get(name)?.circuit_breaker)
+        .unwrap_or(config.resilience.circuit_breaker);
+    // Routes without an upstream share the circuit of their service
+    let upstream = match &route.upstream {
+        Some(name) => name.as_str(),
+        None => route.service.split('/').next().unwrap_or_default(),
+    };
+    // Streamed bodies can only be sent once
+    let replay = call.body.as_bytes().map(Bytes::copy_from_slice);
+    let max_retries = match replay {
+        Some(_)
+            if policy
+                .methods
+                .iter()
+                .any(|method| method == call.method.as_str()) =>
+        {
+            policy.max_retries
+        }
+        _ => 0,
+    };
+    deposit(upstream, &policy.budget);
+
+    let mut body = Some(call.body);
+    let mut retry = 0;
+    loop {
+        let lease = match &route.upstream {
+            Some(name) => Some(
+                config
+                    .pools
+                    .get(name)
+                    .and_then(|pool| pool.pick(&call.user_id.to_string()))
+                    .ok_or(UpstreamError::NoEndpoint)?,
+            ),
+            None => None,
+        };
+        let service = lease
+            .as_ref()
+            .map_or(route.service.as_str(), |lease| lease.address());
+        let permit = acquire(upstream, &breaker).map_err(UpstreamError::CircuitOpen)?;
+
+        // We support proxying variable path this way
+        let mut url = format!("http://{service}{}", found.path);
+        if !call.query.is_empty() {
+            // Rewrites may set a query of their own
+            let separator = if found.path.contains('?') { '&' } else { '?' };
+            url = format!("{url}{separator}{}", call.query);
+        }
+        let request = UpstreamRequest {
+            method: call.method.clone(),
+            url,
+            headers: call.headers.clone(),
+            body: body
+                .take()
+                .or_else(|| replay.clone().map(reqwest::Body::from))
+                .unwrap_or_else(|| reqwest::Body::from(Vec::new())),
+            connect_timeout: Duration::from_millis(timeouts.connect_ms),
+            timeout: Duration::from_millis(timeouts.request_ms),
+        };
+        // Measured until the response head, the body being streamed afterwards
+        let start = Instant::now();
+        let result = client
+            .make_request(request, call.user_id, call.request_id)
+            .await;
+        let status = match &result {
+            Ok(response) => response.status.as_str().to_owned(),
+            Err(UpstreamError::Timeout) => String::from("timeout"),
+            Err(_) => String::from("unreachable"),
+        };
+        UPSTREAM_REQUEST_DURATION_SECONDS
+            .with_label_values(&[route.label(), &status])
+            .observe(start.elapsed().as_secs_f64());
+        let retryable = match &result {
+            Ok(response) => policy.retry_on.contains(&response.status.as_u16()),
+            Err(err) => {
+                tracing::warn!(%err, service, "could not reach the service");
+                true
+            }
+        };
+        let success = matches!(&result, Ok(response) if !response.status.is_server_error());
+        // No longer outstanding while waiting for the next attempt
+        if let Some(lease) = lease {
+            lease.report(success);
+        }
+        permit.record(success);
+        if !retryable || retry >= max_retries || !withdraw(upstream, &policy.budget) {
+            return result;
+        }
+        retry += 1;
+        UPSTREAM_RETRIES_TOTAL
+            .with_label_values(&[route.label()])
+            .inc();
+        sleep(backoff(policy, retry)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BREAKER: CircuitBreakerConfig = CircuitBreakerConfig {
+        failure_threshold: 2,
+        open_secs: 60,
+        half_open_requests: 1,
+    };
+
+    fn open(upstream: &str) {
+        for _ in 0..BREAKER.failure_threshold {
+            acquire(upstream, &BREAKER).unwrap().record(false);
+        }
+    }
+
+    // As if `open_secs` went by
+    fn expire(upstream: &str) {
+        with_state(upstream, |state| {
+            state.circuit = Circuit::Open {
+                until: Instant::now(),
+            }
+        });
+    }
+
+    fn is_open(upstream: &str) -> bool {
+        with_state(upstream, |state| {
+            matches!(state.circuit, Circuit::Open { .. })
+        })
+    }
+
+    #[test]
+    fn test_circuit() {
+        open("test_circuit");
+        assert!(acquire("test_circuit", &BREAKER).err().unwrap() > Duration::from_secs(59));
+        expire("test_circuit");
+        let trial = acquire("test_circuit", &BREAKER).unwrap();
+        // Only `half_open_requests` trials at once
+        assert!(acquire("test_circuit", &BREAKER).is_err());
+        trial.record(false);
+        assert!(is_open("test_circuit"));
+
+        expire("test_circuit");
+        acquire("test_circuit", &BREAKER).unwrap().record(true);
+        acquire("test_circuit", &BREAKER).unwrap().record(true);
+    }
+
+    #[test]
+    fn test_dropped_trial() {
+        open("test_dropped_trial");
+        expire("test_dropped_trial");
+        // The client went away before the service answered
+        drop(acquire("test_dropped_trial", &BREAKER).unwrap());
+        assert!(is_open("test_dropped_trial"));
+
+        expire("test_dropped_trial");
+        acquire("test_dropped_trial", &BREAKER)
+            .unwrap()
+            .record(true);
+        // Calls of a closed circuit dropped are no failures
+        for _ in 0..BREAKER.failure_threshold {
+            drop(acquire("test_dropped_trial", &BREAKER).unwrap());
+        }
+        assert!(!is_open("test_dropped_trial"));
+    }
+}
@@ -1,17 +1,43 @@
+use std::{io, sync::Arc};
+
 use actix_http::StatusCode;
 use actix_web::{
     cookie::{
         time::{Duration, OffsetDateTime},
         Cookie, SameSite,
     },
-    get, web,
+    get,
+    http::{
+        header::{
+            HeaderValue, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, LOCATION,
+            RETRY_AFTER, TRANSFER_ENCODING, UPGRADE,
+        },
+        Method,
+    },
+    rt, web,
     web::ServiceConfig,
-    HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
 };
+use futures::StreamExt;
+use tokio::io::{AsyncWriteExt, ReadHalf};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::ReaderStream;
 
-use crate::store_interface::UserRepository;
+use crate::accounts::{self, Login, PasswordChange};
+use crate::admin;
+use crate::authn::{self, credential, Credential, TokenRequest};
+use crate::forwarding::{request_headers, response_headers, upgrade};
+use crate::metrics::metrics;
+use crate::oidc::{self, Callback, FLOW_COOKIE, FLOW_TTL_SECS};
+use crate::problem::error_response;
+use crate::routing::RequestInfo;
+use crate::schemas::User;
+use crate::shutdown::ready;
+use crate::store_interface::{UpstreamBody, UpstreamError, UserRepository};
+use crate::stores::config::get_config;
+use crate::telemetry::RequestId;
 use crate::{
-    gateway::{gen_session_token, gen_user, proxy},
+    gateway::{authenticate, gen_session_token, gen_user, proxy, ClientRequest, ProxyError},
     store_interface::Proxy,
 };
 use coi_actix_web::inject;
@@ -24,14 +50,26 @@ pub fn route_config(config: &mut ServiceConfig) {
         .service(
             web::scope("/public") // Everything that does not require any auth
                 .route("sign-up", web::get().to(sign_up))
-                .service(health),
+                .route("register", web::post().to(register))
+                .route("login", web::post().to(login))
+                .route("oidc/login", web::get().to(oidc_login))
+                .route("oidc/callback", web::get().to(oidc_callback))
+                .route("oauth/token", web::post().to(token))
+                .service(health)
+                .service(ready),
         )
+        // Registered before the catch-all scope. Keep it unreachable from outside in production,
+        // e.g. by only exposing it on the internal network your Prometheus scrapes from
+        .service(metrics)
+        .service(admin::scope())
         .service(
-            web::scope("") // Routes are configuration driven
-                .route("/{tail:.*}", web::get().to(req_proxy))
-                .route("/{tail:.*}", web::post().to(req_proxy))
-                .route("/{tail:.*}", web::put().to(req_proxy))
-                .route("/{tail:.*}", web::delete().to(req_proxy)),
+            web::scope("/account") // Reserved to sessions
+                .route("/logout", web::post().to(logout))
+                .route("/password", web::post().to(change_password)),
+        )
+        .service(
+            web::scope("") // Routes are configuration driven, whatever the method
+                .route("/{tail:.*}", web::route().to(req_proxy)),
         );
 }
 
@@ -52,36 +90,182 @@ fn session_cookie_from_token(token_str: &str) -> Cookie {
     cookie
 }
 
+// Only sent back to the callback, within the time given to log in at the provider
+fn flow_cookie(value: String) -> Cookie<'static> {
+    let mut cookie = Cookie::new(FLOW_COOKIE, value);
+    cookie.set_path("/public/oidc");
+    cookie.set_max_age(Duration::seconds(FLOW_TTL_SECS as i64));
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_secure(true);
+    cookie.set_http_only(true);
+    cookie
+}
+
+// Chunks read ahead of the service, bounding the memory of each upload
+const BODY_BUFFER_CHUNKS: usize = 8;
+
+/// Body of the client request as a stream the http client can send from another thread, the
+/// payload being read on the worker thread. Requests without a body get none.
+fn request_body(req: &HttpRequest, mut payload: web::Payload) -> reqwest::Body {
+    let has_body = req.headers().contains_key(TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .is_some_and(|length| length != "0");
+    if !has_body {
+        return reqwest::Body::from(Vec::new());
+    }
+    let (sender, receiver) =
+        tokio::sync::mpsc::channel::<io::Result<web::Bytes>>(BODY_BUFFER_CHUNKS);
+    rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|err| io::Error::other(err.to_string()));
+            // The service stopped reading, e.g. it answered early
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    reqwest::Body::wrap_stream(ReceiverStream::new(receiver))
+}
+
+/// Pipe the client connection, switched to another protocol, to the service one: the client
+/// payload is written to the service from the worker thread, the service output streamed back.
+fn tunnel(
+    upgraded: reqwest::Upgraded,
+    mut payload: web::Payload,
+) -> ReaderStream<ReadHalf<reqwest::Upgraded>> {
+    let (read, mut write) = tokio::io::split(upgraded);
+    rt::spawn(async move {
+        while let Some(Ok(chunk)) = payload.next().await {
+            if write.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        // The client left, the service sees the connection closing
+        let _ = write.shutdown().await;
+    });
+    ReaderStream::new(read)
+}
+
+/// `Allow` header listing the methods the routes serving `request` accept.
+fn allow_header(request: &RequestInfo) -> HeaderValue {
+    let methods = get_config().allowed_methods(request).join(", ");
+    HeaderValue::from_str(&methods).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
 #[inject]
 async fn req_proxy(
     #[inject] repository: Arc<dyn UserRepository>,
     #[inject] cache: Arc<dyn UserRepository>,
     #[inject] client: Arc<dyn Proxy>,
     req: HttpRequest,
+    payload: web::Payload,
 ) -> impl Responder {
-    let cookie = req.cookie("session");
-    let opt_cookie = match cookie {
-        Some(cookie_value) => Some(cookie_value.value().to_owned()),
-        None => None,
-    };
-    match proxy(
-        repository,
-        cache,
-        client,
+    let target = RequestInfo::new(
         req.method().as_str(),
         req.path(),
-        opt_cookie,
-    )
-    .await
+        req.query_string(),
+        req.headers(),
+    );
+    // Answered by the gateway unless a route leaves them to its service
+    let config = get_config();
+    if req.method() == Method::OPTIONS
+        && config.find_route(&target).is_err()
+        && !config.allowed_methods(&target).is_empty()
     {
-        Ok((body, code, token)) => HttpResponseBuilder::new(StatusCode::from_u16(code).unwrap())
-            .cookie(session_cookie_from_token(token.as_str()))
-            .body(body),
-        Err(code) => match code {
-            401_u16 => HttpResponse::Unauthorized().body("Need authentication"),
-            403_u16 => HttpResponse::Forbidden().body("Insuficient permissions"),
-            404_u16 => HttpResponse::NotFound().body("Not found"),
-            _i32 => HttpResponse::BadRequest().body("Bad request"),
+        return HttpResponse::NoContent()
+            .insert_header((ALLOW, allow_header(&target)))
+            .finish();
+    }
+    let cookie = req.cookie("session");
+    let credential = credential(req.headers(), cookie.as_ref().map(|cookie| cookie.value()));
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+        .unwrap_or_default();
+    let client_headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    // The payload of an upgraded connection is tunneled once the service switched
+    let (body, tunneled) = match upgrade(&client_headers) {
+        Some(_) => (reqwest::Body::from(Vec::new()), Some(payload)),
+        None => (request_body(&req, payload), None),
+    };
+    let request = ClientRequest {
+        method: req.method(),
+        target,
+        headers: request_headers(
+            &client_headers,
+            req.peer_addr().map(|addr| addr.ip()),
+            req.connection_info().scheme(),
+        ),
+        body,
+    };
+    match proxy(repository, cache, client, request, credential, &request_id).await {
+        Ok((upstream, token)) => {
+            let mut response = HttpResponseBuilder::new(upstream.status);
+            let headers = response_headers(&upstream.headers);
+            for (name, value) in &headers {
+                if name != CONTENT_LENGTH {
+                    response.append_header((name.clone(), value.clone()));
+                }
+            }
+            // Known lengths are kept, for downloads to show their progress
+            if let Some(length) = headers
+                .get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse().ok())
+            {
+                response.no_chunking(length);
+            }
+            if let Some(token) = token {
+                response.cookie(session_cookie_from_token(token.as_str()));
+            }
+            match (upstream.body, tunneled) {
+                (UpstreamBody::Stream(body), _) => response.streaming(body),
+                (UpstreamBody::Upgraded(upgraded), Some(payload)) => {
+                    if let Some(protocol) = upstream.headers.get(UPGRADE) {
+                        response.upgrade(protocol.clone());
+                    }
+                    response.streaming(tunnel(upgraded, payload))
+                }
+                (UpstreamBody::Upgraded(_), None) => {
+                    error_response(&req, StatusCode::BAD_GATEWAY, "Unexpected protocol switch")
+                }
+            }
+        }
+        Err(ProxyError::Upstream(err)) => {
+            let mut response = error_response(&req, err.status(), err.detail());
+            if let UpstreamError::CircuitOpen(remaining) = err {
+                // Rounded up, for clients waiting the advertised time to find it closed
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(seconds));
+            }
+            response
+        }
+        Err(ProxyError::Rejected(code)) => match code {
+            401_u16 => error_response(&req, StatusCode::UNAUTHORIZED, "Need authentication"),
+            403_u16 => error_response(&req, StatusCode::FORBIDDEN, "Insuficient permissions"),
+            404_u16 => error_response(&req, StatusCode::NOT_FOUND, "Not found"),
+            405_u16 => {
+                let mut response =
+                    error_response(&req, StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+                let target = RequestInfo::new(
+                    req.method().as_str(),
+                    req.path(),
+                    req.query_string(),
+                    req.headers(),
+                );
+                response.headers_mut().insert(ALLOW, allow_header(&target));
+                response
+            }
+            _i32 => error_response(&req, StatusCode::BAD_REQUEST, "Bad request"),
         },
     }
 }
@@ -89,10 +273,197 @@ async fn req_proxy(
 #[inject]
 // Our extremely simplified signup. Get the url to automatically register a new user and get a cookie
 async fn sign_up(#[inject] repository: Arc<dyn UserRepository>) -> impl Responder {
-    let user_id = gen_user(repository).await.unwrap();
-    let token_str = gen_session_token(user_id).await;
+    let user = gen_user(repository).await.unwrap();
+    let token_str = gen_session_token(&user).await;
     let cookie = session_cookie_from_token(token_str.as_str());
     HttpResponse::Ok()
         .cookie(cookie)
         .body("Signed up ! Check http://localhost:8000/hello/")
 }
+
+/// Response opening a session for `user`.
+async fn signed_in(status: StatusCode, user: &User) -> HttpResponse {
+    let token = gen_session_token(user).await;
+    HttpResponse::build(status)
+        .cookie(session_cookie_from_token(&token))
+        .json(serde_json::json!({ "id": user.id.to_string() }))
+}
+
+/// User of the session, or the response refusing the request.
+async fn require_session(
+    req: &HttpRequest,
+    repository: Arc<dyn UserRepository>,
+    cache: Arc<dyn UserRepository>,
+) -> Result<User, HttpResponse> {
+    let cookie = req.cookie("session");
+    let credential = cookie.map(|cookie| Credential::Session(cookie.value().to_owned()));
+    authenticate(repository, cache, credential)
+        .await
+        .map(|(user, _)| user)
+        .map_err(|_| error_response(req, StatusCode::UNAUTHORIZED, "Need authentication"))
+}
+
+/// Create an account, and open its first session.
+///
+/// ```text
+/// curl -i localhost:8000/public/register -H 'Content-Type: application/json' \
+///     -d '{"username": "alice", "password": "correct horse"}'
+/// ```
+#[inject]
+async fn register(
+    #[inject] repository: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    login: web::Json<Login>,
+) -> impl Responder {
+    match accounts::register(repository.as_ref(), login.into_inner()).await {
+        Ok(user) => signed_in(StatusCode::CREATED, &user).await,
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Open a session with the username and password of an account.
+#[inject]
+async fn login(
+    #[inject] repository: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    login: web::Json<Login>,
+) -> impl Responder {
+    match accounts::login(repository.as_ref(), login.into_inner()).await {
+        Ok(user) => signed_in(StatusCode::OK, &user).await,
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Revoke every session of the user, and remove the session cookie.
+#[inject]
+async fn logout(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+) -> impl Responder {
+    let user = match require_session(&req, repository.clone(), cache.clone()).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    match accounts::logout(repository.as_ref(), &user).await {
+        Ok(user) => {
+            // Forgotten at once by this instance, by the others when their cache entry expires
+            let _res = cache.create_user(&user).await;
+            let mut cookie = session_cookie_from_token("");
+            cookie.make_removal();
+            HttpResponse::NoContent().cookie(cookie).finish()
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Change the password of the user, revoking its other sessions.
+#[inject]
+async fn change_password(
+    #[inject] repository: Arc<dyn UserRepository>,
+    #[inject] cache: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    change: web::Json<PasswordChange>,
+) -> impl Responder {
+    let user = match require_session(&req, repository.clone(), cache.clone()).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    match accounts::change_password(repository.as_ref(), &user, change.into_inner()).await {
+        Ok(user) => {
+            let _res = cache.create_user(&user).await;
+            signed_in(StatusCode::OK, &user).await
+        }
+        Err(refused) => error_response(&req, refused.status, &refused.detail),
+    }
+}
+
+/// Redirect the browser to the OpenID Connect provider to log in.
+async fn oidc_login(req: HttpRequest) -> impl Responder {
+    let config = get_config();
+    let Some(oidc) = &config.oidc else {
+        return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            "OpenID Connect login not configured",
+        );
+    };
+    match oidc::start_login(oidc).await {
+        Ok((url, flow)) => HttpResponse::Found()
+            .insert_header((LOCATION, url))
+            .cookie(flow_cookie(flow))
+            .finish(),
+        Err(err) => {
+            tracing::warn!(%err, "could not start an OpenID Connect login");
+            error_response(&req, err.status(), err.detail())
+        }
+    }
+}
+
+/// Open a session for the user the provider redirected back, creating its account on its first
+/// login.
+#[inject]
+async fn oidc_callback(
+    #[inject] repository: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    callback: web::Query<Callback>,
+) -> impl Responder {
+    let config = get_config();
+    let Some(oidc) = &config.oidc else {
+        return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            "OpenID Connect login not configured",
+        );
+    };
+    let flow = req
+        .cookie(FLOW_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    let identity = match oidc::finish_login(oidc, &callback, flow.as_deref()).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            tracing::warn!(%err, "OpenID Connect login failed");
+            return error_response(&req, err.status(), err.detail());
+        }
+    };
+    let user = match accounts::identity_user(repository.as_ref(), &identity).await {
+        Ok(user) => user,
+        Err(refused) => return error_response(&req, refused.status, &refused.detail),
+    };
+    let token = gen_session_token(&user).await;
+    let mut flow = flow_cookie(String::new());
+    flow.make_removal();
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, oidc.landing_path.clone()))
+        .cookie(session_cookie_from_token(&token))
+        .cookie(flow)
+        .finish()
+}
+
+/// Token of a machine client, with the client credentials grant.
+///
+/// ```text
+/// curl localhost:8000/public/oauth/token -u "$CLIENT_ID:$CLIENT_SECRET" \
+///     -d grant_type=client_credentials
+/// ```
+#[inject]
+async fn token(
+    #[inject] repository: Arc<dyn UserRepository>,
+    req: HttpRequest,
+    form: web::Form<TokenRequest>,
+) -> impl Responder {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let response =
+        authn::client_credentials(repository.as_ref(), authorization, form.into_inner()).await;
+    match response {
+        Ok(token) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(token),
+        Err(err) => HttpResponse::build(err.status())
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(serde_json::json!({ "error": err.code() })),
+    }
+}
@@ -0,0 +1,436 @@
+//! Matching of the requests against the routes of `routes.yml`.
+
+use regex::Regex;
+
+use crate::stores::config::{Config, Route};
+
+/// Read access to the headers of a request, whatever the http library.
+pub trait Headers: Sync {
+    /// Value of the header `name`, when present and visible ASCII.
+    fn get_str(&self, name: &str) -> Option<&str>;
+}
+
+impl Headers for reqwest::header::HeaderMap {
+    fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+impl Headers for actix_web::http::header::HeaderMap {
+    fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+/// What the routes are matched on.
+pub struct RequestInfo<'a> {
+    pub method: &'a str,
+    /// Host the request was sent to, without its port.
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a dyn Headers,
+}
+
+impl<'a> RequestInfo<'a> {
+    pub fn new(method: &'a str, path: &'a str, query: &'a str, headers: &'a dyn Headers) -> Self {
+        let host = headers
+            .get_str("host")
+            .map(|host| match host.strip_prefix('[') {
+                // IPv6 literal
+                Some(address) => address.split(']').next().unwrap_or_default(),
+                None => host.split(':').next().unwrap_or_default(),
+            });
+        Self {
+            method,
+            host,
+            path,
+            query,
+            headers,
+        }
+    }
+}
+
+/// Values captured from the path by a route, by name.
+pub type Captures = Vec<(String, String)>;
+
+/// Segment of a path template.
+#[derive(Debug)]
+pub enum Segment {
+    Literal(String),
+    /// `{name}`, a single segment.
+    Capture(String),
+    /// `{name*}`, the rest of the path.
+    Rest(String),
+}
+
+/// How a route matches paths, compiled from its `prefix`, `path` or `regex`.
+#[derive(Debug)]
+pub enum PathMatcher {
+    Prefix(String),
+    Template(Vec<Segment>),
+    Regex(Regex),
+}
+
+fn capture_name(name: &str) -> Result<&str, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "`{{{name}}}` is not a valid capture, names are made of letters, digits and `_`"
+        ));
+    }
+    Ok(name)
+}
+
+fn compile_template(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let parts: Vec<&str> = template.split('/').skip(1).collect();
+    for (index, part) in parts.iter().enumerate() {
+        let segment = match part
+            .strip_prefix('{')
+            .and_then(|part| part.strip_suffix('}'))
+        {
+            Some(name) => match name.strip_suffix('*') {
+                Some(_) if index + 1 < parts.len() => {
+                    return Err(format!("`{{{name}}}` must be the last segment of the path"))
+                }
+                Some(name) => Segment::Rest(capture_name(name)?.to_owned()),
+                None => Segment::Capture(capture_name(name)?.to_owned()),
+            },
+            None if part.contains(['{', '}']) => {
+                return Err(format!("`{part}` mixes a capture with text in a segment"))
+            }
+            None => Segment::Literal((*part).to_owned()),
+        };
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+impl PathMatcher {
+    fn compile(route: &Route) -> Result<Self, String> {
+        let matcher = match (&route.prefix, &route.path, &route.regex) {
+            (Some(prefix), None, None) => {
+                if !prefix.starts_with('/') {
+                    return Err(format!("prefix `{prefix}` must start with `/`"));
+                }
+                Self::Prefix(prefix.trim_end_matches('/').to_owned())
+            }
+            (None, Some(path), None) => {
+                if !path.starts_with('/') {
+                    return Err(format!("path `{path}` must start with `/`"));
+                }
+                Self::Template(compile_template(path)?)
+            }
+            (None, None, Some(regex)) => Self::Regex(
+                // Matched against the whole path
+                Regex::new(&format!("^(?:{regex})$"))
+                    .map_err(|err| format!("invalid regex `{regex}`: {err}"))?,
+            ),
+            _ => return Err("exactly one of `prefix`, `path` and `regex` must be set".to_owned()),
+        };
+        let mut names = matcher.names();
+        names.sort();
+        if let Some(name) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("`{}` is captured more than once", name[0]));
+        }
+        Ok(matcher)
+    }
+
+    /// Names of the values captured from the path.
+    fn names(&self) -> Vec<String> {
+        match self {
+            Self::Prefix(_) => vec!["tail".to_owned()],
+            Self::Template(segments) => segments
+                .iter()
+                .filter_map(|segment| match segment {
+                    Segment::Literal(_) => None,
+                    Segment::Capture(name) | Segment::Rest(name) => Some(name.clone()),
+                })
+                .collect(),
+            Self::Regex(regex) => regex.capture_names().flatten().map(str::to_owned).collect(),
+        }
+    }
+
+    /// Characters of the paths matched literally, the routes with more of them being tried
+    /// first.
+    fn specificity(&self) -> usize {
+        match self {
+            Self::Prefix(prefix) => prefix.len(),
+            Self::Template(segments) => segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(literal) => literal.len() + 1,
+                    _ => 1,
+                })
+                .sum(),
+            Self::Regex(_) => 0,
+        }
+    }
+
+    fn captures(&self, path: &str) -> Option<Captures> {
+        match self {
+            // Whole segments only, `/hello` matching neither `/helloworld` nor `/hello.txt`
+            Self::Prefix(prefix) => {
+                let tail = path.strip_prefix(prefix.as_str())?;
+                (tail.is_empty() || tail.starts_with('/'))
+                    .then(|| vec![("tail".to_owned(), tail.to_owned())])
+            }
+            Self::Template(segments) => {
+                let mut parts = path.split('/').skip(1);
+                let mut captures = Vec::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Literal(literal) => {
+                            if parts.next()? != literal {
+                                return None;
+                            }
+                        }
+                        Segment::Capture(name) => {
+                            let part = parts.next().filter(|part| !part.is_empty())?;
+                            captures.push((name.clone(), part.to_owned()));
+                        }
+                        Segment::Rest(name) => {
+                            let rest: Vec<&str> = parts.by_ref().collect();
+                            captures.push((name.clone(), rest.join("/")));
+                        }
+                    }
+                }
+                parts.next().is_none().then_some(captures)
+            }
+            Self::Regex(regex) => {
+                let found = regex.captures(path)?;
+                Some(
+                    regex
+                        .capture_names()
+                        .flatten()
+                        .filter_map(|name| {
+                            Some((name.to_owned(), found.name(name)?.as_str().to_owned()))
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+/// Fill the `{name}` placeholders of `template` with `captures`.
+fn fill(template: &str, captures: &Captures) -> String {
+    let mut filled = template.to_owned();
+    for (name, value) in captures {
+        filled = filled.replace(&format!("{{{name}}}"), value);
+    }
+    filled
+}
+
+/// Names of the `{name}` placeholders of `template`.
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+impl Route {
+    /// Check the route and compile its path matching, describing what is wrong otherwise.
+    pub fn compile(&mut self) -> Result<(), String> {
+        let matcher = PathMatcher::compile(self)?;
+        if self.methods.is_empty() {
+            return Err("no method is listed".to_owned());
+        }
+        if let Some(method) = self
+            .methods
+            .iter()
+            .find(|method| reqwest::Method::from_bytes(method.as_bytes()).is_err())
+        {
+            return Err(format!("`{method}` is not a valid method"));
+        }
+        if let Some(name) = self
+            .headers
+            .keys()
+            .find(|name| reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(format!("`{name}` is not a valid header name"));
+        }
+        let names = matcher.names();
+        if let Some(rule) = &self.require {
+            rule.check(&names)?;
+        }
+        for (method, rule) in &self.require_by_method {
+            if !self.allows(method) {
+                return Err(format!(
+                    "`require_by_method` has `{method}`, which is not listed"
+                ));
+            }
+            rule.check(&names)
+                .map_err(|reason| format!("{method} requirements: {reason}"))?;
+        }
+        if let Some(rewrite) = &self.rewrite {
+            if !rewrite.starts_with('/') {
+                return Err(format!("rewrite `{rewrite}` must start with `/`"));
+            }
+            if let Some(unknown) = placeholders(rewrite)
+                .into_iter()
+                .find(|placeholder| !names.iter().any(|name| name == placeholder))
+            {
+                return Err(format!(
+                    "rewrite `{rewrite}` uses `{{{unknown}}}`, which the route does not capture"
+                ));
+            }
+        }
+        self.matcher = Some(matcher);
+        Ok(())
+    }
+
+    /// Name of the route in the metrics and rate limit buckets.
+    pub fn label(&self) -> &str {
+        let pattern = self
+            .prefix
+            .as_ref()
+            .or(self.path.as_ref())
+            .or(self.regex.as_ref());
+        pattern.map(String::as_str).unwrap_or_default()
+    }
+
+    fn specificity(&self) -> usize {
+        self.matcher.as_ref().map_or(0, PathMatcher::specificity)
+    }
+
+    /// Captures of the path when the route serves the host, path and predicates of `request`,
+    /// whatever its method.
+    fn matches(&self, request: &RequestInfo, predicates: bool) -> Option<Captures> {
+        if let Some(host) = &self.host {
+            let requested = request.host?;
+            let served = match host.strip_prefix("*.") {
+                Some(domain) => requested
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => requested.eq_ignore_ascii_case(host),
+            };
+            if !served {
+                return None;
+            }
+        }
+        if predicates {
+            let headers = self
+                .headers
+                .iter()
+                .all(|(name, value)| request.headers.get_str(name) == Some(value.as_str()));
+            let query = self.query.iter().all(|(name, value)| {
+                request
+                    .query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+                    .any(|(key, found)| key == name && found == value)
+            });
+            if !headers || !query {
+                return None;
+            }
+        }
+        self.matcher.as_ref()?.captures(request.path)
+    }
+}
+
+/// Route chosen for a request.
+pub struct RouteMatch<'a> {
+    pub route: &'a Route,
+    /// Path to request on the service: the one of the client past the prefix of prefix routes,
+    /// the whole one otherwise, unless the route rewrites it.
+    pub path: String,
+    pub captures: Captures,
+}
+
+/// Why no route was found for a request.
+#[derive(Debug, PartialEq)]
+pub enum NoRoute {
+    NotFound,
+    /// Routes serve the path, with other methods.
+    MethodNotAllowed,
+}
+
+impl Config {
+    /// Check every route and order them: by decreasing `priority`, then by decreasing number of
+    /// characters matched literally, then in the order of the file.
+    pub fn compile_routes(&mut self) -> Result<(), (usize, String)> {
+        for (index, route) in self.routes.iter_mut().enumerate() {
+            route.compile().map_err(|reason| (index, reason))?;
+            match (&route.upstream, route.service.is_empty()) {
+                (Some(name), true) if !self.upstreams.contains_key(name) => {
+                    return Err((index, format!("upstream `{name}` is not defined")));
+                }
+                (Some(_), true) | (None, false) => {}
+                _ => {
+                    let reason = "exactly one of `service` and `upstream` must be set";
+                    return Err((index, reason.to_owned()));
+                }
+            }
+        }
+        self.routes.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(b.specificity().cmp(&a.specificity()))
+        });
+        Ok(())
+    }
+
+    /// First route serving `request`.
+    pub fn find_route(&self, request: &RequestInfo) -> Result<RouteMatch<'_>, NoRoute> {
+        let mut other_methods = false;
+        for route in &self.routes {
+            let Some(captures) = route.matches(request, true) else {
+                continue;
+            };
+            if !route.allows(request.method) {
+                other_methods = true;
+                continue;
+            }
+            let path = match (&route.rewrite, &route.matcher) {
+                (Some(rewrite), _) => fill(rewrite, &captures),
+                (None, Some(PathMatcher::Prefix(prefix))) => {
+                    request.path[prefix.len()..].to_owned()
+                }
+                (None, _) => request.path.to_owned(),
+            };
+            return Ok(RouteMatch {
+                route,
+                path,
+                captures,
+            });
+        }
+        Err(if other_methods {
+            NoRoute::MethodNotAllowed
+        } else {
+            NoRoute::NotFound
+        })
+    }
+
+    /// Methods the routes serving the host and path of `request` accept, `HEAD` and `OPTIONS`
+    /// included. Empty when no route serves them.
+    pub fn allowed_methods(&self, request: &RequestInfo) -> Vec<String> {
+        let mut methods: Vec<String> = Vec::new();
+        // Header and query predicates are left out, preflights being sent without them
+        for route in self
+            .routes
+            .iter()
+            .filter(|route| route.matches(request, false).is_some())
+        {
+            for method in &route.methods {
+                if !methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+        }
+        if methods.is_empty() {
+            return methods;
+        }
+        let listed =
+            |methods: &Vec<String>, name: &str| methods.iter().any(|method| method == name);
+        if listed(&methods, "GET") && !listed(&methods, "HEAD") {
+            methods.push("HEAD".to_owned());
+        }
+        if !listed(&methods, "OPTIONS") {
+            methods.push("OPTIONS".to_owned());
+        }
+        methods
+    }
+}
@@ -0,0 +1,95 @@
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use actix_web::{dev::ServerHandle, get, rt, web, HttpResponse, Responder};
+
+/// Readiness of the service to take new traffic, shared by every worker thread.
+///
+/// It goes unhealthy as soon as a shutdown is requested, while `/health` keeps reporting the
+/// process as alive until it exits.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_not_ready(&self) {
+        self.0.store(false, Ordering::SeqCst)
+    }
+}
+
+#[get("/ready")]
+async fn ready(readiness: web::Data<Readiness>) -> impl Responder {
+    if readiness.is_ready() {
+        HttpResponse::Ok().body("READY")
+    } else {
+        HttpResponse::ServiceUnavailable().body("SHUTTING DOWN")
+    }
+}
+
+/// Timings of the graceful shutdown.
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownConfig {
+    /// Time left to load balancers to notice readiness went down, before new connections are refused.
+    pub grace_period: Duration,
+    /// Deadline given to in-flight requests to complete once connections are refused.
+    pub drain_timeout: Duration,
+}
+
+impl ShutdownConfig {
+    /// Read `SHUTDOWN_GRACE_PERIOD_SECS` (5 by default) and `SHUTDOWN_TIMEOUT_SECS` (30 by default).
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            grace_period: Duration::from_secs(seconds("SHUTDOWN_GRACE_PERIOD_SECS", 5)),
+            drain_timeout: Duration::from_secs(seconds("SHUTDOWN_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+/// Resolve on SIGTERM, sent by docker and kubernetes, or on Ctrl-C.
+pub async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = rt::signal::unix::signal(rt::signal::unix::SignalKind::terminate())
+            .expect("Could not listen to SIGTERM.");
+        futures::future::select(Box::pin(sigterm.recv()), Box::pin(rt::signal::ctrl_c())).await;
+    }
+    #[cfg(not(unix))]
+    let _ = rt::signal::ctrl_c().await;
+}
+
+/// Stop the server gracefully once `signal` resolves.
+///
+/// Readiness goes down first, then after the grace period the listeners are closed and in-flight
+/// requests are drained. The drain deadline itself is enforced by the server, built with
+/// `HttpServer::shutdown_timeout` and with its own signal handling disabled.
+pub async fn graceful_shutdown(
+    handle: ServerHandle,
+    readiness: Readiness,
+    config: ShutdownConfig,
+    signal: impl Future<Output = ()>,
+) {
+    signal.await;
+    tracing::info!(?config, "shutdown requested, draining in-flight requests");
+    readiness.set_not_ready();
+    rt::time::sleep(config.grace_period).await;
+    handle.stop(true).await;
+}
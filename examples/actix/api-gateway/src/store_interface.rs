@@ -1,16 +1,171 @@
-use crate::schemas::User;
+use crate::schemas::{ApiKey, Client, Credentials, User};
 use async_trait::async_trait;
 use bytes::Bytes;
 use coi::Inject;
+use futures::stream::BoxStream;
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use std::fmt;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[async_trait]
 pub trait UserRepository: Inject {
     async fn get_user(&self, id: Uuid) -> Option<User>;
     async fn create_user(&self, u: &User) -> Result<(), ()>;
+
+    // Accounts are optional, repositories knowing the users by id only, such as caches, keep
+    // the defaults below
+
+    /// Store `u` along with the credentials it logs in with.
+    async fn create_account(&self, _u: &User, _c: &Credentials) -> Result<(), AccountError> {
+        Err(AccountError::Storage)
+    }
+    /// Credentials of the account logging in as `username`.
+    async fn find_credentials(&self, _username: &str) -> Option<Credentials> {
+        None
+    }
+    /// Credentials of a user, `None` when it logs in without a password.
+    async fn get_credentials(&self, _id: Uuid) -> Option<Credentials> {
+        None
+    }
+    async fn set_password_hash(&self, _id: Uuid, _password_hash: &str) -> Result<(), ()> {
+        Err(())
+    }
+    /// Revoke every session of a user, answering it with its new session version.
+    async fn revoke_sessions(&self, _id: Uuid) -> Result<User, ()> {
+        Err(())
+    }
+    /// User logging in with the `subject` identity of the `issuer` provider.
+    async fn find_identity(&self, _issuer: &str, _subject: &str) -> Option<Uuid> {
+        None
+    }
+    /// Store `u` along with the provider identity it logs in with.
+    async fn create_identity(&self, _u: &User, _issuer: &str, _subject: &str) -> Result<(), ()> {
+        Err(())
+    }
+    async fn grant_role(&self, _id: Uuid, _role: &str) -> Result<(), ()> {
+        Err(())
+    }
+    async fn revoke_role(&self, _id: Uuid, _role: &str) -> Result<(), ()> {
+        Err(())
+    }
+    async fn create_api_key(&self, _key: &ApiKey) -> Result<(), ()> {
+        Err(())
+    }
+    /// Key of `key_hash`, whether expired or not.
+    async fn find_api_key(&self, _key_hash: &str) -> Option<ApiKey> {
+        None
+    }
+    async fn list_api_keys(&self, _user_id: Uuid) -> Result<Vec<ApiKey>, ()> {
+        Err(())
+    }
+    /// Delete a key of a user, answering whether it existed.
+    async fn delete_api_key(&self, _user_id: Uuid, _id: Uuid) -> Result<bool, ()> {
+        Err(())
+    }
+    /// Store the machine client `c` along with its service principal `u`.
+    async fn create_client(&self, _u: &User, _c: &Client) -> Result<(), ()> {
+        Err(())
+    }
+    async fn find_client(&self, _client_id: &str) -> Option<Client> {
+        None
+    }
+    /// Delete a machine client, answering its service principal when it existed.
+    async fn delete_client(&self, _client_id: &str) -> Result<Option<Uuid>, ()> {
+        Err(())
+    }
+}
+
+/// Why an account could not be stored.
+#[derive(Debug)]
+pub enum AccountError {
+    /// Another account logs in with this username.
+    UsernameTaken,
+    Storage,
+}
+
+/// Request to a proxied service, its body streamed from the client.
+pub struct UpstreamRequest {
+    pub method: Method,
+    /// Url of the service, query string included.
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: reqwest::Body,
+    /// Time to connect to the service.
+    pub connect_timeout: Duration,
+    /// Time to receive the response head, the body being streamed afterwards.
+    pub timeout: Duration,
+}
+
+/// Body of a proxied service response.
+pub enum UpstreamBody {
+    /// Streamed to the client.
+    Stream(BoxStream<'static, Result<Bytes, reqwest::Error>>),
+    /// Connection switched to another protocol, e.g. a websocket, tunneled to the client.
+    Upgraded(reqwest::Upgraded),
+}
+
+/// Response of a proxied service.
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: UpstreamBody,
+}
+
+/// Why a service call got no response.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// No connection could be made to the service, or it broke before the response head.
+    Unreachable(reqwest::Error),
+    /// No response head within the timeout.
+    Timeout,
+    /// Calls to the upstream are short-circuited after failing in a row, for this long.
+    CircuitOpen(Duration),
+    /// Every endpoint of the upstream is set aside.
+    NoEndpoint,
+}
+
+impl UpstreamError {
+    /// Status of the response the client gets instead.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Unreachable(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::CircuitOpen(_) | Self::NoEndpoint => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Detail of the problem the client gets, without the internals of the error.
+    pub fn detail(&self) -> &'static str {
+        match self {
+            Self::Unreachable(_) => "Service unreachable",
+            Self::Timeout => "Service timed out",
+            Self::CircuitOpen(_) => "Service unavailable after failing requests",
+            Self::NoEndpoint => "No healthy instance of the service",
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreachable(err) => write!(f, "service unreachable: {err}"),
+            Self::Timeout => write!(f, "no response within the timeout"),
+            Self::CircuitOpen(remaining) => {
+                write!(f, "circuit open for {}s", remaining.as_secs())
+            }
+            Self::NoEndpoint => write!(f, "no healthy endpoint"),
+        }
+    }
 }
 
 #[async_trait]
 pub trait Proxy: Inject {
-    async fn make_request(&self, method: &str, url: &str, user_id: &Uuid) -> (u16, Bytes);
+    /// Send `request` on behalf of the user, `Err` when the service gave no response in time.
+    async fn make_request(
+        &self,
+        request: UpstreamRequest,
+        user_id: &Uuid,
+        request_id: &str,
+    ) -> Result<UpstreamResponse, UpstreamError>;
 }
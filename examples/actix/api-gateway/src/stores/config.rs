@@ -1,25 +1,299 @@
+use arc_swap::ArcSwap;
 use serde::{Deserialize, Serialize};
 use serde_yaml::{self};
 use lazy_static::lazy_static;
 use jwt_simple::prelude::HS384Key;
-use std::env;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, io};
+use crate::authz::{AccessRule, RoleConfig};
+use crate::balancer::{build_pools, Pool, UpstreamConfig};
+use crate::cors::CorsConfig;
+use crate::discovery::DiscoverySettings;
+use crate::idempotency::IdempotencyConfig;
+use crate::metrics::{CONFIG_RELOADS_TOTAL, CONFIG_VERSION};
+use crate::oidc::OidcConfig;
+use crate::ratelimit::{Quota, RateLimitConfig};
+use crate::resilience::{ResilienceConfig, RetryPolicy, Timeouts};
+use crate::routing::PathMatcher;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Configuration of the gateway, read from `GATEWAY_CONFIG` (`routes.yml` by default) and
+/// reloaded when the file changes, on SIGHUP or from `/admin/config/reload`.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(skip_deserializing)]
+    pub version: ConfigVersion,
     pub routes: Vec<Route>,
+    /// Pools of service instances the routes may be balanced across, by name.
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    #[serde(skip)]
+    pub pools: HashMap<String, Arc<Pool>>,
+    #[serde(default)]
+    pub discovery: DiscoverySettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Its `store` is only read at startup.
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub resilience: ResilienceConfig,
+    /// Login through an OpenID Connect provider, disabled when unset.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+    /// Permissions granted by the roles of the users, by role.
+    #[serde(default)]
+    pub roles: HashMap<String, RoleConfig>,
+}
+
+/// Identifies the configuration in use.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConfigVersion {
+    /// Incremented by every configuration swapped in, 0 when none could be loaded at startup.
+    pub number: u64,
+    /// SHA-256 of the file, hex encoded.
+    pub digest: String,
+    pub path: String,
+    /// Seconds since the epoch.
+    pub loaded_at: u64,
 }
 
+/// A route to a service. Paths are matched with exactly one of `prefix`, `path` or `regex`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Route {
     pub methods: Vec<String>,
-    pub prefix: String,
+    /// Leading segments of the paths, e.g. `/hello` matching `/hello` and `/hello/world` but not
+    /// `/helloworld`. The rest of the path is sent to the service, and captured as `tail`.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Path template, e.g. `/users/{id}/todos`, `{name}` capturing a segment and a last
+    /// `{name*}` the rest of the path.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Regex matched against the whole path, its named groups being captured.
+    #[serde(default)]
+    pub regex: Option<String>,
+    /// Host the requests are sent to, e.g. `api.example.com` or `*.example.com`.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Headers the requests must carry, with these exact values.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Query parameters the requests must carry, with these exact values.
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    /// Routes are tried by decreasing priority, then from the most specific path.
+    #[serde(default)]
+    pub priority: i32,
+    /// Path sent to the service, e.g. `/todos?user={id}`, filled with the captures.
+    #[serde(default)]
+    pub rewrite: Option<String>,
+    /// `host:port` of the service, optionally followed by a path prefix, unless `upstream` is set.
+    #[serde(default)]
     pub service: String,
-    pub restrict_admin: bool
+    /// Name of the upstream pool the requests are balanced across.
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Only serve the admins.
+    #[serde(default)]
+    pub restrict_admin: bool,
+    /// What users need to be served.
+    #[serde(default)]
+    pub require: Option<AccessRule>,
+    /// Replace `require` for the listed methods.
+    #[serde(default)]
+    pub require_by_method: HashMap<String, AccessRule>,
+    #[serde(default)]
+    pub rate_limit: Option<Quota>,
+    /// Replace the `resilience` defaults for this route.
+    #[serde(default)]
+    pub timeouts: Option<Timeouts>,
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    #[serde(skip)]
+    pub matcher: Option<PathMatcher>,
+}
+
+impl Route {
+    /// Whether the route proxies `method`, `HEAD` going with `GET`.
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed == method || (method == "HEAD" && allowed == "GET"))
+    }
+}
+
+/// Why a configuration file could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Yaml(serde_yaml::Error),
+    /// Route at `index` in the file, described by `label`, is invalid.
+    Route { index: usize, label: String, reason: String },
+    Upstream { name: String, reason: String },
 }
 
-pub fn get_config() -> &'static Config {
-    lazy_static!{static ref CONFIG: Config = serde_yaml::from_reader(std::fs::File::open("routes.yml").expect("Could not open config file.")).expect("Could not read config file.");};
-    return &CONFIG;
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not open the file: {err}"),
+            Self::Yaml(err) => write!(f, "could not read the file: {err}"),
+            Self::Route { index, label, reason } if label.is_empty() => {
+                write!(f, "route {}: {reason}", index + 1)
+            }
+            Self::Route { index, label, reason } => {
+                write!(f, "route {} (`{label}`): {reason}", index + 1)
+            }
+            Self::Upstream { name, reason } => write!(f, "upstream `{name}`: {reason}"),
+        }
+    }
+}
+
+impl Config {
+    /// Read and check the configuration, its routes ready to be matched.
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read(path).map_err(ConfigError::Io)?;
+        let mut config: Config = serde_yaml::from_slice(&contents).map_err(ConfigError::Yaml)?;
+        for (name, upstream) in &config.upstreams {
+            upstream.validate().map_err(|reason| ConfigError::Upstream {
+                name: name.clone(),
+                reason,
+            })?;
+        }
+        config.compile_routes().map_err(|(index, reason)| ConfigError::Route {
+            index,
+            label: config.routes[index].label().to_owned(),
+            reason,
+        })?;
+        config.version = ConfigVersion {
+            number: 0,
+            digest: hex::encode(Sha256::digest(&contents)),
+            path: path.to_owned(),
+            loaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+        };
+        Ok(config)
+    }
+}
+
+/// Path of the configuration file, `GATEWAY_CONFIG` (`routes.yml` by default).
+pub fn config_path() -> String {
+    env::var("GATEWAY_CONFIG").unwrap_or_else(|_| "routes.yml".to_owned())
+}
+
+static VERSIONS: AtomicU64 = AtomicU64::new(0);
+
+fn swap_in(mut config: Config) -> ConfigVersion {
+    // Endpoints listed again keep their health and load
+    config.pools = build_pools(&config.upstreams, &CONFIG.load().pools);
+    config.version.number = VERSIONS.fetch_add(1, Ordering::SeqCst) + 1;
+    CONFIG_VERSION.set(config.version.number as i64);
+    let version = config.version.clone();
+    CONFIG.store(Arc::new(config));
+    version
+}
+
+lazy_static! {
+    static ref CONFIG: ArcSwap<Config> = {
+        let path = config_path();
+        let config = match Config::from_file(&path) {
+            Ok(mut config) => {
+                config.pools = build_pools(&config.upstreams, &HashMap::new());
+                config.version.number = VERSIONS.fetch_add(1, Ordering::SeqCst) + 1;
+                CONFIG_VERSION.set(config.version.number as i64);
+                config
+            }
+            // Served as is until a valid file is loaded, rather than stopping the gateway
+            Err(err) => {
+                tracing::error!(%err, path, "invalid configuration, no route is served");
+                Config::default()
+            }
+        };
+        ArcSwap::from_pointee(config)
+    };
+    // Reloads are made one at a time
+    static ref RELOADING: Mutex<()> = Mutex::new(());
+}
+
+/// Configuration in use. Keep it for the time of a request, for the request to see a single
+/// version of it.
+pub fn get_config() -> Arc<Config> {
+    CONFIG.load_full()
+}
+
+/// Load the configuration file again and swap it in, unless invalid: the configuration in use is
+/// kept then, the error being logged and returned.
+pub fn reload_config(trigger: &str) -> Result<ConfigVersion, ConfigError> {
+    let _reloading = RELOADING.lock().unwrap();
+    let current = get_config();
+    let path = config_path();
+    match Config::from_file(&path) {
+        Ok(config) if config.version.digest == current.version.digest => {
+            CONFIG_RELOADS_TOTAL.with_label_values(&["unchanged"]).inc();
+            Ok(current.version.clone())
+        }
+        Ok(config) => {
+            let version = swap_in(config);
+            CONFIG_RELOADS_TOTAL.with_label_values(&["applied"]).inc();
+            tracing::info!(trigger, path, version = version.number, digest = version.digest, "configuration reloaded");
+            Ok(version)
+        }
+        Err(err) => {
+            CONFIG_RELOADS_TOTAL.with_label_values(&["rejected"]).inc();
+            tracing::error!(%err, trigger, path, version = current.version.number, "configuration rejected, keeping the one in use");
+            Err(err)
+        }
+    }
+}
+
+// Writes come in bursts, the file is reloaded once they settled
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Reload the configuration whenever its file changes, until the returned watcher is dropped.
+///
+/// The directory is watched rather than the file, editors and kubernetes replacing files instead
+/// of writing them.
+pub fn watch_config() -> notify::Result<RecommendedWatcher> {
+    let path = config_path();
+    let path = Path::new(&path);
+    let directory = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_owned();
+    let file_name = path.file_name().map(|name| name.to_owned());
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        // Kubernetes swaps the `..data` link of mounted config maps
+        let concerned = event.paths.iter().any(|changed| {
+            changed.file_name() == file_name.as_deref()
+                || changed.file_name().is_some_and(|name| name == "..data")
+        });
+        if concerned && !event.kind.is_access() {
+            let _ = sender.send(());
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    std::thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            std::thread::sleep(WATCH_DEBOUNCE);
+            while receiver.try_recv().is_ok() {}
+            let _ = reload_config("file");
+        }
+    });
+    Ok(watcher)
 }
 
 pub fn get_key() -> &'static HS384Key {
@@ -30,4 +304,21 @@ pub fn get_key() -> &'static HS384Key {
         Err(_) => HS384Key::generate()
     };};
     return &KEY;
-}
\ No newline at end of file
+}
+
+/// Reload the configuration on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_hangup() {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::warn!(%err, "could not listen to SIGHUP, the configuration is not reloaded on it");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let _ = reload_config("signal");
+    }
+}
+
@@ -0,0 +1,151 @@
+// In-memory users repository, standing in for postgres in the tests
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use coi::Inject;
+use uuid::Uuid;
+
+use crate::schemas::{ApiKey, Client, Credentials, User};
+use crate::store_interface::{AccountError, UserRepository};
+
+#[derive(Default, Inject)]
+pub struct InMemoryUsers {
+    pub users: Mutex<HashMap<Uuid, User>>,
+    pub credentials: Mutex<Vec<Credentials>>,
+    /// Issuer, subject and user of the provider identities.
+    pub identities: Mutex<Vec<(String, String, Uuid)>>,
+    pub api_keys: Mutex<Vec<ApiKey>>,
+    pub clients: Mutex<Vec<Client>>,
+}
+
+impl InMemoryUsers {
+    pub fn with_users(users: impl IntoIterator<Item = User>) -> Self {
+        let repository = Self::default();
+        for user in users {
+            repository.users.lock().unwrap().insert(user.id, user);
+        }
+        repository
+    }
+}
+
+#[async_trait]
+impl UserRepository for InMemoryUsers {
+    async fn get_user(&self, id: Uuid) -> Option<User> {
+        self.users.lock().unwrap().get(&id).cloned()
+    }
+
+    async fn create_user(&self, u: &User) -> Result<(), ()> {
+        self.users.lock().unwrap().insert(u.id, u.clone());
+        Ok(())
+    }
+
+    async fn create_account(&self, u: &User, c: &Credentials) -> Result<(), AccountError> {
+        let mut credentials = self.credentials.lock().unwrap();
+        if credentials.iter().any(|known| known.username == c.username) {
+            return Err(AccountError::UsernameTaken);
+        }
+        credentials.push(c.clone());
+        self.users.lock().unwrap().insert(u.id, u.clone());
+        Ok(())
+    }
+
+    async fn find_credentials(&self, username: &str) -> Option<Credentials> {
+        let credentials = self.credentials.lock().unwrap();
+        credentials.iter().find(|c| c.username == username).cloned()
+    }
+
+    async fn get_credentials(&self, id: Uuid) -> Option<Credentials> {
+        let credentials = self.credentials.lock().unwrap();
+        credentials.iter().find(|c| c.user_id == id).cloned()
+    }
+
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), ()> {
+        let mut credentials = self.credentials.lock().unwrap();
+        let c = credentials.iter_mut().find(|c| c.user_id == id).ok_or(())?;
+        c.password_hash = password_hash.to_owned();
+        Ok(())
+    }
+
+    async fn revoke_sessions(&self, id: Uuid) -> Result<User, ()> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(&id).ok_or(())?;
+        user.session_version += 1;
+        Ok(user.clone())
+    }
+
+    async fn find_identity(&self, issuer: &str, subject: &str) -> Option<Uuid> {
+        let identities = self.identities.lock().unwrap();
+        identities
+            .iter()
+            .find(|(i, s, _)| i == issuer && s == subject)
+            .map(|(_, _, user_id)| *user_id)
+    }
+
+    async fn create_identity(&self, u: &User, issuer: &str, subject: &str) -> Result<(), ()> {
+        let identity = (issuer.to_owned(), subject.to_owned(), u.id);
+        self.identities.lock().unwrap().push(identity);
+        self.users.lock().unwrap().insert(u.id, u.clone());
+        Ok(())
+    }
+
+    async fn grant_role(&self, id: Uuid, role: &str) -> Result<(), ()> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(&id).ok_or(())?;
+        if !user.has_role(role) {
+            user.roles.push(role.to_owned());
+        }
+        Ok(())
+    }
+
+    async fn revoke_role(&self, id: Uuid, role: &str) -> Result<(), ()> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(&id).ok_or(())?;
+        user.roles.retain(|granted| granted != role);
+        Ok(())
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<(), ()> {
+        self.api_keys.lock().unwrap().push(key.clone());
+        Ok(())
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Option<ApiKey> {
+        let keys = self.api_keys.lock().unwrap();
+        keys.iter().find(|key| key.key_hash == key_hash).cloned()
+    }
+
+    async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>, ()> {
+        let keys = self.api_keys.lock().unwrap();
+        Ok(keys
+            .iter()
+            .filter(|key| key.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_api_key(&self, user_id: Uuid, id: Uuid) -> Result<bool, ()> {
+        let mut keys = self.api_keys.lock().unwrap();
+        let count = keys.len();
+        keys.retain(|key| key.user_id != user_id || key.id != id);
+        Ok(keys.len() < count)
+    }
+
+    async fn create_client(&self, u: &User, c: &Client) -> Result<(), ()> {
+        self.users.lock().unwrap().insert(u.id, u.clone());
+        self.clients.lock().unwrap().push(c.clone());
+        Ok(())
+    }
+
+    async fn find_client(&self, client_id: &str) -> Option<Client> {
+        let clients = self.clients.lock().unwrap();
+        clients.iter().find(|c| c.client_id == client_id).cloned()
+    }
+
+    async fn delete_client(&self, client_id: &str) -> Result<Option<Uuid>, ()> {
+        let mut clients = self.clients.lock().unwrap();
+        let position = clients.iter().position(|c| c.client_id == client_id);
+        Ok(position.map(|position| clients.remove(position).user_id))
+    }
+}
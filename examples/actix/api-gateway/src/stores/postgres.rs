@@ -1,11 +1,95 @@
-use crate::schemas::User;
-use crate::store_interface::UserRepository;
+use crate::schemas::{ApiKey, Client, Credentials, User};
+use crate::store_interface::{AccountError, UserRepository};
+use crate::stores::idempotency::MIGRATIONS;
 use async_trait::async_trait;
 use coi::{Inject, Provide};
 use deadpool_postgres::*;
-use tokio_postgres::NoTls;
+use tokio_postgres::{error::SqlState, NoTls, Row};
+use tracing::{instrument, Instrument};
 use uuid::Uuid;
 
+const GET_USER: &str = "SELECT id, admin, session_version, \
+    ARRAY(SELECT role FROM user_roles WHERE user_id = users.id ORDER BY role) \
+    FROM users WHERE id = $1;";
+const CREATE_USER: &str = "INSERT INTO users (id, admin) VALUES ($1, $2);";
+const CREATE_CREDENTIALS: &str =
+    "INSERT INTO credentials (user_id, username, password_hash) VALUES ($1, $2, $3);";
+const FIND_CREDENTIALS: &str =
+    "SELECT user_id, username, password_hash FROM credentials WHERE username = $1;";
+const GET_CREDENTIALS: &str =
+    "SELECT user_id, username, password_hash FROM credentials WHERE user_id = $1;";
+const SET_PASSWORD_HASH: &str = "UPDATE credentials SET password_hash = $2 WHERE user_id = $1;";
+const REVOKE_SESSIONS: &str = "UPDATE users SET session_version = session_version + 1 \
+    WHERE id = $1 RETURNING id, admin, session_version, \
+    ARRAY(SELECT role FROM user_roles WHERE user_id = users.id ORDER BY role);";
+const FIND_IDENTITY: &str = "SELECT user_id FROM identities WHERE issuer = $1 AND subject = $2;";
+const CREATE_IDENTITY: &str =
+    "INSERT INTO identities (issuer, subject, user_id) VALUES ($1, $2, $3);";
+const GRANT_ROLE: &str =
+    "INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING;";
+const REVOKE_ROLE: &str = "DELETE FROM user_roles WHERE user_id = $1 AND role = $2;";
+const CREATE_API_KEY: &str = "INSERT INTO api_keys \
+    (id, user_id, name, prefix, key_hash, scopes, expires_at, created_at) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8);";
+const FIND_API_KEY: &str = "SELECT id, user_id, name, prefix, key_hash, scopes, expires_at, \
+    created_at FROM api_keys WHERE key_hash = $1;";
+const LIST_API_KEYS: &str = "SELECT id, user_id, name, prefix, key_hash, scopes, expires_at, \
+    created_at FROM api_keys WHERE user_id = $1 ORDER BY created_at;";
+const DELETE_API_KEY: &str = "DELETE FROM api_keys WHERE user_id = $1 AND id = $2;";
+const CREATE_CLIENT: &str =
+    "INSERT INTO clients (client_id, user_id, name, secret_hash) VALUES ($1, $2, $3, $4);";
+const FIND_CLIENT: &str =
+    "SELECT client_id, user_id, name, secret_hash FROM clients WHERE client_id = $1;";
+const DELETE_CLIENT: &str = "DELETE FROM clients WHERE client_id = $1 RETURNING user_id;";
+
+/// Accounts logging in with a password or through an OpenID Connect provider, their roles, their
+/// API keys, and the machine clients.
+const ACCOUNT_MIGRATIONS: [&str; 6] = [
+    "ALTER TABLE users ADD COLUMN IF NOT EXISTS session_version INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE IF NOT EXISTS credentials (
+        user_id UUID PRIMARY KEY REFERENCES users (id),
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL
+    );",
+    "CREATE TABLE IF NOT EXISTS identities (
+        issuer TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        user_id UUID NOT NULL REFERENCES users (id),
+        PRIMARY KEY (issuer, subject)
+    );",
+    "CREATE TABLE IF NOT EXISTS user_roles (
+        user_id UUID NOT NULL REFERENCES users (id),
+        role TEXT NOT NULL,
+        PRIMARY KEY (user_id, role)
+    );",
+    "CREATE TABLE IF NOT EXISTS api_keys (
+        id UUID PRIMARY KEY,
+        user_id UUID NOT NULL REFERENCES users (id),
+        name TEXT NOT NULL,
+        prefix TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        scopes TEXT[] NOT NULL,
+        expires_at BIGINT,
+        created_at BIGINT NOT NULL
+    );",
+    "CREATE TABLE IF NOT EXISTS clients (
+        client_id TEXT PRIMARY KEY,
+        user_id UUID NOT NULL UNIQUE REFERENCES users (id),
+        name TEXT NOT NULL,
+        secret_hash TEXT NOT NULL
+    );",
+];
+
+// One span per statement sent to the database, named after OpenTelemetry conventions
+pub(crate) fn sql_span(statement: &'static str) -> tracing::Span {
+    tracing::info_span!(
+        "sql",
+        otel.kind = "client",
+        db.system = "postgresql",
+        db.statement = statement
+    )
+}
+
 #[derive(Inject)]
 pub struct PostgresUser {
     pub pool: deadpool_postgres::Pool,
@@ -38,44 +122,288 @@ impl UserPostgresProvider {
         cfg.manager = Some(ManagerConfig {
             recycling_method: RecyclingMethod::Fast,
         });
-        let pool = cfg.create_pool(NoTls).unwrap();
+        let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
         Self { pool: pool }
     }
 
     // For demo setup purpose
     pub async fn migrate(&self) -> Result<u64, tokio_postgres::Error> {
         let client = self.pool.get().await.unwrap();
-        client
+        let mut modified = client
             .execute(
                 "CREATE TABLE IF NOT EXISTS users (id UUID PRIMARY KEY, admin BOOLEAN);",
                 &[],
             )
-            .await
+            .await?;
+        for migration in ACCOUNT_MIGRATIONS {
+            modified += client.execute(migration, &[]).await?;
+        }
+        // Idempotency keys are shared by the gateway instances through the users database
+        for migration in MIGRATIONS {
+            modified += client.execute(migration, &[]).await?;
+        }
+        Ok(modified)
     }
 }
 
 #[async_trait]
 impl UserRepository for PostgresUser {
+    #[instrument(skip(self))]
     async fn get_user(&self, id: Uuid) -> Option<User> {
         let client = self.pool.get().await.unwrap();
+        // Unknown ids, e.g. of the admin endpoints, are no user
         let row = client
-            .query_one("SELECT * FROM users WHERE id = $1;", &[&id])
+            .query_opt(GET_USER, &[&id])
+            .instrument(sql_span(GET_USER))
             .await
-            .unwrap();
-        Some(User {
-            id: row.get::<_, Uuid>(0),
-            admin: row.get::<_, bool>(1),
-        })
+            .unwrap()?;
+        Some(user(&row))
     }
+    #[instrument(skip(self))]
     async fn create_user(&self, u: &User) -> Result<(), ()> {
         let client = self.pool.get().await.unwrap();
+        client
+            .execute(CREATE_USER, &[&u.id, &u.admin])
+            .instrument(sql_span(CREATE_USER))
+            .await
+            .unwrap();
+        Ok(())
+    }
+    #[instrument(skip(self, c))]
+    async fn create_account(&self, u: &User, c: &Credentials) -> Result<(), AccountError> {
+        let mut client = self.pool.get().await.map_err(|_| AccountError::Storage)?;
+        let transaction = client
+            .transaction()
+            .await
+            .map_err(|_| AccountError::Storage)?;
+        transaction
+            .execute(CREATE_USER, &[&u.id, &u.admin])
+            .instrument(sql_span(CREATE_USER))
+            .await
+            .map_err(|_| AccountError::Storage)?;
+        transaction
+            .execute(CREATE_CREDENTIALS, &[&u.id, &c.username, &c.password_hash])
+            .instrument(sql_span(CREATE_CREDENTIALS))
+            .await
+            .map_err(|err| match err.code() {
+                Some(&SqlState::UNIQUE_VIOLATION) => AccountError::UsernameTaken,
+                _ => AccountError::Storage,
+            })?;
+        transaction
+            .commit()
+            .await
+            .map_err(|_| AccountError::Storage)
+    }
+    #[instrument(skip(self))]
+    async fn find_credentials(&self, username: &str) -> Option<Credentials> {
+        let client = self.pool.get().await.ok()?;
+        let row = client
+            .query_opt(FIND_CREDENTIALS, &[&username])
+            .instrument(sql_span(FIND_CREDENTIALS))
+            .await
+            .ok()??;
+        Some(credentials(&row))
+    }
+    #[instrument(skip(self))]
+    async fn get_credentials(&self, id: Uuid) -> Option<Credentials> {
+        let client = self.pool.get().await.ok()?;
+        let row = client
+            .query_opt(GET_CREDENTIALS, &[&id])
+            .instrument(sql_span(GET_CREDENTIALS))
+            .await
+            .ok()??;
+        Some(credentials(&row))
+    }
+    #[instrument(skip(self, password_hash))]
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        client
+            .execute(SET_PASSWORD_HASH, &[&id, &password_hash])
+            .instrument(sql_span(SET_PASSWORD_HASH))
+            .await
+            .map_err(|_| ())?;
+        Ok(())
+    }
+    #[instrument(skip(self))]
+    async fn revoke_sessions(&self, id: Uuid) -> Result<User, ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        let row = client
+            .query_one(REVOKE_SESSIONS, &[&id])
+            .instrument(sql_span(REVOKE_SESSIONS))
+            .await
+            .map_err(|_| ())?;
+        Ok(user(&row))
+    }
+    #[instrument(skip(self))]
+    async fn find_identity(&self, issuer: &str, subject: &str) -> Option<Uuid> {
+        let client = self.pool.get().await.ok()?;
+        let row = client
+            .query_opt(FIND_IDENTITY, &[&issuer, &subject])
+            .instrument(sql_span(FIND_IDENTITY))
+            .await
+            .ok()??;
+        Some(row.get::<_, Uuid>(0))
+    }
+    #[instrument(skip(self))]
+    async fn create_identity(&self, u: &User, issuer: &str, subject: &str) -> Result<(), ()> {
+        let mut client = self.pool.get().await.map_err(|_| ())?;
+        let transaction = client.transaction().await.map_err(|_| ())?;
+        transaction
+            .execute(CREATE_USER, &[&u.id, &u.admin])
+            .instrument(sql_span(CREATE_USER))
+            .await
+            .map_err(|_| ())?;
+        transaction
+            .execute(CREATE_IDENTITY, &[&issuer, &subject, &u.id])
+            .instrument(sql_span(CREATE_IDENTITY))
+            .await
+            .map_err(|_| ())?;
+        transaction.commit().await.map_err(|_| ())
+    }
+    #[instrument(skip(self))]
+    async fn grant_role(&self, id: Uuid, role: &str) -> Result<(), ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        client
+            .execute(GRANT_ROLE, &[&id, &role])
+            .instrument(sql_span(GRANT_ROLE))
+            .await
+            .map_err(|_| ())?;
+        Ok(())
+    }
+    #[instrument(skip(self))]
+    async fn revoke_role(&self, id: Uuid, role: &str) -> Result<(), ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        client
+            .execute(REVOKE_ROLE, &[&id, &role])
+            .instrument(sql_span(REVOKE_ROLE))
+            .await
+            .map_err(|_| ())?;
+        Ok(())
+    }
+    #[instrument(skip(self, key), fields(user = %key.user_id))]
+    async fn create_api_key(&self, key: &ApiKey) -> Result<(), ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
         client
             .execute(
-                "INSERT INTO users (id, admin) VALUES ($1, $2);",
-                &[&u.id, &u.admin],
+                CREATE_API_KEY,
+                &[
+                    &key.id,
+                    &key.user_id,
+                    &key.name,
+                    &key.prefix,
+                    &key.key_hash,
+                    &key.scopes,
+                    &key.expires_at,
+                    &key.created_at,
+                ],
             )
+            .instrument(sql_span(CREATE_API_KEY))
             .await
-            .unwrap();
+            .map_err(|_| ())?;
         Ok(())
     }
+    #[instrument(skip_all)]
+    async fn find_api_key(&self, key_hash: &str) -> Option<ApiKey> {
+        let client = self.pool.get().await.ok()?;
+        let row = client
+            .query_opt(FIND_API_KEY, &[&key_hash])
+            .instrument(sql_span(FIND_API_KEY))
+            .await
+            .ok()??;
+        Some(api_key(&row))
+    }
+    #[instrument(skip(self))]
+    async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>, ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        let rows = client
+            .query(LIST_API_KEYS, &[&user_id])
+            .instrument(sql_span(LIST_API_KEYS))
+            .await
+            .map_err(|_| ())?;
+        Ok(rows.iter().map(api_key).collect())
+    }
+    #[instrument(skip(self))]
+    async fn delete_api_key(&self, user_id: Uuid, id: Uuid) -> Result<bool, ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        let deleted = client
+            .execute(DELETE_API_KEY, &[&user_id, &id])
+            .instrument(sql_span(DELETE_API_KEY))
+            .await
+            .map_err(|_| ())?;
+        Ok(deleted > 0)
+    }
+    #[instrument(skip(self, c), fields(client = c.client_id))]
+    async fn create_client(&self, u: &User, c: &Client) -> Result<(), ()> {
+        let mut client = self.pool.get().await.map_err(|_| ())?;
+        let transaction = client.transaction().await.map_err(|_| ())?;
+        transaction
+            .execute(CREATE_USER, &[&u.id, &u.admin])
+            .instrument(sql_span(CREATE_USER))
+            .await
+            .map_err(|_| ())?;
+        transaction
+            .execute(
+                CREATE_CLIENT,
+                &[&c.client_id, &u.id, &c.name, &c.secret_hash],
+            )
+            .instrument(sql_span(CREATE_CLIENT))
+            .await
+            .map_err(|_| ())?;
+        transaction.commit().await.map_err(|_| ())
+    }
+    #[instrument(skip(self))]
+    async fn find_client(&self, client_id: &str) -> Option<Client> {
+        let client = self.pool.get().await.ok()?;
+        let row = client
+            .query_opt(FIND_CLIENT, &[&client_id])
+            .instrument(sql_span(FIND_CLIENT))
+            .await
+            .ok()??;
+        Some(Client {
+            client_id: row.get::<_, String>(0),
+            user_id: row.get::<_, Uuid>(1),
+            name: row.get::<_, String>(2),
+            secret_hash: row.get::<_, String>(3),
+        })
+    }
+    #[instrument(skip(self))]
+    async fn delete_client(&self, client_id: &str) -> Result<Option<Uuid>, ()> {
+        let client = self.pool.get().await.map_err(|_| ())?;
+        let row = client
+            .query_opt(DELETE_CLIENT, &[&client_id])
+            .instrument(sql_span(DELETE_CLIENT))
+            .await
+            .map_err(|_| ())?;
+        Ok(row.map(|row| row.get::<_, Uuid>(0)))
+    }
+}
+
+fn user(row: &Row) -> User {
+    User {
+        id: row.get::<_, Uuid>(0),
+        admin: row.get::<_, bool>(1),
+        session_version: row.get::<_, i32>(2),
+        roles: row.get::<_, Vec<String>>(3),
+    }
+}
+
+fn api_key(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get::<_, Uuid>(0),
+        user_id: row.get::<_, Uuid>(1),
+        name: row.get::<_, String>(2),
+        prefix: row.get::<_, String>(3),
+        key_hash: row.get::<_, String>(4),
+        scopes: row.get::<_, Vec<String>>(5),
+        expires_at: row.get::<_, Option<i64>>(6),
+        created_at: row.get::<_, i64>(7),
+    }
+}
+
+fn credentials(row: &Row) -> Credentials {
+    Credentials {
+        user_id: row.get::<_, Uuid>(0),
+        username: row.get::<_, String>(1),
+        password_hash: row.get::<_, String>(2),
+    }
 }
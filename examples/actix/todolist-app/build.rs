@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Compiled with protox rather than protoc, so that building needs no system package
+    let descriptors = protox::compile(["proto/todo.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+    println!("cargo:rerun-if-changed=proto");
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Synchronous client, for the callers without an async runtime.
+
+use std::time::Duration;
+
+use reqwest::header::HeaderName;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{Delivery, Error, RetryConfig, Todo, TodoUpdateRequest, Webhook, WebhookRequest};
+
+/// Configuration of a blocking `TodoClient`, see `crate::ClientBuilder`.
+pub struct ClientBuilder(crate::ClientBuilder);
+
+impl ClientBuilder {
+    pub fn api_key(self, key: impl Into<String>) -> Self {
+        Self(self.0.api_key(key))
+    }
+
+    pub fn bearer_token(self, token: impl AsRef<str>) -> Self {
+        Self(self.0.bearer_token(token))
+    }
+
+    pub fn header(self, name: HeaderName, value: impl Into<String>) -> Self {
+        Self(self.0.header(name, value))
+    }
+
+    pub fn retry(self, retry: RetryConfig) -> Self {
+        Self(self.0.retry(retry))
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self(self.0.timeout(timeout))
+    }
+
+    pub fn build(self) -> Result<TodoClient, Error> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| Error::Config(format!("could not start the runtime: {err}")))?;
+        // The connection pool of reqwest is bound to the runtime it is built on
+        let inner = runtime.block_on(async { self.0.build() })?;
+        Ok(TodoClient { inner, runtime })
+    }
+}
+
+/// Blocking client of the todolist REST API, running the async client on its own runtime.
+///
+/// It must not be used from within an async runtime.
+pub struct TodoClient {
+    inner: crate::TodoClient,
+    runtime: Runtime,
+}
+
+impl TodoClient {
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder(crate::TodoClient::builder(base_url))
+    }
+
+    pub fn health(&self) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.health())
+    }
+
+    pub fn ready(&self) -> Result<bool, Error> {
+        self.runtime.block_on(self.inner.ready())
+    }
+
+    pub fn metrics(&self) -> Result<String, Error> {
+        self.runtime.block_on(self.inner.metrics())
+    }
+
+    pub fn list_todos(&self) -> Result<Vec<Todo>, Error> {
+        self.runtime.block_on(self.inner.list_todos())
+    }
+
+    pub fn create_todo(&self, todo: &Todo) -> Result<Todo, Error> {
+        self.runtime.block_on(self.inner.create_todo(todo))
+    }
+
+    pub fn search_todos(&self, value: &str) -> Result<Vec<Todo>, Error> {
+        self.runtime.block_on(self.inner.search_todos(value))
+    }
+
+    pub fn get_todo(&self, id: i64) -> Result<Todo, Error> {
+        self.runtime.block_on(self.inner.get_todo(id))
+    }
+
+    pub fn update_todo(&self, id: i64, update: &TodoUpdateRequest) -> Result<Todo, Error> {
+        self.runtime.block_on(self.inner.update_todo(id, update))
+    }
+
+    pub fn delete_todo(&self, id: i64) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.delete_todo(id))
+    }
+
+    pub fn list_webhooks(&self) -> Result<Vec<Webhook>, Error> {
+        self.runtime.block_on(self.inner.list_webhooks())
+    }
+
+    pub fn create_webhook(&self, webhook: &WebhookRequest) -> Result<Webhook, Error> {
+        self.runtime.block_on(self.inner.create_webhook(webhook))
+    }
+
+    pub fn get_webhook(&self, id: &str) -> Result<Webhook, Error> {
+        self.runtime.block_on(self.inner.get_webhook(id))
+    }
+
+    pub fn delete_webhook(&self, id: &str) -> Result<(), Error> {
+        self.runtime.block_on(self.inner.delete_webhook(id))
+    }
+
+    pub fn webhook_deliveries(&self, id: &str) -> Result<Vec<Delivery>, Error> {
+        self.runtime.block_on(self.inner.webhook_deliveries(id))
+    }
+}
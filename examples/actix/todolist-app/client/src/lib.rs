@@ -0,0 +1,376 @@
+//! Typed client of the todolist REST API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), todolist_client::Error> {
+//! let client = todolist_client::TodoClient::builder("http://localhost:8080")
+//!     .api_key("secret")
+//!     .build()?;
+//! for todo in client.list_todos().await? {
+//!     println!("{}: {}", todo.id, todo.value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, RETRY_AFTER},
+    Method, RequestBuilder, Response, StatusCode,
+};
+use serde::de::DeserializeOwned;
+
+pub use example::schemas::{
+    Delivery, DeliveryStatus, ErrorResponse, FieldError, Todo, TodoUpdateRequest, Webhook,
+    WebhookEvent, WebhookRequest,
+};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(test)]
+mod test_client;
+
+/// Endpoints covered by the client, as method and path template.
+///
+/// The tests check them against `openapi.json`, so that the client follows the API.
+pub const ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/health"),
+    ("GET", "/ready"),
+    ("GET", "/metrics"),
+    ("GET", "/todo"),
+    ("POST", "/todo"),
+    ("GET", "/todo/search"),
+    ("GET", "/todo/{id}"),
+    ("PUT", "/todo/{id}"),
+    ("DELETE", "/todo/{id}"),
+    ("GET", "/webhooks"),
+    ("POST", "/webhooks"),
+    ("GET", "/webhooks/{id}"),
+    ("DELETE", "/webhooks/{id}"),
+    ("GET", "/webhooks/{id}/deliveries"),
+];
+
+/// Failure of a call to the API.
+#[derive(Debug)]
+pub enum Error {
+    /// The API answered with one of its documented errors.
+    Api {
+        status: StatusCode,
+        error: ErrorResponse,
+    },
+    /// The API answered with a status the client did not expect.
+    Status { status: StatusCode, body: String },
+    /// The request could not be sent, or the response could not be read.
+    Http(reqwest::Error),
+    /// The client configuration is invalid, e.g. an api key which is not a valid header value.
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api { status, error } => write!(f, "{status}: {error:?}"),
+            Error::Status { status, body } => write!(f, "unexpected {status}: {body}"),
+            Error::Http(err) => write!(f, "{err}"),
+            Error::Config(message) => write!(f, "invalid client configuration: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+/// Retries of the calls failing with a connection error, a timeout, or a 429, 502, 503 or 504.
+///
+/// Only the calls safe to repeat are retried: every method but `POST`, which is retried only when
+/// the connection could not be made. Waits double after each attempt, from `initial_backoff` up
+/// to `max_backoff`, unless the response carries a `Retry-After`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// No retries at all.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+const RETRY_STATUSES: [StatusCode; 4] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Configuration of a `TodoClient`.
+pub struct ClientBuilder {
+    base_url: String,
+    headers: Vec<(HeaderName, String)>,
+    retry: RetryConfig,
+    timeout: Option<Duration>,
+}
+
+impl ClientBuilder {
+    /// Authenticate with an api key, sent as `X-API-Key`.
+    pub fn api_key(self, key: impl Into<String>) -> Self {
+        self.header(HeaderName::from_static("x-api-key"), key)
+    }
+
+    /// Authenticate with a bearer token, sent as `Authorization`.
+    pub fn bearer_token(self, token: impl AsRef<str>) -> Self {
+        let value = format!("Bearer {}", token.as_ref());
+        self.header(AUTHORIZATION, value)
+    }
+
+    /// Header sent along every request.
+    pub fn header(mut self, name: HeaderName, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Deadline of each attempt, none by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> Result<TodoClient, Error> {
+        let mut headers = HeaderMap::new();
+        // Errors come as `ErrorResponse` rather than problem details
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        for (name, value) in self.headers {
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| Error::Config(format!("invalid value for the {name} header")))?;
+            headers.insert(name, value);
+        }
+        let mut http = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+        Ok(TodoClient {
+            http: http.build()?,
+            base_url: self.base_url.trim_end_matches('/').to_owned(),
+            retry: self.retry,
+        })
+    }
+}
+
+/// Async client of the todolist REST API.
+#[derive(Clone)]
+pub struct TodoClient {
+    http: reqwest::Client,
+    base_url: String,
+    retry: RetryConfig,
+}
+
+impl TodoClient {
+    /// Client of the API served at `base_url`, e.g. `http://localhost:8080`.
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.into(),
+            headers: Vec::new(),
+            retry: RetryConfig::default(),
+            timeout: None,
+        }
+    }
+
+    /// Send a request built by `build`, retrying it according to the retry configuration.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        let url = format!("{}{path}", self.base_url);
+        let idempotent = method != Method::POST;
+        let mut attempt = 0;
+        loop {
+            let result = build(self.http.request(method.clone(), &url)).send().await;
+            let backoff = self.retry.backoff(attempt);
+            let delay = match &result {
+                Ok(response) if idempotent && RETRY_STATUSES.contains(&response.status()) => Some(
+                    retry_after(response)
+                        .unwrap_or(backoff)
+                        .min(self.retry.max_backoff),
+                ),
+                Err(err) if err.is_connect() || (idempotent && err.is_timeout()) => Some(backoff),
+                _ => None,
+            };
+            match delay {
+                Some(delay) if attempt < self.retry.max_retries => {
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return Ok(result?),
+            }
+        }
+    }
+
+    pub async fn health(&self) -> Result<(), Error> {
+        expect(self.send(Method::GET, "/health", |r| r).await?).await?;
+        Ok(())
+    }
+
+    /// Whether the service takes traffic, false once it is shutting down.
+    pub async fn ready(&self) -> Result<bool, Error> {
+        let response = self.send(Method::GET, "/ready", |r| r).await?;
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(false);
+        }
+        expect(response).await?;
+        Ok(true)
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> Result<String, Error> {
+        Ok(expect(self.send(Method::GET, "/metrics", |r| r).await?)
+            .await?
+            .text()
+            .await?)
+    }
+
+    pub async fn list_todos(&self) -> Result<Vec<Todo>, Error> {
+        json(self.send(Method::GET, "/todo", |r| r).await?).await
+    }
+
+    /// Store a new todo, returning it as stored.
+    pub async fn create_todo(&self, todo: &Todo) -> Result<Todo, Error> {
+        json(self.send(Method::POST, "/todo", |r| r.json(todo)).await?).await
+    }
+
+    /// Todos whose value contains `value`.
+    pub async fn search_todos(&self, value: &str) -> Result<Vec<Todo>, Error> {
+        let response = self
+            .send(Method::GET, "/todo/search", |r| {
+                r.query(&[("value", value)])
+            })
+            .await?;
+        json(response).await
+    }
+
+    pub async fn get_todo(&self, id: i64) -> Result<Todo, Error> {
+        json(
+            self.send(Method::GET, &format!("/todo/{id}"), |r| r)
+                .await?,
+        )
+        .await
+    }
+
+    pub async fn update_todo(&self, id: i64, update: &TodoUpdateRequest) -> Result<Todo, Error> {
+        let response = self
+            .send(Method::PUT, &format!("/todo/{id}"), |r| r.json(update))
+            .await?;
+        json(response).await
+    }
+
+    pub async fn delete_todo(&self, id: i64) -> Result<(), Error> {
+        expect(
+            self.send(Method::DELETE, &format!("/todo/{id}"), |r| r)
+                .await?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_webhooks(&self) -> Result<Vec<Webhook>, Error> {
+        json(self.send(Method::GET, "/webhooks", |r| r).await?).await
+    }
+
+    /// Subscribe `webhook.url` to `webhook.events`, returning the webhook without its secret.
+    pub async fn create_webhook(&self, webhook: &WebhookRequest) -> Result<Webhook, Error> {
+        json(
+            self.send(Method::POST, "/webhooks", |r| r.json(webhook))
+                .await?,
+        )
+        .await
+    }
+
+    pub async fn get_webhook(&self, id: &str) -> Result<Webhook, Error> {
+        json(
+            self.send(Method::GET, &format!("/webhooks/{id}"), |r| r)
+                .await?,
+        )
+        .await
+    }
+
+    /// Delete a webhook along with its deliveries.
+    pub async fn delete_webhook(&self, id: &str) -> Result<(), Error> {
+        expect(
+            self.send(Method::DELETE, &format!("/webhooks/{id}"), |r| r)
+                .await?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deliveries of a webhook, the latest first.
+    pub async fn webhook_deliveries(&self, id: &str) -> Result<Vec<Delivery>, Error> {
+        let response = self
+            .send(Method::GET, &format!("/webhooks/{id}/deliveries"), |r| r)
+            .await?;
+        json(response).await
+    }
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.parse().ok().map(Duration::from_secs)
+}
+
+/// The response when successful, its error otherwise.
+async fn expect(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    Err(match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(error) => Error::Api { status, error },
+        Err(_) => Error::Status { status, body },
+    })
+}
+
+async fn json<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+    Ok(expect(response).await?.json().await?)
+}
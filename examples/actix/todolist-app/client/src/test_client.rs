@@ -0,0 +1,216 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde_json::Value;
+
+    use crate::{Error, RetryConfig, Todo, TodoClient, TodoUpdateRequest, ENDPOINTS};
+
+    fn spec() -> Value {
+        let spec = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../openapi.json"));
+        serde_json::from_str(spec).expect("openapi.json is not valid json")
+    }
+
+    fn spec_endpoints(spec: &Value) -> BTreeSet<(String, String)> {
+        spec["paths"]
+            .as_object()
+            .expect("the spec has no paths")
+            .iter()
+            .flat_map(|(path, operations)| {
+                operations
+                    .as_object()
+                    .into_iter()
+                    .flat_map(|operations| operations.keys())
+                    .map(move |method| (method.to_uppercase(), path.clone()))
+            })
+            .collect()
+    }
+
+    fn schema_properties(spec: &Value, name: &str) -> BTreeSet<String> {
+        spec["components"]["schemas"][name]["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("the spec has no {name} schema"))
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn fields(value: impl serde::Serialize) -> BTreeSet<String> {
+        serde_json::to_value(value)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_endpoints_match_spec() {
+        let documented = spec_endpoints(&spec());
+        let covered: BTreeSet<(String, String)> = ENDPOINTS
+            .iter()
+            .map(|(method, path)| (method.to_string(), path.to_string()))
+            .collect();
+        let missing: Vec<_> = documented.difference(&covered).collect();
+        assert!(
+            missing.is_empty(),
+            "endpoints without a client method: {missing:?}"
+        );
+        // The probes and metrics are not part of the API document
+        let undocumented: Vec<_> = covered
+            .difference(&documented)
+            .filter(|(_, path)| path.starts_with("/todo") || path.starts_with("/webhooks"))
+            .collect();
+        assert!(
+            undocumented.is_empty(),
+            "client methods without an endpoint: {undocumented:?}"
+        );
+    }
+
+    #[test]
+    fn test_schemas_match_spec() {
+        let spec = spec();
+        let todo = Todo {
+            id: 1,
+            value: String::from("Buy groceries"),
+            checked: false,
+        };
+        assert_eq!(fields(&todo), schema_properties(&spec, "Todo"));
+        let update = TodoUpdateRequest {
+            value: Some(String::from("Buy groceries")),
+            checked: Some(true),
+        };
+        assert_eq!(
+            fields(&update),
+            schema_properties(&spec, "TodoUpdateRequest")
+        );
+    }
+
+    /// Serve `app` on a free port, returning its base url.
+    fn serve<F>(app: F) -> String
+    where
+        F: Fn(&mut web::ServiceConfig) + Send + Clone + 'static,
+    {
+        let server = HttpServer::new(move || App::new().configure(app.clone()))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    fn fast_retries() -> RetryConfig {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_retries_unavailable() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let url = serve(move |config| {
+            let counter = counter.clone();
+            config.route(
+                "/todo/1",
+                web::get().to(move || {
+                    let call = counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        if call == 0 {
+                            HttpResponse::ServiceUnavailable().finish()
+                        } else {
+                            HttpResponse::Ok()
+                                .json(serde_json::json!({"id": 1, "value": "Buy groceries", "checked": false}))
+                        }
+                    }
+                }),
+            );
+        });
+        let client = TodoClient::builder(url)
+            .retry(fast_retries())
+            .build()
+            .unwrap();
+
+        let todo = client.get_todo(1).await.unwrap();
+
+        assert_eq!(todo.value, "Buy groceries");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_post_not_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let url = serve(move |config| {
+            let counter = counter.clone();
+            config.route(
+                "/todo",
+                web::post().to(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async { HttpResponse::ServiceUnavailable().finish() }
+                }),
+            );
+        });
+        let client = TodoClient::builder(url)
+            .retry(fast_retries())
+            .build()
+            .unwrap();
+        let todo = Todo {
+            id: 1,
+            value: String::from("Buy groceries"),
+            checked: false,
+        };
+
+        let result = client.create_todo(&todo).await;
+
+        assert!(matches!(result, Err(Error::Status { status, .. }) if status == 503));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_api_error_and_headers() {
+        let url = serve(|config| {
+            config.route(
+                "/todo/{id}",
+                web::delete().to(|req: actix_web::HttpRequest| async move {
+                    let header = |name| {
+                        req.headers()
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned()
+                    };
+                    if header("x-api-key") != "secret" || header("accept") != "application/json" {
+                        return HttpResponse::Unauthorized().finish();
+                    }
+                    HttpResponse::NotFound().json(serde_json::json!({"NotFound": "id = 1"}))
+                }),
+            );
+        });
+        let client = TodoClient::builder(format!("{url}/"))
+            .api_key("secret")
+            .build()
+            .unwrap();
+
+        let result = client.delete_todo(1).await;
+
+        match result {
+            Err(Error::Api { status, error }) => {
+                assert_eq!(status, 404);
+                assert_eq!(
+                    serde_json::to_value(error).unwrap(),
+                    serde_json::json!({"NotFound": "id = 1"})
+                );
+            }
+            other => panic!("expected a not found error, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Admin tool of the todolist store, talking to the configured store directly rather than to the API.
+//!
+//! ```text
+//! todoctl --config store.yml check
+//! todoctl list --output json
+//! todoctl create 7 "Buy movie ticket"
+//! todoctl export --file todos.json
+//! ```
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use coi::container;
+use serde_json::json;
+use validator::Validate;
+
+use example::schemas::{field_errors, Todo};
+use example::store_interface::TodoRepository;
+use example::stores::config::{StoreConfig, TodoStoreProvider};
+
+#[cfg(test)]
+mod test_todoctl;
+
+#[derive(Parser)]
+#[command(
+    name = "todoctl",
+    about = "Inspect and fix the todos of the todolist store"
+)]
+struct Cli {
+    /// Store configuration, shared with the server.
+    #[arg(long, global = true, default_value = "store.yml")]
+    config: String,
+    #[arg(long, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum Output {
+    /// Aligned columns, for humans.
+    Table,
+    /// JSON, for scripts.
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create the tables of the store, if missing.
+    Migrate,
+    /// Check the store can be reached.
+    Check,
+    /// List every todo.
+    List,
+    /// List the todos whose value contains the given text.
+    Search { value: String },
+    /// Create a todo.
+    Create {
+        id: i64,
+        value: String,
+        #[arg(long)]
+        checked: bool,
+    },
+    /// Delete a todo.
+    Delete { id: i64 },
+    /// Write every todo as a JSON array, the format `import` reads.
+    Export {
+        /// File to write, standard output when missing.
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Create the todos of a JSON array, skipping the ids already stored.
+    Import {
+        /// File to read, `-` for standard input.
+        file: PathBuf,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut out = io::stdout().lock();
+    let result = match StoreConfig::read(&cli.config) {
+        Ok(config) => {
+            let provider = TodoStoreProvider::new(&config).await;
+            run(cli.command, cli.output, provider, &mut out).await
+        }
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("todoctl: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+async fn run(
+    command: Command,
+    output: Output,
+    provider: TodoStoreProvider,
+    out: &mut impl Write,
+) -> Result<()> {
+    match command {
+        Command::Migrate => {
+            provider.migrate().await?;
+            return status(out, output, "migrated");
+        }
+        Command::Check => {
+            provider.check().await?;
+            return status(out, output, "reachable");
+        }
+        _ => {}
+    }
+    // The repositories panic on database errors, reaching the store first reports them instead
+    provider.check().await?;
+    let repository = repository(provider)?;
+    match command {
+        Command::Migrate | Command::Check => unreachable!("handled above"),
+        Command::List => todos(out, output, repository.read_all().await),
+        Command::Search { value } => todos(out, output, repository.read_filter(&value).await),
+        Command::Create { id, value, checked } => {
+            let todo = Todo { id, value, checked };
+            validate(&todo)?;
+            repository
+                .create_one(&todo)
+                .await
+                .map_err(|existing| format!("todo {} already exists", existing.id))?;
+            todos(out, output, vec![todo])
+        }
+        Command::Delete { id } => {
+            repository
+                .delete_one(id)
+                .await
+                .map_err(|()| format!("todo {id} not found"))?;
+            match output {
+                Output::Table => writeln!(out, "deleted todo {id}")?,
+                Output::Json => writeln!(out, "{}", json!({ "deleted": id }))?,
+            }
+            Ok(())
+        }
+        Command::Export { file } => {
+            let mut all = repository.read_all().await;
+            all.sort_by_key(|todo| todo.id);
+            let exported = serde_json::to_string_pretty(&all)? + "\n";
+            match file {
+                Some(file) => std::fs::write(file, exported)?,
+                None => out.write_all(exported.as_bytes())?,
+            }
+            Ok(())
+        }
+        Command::Import { file } => {
+            let mut input = String::new();
+            if file.as_os_str() == "-" {
+                io::stdin().read_to_string(&mut input)?;
+            } else {
+                input = std::fs::read_to_string(file)?;
+            }
+            let imported: Vec<Todo> = serde_json::from_str(&input)?;
+            // Nothing is written unless every todo is valid
+            for todo in &imported {
+                validate(todo).map_err(|err| format!("todo {}: {err}", todo.id))?;
+            }
+            let mut created = Vec::new();
+            let mut skipped = Vec::new();
+            for todo in imported {
+                match repository.create_one(&todo).await {
+                    Ok(()) => created.push(todo.id),
+                    Err(existing) => skipped.push(existing.id),
+                }
+            }
+            match output {
+                Output::Table => writeln!(
+                    out,
+                    "imported {} todos, skipped {} already stored",
+                    created.len(),
+                    skipped.len()
+                )?,
+                Output::Json => writeln!(
+                    out,
+                    "{}",
+                    json!({ "imported": created, "skipped": skipped })
+                )?,
+            }
+            Ok(())
+        }
+    }
+}
+
+fn repository(provider: TodoStoreProvider) -> Result<Arc<dyn TodoRepository>> {
+    let container = container! {
+        repository => provider; singleton,
+    };
+    Ok(container.resolve::<dyn TodoRepository>("repository")?)
+}
+
+fn validate(todo: &Todo) -> Result<()> {
+    todo.validate().map_err(|errors| {
+        let fields: Vec<String> = field_errors(&errors)
+            .into_iter()
+            .map(|error| format!("{}: {}", error.field, error.messages.join(", ")))
+            .collect();
+        fields.join("; ").into()
+    })
+}
+
+fn status(out: &mut impl Write, output: Output, status: &str) -> Result<()> {
+    match output {
+        Output::Table => writeln!(out, "{status}")?,
+        Output::Json => writeln!(out, "{}", json!({ "status": status }))?,
+    }
+    Ok(())
+}
+
+/// Write `todos` by id.
+fn todos(out: &mut impl Write, output: Output, mut todos: Vec<Todo>) -> Result<()> {
+    todos.sort_by_key(|todo| todo.id);
+    match output {
+        Output::Table => out.write_all(table(&todos).as_bytes())?,
+        Output::Json => writeln!(out, "{}", serde_json::to_string(&todos)?)?,
+    }
+    Ok(())
+}
+
+fn table(todos: &[Todo]) -> String {
+    let rows: Vec<[String; 3]> = todos
+        .iter()
+        .map(|todo| {
+            [
+                todo.id.to_string(),
+                todo.value.clone(),
+                todo.checked.to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        String::from("ID"),
+        String::from("VALUE"),
+        String::from("CHECKED"),
+    ];
+    let mut widths = header.clone().map(|column| column.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            let line = format!(
+                "{:>id$}  {:<value$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                id = widths[0],
+                value = widths[1]
+            );
+            line.trim_end().to_owned() + "\n"
+        })
+        .collect()
+}
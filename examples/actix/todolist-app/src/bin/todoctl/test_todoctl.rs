@@ -0,0 +1,161 @@
+// Unit testing for the admin commands, against the memory store
+
+#[cfg(test)]
+mod tests {
+    use example::schemas::Todo;
+    use example::stores::config::{PostgresConfig, StoreConfig, TodoStoreProvider};
+
+    use crate::{run, Command, Output};
+
+    fn seed() -> Vec<Todo> {
+        vec![
+            Todo {
+                id: 2,
+                value: String::from("something completely different"),
+                checked: false,
+            },
+            Todo {
+                id: 1,
+                value: String::from("some value"),
+                checked: true,
+            },
+        ]
+    }
+
+    async fn exec(command: Command, output: Output) -> Result<String, String> {
+        let provider = TodoStoreProvider::new(&StoreConfig::Memory { todos: seed() }).await;
+        let mut out = Vec::new();
+        run(command, output, provider, &mut out)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_list_table() {
+        let out = exec(Command::List, Output::Table).await.unwrap();
+        assert_eq!(
+            out,
+            "ID  VALUE                           CHECKED\n\
+             \x201  some value                      true\n\
+             \x202  something completely different  false\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_json() {
+        let out = exec(
+            Command::Search {
+                value: String::from("completely"),
+            },
+            Output::Json,
+        )
+        .await
+        .unwrap();
+        let found: Vec<Todo> = serde_json::from_str(&out).unwrap();
+        assert_eq!(found, vec![seed().remove(0)]);
+    }
+
+    #[tokio::test]
+    async fn test_create() {
+        let created = Command::Create {
+            id: 3,
+            value: String::from("new"),
+            checked: false,
+        };
+        let out = exec(created, Output::Json).await.unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Todo>>(&out).unwrap()[0].id, 3);
+
+        let existing = Command::Create {
+            id: 1,
+            value: String::from("again"),
+            checked: false,
+        };
+        assert_eq!(
+            exec(existing, Output::Table).await.unwrap_err(),
+            "todo 1 already exists"
+        );
+
+        let invalid = Command::Create {
+            id: 4,
+            value: String::new(),
+            checked: false,
+        };
+        assert_eq!(
+            exec(invalid, Output::Table).await.unwrap_err(),
+            "value: must be between 1 and 1024 characters"
+        );
+
+        // Fields are listed by name, whatever the order of the validation errors
+        let invalid = Command::Create {
+            id: -1,
+            value: String::new(),
+            checked: false,
+        };
+        assert_eq!(
+            exec(invalid, Output::Table).await.unwrap_err(),
+            "id: must be between 0 and 2147483647; value: must be between 1 and 1024 characters"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        assert_eq!(
+            exec(Command::Delete { id: 1 }, Output::Json).await.unwrap(),
+            "{\"deleted\":1}\n"
+        );
+        assert_eq!(
+            exec(Command::Delete { id: 9 }, Output::Table)
+                .await
+                .unwrap_err(),
+            "todo 9 not found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let exported = exec(Command::Export { file: None }, Output::Table)
+            .await
+            .unwrap();
+        let todos: Vec<Todo> = serde_json::from_str(&exported).unwrap();
+        assert_eq!(
+            todos.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let file = std::env::temp_dir().join(format!("todoctl-import-{}.json", std::process::id()));
+        let mut imported = todos;
+        imported.push(Todo {
+            id: 3,
+            value: String::from("imported"),
+            checked: false,
+        });
+        std::fs::write(&file, serde_json::to_string(&imported).unwrap()).unwrap();
+        let out = exec(Command::Import { file: file.clone() }, Output::Json).await;
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(out.unwrap(), "{\"imported\":[3],\"skipped\":[1,2]}\n");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_store() {
+        let config = StoreConfig::Postgres(PostgresConfig {
+            host: String::from("127.0.0.1"),
+            port: 1,
+            user: String::from("postgres"),
+            password: String::from("postgres"),
+            dbname: String::from("postgres"),
+        });
+        let provider = TodoStoreProvider::new(&config).await;
+        // Reported as an error rather than a panic of the repository
+        let result = run(Command::List, Output::Table, provider, &mut Vec::new()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_memory() {
+        assert_eq!(
+            exec(Command::Check, Output::Json).await.unwrap(),
+            "{\"status\":\"reachable\"}\n"
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::hash_map::DefaultHasher;
+use std::future::{ready, Ready};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_http::encoding::Encoder;
+use actix_web::{
+    body::{self, BodySize, BoxBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{
+            AcceptEncoding, ContentEncoding, Encoding, HeaderMap, HeaderValue, CACHE_CONTROL,
+            CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+        },
+        Method, StatusCode,
+    },
+    mime, Error, HttpMessage, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+
+/// Caching headers of the responses under a path prefix, e.g. `/todo`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheScope {
+    pub prefix: String,
+    /// `Cache-Control` value, set when the handler did not set one.
+    pub cache_control: String,
+    /// Request headers the responses depend on, added to `Vary` along with `Accept-Encoding`.
+    #[serde(default)]
+    pub vary: Vec<String>,
+}
+
+/// Compression and caching of the responses, read from `caching.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachingConfig {
+    /// Bodies smaller than this, in bytes, are sent uncompressed.
+    pub min_compress_size: u64,
+    #[serde(default)]
+    pub scopes: Vec<CacheScope>,
+}
+
+impl Default for CachingConfig {
+    fn default() -> Self {
+        let scope = |prefix: &str, cache_control: &str, vary: &[&str]| CacheScope {
+            prefix: prefix.to_owned(),
+            cache_control: cache_control.to_owned(),
+            vary: vary.iter().map(|header| header.to_string()).collect(),
+        };
+        Self {
+            min_compress_size: 1024,
+            scopes: vec![
+                scope("/todo", "no-cache", &["Accept"]),
+                scope("/webhooks", "no-store", &[]),
+                scope("/api-docs", "public, max-age=300", &[]),
+                scope("/swagger-ui", "public, max-age=3600", &[]),
+                scope("/redoc", "public, max-age=3600", &[]),
+                scope("/rapidoc", "public, max-age=3600", &[]),
+                scope("/health", "no-store", &[]),
+                scope("/ready", "no-store", &[]),
+                scope("/metrics", "no-store", &[]),
+            ],
+        }
+    }
+}
+
+impl CachingConfig {
+    /// Read the configuration from a yml file, falling back to the defaults when there is none.
+    pub fn from_file(path: &str) -> Self {
+        match std::fs::File::open(path) {
+            Ok(file) => serde_yaml::from_reader(file).expect("Could not read caching file."),
+            Err(_) => {
+                tracing::warn!(path, "no caching file, using the defaults");
+                Self::default()
+            }
+        }
+    }
+
+    /// Scope of the longest prefix matching `path`, on a segment boundary.
+    fn scope_for(&self, path: &str) -> Option<&CacheScope> {
+        self.scopes
+            .iter()
+            .filter(|scope| {
+                let prefix = scope.prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|scope| scope.prefix.len())
+    }
+}
+
+/// Weak, as the body is hashed before compression, which changes its bytes.
+fn etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Whether `If-None-Match` lists `etag`, with the weak comparison of RFC 9110.
+fn none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn add_vary(headers: &mut HeaderMap, vary: &[String]) {
+    let mut values: Vec<String> = headers
+        .get_all(VARY)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect();
+    for header in vary {
+        if !values.iter().any(|value| value.eq_ignore_ascii_case(header)) {
+            values.push(header.clone());
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(VARY, value);
+    }
+}
+
+/// Middleware setting `Cache-Control` and `Vary` by scope, and answering conditional requests.
+///
+/// Successful `GET` responses get an `ETag`; a request whose `If-None-Match` lists it is answered
+/// with a body-less 304. Responses of scopes marked `no-store` are left without one.
+#[derive(Clone)]
+pub struct Caching {
+    config: Arc<CachingConfig>,
+}
+
+impl Caching {
+    pub fn new(config: Arc<CachingConfig>) -> Self {
+        Self { config }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Caching
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CachingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CachingMiddleware {
+            service: Rc::new(service),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct CachingMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<CachingConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for CachingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let scope = self.config.scope_for(req.path()).cloned();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let mut res = service.call(req).await?.map_into_boxed_body();
+            let Some(scope) = scope else {
+                return Ok(res);
+            };
+            let headers = res.headers_mut();
+            if !headers.contains_key(CACHE_CONTROL) {
+                if let Ok(value) = HeaderValue::from_str(&scope.cache_control) {
+                    headers.insert(CACHE_CONTROL, value);
+                }
+            }
+            // Compression only adds it to the responses it did encode
+            add_vary(headers, &["Accept-Encoding".to_owned()]);
+            add_vary(headers, &scope.vary);
+
+            let conditional = res.request().method() == Method::GET
+                && res.status() == StatusCode::OK
+                && !res.headers().contains_key(ETAG)
+                && !scope.cache_control.contains("no-store")
+                && matches!(res.response().body().size(), BodySize::Sized(_));
+            if !conditional {
+                return Ok(res);
+            }
+            let (req, response) = res.into_parts();
+            let (mut response, body) = response.into_parts();
+            // Sized bodies are already in memory
+            let body = body::to_bytes(body)
+                .await
+                .map_err(|err| actix_web::error::ErrorInternalServerError(err.to_string()))?;
+            let tag = etag(&body);
+            response.headers_mut().insert(
+                ETAG,
+                HeaderValue::from_str(&tag).expect("hex etags are valid header values"),
+            );
+            if none_match(req.headers(), &tag) {
+                let mut not_modified = HttpResponse::NotModified();
+                for (name, value) in response.headers() {
+                    if name != CONTENT_ENCODING {
+                        not_modified.append_header((name.clone(), value.clone()));
+                    }
+                }
+                return Ok(ServiceResponse::new(req, not_modified.finish()));
+            }
+            Ok(ServiceResponse::new(req, response.set_body(BoxBody::new(body))))
+        })
+    }
+}
+
+/// Compression of the response bodies, negotiated with `Accept-Encoding` as `Compress` does.
+///
+/// Bodies smaller than the given size are sent as they are, the framing costing more than it
+/// saves. `Compress` has no such threshold, hence the encoding is chosen here.
+#[derive(Clone, Copy)]
+pub struct CompressAbove(pub u64);
+
+const ENCODINGS: &[Encoding] = &[
+    Encoding::identity(),
+    Encoding::brotli(),
+    Encoding::gzip(),
+    Encoding::deflate(),
+    Encoding::zstd(),
+];
+
+impl<S, B> Transform<S, ServiceRequest> for CompressAbove
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CompressAboveMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CompressAboveMiddleware {
+            service: Rc::new(service),
+            min_size: self.0,
+        }))
+    }
+}
+
+pub struct CompressAboveMiddleware<S> {
+    service: Rc<S>,
+    min_size: u64,
+}
+
+impl<S, B> Service<ServiceRequest> for CompressAboveMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let min_size = self.min_size;
+        let encoding = match req.get_header::<AcceptEncoding>() {
+            None => Some(ContentEncoding::Identity),
+            Some(accept) => accept.negotiate(ENCODINGS.iter()).map(|encoding| match encoding {
+                Encoding::Known(encoding) => encoding,
+                Encoding::Unknown(_) => ContentEncoding::Identity,
+            }),
+        };
+
+        Box::pin(async move {
+            let Some(encoding) = encoding else {
+                let mut res = HttpResponse::NotAcceptable().body(
+                    ENCODINGS[1..].iter().map(ToString::to_string).collect::<Vec<_>>().join(", "),
+                );
+                res.headers_mut().insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+                return Ok(req.into_response(res));
+            };
+            let res = service.call(req).await?;
+            Ok(res
+                .map_body(|head, body| {
+                    let small = matches!(body.size(), BodySize::Sized(size) if size < min_size);
+                    let encoding = if small || !compressible(head.headers()) {
+                        ContentEncoding::Identity
+                    } else {
+                        encoding
+                    };
+                    Encoder::response(encoding, head, body)
+                })
+                .map_into_boxed_body())
+        })
+    }
+}
+
+/// Images, but for SVG, and videos are compressed already.
+fn compressible(headers: &HeaderMap) -> bool {
+    let mime = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok());
+    match mime {
+        Some(mime) if mime.type_() == mime::IMAGE => mime.subtype() == mime::SVG,
+        Some(mime) if mime.type_() == mime::VIDEO => false,
+        _ => true,
+    }
+}
@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use actix_web::{
+    get, post,
+    web::{self, Payload, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
+};
+use async_graphql::{
+    http::GraphiQLSource, Context, Data, ErrorExtensions, InputObject, Object, Schema,
+    Subscription, Value,
+};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use coi_actix_web::inject;
+use futures::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use validator::Validate;
+
+use crate::schemas::{field_errors, Todo, TodoChange, TodoUpdateRequest};
+use crate::store_interface::TodoRepository;
+use crate::stores::changes::TodoChanges;
+
+pub type TodoSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+/// Build the schema; the repository is given to each request, as it is resolved per request.
+pub fn schema(changes: TodoChanges) -> TodoSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(changes)
+        .limit_depth(10)
+        .finish()
+}
+
+pub(super) fn configure() -> impl FnOnce(&mut ServiceConfig) {
+    |config: &mut ServiceConfig| {
+        config
+            .service(graphql)
+            .route("/graphql/ws", web::get().to(graphql_ws))
+            .service(graphiql)
+            .service(sdl);
+    }
+}
+
+/// Todos matching every given criteria.
+#[derive(InputObject, Default)]
+pub struct TodoFilter {
+    id: Option<i64>,
+    checked: Option<bool>,
+    /// Case insensitive substring of the value.
+    value_contains: Option<String>,
+    /// Todos matching all of these filters.
+    and: Option<Vec<TodoFilter>>,
+    /// Todos matching any of these filters.
+    or: Option<Vec<TodoFilter>>,
+    /// Todos not matching this filter.
+    not: Option<Box<TodoFilter>>,
+}
+
+impl TodoFilter {
+    fn matches(&self, todo: &Todo) -> bool {
+        self.id.is_none_or(|id| todo.id == id)
+            && self.checked.is_none_or(|checked| todo.checked == checked)
+            && self
+                .value_contains
+                .as_ref()
+                .is_none_or(|text| todo.value.to_lowercase().contains(&text.to_lowercase()))
+            && self
+                .and
+                .as_ref()
+                .is_none_or(|filters| filters.iter().all(|f| f.matches(todo)))
+            && self
+                .or
+                .as_ref()
+                .is_none_or(|filters| filters.iter().any(|f| f.matches(todo)))
+            && self.not.as_ref().is_none_or(|filter| !filter.matches(todo))
+    }
+}
+
+fn repository<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Arc<dyn TodoRepository>> {
+    ctx.data::<Arc<dyn TodoRepository>>()
+}
+
+fn not_found(id: i64) -> async_graphql::Error {
+    async_graphql::Error::new(format!("Todo not found, id = {id}"))
+        .extend_with(|_, e| e.set("code", "NOT_FOUND"))
+}
+
+fn validate(value: &impl Validate) -> async_graphql::Result<()> {
+    value.validate().map_err(|errors| {
+        let errors = serde_json::to_value(field_errors(&errors)).unwrap_or_default();
+        async_graphql::Error::new("Invalid input").extend_with(|_, e| {
+            e.set("code", "VALIDATION");
+            e.set("errors", Value::from_json(errors).unwrap_or_default());
+        })
+    })
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Todos matching the filter, all of them without one, by id.
+    async fn todos(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<TodoFilter>,
+    ) -> async_graphql::Result<Vec<Todo>> {
+        let filter = filter.unwrap_or_default();
+        let mut todos: Vec<Todo> = repository(ctx)?
+            .read_all()
+            .await
+            .into_iter()
+            .filter(|todo| filter.matches(todo))
+            .collect();
+        todos.sort_by_key(|todo| todo.id);
+        Ok(todos)
+    }
+
+    async fn todo(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Todo>> {
+        Ok(repository(ctx)?.read_one(id).await.ok())
+    }
+
+    /// Todos whose value contains `text`, like `GET /todo/search`.
+    async fn search(&self, ctx: &Context<'_>, text: String) -> async_graphql::Result<Vec<Todo>> {
+        Ok(repository(ctx)?.read_filter(&text).await)
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_todo(&self, ctx: &Context<'_>, todo: Todo) -> async_graphql::Result<Todo> {
+        validate(&todo)?;
+        repository(ctx)?
+            .create_one(&todo)
+            .await
+            .map_err(|existing| {
+                async_graphql::Error::new(format!("Todo already exists, id = {}", existing.id))
+                    .extend_with(|_, e| e.set("code", "CONFLICT"))
+            })?;
+        Ok(todo)
+    }
+
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: i64,
+        update: TodoUpdateRequest,
+    ) -> async_graphql::Result<Todo> {
+        validate(&update)?;
+        repository(ctx)?
+            .update_one(id, update)
+            .await
+            .map_err(|()| not_found(id))
+    }
+
+    /// Delete a todo, returning its id.
+    async fn delete_todo(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<i64> {
+        repository(ctx)?
+            .delete_one(id)
+            .await
+            .map_err(|()| not_found(id))?;
+        Ok(id)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Changes made to the todos from now on, only those of the todo `id` when given.
+    async fn todo_changes(
+        &self,
+        ctx: &Context<'_>,
+        id: Option<i64>,
+    ) -> async_graphql::Result<impl Stream<Item = TodoChange>> {
+        let changes = ctx.data::<TodoChanges>()?.subscribe();
+        Ok(
+            BroadcastStream::new(changes).filter_map(move |change| async move {
+                // Lagging subscribers skip the changes they missed
+                change
+                    .ok()
+                    .filter(|change| id.is_none_or(|id| change.id == id))
+            }),
+        )
+    }
+}
+
+#[post("/graphql")]
+#[inject]
+async fn graphql(
+    schema: web::Data<TodoSchema>,
+    request: GraphQLRequest,
+    #[inject] repository: Arc<dyn TodoRepository>,
+) -> GraphQLResponse {
+    schema
+        .execute(request.into_inner().data(repository))
+        .await
+        .into()
+}
+
+/// Subscriptions, over the `graphql-ws` and `graphql-transport-ws` websocket protocols.
+#[inject]
+async fn graphql_ws(
+    schema: web::Data<TodoSchema>,
+    req: HttpRequest,
+    payload: Payload,
+    #[inject] repository: Arc<dyn TodoRepository>,
+) -> actix_web::Result<HttpResponse> {
+    let mut data = Data::default();
+    data.insert(repository);
+    GraphQLSubscription::new(TodoSchema::clone(&schema))
+        .with_data(data)
+        .start(&req, payload)
+}
+
+#[get("/graphiql")]
+async fn graphiql() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            GraphiQLSource::build()
+                .endpoint("/graphql")
+                .subscription_endpoint("/graphql/ws")
+                .finish(),
+        )
+}
+
+/// Schema in the GraphQL SDL, served next to the OpenAPI document.
+#[get("/api-docs/schema.graphql")]
+async fn sdl(schema: web::Data<TodoSchema>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(schema.sdl())
+}
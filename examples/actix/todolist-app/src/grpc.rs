@@ -0,0 +1,200 @@
+// `Status` is the error of every tonic handler, boxing it would only get in the way
+#![allow(clippy::result_large_err)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::Stream;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+use validator::Validate;
+
+use crate::schemas::{field_errors, Todo, TodoUpdateRequest};
+use crate::store_interface::TodoRepository;
+
+pub mod proto {
+    tonic::include_proto!("todolist.v1");
+}
+
+use proto::todo_service_server::{TodoService, TodoServiceServer};
+
+impl From<Todo> for proto::Todo {
+    fn from(todo: Todo) -> Self {
+        Self {
+            id: todo.id,
+            value: todo.value,
+            checked: todo.checked,
+        }
+    }
+}
+
+impl From<proto::Todo> for Todo {
+    fn from(todo: proto::Todo) -> Self {
+        Self {
+            id: todo.id,
+            value: todo.value,
+            checked: todo.checked,
+        }
+    }
+}
+
+impl From<proto::TodoUpdate> for TodoUpdateRequest {
+    fn from(update: proto::TodoUpdate) -> Self {
+        Self {
+            value: update.value,
+            checked: update.checked,
+        }
+    }
+}
+
+fn not_found(id: i64) -> Status {
+    Status::not_found(format!("Todo not found, id = {id}"))
+}
+
+/// `INVALID_ARGUMENT` listing the broken constraints by field, like the REST validation problems.
+fn validate(value: &impl Validate) -> Result<(), Status> {
+    value.validate().map_err(|errors| {
+        let fields: Vec<String> = field_errors(&errors)
+            .into_iter()
+            .map(|field| format!("{}: {}", field.field, field.messages.join(", ")))
+            .collect();
+        Status::invalid_argument(fields.join("; "))
+    })
+}
+
+type TodoStream = Pin<Box<dyn Stream<Item = Result<proto::Todo, Status>> + Send>>;
+
+fn stream(todos: Vec<Todo>) -> TodoStream {
+    Box::pin(futures::stream::iter(
+        todos.into_iter().map(|todo| Ok(todo.into())),
+    ))
+}
+
+/// gRPC front of the repository, sharing its instance with the HTTP server.
+pub struct TodoGrpc {
+    repository: Arc<dyn TodoRepository>,
+}
+
+impl TodoGrpc {
+    pub fn new(repository: Arc<dyn TodoRepository>) -> Self {
+        Self { repository }
+    }
+}
+
+#[tonic::async_trait]
+impl TodoService for TodoGrpc {
+    type ListTodosStream = TodoStream;
+    type SearchTodosStream = TodoStream;
+
+    async fn list_todos(
+        &self,
+        _request: Request<proto::ListTodosRequest>,
+    ) -> Result<Response<Self::ListTodosStream>, Status> {
+        Ok(Response::new(stream(self.repository.read_all().await)))
+    }
+
+    async fn search_todos(
+        &self,
+        request: Request<proto::SearchTodosRequest>,
+    ) -> Result<Response<Self::SearchTodosStream>, Status> {
+        let todos = self
+            .repository
+            .read_filter(&request.into_inner().value)
+            .await;
+        Ok(Response::new(stream(todos)))
+    }
+
+    async fn get_todo(
+        &self,
+        request: Request<proto::GetTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let id = request.into_inner().id;
+        let todo = self
+            .repository
+            .read_one(id)
+            .await
+            .map_err(|()| not_found(id))?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn create_todo(
+        &self,
+        request: Request<proto::CreateTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let todo: Todo = request
+            .into_inner()
+            .todo
+            .ok_or_else(|| Status::invalid_argument("todo: required"))?
+            .into();
+        validate(&todo)?;
+        self.repository
+            .create_one(&todo)
+            .await
+            .map_err(|existing| {
+                Status::already_exists(format!("Todo already exists, id = {}", existing.id))
+            })?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn update_todo(
+        &self,
+        request: Request<proto::UpdateTodoRequest>,
+    ) -> Result<Response<proto::Todo>, Status> {
+        let request = request.into_inner();
+        let update: TodoUpdateRequest = request.update.unwrap_or_default().into();
+        validate(&update)?;
+        let todo = self
+            .repository
+            .update_one(request.id, update)
+            .await
+            .map_err(|()| not_found(request.id))?;
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn delete_todo(
+        &self,
+        request: Request<proto::DeleteTodoRequest>,
+    ) -> Result<Response<proto::DeleteTodoResponse>, Status> {
+        let id = request.into_inner().id;
+        self.repository
+            .delete_one(id)
+            .await
+            .map_err(|()| not_found(id))?;
+        Ok(Response::new(proto::DeleteTodoResponse {}))
+    }
+}
+
+/// Serve the todo service and the gRPC health service on `listener`, until `shutdown` resolves.
+///
+/// The health service reports the todo service as serving until then.
+pub async fn serve(
+    listener: TcpListener,
+    repository: Arc<dyn TodoRepository>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let (mut reporter, health) = tonic_health::server::health_reporter();
+    reporter.set_serving::<TodoServiceServer<TodoGrpc>>().await;
+    if let Ok(addr) = listener.local_addr() {
+        tracing::info!(%addr, "serving gRPC");
+    }
+    Server::builder()
+        .trace_fn(|request| {
+            tracing::info_span!(
+                "grpc",
+                otel.kind = "server",
+                rpc.system = "grpc",
+                rpc.method = request.uri().path()
+            )
+        })
+        .add_service(health)
+        .add_service(TodoServiceServer::new(TodoGrpc::new(repository)))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+            shutdown.await;
+            reporter
+                .set_not_serving::<TodoServiceServer<TodoGrpc>>()
+                .await;
+        })
+        .await
+}
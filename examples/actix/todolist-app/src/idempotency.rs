@@ -0,0 +1,281 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::{self, BoxBody, EitherBody, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
+    rt,
+    web::{Bytes, BytesMut},
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures::{future::LocalBoxFuture, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::problem::error_response;
+use crate::ratelimit::client_key;
+use crate::schemas::{ErrorResponse, FieldError};
+use crate::stores::idempotency::{Claim, IdempotencyStore, StoredResponse};
+use crate::validation::PAYLOAD_LIMIT;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Header flagging the responses replayed from a previous request.
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Handling of the `Idempotency-Key` header, read from `idempotency.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdempotencyConfig {
+    /// Methods the header applies to.
+    pub methods: Vec<String>,
+    /// How long the response to a key is replayed.
+    pub ttl_secs: i64,
+    /// How long a request holds its key before a retry may take it over, should its instance die.
+    pub lock_secs: i64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            methods: vec!["POST".to_owned()],
+            ttl_secs: 24 * 3600,
+            lock_secs: 60,
+        }
+    }
+}
+
+impl IdempotencyConfig {
+    /// Read the settings from a yml file, falling back to the defaults when there is none.
+    pub fn from_file(path: &str) -> Self {
+        match std::fs::File::open(path) {
+            Ok(file) => serde_yaml::from_reader(file).expect("Could not read idempotency file."),
+            Err(_) => {
+                tracing::warn!(path, "no idempotency file, using the defaults");
+                Self::default()
+            }
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+// How often the expired keys are deleted from the store
+const PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Delete the expired keys of `store` periodically, rather than on the claims of the requests.
+pub async fn purge_expired(store: Arc<dyn IdempotencyStore>) {
+    loop {
+        rt::time::sleep(PURGE_INTERVAL).await;
+        store.purge(now()).await;
+    }
+}
+
+/// Hash of what makes a request: its method, uri and body as sent, still encoded.
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b" ");
+    hasher.update(req.uri().to_string());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Read the body, up to one chunk past the payload limit so that the handler still rejects it.
+async fn read_body(payload: &mut Payload) -> Result<Bytes, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > PAYLOAD_LIMIT {
+            break;
+        }
+    }
+    Ok(body.freeze())
+}
+
+fn replay(response: StoredResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+    let mut replayed = HttpResponse::build(status);
+    for (name, value) in response.headers {
+        replayed.append_header((name, value));
+    }
+    replayed
+        .insert_header((IDEMPOTENT_REPLAYED, "true"))
+        .body(response.body)
+}
+
+fn invalid_key(req: &HttpRequest) -> HttpResponse {
+    let error = ErrorResponse::Validation(vec![FieldError {
+        field: String::from("Idempotency-Key"),
+        messages: vec![format!(
+            "must be between 1 and {MAX_KEY_LENGTH} visible ASCII characters"
+        )],
+    }]);
+    error_response(req, StatusCode::BAD_REQUEST, error)
+}
+
+/// Middleware making the requests with an `Idempotency-Key` header safe to retry.
+///
+/// The first response to a key is stored, then replayed verbatim to the requests sent again with
+/// it, flagged with `Idempotent-Replayed`. A request made while another one holds its key gets a
+/// 409 problem, one reusing a key with another method, uri or body gets a 422 problem. Keys are
+/// scoped to the client, as the rate limits are. Server errors are not stored, so that the request
+/// can be retried.
+#[derive(Clone)]
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore>,
+    config: Arc<IdempotencyConfig>,
+}
+
+impl Idempotency {
+    pub fn new(store: Arc<dyn IdempotencyStore>, config: IdempotencyConfig) -> Self {
+        Self {
+            store,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IdempotencyMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            store: self.store.clone(),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    store: Arc<dyn IdempotencyStore>,
+    config: Arc<IdempotencyConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let applies = self
+            .config
+            .methods
+            .iter()
+            .any(|method| method == req.method().as_str());
+        let key = req.headers().get(IDEMPOTENCY_KEY).cloned();
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+
+        Box::pin(async move {
+            let Some(key) = key.filter(|_| applies) else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+            let key = match key.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_owned(),
+                _ => {
+                    let (req, _) = req.into_parts();
+                    let response = invalid_key(&req);
+                    return Ok(ServiceResponse::new(req, response).map_into_right_body());
+                }
+            };
+            let key = format!("{}:{key}", client_key(&req));
+            let body = read_body(&mut req.take_payload()).await?;
+            let fingerprint = fingerprint(&req, &body);
+            req.set_payload(Payload::from(body));
+
+            let now = now();
+            let claim = store
+                .claim(
+                    &key,
+                    &fingerprint,
+                    now,
+                    now + config.lock_secs,
+                    now + config.ttl_secs,
+                )
+                .await;
+            let error = match claim {
+                Claim::Acquired => None,
+                Claim::Completed(response) => {
+                    let (req, _) = req.into_parts();
+                    return Ok(ServiceResponse::new(req, replay(response)).map_into_right_body());
+                }
+                Claim::InProgress => Some(ErrorResponse::RequestInProgress(String::from(
+                    "a request with the same Idempotency-Key is being processed",
+                ))),
+                Claim::Mismatch => Some(ErrorResponse::IdempotencyKeyReused(String::from(
+                    "the Idempotency-Key was used for another request",
+                ))),
+            };
+            if let Some(error) = error {
+                let (req, _) = req.into_parts();
+                let response = error_response(&req, error.status(), error);
+                return Ok(ServiceResponse::new(req, response).map_into_right_body());
+            }
+
+            let res = match service.call(req).await {
+                Ok(res) if !res.status().is_server_error() => res,
+                res => {
+                    store.release(&key).await;
+                    return res.map(|res| res.map_into_left_body());
+                }
+            };
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let body = match body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    store.release(&key).await;
+                    let err: Box<dyn std::error::Error> = err.into();
+                    return Err(actix_web::error::ErrorInternalServerError(err));
+                }
+            };
+            let headers = res
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect();
+            store
+                .complete(
+                    &key,
+                    &StoredResponse {
+                        status: res.status().as_u16(),
+                        headers,
+                        body: body.to_vec(),
+                    },
+                )
+                .await;
+            let res = res.set_body(BoxBody::new(body));
+            Ok(ServiceResponse::new(req, res).map_into_right_body())
+        })
+    }
+}
@@ -1,2 +1,9 @@
 pub mod store_interface;
-pub mod schemas;
\ No newline at end of file
+pub mod schemas;
+pub mod stores {
+    pub mod config;
+    pub mod idempotency;
+    pub mod memory;
+    pub mod postgres;
+    pub mod webhooks;
+}
@@ -1,73 +1,144 @@
 use std::{
     error::Error,
     net::Ipv4Addr,
+    sync::Arc,
 };
 
 use actix_web::{
-    middleware::Logger,
-    App, HttpServer,
+    rt, web, App, HttpServer,
 };
 use coi::container;
-//use stores::memory::TodoMemoryProvider;
-use stores::postgres::TodoPostgresProvider;
+use stores::changes::{NotifyingProvider, TodoChanges};
+use stores::config::{StoreConfig, TodoStoreProvider};
 
+mod caching;
+mod graphql;
+mod grpc;
+mod idempotency;
+mod metrics;
+mod negotiation;
+mod openapi;
+mod problem;
+mod ratelimit;
 mod rest;
 mod store_interface;
 mod schemas;
+mod shutdown;
+mod telemetry;
+mod validation;
+mod webhooks;
 mod stores {
+    pub mod changes;
+    pub mod config;
+    pub mod idempotency;
     pub mod memory;
     #[cfg(test)]
     pub mod test_memory;
     pub mod postgres;
+    pub mod webhooks;
 }
 #[cfg(test)]
 pub mod test_rest;
+#[cfg(test)]
+mod test_caching;
+#[cfg(test)]
+mod test_graphql;
+#[cfg(test)]
+mod test_grpc;
+#[cfg(test)]
+mod test_idempotency;
+#[cfg(test)]
+mod test_openapi;
+#[cfg(test)]
+mod test_ratelimit;
+#[cfg(test)]
+mod test_shutdown;
+#[cfg(test)]
+mod test_webhooks;
 
 use utoipa::OpenApi;
 use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
-use crate::schemas::{ErrorResponse, Todo, TodoUpdateRequest};
+use crate::caching::{Caching, CachingConfig, CompressAbove};
+use crate::idempotency::{purge_expired, Idempotency, IdempotencyConfig};
+use crate::metrics::{PoolMetrics, RequestMetrics};
+use crate::openapi::ApiDoc;
+use crate::ratelimit::{InMemoryBackend, RateLimit, RateLimitConfig};
+use crate::shutdown::{Readiness, ShutdownConfig};
+use crate::store_interface::TodoRepository;
+use crate::telemetry::RequestTracing;
+use crate::webhooks::{Dispatcher, WebhookConfig};
 
 
 #[actix_web::main]
 async fn main() -> Result<(), impl Error> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    //Swap here as needed
-    //let provider = TodoMemoryProvider{todo_list: Vec::new()};
-    let provider = TodoPostgresProvider::new("some_postgres", "postgres", "replacethisplease", "postgres", "5432").await;
-    let _res = provider.migrate().await;
+    let tracer_provider = telemetry::init("todolist-app");
+    // The store is picked in store.yml, shared with todoctl
+    let provider = TodoStoreProvider::new(&StoreConfig::from_file("store.yml")).await;
+    if let Err(err) = provider.check().await {
+        tracing::error!(%err, "could not reach the store");
+    } else if let Err(err) = provider.migrate().await {
+        tracing::error!(%err, "could not migrate the store");
+    }
+    let pool = provider.pool().cloned();
+    let webhook_store = provider.webhooks();
+    let idempotency_store = provider.idempotency();
+    rt::spawn(purge_expired(idempotency_store.clone()));
+    if let Some(pool) = &pool {
+        prometheus::register(Box::new(PoolMetrics::new("todo", pool.clone())))
+            .expect("Could not register pool metrics.");
+    }
+    // Changes are published to the GraphQL subscriptions, whichever endpoint made them
+    let changes = TodoChanges::default();
     let containers = container!{
-        repository => provider; singleton,
+        repository => NotifyingProvider::new(provider, changes.clone()); singleton,
     };
-    #[derive(OpenApi)]
-    #[openapi(
-        paths(
-            rest::get_todos,
-            rest::create_todo,
-            rest::delete_todo,
-            rest::get_todo_by_id,
-            rest::update_todo,
-            rest::search_todos
-        ),
-        components(
-            schemas(Todo, TodoUpdateRequest, ErrorResponse)
-        ),
-        tags(
-            (name = "todo", description = "Todo management endpoints.")
-        ),
-    )]
-    struct ApiDoc;
+    let webhook_config = WebhookConfig::from_file("webhooks.yml");
+    Dispatcher::new(webhook_store.clone(), webhook_config.clone()).spawn(&changes);
+    let schema = graphql::schema(changes);
+    // The gRPC server shares the repository instance of the HTTP server
+    let repository = containers
+        .resolve::<dyn TodoRepository>("repository")
+        .expect("Could not resolve the todo repository.");
     // Make instance variable of ApiDoc so all worker threads gets the same instance.
     let openapi = ApiDoc::openapi();
 
-    HttpServer::new(move || {
+    let readiness = Readiness::default();
+    let shutdown_config = ShutdownConfig::from_env();
+    let app_readiness = readiness.clone();
+    // Buckets are shared by every worker thread
+    let rate_limit = RateLimit::new(
+        Arc::new(InMemoryBackend::default()),
+        RateLimitConfig::from_file("rate_limits.yml"),
+    );
+    let caching = Arc::new(CachingConfig::from_file("caching.yml"));
+    let idempotency = Idempotency::new(
+        idempotency_store,
+        IdempotencyConfig::from_file("idempotency.yml"),
+    );
+
+    let server = HttpServer::new(move || {
         // This factory closure is called on each worker thread independently.
         App::new()
-            .wrap(Logger::default())
+            // Innermost, so that the responses are stored before being compressed
+            .wrap(idempotency.clone())
+            .wrap(rate_limit.clone())
+            .wrap(Caching::new(caching.clone()))
+            // Compression is negotiated with Accept-Encoding, request bodies are decompressed
+            // by the extractors according to their Content-Encoding
+            .wrap(CompressAbove(caching.min_compress_size))
+            .wrap(RequestMetrics)
+            .wrap(RequestTracing)
             .app_data(containers.clone())
+            .app_data(web::Data::new(app_readiness.clone()))
+            .app_data(web::Data::new(schema.clone()))
+            .app_data(web::Data::from(webhook_store.clone()))
+            .app_data(web::Data::new(webhook_config.clone()))
             .configure(rest::configure())
+            .configure(graphql::configure())
+            .configure(webhooks::configure())
             .service(Redoc::with_url("/redoc", openapi.clone()))
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}").url("/api-docs/openapi.json", openapi.clone()),
@@ -78,8 +149,40 @@ async fn main() -> Result<(), impl Error> {
             // Alternative to above
             // .service(RapiDoc::with_openapi("/api-docs/openapi2.json", openapi.clone()).path("/rapidoc"))
     })
+    // Signals are handled below, to flag the service as not ready before refusing connections
+    .disable_signals()
+    .shutdown_timeout(shutdown_config.drain_timeout.as_secs())
     .bind((Ipv4Addr::UNSPECIFIED, 8080))?
-    .run()
-    .await
+    .run();
+    rt::spawn(shutdown::graceful_shutdown(
+        server.handle(),
+        readiness,
+        shutdown_config,
+        shutdown::termination_signal(),
+    ));
+    // gRPC on its own port
+    let grpc_listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 50051)).await?;
+    let (stop_grpc, grpc_stopped) = tokio::sync::oneshot::channel::<()>();
+    let grpc_server = rt::spawn(grpc::serve(grpc_listener, repository, async {
+        let _ = grpc_stopped.await;
+    }));
+    let result = server.await;
+
+    // The HTTP server drained, gRPC stops along with it
+    let _ = stop_grpc.send(());
+    match grpc_server.await {
+        Ok(Err(err)) => tracing::error!(%err, "gRPC server failed"),
+        Err(err) => tracing::error!(%err, "gRPC server panicked"),
+        Ok(Ok(())) => {}
+    }
+    // Nothing uses the database past this point
+    if let Some(pool) = pool {
+        pool.close();
+    }
+    // Flush the spans not exported yet
+    if let Some(provider) = tracer_provider {
+        let _ = provider.shutdown();
+    }
+    result
 }
 
@@ -0,0 +1,172 @@
+use std::future::{ready, Ready};
+use std::time::Instant;
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    get, Error, HttpResponse, Responder,
+};
+use futures::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    IntGauge, Opts, TextEncoder,
+};
+
+// Metrics are registered once in the default prometheus registry, and shared by every worker thread.
+lazy_static! {
+    pub static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests handled, by method, route pattern and status code.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency in seconds, by method and route pattern.",
+        &["method", "route"]
+    )
+    .unwrap();
+}
+
+/// Expose every registered metric in the Prometheus text format.
+///
+/// One could scrape the metrics with.
+/// ```text
+/// curl localhost:8080/metrics
+/// ```
+#[get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Middleware counting requests and measuring their latency.
+///
+/// Requests are labelled with the matched route pattern (`/todo/{id}`) rather than the raw path,
+/// so that the number of series stays bounded whatever the clients send.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let method = req.method().to_string();
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let start = Instant::now();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(response) => response.status().as_u16(),
+                Err(err) => err.as_response_error().status_code().as_u16(),
+            };
+            HTTP_REQUESTS_TOTAL
+                .with_label_values(&[&method, &route, &status.to_string()])
+                .inc();
+            HTTP_REQUEST_DURATION_SECONDS
+                .with_label_values(&[&method, &route])
+                .observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+/// Gauges reporting the state of a deadpool connection pool.
+///
+/// Values are read from the pool at scrape time, so there is nothing to update on the request path.
+pub struct PoolMetrics {
+    pool: deadpool_postgres::Pool,
+    max_size: IntGauge,
+    size: IntGauge,
+    available: IntGauge,
+    waiting: IntGauge,
+}
+
+impl PoolMetrics {
+    pub fn new(name: &str, pool: deadpool_postgres::Pool) -> Self {
+        let gauge = |metric: &str, help: &str| {
+            IntGauge::with_opts(Opts::new(metric, help).const_label("pool", name)).unwrap()
+        };
+        Self {
+            pool,
+            max_size: gauge(
+                "db_pool_max_size",
+                "Maximum number of connections of the pool.",
+            ),
+            size: gauge("db_pool_size", "Current number of connections of the pool."),
+            available: gauge("db_pool_available", "Idle connections of the pool."),
+            waiting: gauge(
+                "db_pool_waiting",
+                "Number of requests waiting for a connection of the pool.",
+            ),
+        }
+    }
+}
+
+impl Collector for PoolMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.max_size.desc(),
+            self.size.desc(),
+            self.available.desc(),
+            self.waiting.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let status = self.pool.status();
+        self.max_size.set(status.max_size as i64);
+        self.size.set(status.size as i64);
+        self.available.set(status.available as i64);
+        self.waiting.set(status.waiting as i64);
+        [
+            self.max_size.collect(),
+            self.size.collect(),
+            self.available.collect(),
+            self.waiting.collect(),
+        ]
+        .concat()
+    }
+}
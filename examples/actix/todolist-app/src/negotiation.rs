@@ -0,0 +1,235 @@
+use std::future::{ready, Future};
+use std::pin::Pin;
+
+use actix_web::{
+    body::BoxBody,
+    dev::Payload,
+    error::InternalError,
+    http::{
+        header::{HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE, VARY},
+        StatusCode,
+    },
+    web::{Bytes, Json},
+    Error, FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use utoipa::{
+    openapi::{OpenApi, RefOr},
+    Modify,
+};
+
+use crate::problem::error_response;
+use crate::schemas::{ErrorResponse, FieldError};
+
+pub const MSGPACK: &str = "application/msgpack";
+pub const CBOR: &str = "application/cbor";
+
+/// Body encodings the todo endpoints speak.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => MSGPACK,
+            Format::Cbor => CBOR,
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            MSGPACK | "application/x-msgpack" => Some(Format::MsgPack),
+            CBOR => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Encoding of the request body, JSON unless `Content-Type` says otherwise, `None` when it
+    /// names an encoding not spoken here.
+    pub fn of_request(req: &HttpRequest) -> Option<Self> {
+        match req.headers().get(CONTENT_TYPE) {
+            None => Some(Format::Json),
+            Some(value) => {
+                let media = media_type(value.to_str().ok()?);
+                // The `Json` extractor takes any JSON based type
+                let json = media.ends_with("/json") || media.ends_with("+json");
+                Format::from_mime(&media).or(json.then_some(Format::Json))
+            }
+        }
+    }
+
+    /// Preferred encoding of the response according to `Accept`, JSON when nothing else fits.
+    pub fn accepted(req: &HttpRequest) -> Self {
+        let mut accepted = req
+            .headers()
+            .get_all(ACCEPT)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| {
+                let format = Format::from_mime(&media_type(range))?;
+                let quality = range
+                    .split(';')
+                    .skip(1)
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((format, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+        // Stable, ties go to the first listed
+        accepted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        accepted
+            .first()
+            .map(|(format, _)| *format)
+            .unwrap_or(Format::Json)
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            // Structs are encoded as maps, as clients expect field names
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Format::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(value, &mut buffer).map_err(|err| err.to_string())?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Body encoded as JSON, MessagePack or CBOR.
+///
+/// As an extractor, the body is decoded according to its `Content-Type`; JSON bodies go through
+/// the `Json` extractor, and its configuration. As a responder, the value is encoded according to
+/// the `Accept` header of the request.
+pub struct Negotiated<T>(pub T);
+
+impl<T> Negotiated<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Negotiated<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let Some(format) = Format::of_request(req) else {
+            return Box::pin(ready(Err(unsupported_media_type(req))));
+        };
+        if format == Format::Json {
+            let json = Json::<T>::from_request(req, payload);
+            return Box::pin(async move { Ok(Negotiated(json.await?.into_inner())) });
+        }
+        // Size limited by the `PayloadConfig` of the app
+        let bytes = Bytes::from_request(req, payload);
+        let req = req.clone();
+        Box::pin(async move {
+            let body_error = |status: StatusCode, message: String| {
+                let error = ErrorResponse::Validation(vec![FieldError {
+                    field: "body".to_owned(),
+                    messages: vec![message.clone()],
+                }]);
+                let response = error_response(&req, status, error);
+                Error::from(InternalError::from_response(message, response))
+            };
+            let bytes = bytes.await.map_err(|err| {
+                body_error(err.as_response_error().status_code(), err.to_string())
+            })?;
+            format
+                .decode(&bytes)
+                .map(Negotiated)
+                .map_err(|message| body_error(StatusCode::BAD_REQUEST, message))
+        })
+    }
+}
+
+/// `415 Unsupported Media Type`, listing the encodings spoken in `Accept-Post` and the problem.
+fn unsupported_media_type(req: &HttpRequest) -> Error {
+    let supported = [Format::Json, Format::MsgPack, Format::Cbor]
+        .map(Format::mime)
+        .join(", ");
+    let message = format!("Content-Type not supported, use one of {supported}");
+    let error = ErrorResponse::UnsupportedMediaType(message.clone());
+    let mut response = error_response(req, StatusCode::UNSUPPORTED_MEDIA_TYPE, error);
+    response.headers_mut().insert(
+        HeaderName::from_static("accept-post"),
+        HeaderValue::from_str(&supported).expect("media types are valid header values"),
+    );
+    Error::from(InternalError::from_response(message, response))
+}
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let format = Format::accepted(req);
+        match format.encode(&self.0) {
+            Ok(body) => HttpResponse::Ok()
+                .content_type(format.mime())
+                .insert_header((VARY, "Accept"))
+                .body(body),
+            Err(err) => HttpResponse::InternalServerError().body(err),
+        }
+    }
+}
+
+/// Lists MessagePack and CBOR next to JSON in the OpenAPI document, for request bodies and
+/// successful responses.
+pub struct NegotiatedMediaTypes;
+
+impl Modify for NegotiatedMediaTypes {
+    fn modify(&self, openapi: &mut OpenApi) {
+        for operation in openapi
+            .paths
+            .paths
+            .values_mut()
+            .flat_map(|item| item.operations.values_mut())
+        {
+            if let Some(body) = operation.request_body.as_mut() {
+                if let Some(json) = body.content.get("application/json").cloned() {
+                    body.content.insert(MSGPACK.to_owned(), json.clone());
+                    body.content.insert(CBOR.to_owned(), json);
+                }
+            }
+            for (status, response) in operation.responses.responses.iter_mut() {
+                let RefOr::T(response) = response else {
+                    continue;
+                };
+                if !status.starts_with('2') {
+                    continue;
+                }
+                if let Some(json) = response.content.get("application/json").cloned() {
+                    response.content.insert(MSGPACK.to_owned(), json.clone());
+                    response.content.insert(CBOR.to_owned(), json);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use utoipa::OpenApi;
+
+use crate::negotiation::NegotiatedMediaTypes;
+use crate::problem::Problem;
+use crate::rest;
+use crate::schemas::{
+    Delivery, DeliveryStatus, ErrorResponse, FieldError, Todo, TodoUpdateRequest, Webhook,
+    WebhookEvent, WebhookRequest,
+};
+use crate::webhooks;
+
+/// OpenAPI document of the REST API.
+///
+/// `openapi.json`, at the root of the crate, is a copy of it for the tools and crates which do not
+/// build this binary, such as the client; `test_openapi` fails when it is out of date.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        rest::get_todos,
+        rest::create_todo,
+        rest::delete_todo,
+        rest::get_todo_by_id,
+        rest::update_todo,
+        rest::search_todos,
+        webhooks::get_webhooks,
+        webhooks::create_webhook,
+        webhooks::get_webhook,
+        webhooks::delete_webhook,
+        webhooks::get_deliveries
+    ),
+    components(schemas(
+        Todo,
+        TodoUpdateRequest,
+        ErrorResponse,
+        FieldError,
+        Problem,
+        Webhook,
+        WebhookRequest,
+        WebhookEvent,
+        Delivery,
+        DeliveryStatus
+    )),
+    tags(
+        (name = "todo", description = "Todo management endpoints."),
+        (name = "webhooks", description = "Todo event subscriptions and their deliveries.")
+    ),
+    modifiers(&NegotiatedMediaTypes),
+)]
+pub struct ApiDoc;
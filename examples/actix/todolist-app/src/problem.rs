@@ -0,0 +1,132 @@
+use actix_web::{
+    body::BoxBody,
+    http::{header::ACCEPT, StatusCode},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::schemas::{ErrorResponse, FieldError};
+use crate::telemetry::current_trace_id;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Error details following RFC 7807, served as `application/problem+json`.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Problem {
+    /// URI reference identifying the kind of problem, `about:blank` when the status says it all.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Short summary of the kind of problem.
+    pub title: String,
+    /// HTTP status code of the response.
+    pub status: u16,
+    /// Explanation specific to this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Path of the request which failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Id of the trace of the request, when traces are exported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Broken constraints, for validation problems.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+impl Problem {
+    fn from_error(req: &HttpRequest, status: StatusCode, error: ErrorResponse) -> Self {
+        let (problem_type, title, detail, errors) = match error {
+            ErrorResponse::NotFound(detail) => {
+                (None, None, format!("Todo not found, {detail}"), None)
+            }
+            ErrorResponse::WebhookNotFound(detail) => {
+                (None, None, format!("Webhook not found, {detail}"), None)
+            }
+            ErrorResponse::Conflict(detail) => {
+                (None, None, format!("Todo already exists, {detail}"), None)
+            }
+            ErrorResponse::Unauthorized(detail) => (None, None, detail, None),
+            ErrorResponse::TooManyRequests(detail) => (None, None, detail, None),
+            ErrorResponse::RequestInProgress(detail) => (None, None, detail, None),
+            ErrorResponse::IdempotencyKeyReused(detail) => (None, None, detail, None),
+            ErrorResponse::UnsupportedMediaType(detail) => (None, None, detail, None),
+            ErrorResponse::Validation(errors) => (
+                Some("/problems/validation"),
+                Some("Invalid request body"),
+                "The request body is malformed or breaks some constraints.".to_owned(),
+                Some(errors),
+            ),
+        };
+        Self {
+            problem_type: problem_type.unwrap_or("about:blank").to_owned(),
+            title: title
+                .or(status.canonical_reason())
+                .unwrap_or_default()
+                .to_owned(),
+            status: status.as_u16(),
+            detail: Some(detail),
+            instance: Some(req.path().to_owned()),
+            trace_id: current_trace_id(),
+            errors,
+        }
+    }
+}
+
+/// Whether the client asked for the legacy `ErrorResponse` shape, sending `Accept: application/json`
+/// without listing `application/problem+json`.
+fn wants_legacy(req: &HttpRequest) -> bool {
+    let accept = req
+        .headers()
+        .get_all(ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media| {
+            media
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .collect::<Vec<_>>();
+    accept.iter().any(|media| media == "application/json")
+        && !accept.iter().any(|media| media == PROBLEM_JSON)
+}
+
+/// Render an error as `application/problem+json`, or as the legacy `ErrorResponse` JSON when the
+/// client asked for it.
+pub fn error_response(req: &HttpRequest, status: StatusCode, error: ErrorResponse) -> HttpResponse {
+    if wants_legacy(req) {
+        HttpResponse::build(status).json(error)
+    } else {
+        HttpResponse::build(status)
+            .content_type(PROBLEM_JSON)
+            .json(Problem::from_error(req, status, error))
+    }
+}
+
+impl ErrorResponse {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorResponse::NotFound(_) | ErrorResponse::WebhookNotFound(_) => StatusCode::NOT_FOUND,
+            ErrorResponse::Conflict(_) | ErrorResponse::RequestInProgress(_) => {
+                StatusCode::CONFLICT
+            }
+            ErrorResponse::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ErrorResponse::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ErrorResponse::IdempotencyKeyReused(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorResponse::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorResponse::Validation(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Responder for ErrorResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        error_response(req, self.status(), self)
+    }
+}
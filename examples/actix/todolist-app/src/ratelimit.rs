@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    Error,
+};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::problem::error_response;
+use crate::schemas::ErrorResponse;
+
+/// Token bucket parameters: `per_second` tokens are given back every second, up to `burst`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Limits of a route, matched on its method and pattern, e.g. `PUT /todo/{id}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RouteQuota {
+    pub methods: Vec<String>,
+    pub route: String,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+/// Rate limits of the service, read from `rate_limits.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Limits shared by every route without its own.
+    pub default: Quota,
+    /// Route patterns never limited, such as the probes.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+    #[serde(default)]
+    pub routes: Vec<RouteQuota>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: Quota {
+                per_second: 20.0,
+                burst: 40,
+            },
+            exempt: vec![
+                "/health".to_owned(),
+                "/ready".to_owned(),
+                "/metrics".to_owned(),
+            ],
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Read the limits from a yml file, falling back to the defaults when there is none.
+    pub fn from_file(path: &str) -> Self {
+        match std::fs::File::open(path) {
+            Ok(file) => serde_yaml::from_reader(file).expect("Could not read rate limits file."),
+            Err(_) => {
+                tracing::warn!(path, "no rate limits file, using the defaults");
+                Self::default()
+            }
+        }
+    }
+
+    /// Name and limits of the bucket a request goes to, `None` when the route is exempt.
+    fn quota_for(&self, method: &str, route: &str) -> Option<(String, Quota)> {
+        if self.exempt.iter().any(|exempt| exempt == route) {
+            return None;
+        }
+        let quota = self
+            .routes
+            .iter()
+            .find(|quota| quota.route == route && quota.methods.iter().any(|m| m == method))
+            .map(|quota| (format!("{method} {route}"), quota.quota))
+            .unwrap_or_else(|| ("default".to_owned(), self.default));
+        Some(quota)
+    }
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until a token is available, zero when the request was allowed.
+    pub retry_after: Duration,
+}
+
+/// Storage of the token buckets.
+///
+/// The in-memory backend limits each instance on its own; implement this trait over a shared
+/// store, e.g. redis, to enforce the limits across instances.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Take a token from the bucket of `key`, created full on first use.
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+// Past this number of buckets, the full ones are dropped as they hold no information
+const MAX_BUCKETS: usize = 100_000;
+
+/// Token buckets kept in the memory of the instance.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+fn secs(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBackend {
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision {
+        let now = Instant::now();
+        let burst = f64::from(quota.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * quota.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = secs((burst - bucket.tokens) / quota.per_second);
+        bucket.full_at = now + reset;
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: bucket.tokens as u32,
+            reset,
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                secs((1.0 - bucket.tokens) / quota.per_second)
+            },
+        }
+    }
+}
+
+// How far the signing time of the gateway headers may be from ours
+const SIGNATURE_TOLERANCE_SECS: u64 = 300;
+
+lazy_static! {
+    // Shared with the gateway, which signs the identity headers it sends
+    static ref SIGNING_KEY: Option<Vec<u8>> = env::var("UPSTREAM_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes);
+}
+
+/// `X-User` of a request, when the gateway signed it with `key` in `X-User-Signature`, along with
+/// `X-User-Roles` and `X-Auth-Method`, less than `SIGNATURE_TOLERANCE_SECS` from `now`.
+pub(crate) fn signed_user<'a>(headers: &'a HeaderMap, key: &[u8], now: u64) -> Option<&'a str> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let user = header("x-user").filter(|user| !user.is_empty())?;
+    let (timestamp, signature) = header("x-user-signature")?
+        .split_once(',')
+        .and_then(|(t, v1)| Some((t.strip_prefix("t=")?, v1.strip_prefix("v1=")?)))?;
+    if timestamp.parse::<u64>().ok()?.abs_diff(now) > SIGNATURE_TOLERANCE_SECS {
+        return None;
+    }
+    let roles = header("x-user-roles").unwrap_or_default();
+    let method = header("x-auth-method").unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.{user}.{roles}.{method}").as_bytes());
+    mac.verify_slice(&hex::decode(signature).ok()?).ok()?;
+    Some(user)
+}
+
+/// Who the request is counted against: the user the gateway vouched for, or its peer address.
+/// Unsigned `X-User` and `X-Api-Key` headers are anyone's to send, and left to the address.
+pub(crate) fn client_key(req: &ServiceRequest) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let user = SIGNING_KEY
+        .as_ref()
+        .and_then(|key| signed_user(req.headers(), key, now));
+    if let Some(user) = user {
+        format!("user:{user}")
+    } else {
+        let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+        format!("ip:{}", peer.unwrap_or_default())
+    }
+}
+
+// Round up, so that clients waiting the advertised time do get a token
+fn header_secs(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs_f64().ceil() as u64)
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        header_secs(decision.reset),
+    );
+}
+
+/// Middleware limiting the request rate of each client with token buckets.
+///
+/// Requests over the limit are answered with a 429 problem and a `Retry-After` header; every
+/// limited response carries the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers.
+#[derive(Clone)]
+pub struct RateLimit {
+    backend: Arc<dyn RateLimitBackend>,
+    config: Arc<RateLimitConfig>,
+}
+
+impl RateLimit {
+    pub fn new(backend: Arc<dyn RateLimitBackend>, config: RateLimitConfig) -> Self {
+        Self {
+            backend,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            backend: self.backend.clone(),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    backend: Arc<dyn RateLimitBackend>,
+    config: Arc<RateLimitConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let quota = self.config.quota_for(req.method().as_str(), &route);
+        let service = self.service.clone();
+        let backend = self.backend.clone();
+
+        Box::pin(async move {
+            let Some((name, quota)) = quota else {
+                return service.call(req).await.map(|res| res.map_into_left_body());
+            };
+            let decision = backend
+                .acquire(&format!("{name}:{}", client_key(&req)), quota)
+                .await;
+            if !decision.allowed {
+                let (req, _) = req.into_parts();
+                let error = ErrorResponse::TooManyRequests(format!(
+                    "{} requests allowed per second, with bursts of {}",
+                    quota.per_second, quota.burst
+                ));
+                let mut response = error_response(&req, StatusCode::TOO_MANY_REQUESTS, error);
+                set_headers(response.headers_mut(), &decision);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, header_secs(decision.retry_after));
+                return Ok(ServiceResponse::new(req, response).map_into_right_body());
+            }
+            let mut res = service.call(req).await?;
+            set_headers(res.headers_mut(), &decision);
+            Ok(res.map_into_left_body())
+        })
+    }
+}
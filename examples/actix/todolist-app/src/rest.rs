@@ -1,15 +1,21 @@
 use actix_web::{
     web,
     get,
-    web::{Json, Path, Query, ServiceConfig},
+    Either,
+    http::StatusCode,
+    web::{Path, Query, ServiceConfig},
     HttpResponse, Responder,
 };
 use serde::Deserialize;
 use coi_actix_web::inject;
 
+use crate::metrics::metrics;
+use crate::shutdown::ready;
 use crate::store_interface::TodoRepository;
 
-use crate::schemas::{ErrorResponse, TodoUpdateRequest, Todo};
+use crate::schemas::{ErrorResponse, FieldError, TodoUpdateRequest, Todo};
+use crate::negotiation::Negotiated;
+use crate::validation::{json_config, payload_config, Validated};
 use utoipa::IntoParams;
 
 
@@ -19,7 +25,9 @@ pub(super) fn configure() -> impl FnOnce(&mut ServiceConfig) {
     }
 }
 pub fn route_config(config: &mut ServiceConfig) {
-    config.service(
+    config.app_data(json_config())
+    .app_data(payload_config())
+    .service(
         web::scope("/todo")
             .route("", web::get().to(get_todos))
             .route("", web::post().to(create_todo))
@@ -27,7 +35,9 @@ pub fn route_config(config: &mut ServiceConfig) {
             .route("/{id}", web::delete().to(delete_todo))
             .route("/{id}", web::get().to(get_todo_by_id))
             .route("/{id}", web::put().to(update_todo))
-    ).service(health);
+    ).service(health)
+    .service(ready)
+    .service(metrics);
 }
 
 #[get("/health")]
@@ -52,13 +62,15 @@ async fn health() -> impl Responder {
 )]
 #[inject]
 async fn get_todos(#[inject] repository: Arc<dyn TodoRepository>) -> impl Responder {
-    HttpResponse::Ok().json(repository.read_all().await)
+    Negotiated(repository.read_all().await)
 }
 
 /// Create new Todo to storage.
 ///
 /// Post a new `Todo` in request body as json to store it. Api will return
-/// created `Todo` on success or `ErrorResponse::Conflict` if todo with same id already exists.
+/// created `Todo` on success or a conflict problem if todo with same id already exists.
+/// A body breaking the `Todo` constraints is rejected with a validation problem.
+/// Retries sent with the `Idempotency-Key` of the first attempt get its response replayed.
 ///
 /// One could call the api with.
 /// ```text
@@ -70,45 +82,47 @@ async fn get_todos(#[inject] repository: Arc<dyn TodoRepository>) -> impl Respon
     request_body = Todo,
     responses(
         (status = 201, description = "Todo created successfully", body = Todo),
-        (status = 409, description = "Todo with id already exists", body = ErrorResponse, example = json!(ErrorResponse::Conflict(String::from("id = 1"))))
+        (status = 400, description = "Malformed or invalid Todo", content(("application/problem+json" = Problem, example = json!({"type": "/problems/validation", "title": "Invalid request body", "status": 400, "detail": "The request body is malformed or breaks some constraints.", "instance": "/todo", "errors": [{"field": "value", "messages": ["must be between 1 and 1024 characters"]}]})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::Validation(vec![FieldError{field: String::from("value"), messages: vec![String::from("must be between 1 and 1024 characters")]}]))))),
+        (status = 413, description = "Body larger than 16KiB", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 415, description = "Body in an encoding other than JSON, MessagePack or CBOR", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 409, description = "Todo with id already exists, or a request with the same Idempotency-Key is being processed", content(("application/problem+json" = Problem, example = json!({"type": "about:blank", "title": "Conflict", "status": 409, "detail": "Todo already exists, id = 1", "instance": "/todo"})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::Conflict(String::from("id = 1")))))),
+        (status = 422, description = "Idempotency-Key already used for another request", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse)))
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Key of the request, for its retries to get its response replayed rather than be processed again")
     )
 )]
 #[inject]
-async fn create_todo(todo: Json<Todo>, #[inject] repository: Arc<dyn TodoRepository>) -> impl Responder {
-    let result = repository.create_one(&todo.into_inner()).await;
+async fn create_todo(todo: Validated<Todo>, #[inject] repository: Arc<dyn TodoRepository>) -> impl Responder {
+    let todo = todo.into_inner();
+    let result = repository.create_one(&todo).await;
     match result {
-        Ok(todo_ret) => HttpResponse::Created().json(todo_ret),
-        Err(existing) =>  HttpResponse::Conflict().json(ErrorResponse::Conflict(format!("id = {}", existing.id)))
+        Ok(()) => Either::Left(Negotiated(todo).customize().with_status(StatusCode::CREATED)),
+        Err(existing) => Either::Right(ErrorResponse::Conflict(format!("id = {}", existing.id)))
     }
 }
 
 /// Delete Todo by given path variable id.
 ///
-/// This endpoint needs `api_key` authentication in order to call. Api key can be found from README.md.
-///
 /// Api will delete todo from storage by the provided id and return success 200.
 /// If storage does not contain `Todo` with given id 404 not found will be returned.
 #[utoipa::path(
     delete,
-    path = "/todo",
+    path = "/todo/{id}",
     responses(
         (status = 200, description = "Todo deleted successfully"),
-        (status = 401, description = "Unauthorized to delete Todo", body = ErrorResponse, example = json!(ErrorResponse::Unauthorized(String::from("missing api key")))),
-        (status = 404, description = "Todo not found by id", body = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))
+        (status = 404, description = "Todo not found by id", content(("application/problem+json" = Problem, example = json!({"type": "about:blank", "title": "Not Found", "status": 404, "detail": "Todo not found, id = 1", "instance": "/todo/1"})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))))
     ),
     params(
         ("id", description = "Unique storage id of Todo")
-    ),
-    security(
-        ("api_key" = [])
     )
 )]
 #[inject]
 async fn delete_todo(id: Path<i64>, #[inject] repository: Arc<dyn TodoRepository>) -> impl Responder {
     let result = repository.delete_one(*id).await;
     match result {
-        Ok(()) => HttpResponse::Ok().finish(),
-        Err(()) => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}")))
+        Ok(()) => Either::Left(HttpResponse::Ok().finish()),
+        Err(()) => Either::Right(ErrorResponse::NotFound(format!("id = {id}")))
     }
 }
 
@@ -120,7 +134,7 @@ async fn delete_todo(id: Path<i64>, #[inject] repository: Arc<dyn TodoRepository
     path = "/todo/{id}",
     responses(
         (status = 200, description = "Todo found from storage", body = Todo),
-        (status = 404, description = "Todo not found by id", body = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))
+        (status = 404, description = "Todo not found by id", content(("application/problem+json" = Problem, example = json!({"type": "about:blank", "title": "Not Found", "status": 404, "detail": "Todo not found, id = 1", "instance": "/todo/1"})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))))
     ),
     params(
         ("id", description = "Unique storage id of Todo")
@@ -130,45 +144,43 @@ async fn delete_todo(id: Path<i64>, #[inject] repository: Arc<dyn TodoRepository
 async fn get_todo_by_id(id: Path<i64>, #[inject] repository: Arc<dyn TodoRepository>) -> impl Responder {
     let result = repository.read_one(*id).await;
     match result {
-        Ok(todo) => HttpResponse::Ok().json(todo),
-        Err(()) => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}")))
+        Ok(todo) => Either::Left(Negotiated(todo)),
+        Err(()) => Either::Right(ErrorResponse::NotFound(format!("id = {id}")))
     }
 
 }
 
 /// Update Todo with given id.
 ///
-/// This endpoint supports optional authentication.
-///
 /// Tries to update `Todo` by given id as path variable. If todo is found by id values are
 /// updated according `TodoUpdateRequest` and updated `Todo` is returned with status 200.
-/// If todo is not found then 404 not found is returned.
+/// If todo is not found then 404 not found is returned, and an invalid `TodoUpdateRequest` is
+/// rejected with a validation problem.
 #[utoipa::path(
     put,
     path = "/todo/{id}",
     request_body = TodoUpdateRequest,
     responses(
         (status = 200, description = "Todo updated successfully", body = Todo),
-        (status = 404, description = "Todo not found by id", body = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))
+        (status = 400, description = "Malformed or invalid TodoUpdateRequest", content(("application/problem+json" = Problem, example = json!({"type": "/problems/validation", "title": "Invalid request body", "status": 400, "detail": "The request body is malformed or breaks some constraints.", "instance": "/todo/1", "errors": [{"field": "value", "messages": ["must be between 1 and 1024 characters"]}]})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::Validation(vec![FieldError{field: String::from("value"), messages: vec![String::from("must be between 1 and 1024 characters")]}]))))),
+        (status = 413, description = "Body larger than 16KiB", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 415, description = "Body in an encoding other than JSON, MessagePack or CBOR", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 404, description = "Todo not found by id", content(("application/problem+json" = Problem, example = json!({"type": "about:blank", "title": "Not Found", "status": 404, "detail": "Todo not found, id = 1", "instance": "/todo/1"})), ("application/json" = ErrorResponse, example = json!(ErrorResponse::NotFound(String::from("id = 1"))))))
     ),
     params(
         ("id", description = "Unique storage id of Todo")
-    ),
-    security(
-        (),
-        ("api_key" = [])
     )
 )]
 #[inject]
 async fn update_todo(
     id: Path<i64>,
-    todo: Json<TodoUpdateRequest>,
+    todo: Validated<TodoUpdateRequest>,
     #[inject] repository: Arc<dyn TodoRepository>,
 ) -> impl Responder {
     let result = repository.update_one(*id, todo.into_inner()).await;
     match result {
-        Ok(todo) => HttpResponse::Ok().json(todo),
-        Err(()) => HttpResponse::NotFound().json(ErrorResponse::NotFound(format!("id = {id}")))
+        Ok(todo) => Either::Left(Negotiated(todo)),
+        Err(()) => Either::Right(ErrorResponse::NotFound(format!("id = {id}")))
     }
 }
 
@@ -198,7 +210,7 @@ async fn search_todos(
     query: Query<SearchTodos>,
     #[inject] repository: Arc<dyn TodoRepository>,
 ) -> impl Responder {
-    HttpResponse::Ok().json(repository.read_filter(&query.value).await)
+    Negotiated(repository.read_filter(&query.value).await)
 }
 
 
@@ -1,33 +1,208 @@
+use async_graphql::{Enum, InputObject, SimpleObject};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
+use validator::{Validate, ValidationErrors};
 
 /// Task to do.
-#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, ToSchema, Validate, SimpleObject, InputObject, Clone, Debug, PartialEq, Eq, Hash)]
+#[graphql(input_name = "TodoInput")]
 pub struct Todo {
     /// Unique id for the todo item.
+    #[validate(range(min = 0, max = 2147483647, message = "must be between 0 and 2147483647"))]
+    #[schema(minimum = 0, maximum = 2147483647)]
     pub id: i64,
     /// Description of the tasks to do.
+    #[validate(length(min = 1, max = 1024, message = "must be between 1 and 1024 characters"))]
+    #[schema(min_length = 1, max_length = 1024)]
     pub value: String,
     /// Mark is the task done or not
     pub checked: bool,
 }
 
 /// Request to update existing `Todo` item.
-#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Validate, InputObject, Clone, Debug)]
+#[graphql(name = "TodoUpdate")]
 pub struct TodoUpdateRequest {
     /// Optional new value for the `Todo` task.
+    #[validate(length(min = 1, max = 1024, message = "must be between 1 and 1024 characters"))]
+    #[schema(min_length = 1, max_length = 1024)]
     pub value: Option<String>,
     /// Optional check status to mark is the task done or not.
     pub checked: Option<bool>,
 }
 
 /// Todo endpoint error responses
-#[derive(Serialize, Deserialize, ToSchema, Clone)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
 pub enum ErrorResponse {
     /// When Todo is not found by search term.
     NotFound(String),
+    /// When a webhook is not found by id.
+    WebhookNotFound(String),
     /// When there is a conflict storing a new todo.
     Conflict(String),
     /// When todo endpoint was called without correct credentials
     Unauthorized(String),
-}
\ No newline at end of file
+    /// When the client went over its rate limit.
+    TooManyRequests(String),
+    /// When a request with the same idempotency key is still being processed.
+    RequestInProgress(String),
+    /// When an idempotency key is sent again along with a different request.
+    IdempotencyKeyReused(String),
+    /// When the request body is in an encoding the endpoint does not speak.
+    UnsupportedMediaType(String),
+    /// When the request body is malformed or breaks a constraint, with the messages by field.
+    Validation(Vec<FieldError>),
+}
+
+/// Constraints broken by a field of the request body.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    /// Name of the field, or `body` for errors not tied to a field such as malformed JSON.
+    pub field: String,
+    /// Why the value was rejected.
+    pub messages: Vec<String>,
+}
+
+/// Messages of every failed constraint, by field name.
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut fields: Vec<FieldError> = errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| FieldError {
+            field: field.to_string(),
+            messages: errors
+                .iter()
+                .map(|error| match &error.message {
+                    Some(message) => message.to_string(),
+                    None => error.code.to_string(),
+                })
+                .collect(),
+        })
+        .collect();
+    // Field errors come out of a hash map, keep responses stable
+    fields.sort_by(|a, b| a.field.cmp(&b.field));
+    fields
+}
+/// What happened to a todo.
+#[derive(Serialize, Deserialize, Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    /// Updated, an unchecked todo being checked.
+    Completed,
+    Deleted,
+}
+
+/// Change made to the todos, streamed to the GraphQL subscribers.
+#[derive(Serialize, Deserialize, SimpleObject, Clone, Debug, PartialEq, Eq)]
+pub struct TodoChange {
+    pub kind: ChangeKind,
+    /// Id of the todo which changed.
+    pub id: i64,
+    /// State of the todo after the change, none once deleted.
+    pub todo: Option<Todo>,
+}
+
+/// Todo lifecycle event a webhook can subscribe to.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WebhookEvent {
+    #[serde(rename = "todo.created")]
+    Created,
+    /// A todo was checked.
+    #[serde(rename = "todo.completed")]
+    Completed,
+    #[serde(rename = "todo.deleted")]
+    Deleted,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Created => "todo.created",
+            WebhookEvent::Completed => "todo.completed",
+            WebhookEvent::Deleted => "todo.deleted",
+        }
+    }
+
+    pub fn parse(event: &str) -> Option<Self> {
+        [WebhookEvent::Created, WebhookEvent::Completed, WebhookEvent::Deleted]
+            .into_iter()
+            .find(|known| known.as_str() == event)
+    }
+}
+
+/// Request to subscribe an url to todo events.
+#[derive(Serialize, Deserialize, ToSchema, Validate, Clone, Debug)]
+pub struct WebhookRequest {
+    /// Url the events are posted to.
+    #[validate(url(message = "must be an absolute url"))]
+    #[schema(example = "https://example.com/hooks/todo")]
+    pub url: String,
+    /// Events to deliver.
+    #[validate(length(min = 1, message = "must list at least one event"))]
+    #[schema(min_items = 1)]
+    pub events: Vec<WebhookEvent>,
+    /// Key of the `X-Webhook-Signature` HMAC-SHA256 of the deliveries, never returned.
+    #[validate(length(min = 16, max = 256, message = "must be between 16 and 256 characters"))]
+    #[schema(min_length = 16, max_length = 256)]
+    pub secret: String,
+}
+
+/// Url subscribed to todo events.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Unix time of the subscription, in seconds.
+    pub created_at: i64,
+}
+
+/// Where a delivery stands.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for its first or next attempt.
+    Pending,
+    Delivered,
+    /// Given up on after too many failed attempts.
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        [DeliveryStatus::Pending, DeliveryStatus::Delivered, DeliveryStatus::Dead]
+            .into_iter()
+            .find(|known| known.as_str() == status)
+    }
+}
+
+/// Event sent, or to be sent, to a webhook.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct Delivery {
+    /// Sent as `X-Webhook-Delivery`, the same on every attempt.
+    pub id: String,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    /// Body posted to the webhook.
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    /// Unix time of the next attempt, in seconds, while pending.
+    pub next_attempt_at: i64,
+    /// Status code of the last attempt, if it got a response.
+    pub last_status: Option<i32>,
+    /// Why the last attempt failed.
+    pub last_error: Option<String>,
+    /// Unix time of the event, in seconds.
+    pub created_at: i64,
+}
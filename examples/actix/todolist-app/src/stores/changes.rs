@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use coi::{Container, Inject, Provide};
+use tokio::sync::broadcast;
+
+use crate::schemas::{ChangeKind, Todo, TodoChange, TodoUpdateRequest};
+use crate::store_interface::TodoRepository;
+
+// Subscribers lagging further behind miss the oldest changes
+const CHANGES_CAPACITY: usize = 256;
+
+/// Broadcast of the changes made through the repository, within this instance.
+#[derive(Clone)]
+pub struct TodoChanges(broadcast::Sender<TodoChange>);
+
+impl Default for TodoChanges {
+    fn default() -> Self {
+        Self(broadcast::channel(CHANGES_CAPACITY).0)
+    }
+}
+
+impl TodoChanges {
+    pub fn subscribe(&self) -> broadcast::Receiver<TodoChange> {
+        self.0.subscribe()
+    }
+
+    fn publish(&self, kind: ChangeKind, id: i64, todo: Option<Todo>) {
+        // Nobody listening is not an error
+        let _ = self.0.send(TodoChange { kind, id, todo });
+    }
+}
+
+/// Repository publishing the changes made through another one.
+#[derive(Inject)]
+pub struct NotifyingTodo {
+    inner: Arc<dyn TodoRepository>,
+    changes: TodoChanges,
+}
+
+/// Provides the repository of `inner`, publishing its changes to `changes`.
+pub struct NotifyingProvider<P> {
+    inner: P,
+    changes: TodoChanges,
+}
+
+impl<P> NotifyingProvider<P> {
+    pub fn new(inner: P, changes: TodoChanges) -> Self {
+        Self { inner, changes }
+    }
+}
+
+impl<P: Provide<Output = dyn TodoRepository>> Provide for NotifyingProvider<P> {
+    type Output = dyn TodoRepository;
+
+    fn provide(&self, container: &Container) -> coi::Result<Arc<Self::Output>> {
+        Ok(Arc::new(NotifyingTodo {
+            inner: self.inner.provide(container)?,
+            changes: self.changes.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl TodoRepository for NotifyingTodo {
+    async fn read_all(&self) -> Vec<Todo> {
+        self.inner.read_all().await
+    }
+
+    async fn read_one(&self, id: i64) -> Result<Todo, ()> {
+        self.inner.read_one(id).await
+    }
+
+    async fn create_one(&self, t: &Todo) -> Result<(), Todo> {
+        self.inner.create_one(t).await?;
+        self.changes.publish(ChangeKind::Created, t.id, Some(t.clone()));
+        Ok(())
+    }
+
+    async fn update_one(&self, id: i64, t: TodoUpdateRequest) -> Result<Todo, ()> {
+        // Only checking an unchecked todo completes it
+        let completes = t.checked == Some(true)
+            && self.inner.read_one(id).await.is_ok_and(|todo| !todo.checked);
+        let todo = self.inner.update_one(id, t).await?;
+        let kind = if completes {
+            ChangeKind::Completed
+        } else {
+            ChangeKind::Updated
+        };
+        self.changes.publish(kind, id, Some(todo.clone()));
+        Ok(todo)
+    }
+
+    async fn delete_one(&self, id: i64) -> Result<(), ()> {
+        self.inner.delete_one(id).await?;
+        self.changes.publish(ChangeKind::Deleted, id, None);
+        Ok(())
+    }
+
+    async fn read_filter(&self, search_text: &str) -> Vec<Todo> {
+        self.inner.read_filter(search_text).await
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use coi::{Container, Provide};
+use serde::{Deserialize, Serialize};
+
+use crate::schemas::Todo;
+use crate::store_interface::TodoRepository;
+use crate::stores::idempotency::{
+    IdempotencyStore, MemoryIdempotencyStore, PostgresIdempotencyStore,
+};
+use crate::stores::memory::TodoMemoryProvider;
+use crate::stores::postgres::TodoPostgresProvider;
+use crate::stores::webhooks::{MemoryWebhookStore, PostgresWebhookStore, WebhookStore};
+use crate::stores::{idempotency, webhooks};
+
+/// Connection to the postgres database.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PostgresConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub dbname: String,
+}
+
+/// Store of the todos, read from `store.yml` by the server and `todoctl` alike.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StoreConfig {
+    /// Todos kept in memory, lost on exit.
+    Memory {
+        #[serde(default)]
+        todos: Vec<Todo>,
+    },
+    Postgres(PostgresConfig),
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Postgres(PostgresConfig {
+            host: String::from("some_postgres"),
+            port: 5432,
+            user: String::from("postgres"),
+            password: String::from("replacethisplease"),
+            dbname: String::from("postgres"),
+        })
+    }
+}
+
+impl StoreConfig {
+    /// Read the store from a yml file, falling back to the defaults when there is none.
+    pub fn from_file(path: &str) -> Self {
+        Self::read(path).expect("Could not read store file.")
+    }
+
+    /// Like `from_file`, with an error rather than a panic when the file is malformed.
+    pub fn read(path: &str) -> Result<Self, String> {
+        match std::fs::File::open(path) {
+            Ok(file) => serde_yaml::from_reader(file).map_err(|err| format!("{path}: {err}")),
+            Err(_) => {
+                tracing::warn!(path, "no store file, using the defaults");
+                Ok(Self::default())
+            }
+        }
+    }
+}
+
+/// Provides the repository of the configured store.
+pub enum TodoStoreProvider {
+    Memory(TodoMemoryProvider),
+    Postgres(TodoPostgresProvider),
+}
+
+impl TodoStoreProvider {
+    pub async fn new(config: &StoreConfig) -> Self {
+        match config {
+            StoreConfig::Memory { todos } => TodoStoreProvider::Memory(TodoMemoryProvider {
+                todo_list: todos.clone(),
+            }),
+            StoreConfig::Postgres(postgres) => TodoStoreProvider::Postgres(
+                TodoPostgresProvider::new(
+                    &postgres.host,
+                    &postgres.user,
+                    &postgres.password,
+                    &postgres.dbname,
+                    &postgres.port.to_string(),
+                )
+                .await,
+            ),
+        }
+    }
+
+    /// Connection pool of the database, if the store has one.
+    pub fn pool(&self) -> Option<&deadpool_postgres::Pool> {
+        match self {
+            TodoStoreProvider::Memory(_) => None,
+            TodoStoreProvider::Postgres(provider) => Some(&provider.pool),
+        }
+    }
+
+    /// Webhook store kept along with the todos.
+    pub fn webhooks(&self) -> Arc<dyn WebhookStore> {
+        match self {
+            TodoStoreProvider::Memory(_) => Arc::new(MemoryWebhookStore::default()),
+            TodoStoreProvider::Postgres(provider) => Arc::new(PostgresWebhookStore {
+                pool: provider.pool.clone(),
+            }),
+        }
+    }
+
+    /// Idempotency keys kept along with the todos.
+    pub fn idempotency(&self) -> Arc<dyn IdempotencyStore> {
+        match self {
+            TodoStoreProvider::Memory(_) => Arc::new(MemoryIdempotencyStore::default()),
+            TodoStoreProvider::Postgres(provider) => Arc::new(PostgresIdempotencyStore {
+                pool: provider.pool.clone(),
+            }),
+        }
+    }
+
+    /// Create the tables the store needs, if missing.
+    pub async fn migrate(&self) -> Result<(), String> {
+        match self {
+            TodoStoreProvider::Memory(_) => Ok(()),
+            TodoStoreProvider::Postgres(provider) => {
+                // The todo migration panics when the database cannot be reached
+                let client = connect(&provider.pool).await?;
+                provider.migrate().await.map_err(|err| err.to_string())?;
+                for migration in webhooks::MIGRATIONS.iter().chain(&idempotency::MIGRATIONS) {
+                    client
+                        .execute(*migration, &[])
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the store can be reached, sending a trivial query to the database.
+    pub async fn check(&self) -> Result<(), String> {
+        match self {
+            TodoStoreProvider::Memory(_) => Ok(()),
+            TodoStoreProvider::Postgres(provider) => {
+                let client = connect(&provider.pool).await?;
+                client
+                    .simple_query("SELECT 1")
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+async fn connect(pool: &deadpool_postgres::Pool) -> Result<deadpool_postgres::Object, String> {
+    pool.get().await.map_err(|err| err.to_string())
+}
+
+impl Provide for TodoStoreProvider {
+    type Output = dyn TodoRepository;
+
+    fn provide(&self, container: &Container) -> coi::Result<Arc<Self::Output>> {
+        match self {
+            TodoStoreProvider::Memory(provider) => provider.provide(container),
+            TodoStoreProvider::Postgres(provider) => provider.provide(container),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tracing::{instrument, Instrument};
+
+use crate::stores::postgres::sql_span;
+
+/// First response given to a request made with an idempotency key, replayed to its retries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Outcome of claiming an idempotency key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// The key is free, the request is to be processed.
+    Acquired,
+    /// A request with the same key is being processed.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+    /// A request with the same key was processed, its response is to be replayed.
+    Completed(StoredResponse),
+}
+
+/// Idempotency keys along with the fingerprint of their request and its response.
+///
+/// Times are in seconds since the epoch; a key is forgotten past its `expires_at`.
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    /// Claim `key` for the request of `fingerprint` until `lock_until`, unless a request holds it
+    /// already. A claim whose request never completed is taken over once its lock expired.
+    async fn claim(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        lock_until: i64,
+        expires_at: i64,
+    ) -> Claim;
+    /// Record the response of the request holding `key`.
+    async fn complete(&self, key: &str, response: &StoredResponse);
+    /// Free `key` without recording a response, so that the request may be retried.
+    async fn release(&self, key: &str);
+    /// Delete the keys expired by `now`; expired keys are ignored until then.
+    async fn purge(&self, now: i64);
+}
+
+struct Record {
+    fingerprint: String,
+    response: Option<StoredResponse>,
+    locked_until: i64,
+    expires_at: i64,
+}
+
+// Past this number of keys, the expired ones are dropped
+const MAX_KEYS: usize = 100_000;
+
+/// Idempotency keys kept in the memory of the instance.
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    records: Mutex<HashMap<String, Record>>,
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn claim(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        lock_until: i64,
+        expires_at: i64,
+    ) -> Claim {
+        let mut records = self.records.lock().unwrap();
+        if records.len() >= MAX_KEYS {
+            records.retain(|_, record| record.expires_at > now);
+        }
+        if let Some(record) = records
+            .get_mut(key)
+            .filter(|record| record.expires_at > now)
+        {
+            if record.fingerprint != fingerprint {
+                return Claim::Mismatch;
+            }
+            if let Some(response) = &record.response {
+                return Claim::Completed(response.clone());
+            }
+            if record.locked_until > now {
+                return Claim::InProgress;
+            }
+            record.locked_until = lock_until;
+            return Claim::Acquired;
+        }
+        records.insert(
+            key.to_owned(),
+            Record {
+                fingerprint: fingerprint.to_owned(),
+                response: None,
+                locked_until: lock_until,
+                expires_at,
+            },
+        );
+        Claim::Acquired
+    }
+
+    async fn complete(&self, key: &str, response: &StoredResponse) {
+        if let Some(record) = self.records.lock().unwrap().get_mut(key) {
+            record.response = Some(response.clone());
+        }
+    }
+
+    async fn release(&self, key: &str) {
+        let mut records = self.records.lock().unwrap();
+        if records
+            .get(key)
+            .is_some_and(|record| record.response.is_none())
+        {
+            records.remove(key);
+        }
+    }
+
+    async fn purge(&self, now: i64) {
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, record| record.expires_at > now);
+    }
+}
+
+pub const MIGRATIONS: [&str; 2] = [
+    "CREATE TABLE IF NOT EXISTS idempotency_key (key TEXT PRIMARY KEY, fingerprint TEXT NOT NULL, status INT, headers TEXT, body BYTEA, locked_until BIGINT NOT NULL, expires_at BIGINT NOT NULL);",
+    "CREATE INDEX IF NOT EXISTS idempotency_key_expiry ON idempotency_key (expires_at);",
+];
+
+const PURGE: &str = "DELETE FROM idempotency_key WHERE expires_at <= $1;";
+// Inserts a new key, or takes over an expired key or the stale claim of the same request
+const CLAIM: &str = "INSERT INTO idempotency_key VALUES ($1, $2, NULL, NULL, NULL, $4, $5) ON CONFLICT (key) DO UPDATE SET fingerprint = EXCLUDED.fingerprint, status = NULL, headers = NULL, body = NULL, locked_until = EXCLUDED.locked_until, expires_at = CASE WHEN idempotency_key.expires_at <= $3 THEN EXCLUDED.expires_at ELSE idempotency_key.expires_at END WHERE idempotency_key.expires_at <= $3 OR (idempotency_key.status IS NULL AND idempotency_key.fingerprint = EXCLUDED.fingerprint AND idempotency_key.locked_until <= $3) RETURNING key;";
+const READ: &str = "SELECT fingerprint, status, headers, body FROM idempotency_key WHERE key = $1 AND expires_at > $2;";
+const COMPLETE: &str =
+    "UPDATE idempotency_key SET status = $2, headers = $3, body = $4 WHERE key = $1;";
+const RELEASE: &str = "DELETE FROM idempotency_key WHERE key = $1 AND status IS NULL;";
+
+pub struct PostgresIdempotencyStore {
+    pub pool: deadpool_postgres::Pool,
+}
+
+#[async_trait]
+impl IdempotencyStore for PostgresIdempotencyStore {
+    #[instrument(skip(self, fingerprint))]
+    async fn claim(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        lock_until: i64,
+        expires_at: i64,
+    ) -> Claim {
+        let client = self.pool.get().await.unwrap();
+        let claimed = client
+            .query_opt(CLAIM, &[&key, &fingerprint, &now, &lock_until, &expires_at])
+            .instrument(sql_span(CLAIM))
+            .await
+            .unwrap();
+        if claimed.is_some() {
+            return Claim::Acquired;
+        }
+        let row = client
+            .query_opt(READ, &[&key, &now])
+            .instrument(sql_span(READ))
+            .await
+            .unwrap();
+        // Released or expired in between, the client may retry
+        let Some(row) = row else {
+            return Claim::InProgress;
+        };
+        let stored: &str = row.get(0);
+        let status: Option<i32> = row.get(1);
+        match status {
+            _ if stored != fingerprint => Claim::Mismatch,
+            Some(status) => {
+                let headers: &str = row.get(2);
+                Claim::Completed(StoredResponse {
+                    status: status as u16,
+                    headers: serde_json::from_str(headers).unwrap_or_default(),
+                    body: row.get(3),
+                })
+            }
+            None => Claim::InProgress,
+        }
+    }
+
+    #[instrument(skip(self, response))]
+    async fn complete(&self, key: &str, response: &StoredResponse) {
+        let client = self.pool.get().await.unwrap();
+        let status = i32::from(response.status);
+        let headers = serde_json::to_string(&response.headers).unwrap();
+        client
+            .execute(COMPLETE, &[&key, &status, &headers, &response.body])
+            .instrument(sql_span(COMPLETE))
+            .await
+            .unwrap();
+    }
+
+    #[instrument(skip(self))]
+    async fn release(&self, key: &str) {
+        let client = self.pool.get().await.unwrap();
+        client
+            .execute(RELEASE, &[&key])
+            .instrument(sql_span(RELEASE))
+            .await
+            .unwrap();
+    }
+
+    #[instrument(skip(self))]
+    async fn purge(&self, now: i64) {
+        let client = match self.pool.get().await {
+            Ok(client) => client,
+            Err(err) => {
+                tracing::warn!(%err, "could not purge the idempotency keys");
+                return;
+            }
+        };
+        let purged = client
+            .execute(PURGE, &[&now])
+            .instrument(sql_span(PURGE))
+            .await;
+        if let Err(err) = purged {
+            tracing::warn!(%err, "could not purge the idempotency keys");
+        }
+    }
+}
@@ -79,10 +79,10 @@ impl TodoRepository for InMemoryTodo {
     }
 
     async fn read_filter(&self, search_text: &str) -> Vec<Todo>  {
-        self.todos.lock().unwrap().values().cloned().filter(|todo| {
+        self.todos.lock().unwrap().values().filter(|todo| {
             todo.value
                 .to_lowercase()
                 .contains(&search_text.to_lowercase())
-        }).collect()
+        }).cloned().collect()
     }
 }
@@ -5,6 +5,19 @@ use coi::{Provide, Inject};
 use crate::store_interface::TodoRepository;
 use crate::schemas::{Todo, TodoUpdateRequest};
 use async_trait::async_trait;
+use tracing::{instrument, Instrument};
+
+const READ_ALL: &str = "SELECT * FROM todo;";
+const READ_ONE: &str = "SELECT * FROM todo WHERE id = $1;";
+const CREATE_ONE: &str = "INSERT INTO todo VALUES ($1, $2, $3) ON CONFLICT DO NOTHING RETURNING id;";
+const UPDATE_ONE: &str = "UPDATE todo SET value=COALESCE($1, value), checked=COALESCE($2, checked) FROM todo WHERE id=$3 RETURNING *;";
+const DELETE_ONE: &str = "DELETE FROM todo WHERE id=$1;";
+const READ_FILTER: &str = "SELECT * FROM todo WHERE value LIKE $1;";
+
+// One span per statement sent to the database, named after OpenTelemetry conventions
+pub(crate) fn sql_span(statement: &'static str) -> tracing::Span {
+    tracing::info_span!("sql", otel.kind = "client", db.system = "postgresql", db.statement = statement)
+}
 
 #[derive(Inject)]
 pub struct PostgresTodo {
@@ -13,7 +26,7 @@ pub struct PostgresTodo {
 
 impl PostgresTodo {
     pub fn new(pool: deadpool_postgres::Pool) -> Self {
-        Self { pool: pool }
+        Self { pool }
     }
 }
 
@@ -29,18 +42,18 @@ impl TodoPostgresProvider
     pub async fn new(host: &str, user: &str, password: &str, dbname: &str, port: &str) -> Self {
         // Connect to the database.
         let mut cfg = Config::new();
-        if user.len() > 0{
+        if !user.is_empty(){
             cfg.user = Some(user.to_string());
         }
-        if password.len() > 0{
+        if !password.is_empty(){
             cfg.password = Some(password.to_string());
         }
         cfg.host = Some(host.to_string());
         cfg.port = Some(port.parse::<u16>().unwrap());
         cfg.dbname = Some(dbname.to_string());
         cfg.manager = Some(ManagerConfig { recycling_method: RecyclingMethod::Fast });
-        let pool = cfg.create_pool(NoTls).unwrap();
-        Self {pool: pool}
+        let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        Self {pool}
     }
     pub async fn migrate(&self) -> Result<u64, tokio_postgres::Error>{
         let client = self.pool.get().await.unwrap();
@@ -51,9 +64,10 @@ impl TodoPostgresProvider
 
 #[async_trait]
 impl TodoRepository for PostgresTodo {
+    #[instrument(skip(self))]
     async fn read_all(&self) -> Vec<Todo> {
         let client = self.pool.get().await.unwrap();
-        let rows = client.query("SELECT * FROM todo;", &[]).await.unwrap();
+        let rows = client.query(READ_ALL, &[]).instrument(sql_span(READ_ALL)).await.unwrap();
         let mut todos: Vec<Todo> = Vec::new();
         for row in rows{
             todos.push(Todo{id: i64::from(row.get::<_, i32>(0)), value: row.get(1), checked: row.get(2)});
@@ -61,49 +75,59 @@ impl TodoRepository for PostgresTodo {
         todos
     }
 
+    #[instrument(skip(self))]
     async fn read_one(&self, id: i64) -> Result<Todo, ()> {
+        // Ids past the INT column were never stored, rather than wrapped onto another row
+        let id = i32::try_from(id).map_err(|_| ())?;
         let client = self.pool.get().await.unwrap();
-        let result = client.query_one("SELECT * FROM todo WHERE id = $1;", &[&(id as i32)]).await;
+        let result = client.query_one(READ_ONE, &[&id]).instrument(sql_span(READ_ONE)).await;
         match result {
             Ok(row) => Ok(Todo{id: i64::from(row.get::<_, i32>(0)), value: row.get(1), checked: row.get(2)}),
             Err(_err) => Err(())
         }
     }
 
+    #[instrument(skip(self))]
     async fn create_one(&self, t: &Todo) -> Result<(), Todo> {
+        // Todos are validated to ids within the INT column
         let client = self.pool.get().await.unwrap();
-        let result = client.query_one("INSERT INTO todo VALUES ($1, $2, $3) ON CONFLICT DO NOTHING RETURNING id;", &[&(t.id as i32), &t.value, &t.checked]).await;
+        let result = client.query_one(CREATE_ONE, &[&(t.id as i32), &t.value, &t.checked]).instrument(sql_span(CREATE_ONE)).await;
         let row = result.unwrap();
         let ret_id = row.get::<_, Option<i32>>(&0);
         if ret_id.is_none(){
-            let todo = self.read_one(t.id.clone()).await.unwrap();
+            let todo = self.read_one(t.id).await.unwrap();
             return Err(todo)
         }
         Ok(())
     }
+    #[instrument(skip(self))]
     async fn update_one(&self, id: i64, todo_update: TodoUpdateRequest) -> Result<Todo, ()> {
+        let id = i32::try_from(id).map_err(|_| ())?;
         let client = self.pool.get().await.unwrap();
-        let result = client.query_one("UPDATE todo SET value=COALESCE($1, value), checked=COALESCE($2, checked) FROM todo WHERE id=$3 RETURNING *;",
-         &[&todo_update.value, &todo_update.checked, &id]).await;
+        let result = client.query_one(UPDATE_ONE,
+         &[&todo_update.value, &todo_update.checked, &id]).instrument(sql_span(UPDATE_ONE)).await;
         match result {
             Ok(row) => Ok(Todo{id: i64::from(row.get::<_, i32>(0)), value: row.get(1), checked: row.get(2)}),
             Err(_err) => Err(())
         }
     }
 
+    #[instrument(skip(self))]
     async fn delete_one(&self, id: i64) -> Result<(), ()> {
+        let id = i32::try_from(id).map_err(|_| ())?;
         let client = self.pool.get().await.unwrap();
-        let result = client.execute("DELETE FROM todo WHERE id=$1;",&[&(id as i32)]).await;
+        let result = client.execute(DELETE_ONE,&[&id]).instrument(sql_span(DELETE_ONE)).await;
         match result {
             Ok(_status) => Ok(()),
             Err(_err) => Err(())
         }
     }
 
+    #[instrument(skip(self))]
     async fn read_filter(&self, search_text: &str) -> Vec<Todo>  {
         let like_search_text = format!("%{}%", search_text);
         let client = self.pool.get().await.unwrap();
-        let rows = client.query("SELECT * FROM todo WHERE value LIKE $1;", &[&like_search_text]).await.unwrap();
+        let rows = client.query(READ_FILTER, &[&like_search_text]).instrument(sql_span(READ_FILTER)).await.unwrap();
         let mut todos: Vec<Todo> = Vec::new();
         for row in rows{
             todos.push(Todo{id: i64::from(row.get::<_, i32>(0)), value: row.get(1), checked: row.get(2)});
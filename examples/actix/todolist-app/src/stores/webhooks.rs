@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tracing::{instrument, Instrument};
+
+use crate::schemas::{Delivery, DeliveryStatus, Webhook, WebhookEvent};
+use crate::stores::postgres::sql_span;
+
+/// Webhook along with the secret its deliveries are signed with.
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+/// Webhook subscriptions and their deliveries, which are both the outbound queue and its log.
+#[async_trait]
+pub trait WebhookStore: Send + Sync {
+    async fn create_webhook(&self, subscription: &Subscription);
+    async fn read_webhooks(&self) -> Vec<Subscription>;
+    async fn read_webhook(&self, id: &str) -> Result<Subscription, ()>;
+    /// Delete a webhook along with its deliveries.
+    async fn delete_webhook(&self, id: &str) -> Result<(), ()>;
+    async fn enqueue(&self, delivery: &Delivery);
+    /// Take up to `limit` pending deliveries due at `now`, pushing their next attempt to `lease_until`
+    /// so that other workers leave them alone meanwhile.
+    async fn claim_due(&self, now: i64, lease_until: i64, limit: i64) -> Vec<Delivery>;
+    async fn update_delivery(&self, delivery: &Delivery);
+    /// Deliveries of a webhook, the latest first.
+    async fn read_deliveries(&self, webhook_id: &str) -> Vec<Delivery>;
+}
+
+#[derive(Default)]
+pub struct MemoryWebhookStore {
+    webhooks: Mutex<HashMap<String, Subscription>>,
+    deliveries: Mutex<Vec<Delivery>>,
+}
+
+#[async_trait]
+impl WebhookStore for MemoryWebhookStore {
+    async fn create_webhook(&self, subscription: &Subscription) {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .insert(subscription.webhook.id.clone(), subscription.clone());
+    }
+
+    async fn read_webhooks(&self) -> Vec<Subscription> {
+        let mut webhooks: Vec<Subscription> =
+            self.webhooks.lock().unwrap().values().cloned().collect();
+        webhooks.sort_by_key(|subscription| subscription.webhook.created_at);
+        webhooks
+    }
+
+    async fn read_webhook(&self, id: &str) -> Result<Subscription, ()> {
+        self.webhooks.lock().unwrap().get(id).cloned().ok_or(())
+    }
+
+    async fn delete_webhook(&self, id: &str) -> Result<(), ()> {
+        self.webhooks.lock().unwrap().remove(id).ok_or(())?;
+        self.deliveries
+            .lock()
+            .unwrap()
+            .retain(|delivery| delivery.webhook_id != id);
+        Ok(())
+    }
+
+    async fn enqueue(&self, delivery: &Delivery) {
+        self.deliveries.lock().unwrap().push(delivery.clone());
+    }
+
+    async fn claim_due(&self, now: i64, lease_until: i64, limit: i64) -> Vec<Delivery> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let mut due: Vec<&mut Delivery> = deliveries
+            .iter_mut()
+            .filter(|delivery| {
+                delivery.status == DeliveryStatus::Pending && delivery.next_attempt_at <= now
+            })
+            .collect();
+        due.sort_by_key(|delivery| delivery.next_attempt_at);
+        due.into_iter()
+            .take(limit as usize)
+            .map(|delivery| {
+                delivery.next_attempt_at = lease_until;
+                delivery.clone()
+            })
+            .collect()
+    }
+
+    async fn update_delivery(&self, delivery: &Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if let Some(stored) = deliveries
+            .iter_mut()
+            .find(|stored| stored.id == delivery.id)
+        {
+            *stored = delivery.clone();
+        }
+    }
+
+    async fn read_deliveries(&self, webhook_id: &str) -> Vec<Delivery> {
+        let mut deliveries: Vec<Delivery> = self
+            .deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|delivery| delivery.webhook_id == webhook_id)
+            .cloned()
+            .collect();
+        deliveries.reverse();
+        deliveries
+    }
+}
+
+pub const MIGRATIONS: [&str; 3] = [
+    "CREATE TABLE IF NOT EXISTS webhook (id TEXT PRIMARY KEY, url TEXT NOT NULL, events TEXT[] NOT NULL, secret TEXT NOT NULL, created_at BIGINT NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS webhook_delivery (id TEXT PRIMARY KEY, webhook_id TEXT NOT NULL REFERENCES webhook(id) ON DELETE CASCADE, event TEXT NOT NULL, payload TEXT NOT NULL, status TEXT NOT NULL, attempts INT NOT NULL, next_attempt_at BIGINT NOT NULL, last_status INT, last_error TEXT, created_at BIGINT NOT NULL);",
+    "CREATE INDEX IF NOT EXISTS webhook_delivery_due ON webhook_delivery (status, next_attempt_at);",
+];
+
+const CREATE_WEBHOOK: &str = "INSERT INTO webhook VALUES ($1, $2, $3, $4, $5);";
+const READ_WEBHOOKS: &str = "SELECT * FROM webhook ORDER BY created_at;";
+const READ_WEBHOOK: &str = "SELECT * FROM webhook WHERE id = $1;";
+const DELETE_WEBHOOK: &str = "DELETE FROM webhook WHERE id = $1;";
+const ENQUEUE: &str =
+    "INSERT INTO webhook_delivery VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10);";
+// Locked rows are being claimed by another worker
+const CLAIM_DUE: &str = "UPDATE webhook_delivery SET next_attempt_at = $2 WHERE id IN (SELECT id FROM webhook_delivery WHERE status = 'pending' AND next_attempt_at <= $1 ORDER BY next_attempt_at LIMIT $3 FOR UPDATE SKIP LOCKED) RETURNING *;";
+const UPDATE_DELIVERY: &str = "UPDATE webhook_delivery SET status = $2, attempts = $3, next_attempt_at = $4, last_status = $5, last_error = $6 WHERE id = $1;";
+const READ_DELIVERIES: &str =
+    "SELECT * FROM webhook_delivery WHERE webhook_id = $1 ORDER BY created_at DESC, id;";
+
+fn subscription(row: &deadpool_postgres::tokio_postgres::Row) -> Subscription {
+    let events: Vec<String> = row.get(2);
+    Subscription {
+        webhook: Webhook {
+            id: row.get(0),
+            url: row.get(1),
+            events: events
+                .iter()
+                .filter_map(|event| WebhookEvent::parse(event))
+                .collect(),
+            created_at: row.get(4),
+        },
+        secret: row.get(3),
+    }
+}
+
+fn delivery(row: &deadpool_postgres::tokio_postgres::Row) -> Delivery {
+    Delivery {
+        id: row.get(0),
+        webhook_id: row.get(1),
+        event: WebhookEvent::parse(row.get(2)).unwrap_or(WebhookEvent::Created),
+        payload: serde_json::from_str(row.get(3)).unwrap_or_default(),
+        status: DeliveryStatus::parse(row.get(4)).unwrap_or(DeliveryStatus::Dead),
+        attempts: row.get(5),
+        next_attempt_at: row.get(6),
+        last_status: row.get(7),
+        last_error: row.get(8),
+        created_at: row.get(9),
+    }
+}
+
+pub struct PostgresWebhookStore {
+    pub pool: deadpool_postgres::Pool,
+}
+
+#[async_trait]
+impl WebhookStore for PostgresWebhookStore {
+    #[instrument(skip_all)]
+    async fn create_webhook(&self, subscription: &Subscription) {
+        let client = self.pool.get().await.unwrap();
+        let webhook = &subscription.webhook;
+        let events: Vec<&str> = webhook.events.iter().map(|event| event.as_str()).collect();
+        client
+            .execute(
+                CREATE_WEBHOOK,
+                &[
+                    &webhook.id,
+                    &webhook.url,
+                    &events,
+                    &subscription.secret,
+                    &webhook.created_at,
+                ],
+            )
+            .instrument(sql_span(CREATE_WEBHOOK))
+            .await
+            .unwrap();
+    }
+
+    #[instrument(skip(self))]
+    async fn read_webhooks(&self) -> Vec<Subscription> {
+        let client = self.pool.get().await.unwrap();
+        let rows = client
+            .query(READ_WEBHOOKS, &[])
+            .instrument(sql_span(READ_WEBHOOKS))
+            .await
+            .unwrap();
+        rows.iter().map(subscription).collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn read_webhook(&self, id: &str) -> Result<Subscription, ()> {
+        let client = self.pool.get().await.unwrap();
+        let row = client
+            .query_opt(READ_WEBHOOK, &[&id])
+            .instrument(sql_span(READ_WEBHOOK))
+            .await
+            .unwrap();
+        row.as_ref().map(subscription).ok_or(())
+    }
+
+    #[instrument(skip(self))]
+    async fn delete_webhook(&self, id: &str) -> Result<(), ()> {
+        let client = self.pool.get().await.unwrap();
+        let deleted = client
+            .execute(DELETE_WEBHOOK, &[&id])
+            .instrument(sql_span(DELETE_WEBHOOK))
+            .await
+            .unwrap();
+        if deleted == 0 {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn enqueue(&self, delivery: &Delivery) {
+        let client = self.pool.get().await.unwrap();
+        client
+            .execute(
+                ENQUEUE,
+                &[
+                    &delivery.id,
+                    &delivery.webhook_id,
+                    &delivery.event.as_str(),
+                    &delivery.payload.to_string(),
+                    &delivery.status.as_str(),
+                    &delivery.attempts,
+                    &delivery.next_attempt_at,
+                    &delivery.last_status,
+                    &delivery.last_error,
+                    &delivery.created_at,
+                ],
+            )
+            .instrument(sql_span(ENQUEUE))
+            .await
+            .unwrap();
+    }
+
+    #[instrument(skip(self))]
+    async fn claim_due(&self, now: i64, lease_until: i64, limit: i64) -> Vec<Delivery> {
+        let client = self.pool.get().await.unwrap();
+        let rows = client
+            .query(CLAIM_DUE, &[&now, &lease_until, &limit])
+            .instrument(sql_span(CLAIM_DUE))
+            .await
+            .unwrap();
+        rows.iter().map(delivery).collect()
+    }
+
+    #[instrument(skip_all)]
+    async fn update_delivery(&self, delivery: &Delivery) {
+        let client = self.pool.get().await.unwrap();
+        client
+            .execute(
+                UPDATE_DELIVERY,
+                &[
+                    &delivery.id,
+                    &delivery.status.as_str(),
+                    &delivery.attempts,
+                    &delivery.next_attempt_at,
+                    &delivery.last_status,
+                    &delivery.last_error,
+                ],
+            )
+            .instrument(sql_span(UPDATE_DELIVERY))
+            .await
+            .unwrap();
+    }
+
+    #[instrument(skip(self))]
+    async fn read_deliveries(&self, webhook_id: &str) -> Vec<Delivery> {
+        let client = self.pool.get().await.unwrap();
+        let rows = client
+            .query(READ_DELIVERIES, &[&webhook_id])
+            .instrument(sql_span(READ_DELIVERIES))
+            .await
+            .unwrap();
+        rows.iter().map(delivery).collect()
+    }
+}
@@ -0,0 +1,115 @@
+// Unit testing for the compression and caching headers
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use coi::container;
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use crate::caching::{Caching, CachingConfig, CompressAbove};
+    use crate::rest::configure;
+    use crate::schemas::Todo;
+    use crate::stores::memory::TodoMemoryProvider;
+
+    fn todos(count: i64) -> Vec<Todo> {
+        (0..count).map(|id| Todo{id, value: format!("todo number {id}"), checked: false}).collect()
+    }
+
+    macro_rules! app {
+        ($todos:expr) => {{
+            let memory_provider = TodoMemoryProvider{todo_list: $todos};
+            let container = container!{
+                repository => memory_provider; singleton
+            };
+            let config = Arc::new(CachingConfig::default());
+            test::init_service(
+                App::new()
+                    .wrap(Caching::new(config.clone()))
+                    .wrap(CompressAbove(config.min_compress_size))
+                    .app_data(container)
+                    .configure(configure())
+            ).await
+        }};
+    }
+
+    #[actix_web::test]
+    async fn test_compression() {
+        let app = app!(todos(100));
+
+        let req = test::TestRequest::get().uri("/todo").insert_header(("Accept-Encoding", "gzip"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.headers().get("Content-Encoding").unwrap(), "gzip");
+        let body = test::read_body(resp).await;
+        let mut json = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut json).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Todo>>(&json).unwrap().len(), 100);
+
+        for encoding in ["br", "zstd"] {
+            let req = test::TestRequest::get().uri("/todo").insert_header(("Accept-Encoding", encoding));
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.headers().get("Content-Encoding").unwrap(), encoding);
+        }
+
+        // Below the threshold
+        let req = test::TestRequest::get().uri("/todo/1").insert_header(("Accept-Encoding", "gzip"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert!(resp.headers().get("Content-Encoding").is_none());
+        assert_eq!(test::read_body_json::<Todo, _>(resp).await.id, 1);
+
+        // No acceptable encoding
+        let req = test::TestRequest::get().uri("/todo").insert_header(("Accept-Encoding", "identity;q=0, compress"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 406);
+        assert!(resp.headers().get("Vary").unwrap().to_str().unwrap().contains("Accept-Encoding"));
+    }
+
+    #[actix_web::test]
+    async fn test_request_decompression() {
+        let app = app!(todos(1));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"id": 42, "value": "compressed", "checked": false}"#).unwrap();
+        let req = test::TestRequest::post().uri("/todo")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Content-Encoding", "gzip"))
+            .set_payload(encoder.finish().unwrap());
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 201);
+
+        let req = test::TestRequest::get().uri("/todo/42");
+        let resp = test::call_and_read_body_json::<_, _, Todo>(&app, req.to_request()).await;
+        assert_eq!(resp.value, "compressed");
+    }
+
+    #[actix_web::test]
+    async fn test_caching_headers() {
+        let app = app!(todos(2));
+
+        let req = test::TestRequest::get().uri("/todo/1");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.headers().get("Cache-Control").unwrap(), "no-cache");
+        let vary = resp.headers().get("Vary").unwrap().to_str().unwrap();
+        assert_eq!(vary, "Accept, Accept-Encoding");
+        let etag = resp.headers().get("ETag").unwrap().clone();
+
+        // Revalidation
+        let req = test::TestRequest::get().uri("/todo/1").insert_header(("If-None-Match", etag.clone()));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 304);
+        assert_eq!(resp.headers().get("ETag").unwrap(), &etag);
+        assert!(test::read_body(resp).await.is_empty());
+
+        // The representation changes with the encoding
+        let req = test::TestRequest::get().uri("/todo/1")
+            .insert_header(("If-None-Match", etag))
+            .insert_header(("Accept", "application/cbor"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+
+        let req = test::TestRequest::get().uri("/health");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.headers().get("Cache-Control").unwrap(), "no-store");
+        assert!(resp.headers().get("ETag").is_none());
+    }
+}
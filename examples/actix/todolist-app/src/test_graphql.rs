@@ -0,0 +1,131 @@
+// Unit testing for the GraphQL endpoint
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{test, App, web::Data};
+    use coi::{container, Container};
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use crate::graphql::{self, TodoSchema};
+    use crate::rest::configure;
+    use crate::schemas::Todo;
+    use crate::stores::changes::{NotifyingProvider, TodoChanges};
+    use crate::stores::memory::TodoMemoryProvider;
+
+    fn test_data() -> Vec<Todo> {
+        [Todo{id:1, value:String::from("some value"), checked:true},
+         Todo{id:2, value:String::from("something completely different"), checked:false},
+         Todo{id:3, value:String::from("another one"), checked:false}
+        ].to_vec()
+    }
+
+    fn prepare(data: Vec<Todo>) -> (Container, TodoSchema) {
+        let changes = TodoChanges::default();
+        let memory_provider = TodoMemoryProvider{todo_list: data};
+        let container = container!{
+            repository => NotifyingProvider::new(memory_provider, changes.clone()); singleton
+        };
+        (container, graphql::schema(changes))
+    }
+
+    macro_rules! app {
+        ($container:expr, $schema:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($container)
+                    .app_data(Data::new($schema))
+                    .configure(configure())
+                    .configure(graphql::configure())
+            ).await
+        };
+    }
+
+    async fn execute<S>(app: &S, query: &str) -> Value
+    where
+        S: actix_web::dev::Service<actix_http::Request, Response = actix_web::dev::ServiceResponse, Error = actix_web::Error>,
+    {
+        let req = test::TestRequest::post().uri("/graphql").set_json(json!({"query": query}));
+        test::call_and_read_body_json(app, req.to_request()).await
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_query() {
+        let (container, schema) = prepare(test_data());
+        let app = app!(container, schema);
+
+        let resp = execute(&app, r#"{ todos { id } }"#).await;
+        assert_eq!(resp, json!({"data": {"todos": [{"id": 1}, {"id": 2}, {"id": 3}]}}));
+
+        let resp = execute(&app, r#"{ todos(filter: {or: [{checked: true}, {valueContains: "DIFFERENT"}], not: {id: 1}}) { id value } }"#).await;
+        assert_eq!(resp, json!({"data": {"todos": [{"id": 2, "value": "something completely different"}]}}));
+
+        let resp = execute(&app, r#"{ todo(id: 3) { checked } missing: todo(id: 42) { id } search(text: "some") { id } }"#).await;
+        assert_eq!(resp["data"]["todo"], json!({"checked": false}));
+        assert_eq!(resp["data"]["missing"], Value::Null);
+        assert_eq!(resp["data"]["search"].as_array().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_mutations() {
+        let (container, schema) = prepare(test_data());
+        let app = app!(container, schema);
+
+        let resp = execute(&app, r#"mutation { createTodo(todo: {id: 42, value: "new", checked: false}) { id value } }"#).await;
+        assert_eq!(resp, json!({"data": {"createTodo": {"id": 42, "value": "new"}}}));
+
+        let resp = execute(&app, r#"mutation { createTodo(todo: {id: 42, value: "again", checked: false}) { id } }"#).await;
+        assert_eq!(resp["errors"][0]["extensions"]["code"], "CONFLICT");
+
+        let resp = execute(&app, r#"mutation { createTodo(todo: {id: -1, value: "", checked: false}) { id } }"#).await;
+        assert_eq!(resp["errors"][0]["extensions"]["code"], "VALIDATION");
+        assert_eq!(resp["errors"][0]["extensions"]["errors"][0]["field"], "id");
+
+        let resp = execute(&app, r#"mutation { updateTodo(id: 42, update: {checked: true}) { value checked } }"#).await;
+        assert_eq!(resp, json!({"data": {"updateTodo": {"value": "new", "checked": true}}}));
+
+        let resp = execute(&app, r#"mutation { deleteTodo(id: 42) }"#).await;
+        assert_eq!(resp, json!({"data": {"deleteTodo": 42}}));
+        let resp = execute(&app, r#"mutation { deleteTodo(id: 42) }"#).await;
+        assert_eq!(resp["errors"][0]["extensions"]["code"], "NOT_FOUND");
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_subscription() {
+        let (container, schema) = prepare(test_data());
+        let stream = schema.execute_stream("subscription { todoChanges(id: 42) { kind id todo { value } } }");
+        let received = actix_web::rt::spawn(stream.take(2).collect::<Vec<_>>());
+        // Let the subscription start before making changes
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        let app = app!(container, schema);
+
+        // Changes made over REST are published too
+        let req = test::TestRequest::post().uri("/todo").set_json(Todo{id: 3, value: "other".to_owned(), checked: false});
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 409);
+        let req = test::TestRequest::post().uri("/todo").set_json(Todo{id: 42, value: "new".to_owned(), checked: false});
+        assert!(test::call_service(&app, req.to_request()).await.status().is_success());
+        execute(&app, r#"mutation { deleteTodo(id: 1) }"#).await;
+        execute(&app, r#"mutation { deleteTodo(id: 42) }"#).await;
+
+        let received: Vec<Value> = received.await.unwrap().into_iter()
+            .map(|resp| resp.into_result().unwrap().data.into_json().unwrap())
+            .collect();
+        assert_eq!(received, vec![
+            json!({"todoChanges": {"kind": "CREATED", "id": 42, "todo": {"value": "new"}}}),
+            json!({"todoChanges": {"kind": "DELETED", "id": 42, "todo": null}}),
+        ]);
+    }
+
+    #[actix_web::test]
+    async fn test_graphql_sdl() {
+        let (container, schema) = prepare(test_data());
+        let app = app!(container, schema);
+        let req = test::TestRequest::get().uri("/api-docs/schema.graphql");
+        let body = test::call_and_read_body(&app, req.to_request()).await;
+        let sdl = std::str::from_utf8(&body).unwrap();
+        assert!(sdl.contains("type Todo {"));
+        assert!(sdl.contains("input TodoFilter {"));
+        assert!(sdl.contains("todoChanges(id: Int): TodoChange!"));
+    }
+}
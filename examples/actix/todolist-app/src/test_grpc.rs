@@ -0,0 +1,77 @@
+// Unit testing for the gRPC service
+
+#[cfg(test)]
+mod tests {
+    use coi::container;
+    use futures::TryStreamExt;
+    use tokio::net::TcpListener;
+    use tonic::{transport::Channel, Code, Request};
+    use tonic_health::pb::{health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest};
+    use crate::grpc::{self, proto, TodoGrpc};
+    use crate::grpc::proto::todo_service_client::TodoServiceClient;
+    use crate::grpc::proto::todo_service_server::TodoService;
+    use crate::schemas::Todo;
+    use crate::stores::memory::{TodoMemoryProvider, TodoRepository};
+
+    fn repository() -> std::sync::Arc<dyn TodoRepository> {
+        let memory_provider = TodoMemoryProvider{todo_list: vec![
+            Todo{id:1, value:String::from("some value"), checked:true},
+            Todo{id:2, value:String::from("something completely different"), checked:false},
+        ]};
+        let container = container!{
+            repository => memory_provider; singleton
+        };
+        container.resolve::<dyn TodoRepository>("repository").unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_service() {
+        let service = TodoGrpc::new(repository());
+
+        let todo = proto::Todo{id: 42, value: "new".to_owned(), checked: false};
+        let created = service.create_todo(Request::new(proto::CreateTodoRequest{todo: Some(todo.clone())})).await.unwrap();
+        assert_eq!(created.into_inner(), todo);
+        let conflict = service.create_todo(Request::new(proto::CreateTodoRequest{todo: Some(todo.clone())})).await.unwrap_err();
+        assert_eq!(conflict.code(), Code::AlreadyExists);
+        let invalid = proto::Todo{id: -1, value: String::new(), checked: false};
+        let invalid = service.create_todo(Request::new(proto::CreateTodoRequest{todo: Some(invalid)})).await.unwrap_err();
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+        assert!(invalid.message().starts_with("id: must be between 0 and 2147483647"));
+
+        let update = proto::TodoUpdate{value: None, checked: Some(true)};
+        let updated = service.update_todo(Request::new(proto::UpdateTodoRequest{id: 42, update: Some(update)})).await.unwrap();
+        assert!(updated.into_inner().checked);
+
+        let listed: Vec<proto::Todo> = service.list_todos(Request::new(proto::ListTodosRequest{})).await.unwrap().into_inner().try_collect().await.unwrap();
+        assert_eq!(listed.len(), 3);
+        let found: Vec<proto::Todo> = service.search_todos(Request::new(proto::SearchTodosRequest{value: "different".to_owned()})).await.unwrap().into_inner().try_collect().await.unwrap();
+        assert_eq!(found.iter().map(|todo| todo.id).collect::<Vec<_>>(), vec![2]);
+
+        service.delete_todo(Request::new(proto::DeleteTodoRequest{id: 42})).await.unwrap();
+        let missing = service.get_todo(Request::new(proto::GetTodoRequest{id: 42})).await.unwrap_err();
+        assert_eq!(missing.code(), Code::NotFound);
+        assert_eq!(missing.message(), "Todo not found, id = 42");
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = actix_web::rt::spawn(grpc::serve(listener, repository(), async { let _ = stopped.await; }));
+
+        let channel = Channel::from_shared(url.clone()).unwrap().connect().await.unwrap();
+        let mut health = HealthClient::new(channel);
+        let status = health.check(HealthCheckRequest{service: "todolist.v1.TodoService".to_owned()}).await.unwrap();
+        assert_eq!(status.into_inner().status(), ServingStatus::Serving);
+
+        let mut client = TodoServiceClient::connect(url).await.unwrap();
+        let todo = client.get_todo(proto::GetTodoRequest{id: 1}).await.unwrap().into_inner();
+        assert_eq!(todo.value, "some value");
+        let listed: Vec<proto::Todo> = client.list_todos(proto::ListTodosRequest{}).await.unwrap().into_inner().try_collect().await.unwrap();
+        assert_eq!(listed.len(), 2);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+// Testing of the idempotency keys, against the memory stores
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_web::{test, web, App, HttpResponse};
+    use coi::container;
+    use serde_json::{json, Value};
+    use tokio::sync::Notify;
+
+    use crate::idempotency::{Idempotency, IdempotencyConfig, IDEMPOTENT_REPLAYED};
+    use crate::rest::configure;
+    use crate::stores::idempotency::{Claim, IdempotencyStore, MemoryIdempotencyStore, StoredResponse};
+    use crate::stores::memory::TodoMemoryProvider;
+
+    fn idempotency(store: Arc<dyn IdempotencyStore>) -> Idempotency {
+        Idempotency::new(store, IdempotencyConfig::default())
+    }
+
+    fn post(key: Option<&str>, body: Value) -> test::TestRequest {
+        let req = test::TestRequest::post().uri("/todo").set_json(body);
+        match key {
+            Some(key) => req.insert_header(("Idempotency-Key", key)),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_replay() {
+        let container = container! { repository => TodoMemoryProvider { todo_list: Vec::new() }; singleton };
+        let app = test::init_service(
+            App::new()
+                .wrap(idempotency(Arc::new(MemoryIdempotencyStore::default())))
+                .app_data(container)
+                .configure(configure()),
+        ).await;
+        let todo = json!({"id": 1, "value": "some value", "checked": false});
+
+        let first = test::call_service(&app, post(Some("a"), todo.clone()).to_request()).await;
+        assert_eq!(first.status(), 201);
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+        let content_type = first.headers().get("content-type").cloned();
+        let first = test::read_body(first).await;
+
+        // Without the replay, the todo would conflict with the one created
+        let retry = test::call_service(&app, post(Some("a"), todo.clone()).to_request()).await;
+        assert_eq!(retry.status(), 201);
+        assert_eq!(retry.headers().get(IDEMPOTENT_REPLAYED).unwrap(), "true");
+        assert_eq!(retry.headers().get("content-type").cloned(), content_type);
+        assert_eq!(test::read_body(retry).await, first);
+
+        let resp = test::call_service(&app, post(None, todo.clone()).to_request()).await;
+        assert_eq!(resp.status(), 409);
+        let resp = test::call_service(&app, post(Some("b"), todo).to_request()).await;
+        assert_eq!(resp.status(), 409);
+        assert!(resp.headers().get(IDEMPOTENT_REPLAYED).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_key_reused() {
+        let container = container! { repository => TodoMemoryProvider { todo_list: Vec::new() }; singleton };
+        let app = test::init_service(
+            App::new()
+                .wrap(idempotency(Arc::new(MemoryIdempotencyStore::default())))
+                .app_data(container)
+                .configure(configure()),
+        ).await;
+
+        let req = post(Some("a"), json!({"id": 1, "value": "some value", "checked": false}));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 201);
+        let req = post(Some("a"), json!({"id": 2, "value": "other value", "checked": false}))
+            .insert_header(("Accept", "application/json"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 422);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body, json!({"IdempotencyKeyReused": "the Idempotency-Key was used for another request"}));
+
+        // Keys belong to their client
+        let req = post(Some("a"), json!({"id": 2, "value": "other value", "checked": false}))
+            .peer_addr("10.0.0.2:1234".parse().unwrap());
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 201);
+    }
+
+    #[actix_web::test]
+    async fn test_in_progress() {
+        let gate = Arc::new(Notify::new());
+        let held = gate.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(idempotency(Arc::new(MemoryIdempotencyStore::default())))
+                .route("/todo", web::post().to(move || {
+                    let held = held.clone();
+                    async move {
+                        held.notified().await;
+                        HttpResponse::Created().finish()
+                    }
+                })),
+        ).await;
+
+        // The first request holds the key until the gate opens
+        let first = test::call_service(&app, post(Some("a"), json!({})).to_request());
+        let duplicate = async {
+            let req = post(Some("a"), json!({})).insert_header(("Accept", "application/json"));
+            let resp = test::call_service(&app, req.to_request()).await;
+            gate.notify_one();
+            resp
+        };
+        let (first, duplicate) = futures::join!(first, duplicate);
+        assert_eq!(first.status(), 201);
+        assert_eq!(duplicate.status(), 409);
+        let body: Value = test::read_body_json(duplicate).await;
+        assert_eq!(body, json!({"RequestInProgress": "a request with the same Idempotency-Key is being processed"}));
+    }
+
+    #[actix_web::test]
+    async fn test_memory_claims() {
+        let store = MemoryIdempotencyStore::default();
+        assert_eq!(store.claim("k", "f", 10, 20, 100).await, Claim::Acquired);
+        assert_eq!(store.claim("k", "f", 15, 25, 100).await, Claim::InProgress);
+        assert_eq!(store.claim("k", "g", 15, 25, 100).await, Claim::Mismatch);
+        // The lock expired, its request is assumed dead
+        assert_eq!(store.claim("k", "f", 20, 30, 100).await, Claim::Acquired);
+        let response = StoredResponse { status: 201, headers: Vec::new(), body: b"{}".to_vec() };
+        store.complete("k", &response).await;
+        assert_eq!(store.claim("k", "f", 50, 60, 100).await, Claim::Completed(response));
+        // Stored responses are not released
+        store.release("k").await;
+        assert_eq!(store.claim("k", "g", 50, 60, 100).await, Claim::Mismatch);
+        // Expired, the key is free again
+        assert_eq!(store.claim("k", "g", 100, 110, 200).await, Claim::Acquired);
+    }
+
+    #[actix_web::test]
+    async fn test_memory_purge() {
+        let store = MemoryIdempotencyStore::default();
+        let response = StoredResponse { status: 201, headers: Vec::new(), body: b"{}".to_vec() };
+        assert_eq!(store.claim("old", "f", 10, 20, 100).await, Claim::Acquired);
+        store.complete("old", &response).await;
+        assert_eq!(store.claim("new", "f", 90, 100, 200).await, Claim::Acquired);
+        store.complete("new", &response).await;
+        store.purge(100).await;
+        // Only the expired key is forgotten
+        assert_eq!(store.claim("old", "g", 50, 60, 200).await, Claim::Acquired);
+        assert_eq!(store.claim("new", "f", 50, 60, 200).await, Claim::Completed(response));
+    }
+
+    #[actix_web::test]
+    async fn test_server_error_released() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(idempotency(Arc::new(MemoryIdempotencyStore::default())))
+                .route("/todo", web::post().to(move || {
+                    let calls = counted.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        if calls == 0 {
+                            HttpResponse::ServiceUnavailable().finish()
+                        } else {
+                            HttpResponse::Created().finish()
+                        }
+                    }
+                })),
+        ).await;
+
+        for status in [503, 201, 201] {
+            let resp = test::call_service(&app, post(Some("a"), json!({})).to_request()).await;
+            assert_eq!(resp.status(), status);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_key() {
+        let app = test::init_service(
+            App::new()
+                .wrap(idempotency(Arc::new(MemoryIdempotencyStore::default())))
+                .route("/todo", web::post().to(HttpResponse::Created)),
+        ).await;
+
+        for key in ["", &"a".repeat(256)] {
+            let req = post(Some(key), json!({})).insert_header(("Accept", "application/json"));
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), 400);
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(body["Validation"][0]["field"], "Idempotency-Key");
+        }
+        // Only the configured methods are concerned
+        let req = test::TestRequest::get().uri("/todo").insert_header(("Idempotency-Key", ""));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 404);
+    }
+}
@@ -0,0 +1,446 @@
+// Unit testing for the OpenAPI document
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use actix_web::{
+        http::{
+            header::{ACCEPT, CONTENT_TYPE},
+            Method,
+        },
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+    use async_trait::async_trait;
+    use coi::container;
+    use serde_json::{json, Map, Value};
+    use utoipa::OpenApi;
+
+    use crate::idempotency::{Idempotency, IdempotencyConfig, IDEMPOTENCY_KEY};
+    use crate::openapi::ApiDoc;
+    use crate::rest::configure;
+    use crate::schemas::{Delivery, DeliveryStatus, Todo, Webhook, WebhookEvent};
+    use crate::stores::idempotency::{Claim, IdempotencyStore, StoredResponse};
+    use crate::stores::memory::TodoMemoryProvider;
+    use crate::stores::webhooks::{MemoryWebhookStore, Subscription, WebhookStore};
+    use crate::validation::PAYLOAD_LIMIT;
+    use crate::webhooks::{self, WebhookConfig};
+
+    const OPENAPI_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    #[test]
+    fn test_openapi_file() {
+        let spec = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(OPENAPI_FILE, &spec).unwrap();
+        }
+        let file = std::fs::read_to_string(OPENAPI_FILE).unwrap_or_default();
+        assert!(
+            file == spec,
+            "openapi.json is out of date, run `UPDATE_OPENAPI=1 cargo test test_openapi_file`"
+        );
+    }
+
+    /// Id of the todo the store is seeded with, the other ids are free.
+    const EXISTING_ID: i64 = 1;
+    const MISSING_ID: i64 = 42;
+
+    /// Resolve `$ref`s to the components of the document.
+    fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(reference) => {
+                let name = reference.trim_start_matches("#/components/schemas/");
+                resolve(spec, &spec["components"]["schemas"][name])
+            }
+            None => schema,
+        }
+    }
+
+    /// Check `value` against `schema`, for the subset of JSON schema utoipa generates.
+    fn check(spec: &Value, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        let schema = resolve(spec, schema);
+        if let Some(variants) = schema["oneOf"].as_array() {
+            let matching = variants
+                .iter()
+                .filter(|variant| check(spec, variant, value, at).is_ok())
+                .count();
+            return match matching {
+                1 => Ok(()),
+                n => Err(format!("{at}: {value} matches {n} of the oneOf variants")),
+            };
+        }
+        if value.is_null() && schema["nullable"] == json!(true) {
+            return Ok(());
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{at}: {value} is not one of {allowed:?}"));
+            }
+        }
+        let mismatch = || Err(format!("{at}: {value} is not of type {}", schema["type"]));
+        match schema["type"].as_str() {
+            Some("object") => {
+                let Some(object) = value.as_object() else {
+                    return mismatch();
+                };
+                // Free-form objects
+                let Some(properties) = schema["properties"].as_object() else {
+                    return Ok(());
+                };
+                for required in schema["required"].as_array().into_iter().flatten() {
+                    let name = required.as_str().unwrap_or_default();
+                    if !object.contains_key(name) {
+                        return Err(format!("{at}: missing required property {name}"));
+                    }
+                }
+                for (name, value) in object {
+                    let Some(property) = properties.get(name) else {
+                        return Err(format!("{at}: undocumented property {name}"));
+                    };
+                    check(spec, property, value, &format!("{at}.{name}"))?;
+                }
+                Ok(())
+            }
+            Some("array") => {
+                let Some(items) = value.as_array() else {
+                    return mismatch();
+                };
+                items.iter().enumerate().try_for_each(|(i, item)| {
+                    check(spec, &schema["items"], item, &format!("{at}[{i}]"))
+                })
+            }
+            Some("string") => {
+                let Some(string) = value.as_str() else {
+                    return mismatch();
+                };
+                let length = string.chars().count() as u64;
+                if schema["minLength"].as_u64().is_some_and(|min| length < min)
+                    || schema["maxLength"].as_u64().is_some_and(|max| length > max)
+                {
+                    return Err(format!("{at}: length {length} out of bounds"));
+                }
+                Ok(())
+            }
+            Some("integer") => {
+                let Some(integer) = value.as_i64() else {
+                    return mismatch();
+                };
+                if schema["minimum"]
+                    .as_f64()
+                    .is_some_and(|min| (integer as f64) < min)
+                {
+                    return Err(format!("{at}: {integer} under the minimum"));
+                }
+                if schema["maximum"]
+                    .as_f64()
+                    .is_some_and(|max| (integer as f64) > max)
+                {
+                    return Err(format!("{at}: {integer} over the maximum"));
+                }
+                Ok(())
+            }
+            Some("number") if value.is_number() => Ok(()),
+            Some("boolean") if value.is_boolean() => Ok(()),
+            None => Ok(()),
+            Some(_) => mismatch(),
+        }
+    }
+
+    /// Value following `schema`, its integer `id`s set to `id`.
+    fn generate(spec: &Value, schema: &Value, id: i64) -> Value {
+        let schema = resolve(spec, schema);
+        if let Some(example) = schema.get("example") {
+            return example.clone();
+        }
+        if let Some(first) = schema["enum"].get(0) {
+            return first.clone();
+        }
+        match schema["type"].as_str() {
+            Some("object") => {
+                let properties = schema["properties"]
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default();
+                Value::Object(
+                    properties
+                        .iter()
+                        .map(|(name, property)| {
+                            let value =
+                                match (name.as_str(), resolve(spec, property)["type"].as_str()) {
+                                    ("id", Some("integer")) => json!(id),
+                                    _ => generate(spec, property, id),
+                                };
+                            (name.clone(), value)
+                        })
+                        .collect::<Map<_, _>>(),
+                )
+            }
+            Some("array") => json!([generate(spec, &schema["items"], id)]),
+            Some("string") => {
+                let length = schema["minLength"].as_u64().unwrap_or(1).max(1);
+                json!("a".repeat(length as usize))
+            }
+            Some("integer") | Some("number") => json!(0),
+            Some("boolean") => json!(false),
+            _ => Value::Null,
+        }
+    }
+
+    /// `valid` with its first bounded string made one character too long, if it has one.
+    fn invalidate(spec: &Value, schema: &Value, valid: &Value) -> Option<Value> {
+        let schema = resolve(spec, schema);
+        let properties = schema["properties"].as_object()?;
+        properties.iter().find_map(|(name, property)| {
+            let max = resolve(spec, property)["maxLength"].as_u64()?;
+            let mut invalid = valid.clone();
+            invalid[name] = json!("a".repeat(max as usize + 1));
+            Some(invalid)
+        })
+    }
+
+    /// Request bodies to send to an operation, with their content type: none, or every kind its
+    /// responses document.
+    fn bodies(spec: &Value, operation: &Value, id: i64) -> Vec<Option<(&'static str, String)>> {
+        let Some(schema) = operation["requestBody"]["content"]["application/json"].get("schema")
+        else {
+            return vec![None];
+        };
+        let valid = generate(spec, schema, id);
+        let mut bodies = vec![valid.to_string(), String::from("{")];
+        bodies.extend(invalidate(spec, schema, &valid).map(|invalid| invalid.to_string()));
+        bodies.push(json!({"value": "a".repeat(PAYLOAD_LIMIT)}).to_string());
+        let mut bodies: Vec<_> = bodies
+            .into_iter()
+            .map(|body| Some(("application/json", body)))
+            .collect();
+        bodies.push(Some(("text/plain", valid.to_string())));
+        bodies
+    }
+
+    /// Uri of an operation, its path parameters set to `id` and its required query parameters filled.
+    fn uri(path: &str, operation: &Value, id: i64) -> String {
+        let mut uri = path.replace("{id}", &id.to_string());
+        let query: Vec<String> = operation["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|parameter| parameter["in"] == "query" && parameter["required"] == json!(true))
+            .map(|parameter| format!("{}=a", parameter["name"].as_str().unwrap_or_default()))
+            .collect();
+        if !query.is_empty() {
+            uri = format!("{uri}?{}", query.join("&"));
+        }
+        uri
+    }
+
+    /// Webhook store holding the webhook `EXISTING_ID` and one of its deliveries.
+    async fn webhook_store() -> Arc<dyn WebhookStore> {
+        let store = MemoryWebhookStore::default();
+        let id = EXISTING_ID.to_string();
+        let webhook = Webhook {
+            id: id.clone(),
+            url: String::from("https://example.com/hook"),
+            events: vec![WebhookEvent::Created],
+            created_at: 0,
+        };
+        store
+            .create_webhook(&Subscription {
+                webhook,
+                secret: String::from("0123456789abcdef"),
+            })
+            .await;
+        store
+            .enqueue(&Delivery {
+                id: String::from("a"),
+                webhook_id: id,
+                event: WebhookEvent::Created,
+                payload: json!({"event": "todo.created", "todo_id": 1}),
+                status: DeliveryStatus::Dead,
+                attempts: 8,
+                next_attempt_at: 0,
+                last_status: None,
+                last_error: Some(String::from("connection refused")),
+                created_at: 0,
+            })
+            .await;
+        Arc::new(store)
+    }
+
+    /// Idempotency store where the key `reused` was sent along with another request, and the key
+    /// `in-progress` is held by a request being processed.
+    struct SeededIdempotency;
+
+    #[async_trait]
+    impl IdempotencyStore for SeededIdempotency {
+        async fn claim(&self, key: &str, _: &str, _: i64, _: i64, _: i64) -> Claim {
+            match key.rsplit(':').next() {
+                Some("reused") => Claim::Mismatch,
+                Some("in-progress") => Claim::InProgress,
+                _ => Claim::Acquired,
+            }
+        }
+
+        async fn complete(&self, _: &str, _: &StoredResponse) {}
+
+        async fn release(&self, _: &str) {}
+
+        async fn purge(&self, _: i64) {}
+    }
+
+    /// Idempotency keys to send to an operation: none, or every kind its responses document.
+    fn idempotency_keys(operation: &Value) -> Vec<Option<&'static str>> {
+        let documented = operation["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|parameter| {
+                parameter["in"] == "header"
+                    && parameter["name"].as_str().map(str::to_ascii_lowercase)
+                        == Some(IDEMPOTENCY_KEY.to_owned())
+            });
+        if documented {
+            vec![None, Some("reused"), Some("in-progress")]
+        } else {
+            vec![None]
+        }
+    }
+
+    /// Exercise every documented operation against the routes, checking each response is documented,
+    /// matches its schema, and that every documented response is produced by some request.
+    #[actix_web::test]
+    async fn test_routes_follow_openapi() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut documented = BTreeSet::new();
+        let mut produced = BTreeSet::new();
+        let mut failures = Vec::new();
+
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in operations.as_object().unwrap() {
+                for status in operation["responses"].as_object().unwrap().keys() {
+                    documented.insert((path.clone(), method.clone(), status.clone()));
+                }
+                for id in [EXISTING_ID, MISSING_ID] {
+                    for (body, key) in bodies(&spec, operation, id)
+                        .into_iter()
+                        .flat_map(|body| {
+                            idempotency_keys(operation)
+                                .into_iter()
+                                .map(move |key| (body.clone(), key))
+                        })
+                    {
+                        for accept in [None, Some("application/json")] {
+                            let store = TodoMemoryProvider {
+                                todo_list: vec![Todo {
+                                    id: EXISTING_ID,
+                                    value: String::from("a"),
+                                    checked: false,
+                                }],
+                            };
+                            let container = container! { repository => store; singleton };
+                            let app = init_service(
+                                App::new()
+                                    .wrap(Idempotency::new(
+                                        Arc::new(SeededIdempotency),
+                                        IdempotencyConfig::default(),
+                                    ))
+                                    .app_data(container)
+                                    .app_data(web::Data::from(webhook_store().await))
+                                    .app_data(web::Data::new(WebhookConfig {
+                                        allowed_hosts: vec![String::from("example.com")],
+                                        ..WebhookConfig::default()
+                                    }))
+                                    .configure(configure())
+                                    .configure(webhooks::configure()),
+                            )
+                            .await;
+                            let uri = uri(path, operation, id);
+                            let mut req = TestRequest::default()
+                                .method(
+                                    Method::from_bytes(method.to_uppercase().as_bytes()).unwrap(),
+                                )
+                                .uri(&uri);
+                            if let Some(accept) = accept {
+                                req = req.insert_header((ACCEPT, accept));
+                            }
+                            if let Some(key) = key {
+                                req = req.insert_header((IDEMPOTENCY_KEY, key));
+                            }
+                            if let Some((content_type, body)) = &body {
+                                req = req
+                                    .insert_header((CONTENT_TYPE, *content_type))
+                                    .set_payload(body.clone());
+                            }
+                            let resp = call_service(&app, req.to_request()).await;
+                            let status = resp.status().as_u16().to_string();
+                            let content_type = resp
+                                .headers()
+                                .get(CONTENT_TYPE)
+                                .and_then(|value| value.to_str().ok())
+                                .map(|value| {
+                                    value
+                                        .split(';')
+                                        .next()
+                                        .unwrap_or_default()
+                                        .trim()
+                                        .to_owned()
+                                });
+                            let bytes = read_body(resp).await;
+                            let request = format!(
+                                "{} {uri} (Accept: {accept:?}, Idempotency-Key: {key:?})",
+                                method.to_uppercase()
+                            );
+                            produced.insert((path.clone(), method.clone(), status.clone()));
+
+                            let Some(response) = operation["responses"].get(&status) else {
+                                failures.push(format!("{request}: undocumented status {status}"));
+                                continue;
+                            };
+                            let Some(content) = response["content"].as_object() else {
+                                if !bytes.is_empty() {
+                                    failures.push(format!(
+                                        "{request}: {status} documented without a body"
+                                    ));
+                                }
+                                continue;
+                            };
+                            let Some(media) =
+                                content_type.as_ref().and_then(|media| content.get(media))
+                            else {
+                                failures.push(format!("{request}: {status} with undocumented content type {content_type:?}"));
+                                continue;
+                            };
+                            match serde_json::from_slice::<Value>(&bytes) {
+                                Ok(value) => {
+                                    if let Err(err) = check(&spec, &media["schema"], &value, "body")
+                                    {
+                                        failures.push(format!("{request}: {status} {err}"));
+                                    }
+                                }
+                                Err(err) => failures
+                                    .push(format!("{request}: {status} body is not json: {err}")),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        failures.extend(
+            documented
+                .difference(&produced)
+                .map(|(path, method, status)| {
+                    format!(
+                        "{} {path}: documented {status} is never produced",
+                        method.to_uppercase()
+                    )
+                }),
+        );
+        assert!(
+            failures.is_empty(),
+            "the routes do not follow the OpenAPI document:\n{}",
+            failures.join("\n")
+        );
+    }
+}
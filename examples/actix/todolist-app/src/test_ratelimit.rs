@@ -0,0 +1,110 @@
+// Unit testing for the rate limiter
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::{http::header::{HeaderMap, HeaderName, HeaderValue}, test, App};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use coi::container;
+    use crate::ratelimit::{signed_user, InMemoryBackend, Quota, RateLimit, RateLimitBackend, RateLimitConfig, RouteQuota};
+    use crate::rest::configure;
+    use crate::stores::memory::TodoMemoryProvider;
+
+    #[actix_web::test]
+    async fn test_bucket_refill() {
+        let backend = InMemoryBackend::default();
+        let quota = Quota { per_second: 10.0, burst: 2 };
+        assert!(backend.acquire("client", quota).await.allowed);
+        let decision = backend.acquire("client", quota).await;
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        let decision = backend.acquire("client", quota).await;
+        assert!(!decision.allowed);
+        assert!(decision.retry_after > Duration::ZERO && decision.retry_after <= Duration::from_millis(100));
+        // Buckets are independent
+        assert!(backend.acquire("other client", quota).await.allowed);
+
+        actix_web::rt::time::sleep(Duration::from_millis(150)).await;
+        assert!(backend.acquire("client", quota).await.allowed);
+    }
+
+    fn alice() -> SocketAddr {
+        "10.0.0.1:1234".parse().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit_middleware() {
+        let config = RateLimitConfig {
+            default: Quota { per_second: 1.0, burst: 1 },
+            exempt: vec!["/health".to_owned()],
+            routes: vec![RouteQuota { methods: vec!["GET".to_owned()], route: "/todo/search".to_owned(), quota: Quota { per_second: 1.0, burst: 2 } }],
+        };
+        let container = container!{
+            repository => TodoMemoryProvider{todo_list: Vec::new()}; singleton
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(Arc::new(InMemoryBackend::default()), config))
+                .app_data(container)
+                .configure(configure()),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/todo").peer_addr(alice());
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("RateLimit-Limit").unwrap(), "1");
+        assert_eq!(resp.headers().get("RateLimit-Remaining").unwrap(), "0");
+
+        let req = test::TestRequest::get().uri("/todo").peer_addr(alice());
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/problem+json");
+
+        // Unverified headers do not make another client
+        for header in [("X-Api-Key", "some-key"), ("X-Api-Key", "other-key"), ("X-User", "bob")] {
+            let req = test::TestRequest::get().uri("/todo").peer_addr(alice()).insert_header(header);
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), 429);
+        }
+
+        // Other clients, routes with their own limits and exempt routes are not affected
+        let req = test::TestRequest::get().uri("/todo").peer_addr("10.0.0.2:1234".parse().unwrap());
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200);
+        let req = test::TestRequest::get().uri("/todo/search?value=a").peer_addr(alice());
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("RateLimit-Limit").unwrap(), "2");
+        for _ in 0..3 {
+            let req = test::TestRequest::get().uri("/health").peer_addr(alice());
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_signed_user() {
+        let key = b"shared with the gateway";
+        let headers = |user: &str, signed_user: &str, t: u64| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(format!("{t}.{signed_user}.editor.session").as_bytes());
+            let signature = format!("t={t},v1={}", hex::encode(mac.finalize().into_bytes()));
+            let mut headers = HeaderMap::new();
+            for (name, value) in [("x-user", user), ("x-user-roles", "editor"), ("x-auth-method", "session"), ("x-user-signature", &signature)] {
+                headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+            }
+            headers
+        };
+        assert_eq!(signed_user(&headers("alice", "alice", 1000), key, 1100), Some("alice"));
+        // Signed for someone else, too long ago, or with another key
+        assert_eq!(signed_user(&headers("bob", "alice", 1000), key, 1100), None);
+        assert_eq!(signed_user(&headers("alice", "alice", 1000), key, 2000), None);
+        assert_eq!(signed_user(&headers("alice", "alice", 1000), b"other key", 1100), None);
+        let mut unsigned = headers("alice", "alice", 1000);
+        unsigned.remove("x-user-signature");
+        assert_eq!(signed_user(&unsigned, key, 1100), None);
+    }
+}
@@ -6,8 +6,11 @@ mod tests {
     use std::collections::HashSet;
     use coi::{container, Container};
     use rstest::{fixture, rstest};
-    use crate::{schemas::Todo, stores::memory::{TodoRepository, TodoMemoryProvider}};
+    use crate::{schemas::{ErrorResponse, FieldError, Todo}, stores::memory::{TodoRepository, TodoMemoryProvider}};
     use crate::rest::configure;
+    use crate::problem::Problem;
+    use crate::metrics::RequestMetrics;
+    use crate::telemetry::RequestTracing;
 
     #[fixture]
     fn fixt_container() -> fn(Vec<Todo>) -> Container {
@@ -43,7 +46,7 @@ mod tests {
         let app = test::init_service(App::new().app_data(container).configure(configure())).await;
         let req = test::TestRequest::get().uri("/todo/1");
         let resp = test::call_and_read_body_json::<_, _, Todo>(&app, req.to_request()).await;
-        assert_eq!(&resp, test_data.get(0).unwrap());
+        assert_eq!(&resp, test_data.first().unwrap());
     }
 
     #[rstest]
@@ -61,7 +64,7 @@ mod tests {
         let container = fixt_container(test_data.clone());
         let app = test::init_service(App::new().app_data(container).configure(configure())).await;
 
-        let expected_todo = test_data.get(0).unwrap().clone();
+        let expected_todo = test_data.first().unwrap().clone();
         let todo_expected_list : Vec<Todo> = [expected_todo].to_vec();
 
         let req = test::TestRequest::get().uri("/todo/search?value=value");
@@ -69,5 +72,165 @@ mod tests {
 
         assert_eq!(resp, todo_expected_list);
     }
+
+    #[rstest]
+    async fn test_metrics(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().wrap(RequestMetrics).app_data(container).configure(configure())).await;
+        let req = test::TestRequest::get().uri("/todo/1");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get().uri("/metrics");
+        let body = test::call_and_read_body(&app, req.to_request()).await;
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        // Requests are labelled by route pattern, not by raw path
+        assert!(text.contains(r#"http_requests_total{method="GET",route="/todo/{id}",status="200"}"#));
+        assert!(text.contains("http_request_duration_seconds_bucket"));
+    }
+
+    #[rstest]
+    async fn test_request_id(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().wrap(RequestTracing).app_data(container).configure(configure())).await;
+
+        // Propagated from the caller when present
+        let req = test::TestRequest::get().uri("/todo").insert_header(("X-Request-Id", "some-request-id"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.headers().get("X-Request-Id").unwrap(), "some-request-id");
+
+        // Generated otherwise
+        let req = test::TestRequest::get().uri("/todo");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert!(!resp.headers().get("X-Request-Id").unwrap().is_empty());
+    }
+
+    #[rstest]
+    async fn test_todo_post_invalid(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().app_data(container.clone()).configure(configure())).await;
+        // Legacy error shape
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Accept", "application/json")).set_json(Todo{checked: false, value: "".to_owned(), id: -1});
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 400);
+        match test::read_body_json::<ErrorResponse, _>(resp).await {
+            ErrorResponse::Validation(fields) => assert_eq!(fields, vec![
+                FieldError{field: "id".to_owned(), messages: vec!["must be between 0 and 2147483647".to_owned()]},
+                FieldError{field: "value".to_owned(), messages: vec!["must be between 1 and 1024 characters".to_owned()]},
+            ]),
+            _ => panic!("expected a validation error"),
+        }
+        // Ids must fit the INT column of postgres
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Accept", "application/json")).set_json(Todo{checked: false, value: "some_value".to_owned(), id: i64::from(i32::MAX) + 1});
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 400);
+        // Nothing was stored
+        assert_eq!(container.resolve::<dyn TodoRepository>("repository").unwrap().read_all().await.len(), 2)
+    }
+
+    #[rstest]
+    async fn test_todo_post_malformed(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().app_data(container).configure(configure())).await;
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Content-Type", "application/json")).set_payload(r#"{"id": 3, "value": "#);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 400);
+        let problem = test::read_body_json::<Problem, _>(resp).await;
+        assert_eq!(problem.errors.unwrap()[0].field, "body");
+
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Content-Type", "text/plain")).set_payload("some value");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 415);
+        assert_eq!(resp.headers().get("Accept-Post").unwrap(), "application/json, application/msgpack, application/cbor");
+        let problem = test::read_body_json::<Problem, _>(resp).await;
+        assert_eq!(problem.detail.unwrap(), "Content-Type not supported, use one of application/json, application/msgpack, application/cbor");
+
+        let too_large = format!(r#"{{"id": 3, "value": "{}", "checked": false}}"#, "a".repeat(20 * 1024));
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Content-Type", "application/json")).set_payload(too_large);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 413);
+    }
+
+    #[rstest]
+    async fn test_problem_json(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().app_data(container).configure(configure())).await;
+
+        // Problem details are served unless the legacy shape is asked for
+        for accept in [None, Some("*/*"), Some("application/problem+json, application/json")] {
+            let mut req = test::TestRequest::get().uri("/todo/42");
+            if let Some(accept) = accept {
+                req = req.insert_header(("Accept", accept));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), 404);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/problem+json");
+            let problem = test::read_body_json::<Problem, _>(resp).await;
+            assert_eq!(problem.problem_type, "about:blank");
+            assert_eq!(problem.title, "Not Found");
+            assert_eq!(problem.status, 404);
+            assert_eq!(problem.detail.unwrap(), "Todo not found, id = 42");
+            assert_eq!(problem.instance.unwrap(), "/todo/42");
+        }
+
+        let req = test::TestRequest::get().uri("/todo/42").insert_header(("Accept", "application/json"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/json");
+        match test::read_body_json::<ErrorResponse, _>(resp).await {
+            ErrorResponse::NotFound(detail) => assert_eq!(detail, "id = 42"),
+            _ => panic!("expected a not found error"),
+        }
+    }
+
+    #[rstest]
+    async fn test_msgpack_round_trip(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().app_data(container).configure(configure())).await;
+        let todo = Todo{id: 42, value: "some_value".to_owned(), checked: false};
+        let req = test::TestRequest::post().uri("/todo")
+            .insert_header(("Content-Type", "application/msgpack"))
+            .insert_header(("Accept", "application/msgpack"))
+            .set_payload(rmp_serde::to_vec_named(&todo).unwrap());
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/msgpack");
+
+        let req = test::TestRequest::get().uri("/todo/42").insert_header(("Accept", "application/json;q=0.5, application/msgpack"));
+        let body = test::call_and_read_body(&app, req.to_request()).await;
+        assert_eq!(rmp_serde::from_slice::<Todo>(&body).unwrap(), todo);
+    }
+
+    #[rstest]
+    async fn test_cbor_round_trip(fixt_container:fn(Vec<Todo>) -> Container, test_data: Vec<Todo>) {
+        let container = fixt_container(test_data);
+        let app = test::init_service(App::new().app_data(container).configure(configure())).await;
+        let todo = Todo{id: 42, value: "some_value".to_owned(), checked: false};
+        let mut payload = Vec::new();
+        ciborium::into_writer(&todo, &mut payload).unwrap();
+        let req = test::TestRequest::post().uri("/todo")
+            .insert_header(("Content-Type", "application/cbor"))
+            .insert_header(("Accept", "application/cbor"))
+            .set_payload(payload);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/cbor");
+
+        let req = test::TestRequest::get().uri("/todo/42").insert_header(("Accept", "application/cbor"));
+        let body = test::call_and_read_body(&app, req.to_request()).await;
+        assert_eq!(ciborium::from_reader::<Todo, _>(&body[..]).unwrap(), todo);
+
+        // JSON stays the default
+        let req = test::TestRequest::get().uri("/todo/42");
+        let resp = test::call_and_read_body_json::<_, _, Todo>(&app, req.to_request()).await;
+        assert_eq!(resp, todo);
+
+        // Undecodable bodies are validation problems
+        let req = test::TestRequest::post().uri("/todo").insert_header(("Content-Type", "application/cbor")).set_payload(vec![0xff, 0x00]);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 400);
+        let problem = test::read_body_json::<Problem, _>(resp).await;
+        assert_eq!(problem.errors.unwrap()[0].field, "body");
+    }
     // [...]
 }
\ No newline at end of file
@@ -0,0 +1,57 @@
+// Testing of the graceful shutdown, against a real server
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{rt, test, web, App, HttpResponse, HttpServer};
+    use futures::channel::oneshot;
+    use crate::shutdown::{graceful_shutdown, ready, Readiness, ShutdownConfig};
+
+    async fn slow() -> HttpResponse {
+        rt::time::sleep(Duration::from_millis(500)).await;
+        HttpResponse::Ok().body("done")
+    }
+
+    #[actix_web::test]
+    async fn test_ready() {
+        let readiness = Readiness::default();
+        let app = test::init_service(App::new().app_data(web::Data::new(readiness.clone())).service(ready)).await;
+        let req = test::TestRequest::get().uri("/ready");
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200);
+
+        readiness.set_not_ready();
+        let req = test::TestRequest::get().uri("/ready");
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_in_flight_request_completes() {
+        let readiness = Readiness::default();
+        let config = ShutdownConfig { grace_period: Duration::ZERO, drain_timeout: Duration::from_secs(5) };
+        let server = HttpServer::new(|| App::new().route("/slow", web::get().to(slow)))
+            .workers(1)
+            .disable_signals()
+            .shutdown_timeout(config.drain_timeout.as_secs())
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let (trigger, signal) = oneshot::channel::<()>();
+        rt::spawn(graceful_shutdown(server.handle(), readiness.clone(), config, async {
+            let _ = signal.await;
+        }));
+        let server = rt::spawn(server);
+
+        let in_flight = rt::spawn(reqwest::get(format!("http://{addr}/slow")));
+        // Let the request reach the handler before asking for the shutdown
+        rt::time::sleep(Duration::from_millis(100)).await;
+        trigger.send(()).unwrap();
+
+        let resp = in_flight.await.unwrap().unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "done");
+        assert!(!readiness.is_ready());
+        server.await.unwrap().unwrap();
+    }
+}
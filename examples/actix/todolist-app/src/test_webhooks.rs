@@ -0,0 +1,226 @@
+// Testing of the webhooks, delivering to a local receiver
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{rt, test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use coi::container;
+    use serde_json::{json, Value};
+
+    use crate::rest::configure;
+    use crate::schemas::{ChangeKind, DeliveryStatus, Todo, TodoChange, TodoUpdateRequest, Webhook, WebhookEvent};
+    use crate::store_interface::TodoRepository;
+    use crate::stores::changes::{NotifyingProvider, TodoChanges};
+    use crate::stores::memory::TodoMemoryProvider;
+    use crate::stores::webhooks::{MemoryWebhookStore, Subscription, WebhookStore};
+    use crate::webhooks::{self, sign, Dispatcher, WebhookConfig};
+
+    const SECRET: &str = "0123456789abcdef";
+
+    /// Request received by the local receiver.
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> &str {
+            self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str()).unwrap_or_default()
+        }
+    }
+
+    /// Start a receiver answering `status`, returning its url and what it received.
+    fn receiver(status: u16) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let server = HttpServer::new(move || {
+            let log = log.clone();
+            App::new().route("/hook", web::post().to(move |req: HttpRequest, body: String| {
+                let log = log.clone();
+                async move {
+                    let headers = req.headers().iter()
+                        .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_owned()))
+                        .collect();
+                    log.lock().unwrap().push(Received { headers, body });
+                    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish()
+                }
+            }))
+        })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        rt::spawn(server.run());
+        (url, received)
+    }
+
+    async fn subscribe(store: &dyn WebhookStore, id: &str, url: &str, events: Vec<WebhookEvent>) {
+        let webhook = Webhook { id: id.to_owned(), url: url.to_owned(), events, created_at: 0 };
+        store.create_webhook(&Subscription { webhook, secret: SECRET.to_owned() }).await;
+    }
+
+    fn change(kind: ChangeKind, id: i64) -> TodoChange {
+        let todo = (kind != ChangeKind::Deleted).then(|| Todo { id, value: String::from("some value"), checked: kind == ChangeKind::Completed });
+        TodoChange { kind, id, todo }
+    }
+
+    // The receivers are local, the urls of the endpoints are not resolved
+    fn config() -> WebhookConfig {
+        let allowed_hosts = vec![String::from("127.0.0.1"), String::from("example.com")];
+        WebhookConfig { max_attempts: 3, initial_backoff_secs: 0, allowed_hosts, ..WebhookConfig::default() }
+    }
+
+    #[actix_web::test]
+    async fn test_signed_delivery() {
+        let (url, received) = receiver(204);
+        let store = Arc::new(MemoryWebhookStore::default());
+        subscribe(store.as_ref(), "created", &url, vec![WebhookEvent::Created]).await;
+        subscribe(store.as_ref(), "deleted", &url, vec![WebhookEvent::Deleted]).await;
+        let dispatcher = Dispatcher::new(store.clone(), config());
+
+        dispatcher.enqueue(&change(ChangeKind::Created, 1)).await;
+        // Updates which do not complete a todo are not events
+        dispatcher.enqueue(&change(ChangeKind::Updated, 1)).await;
+        assert_eq!(dispatcher.deliver_due().await, 1);
+        assert_eq!(dispatcher.deliver_due().await, 0);
+
+        let mut received = std::mem::take(&mut *received.lock().unwrap());
+        assert_eq!(received.len(), 1);
+        let request = received.remove(0);
+        assert_eq!(request.header("x-webhook-event"), "todo.created");
+        let timestamp: i64 = request.header("x-webhook-timestamp").parse().unwrap();
+        assert_eq!(request.header("x-webhook-signature"), sign(SECRET, timestamp, request.body.as_bytes()));
+        let payload: Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["event"], "todo.created");
+        assert_eq!(payload["todo"], json!({"id": 1, "value": "some value", "checked": false}));
+
+        let deliveries = store.read_deliveries("created").await;
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].id, request.header("x-webhook-delivery"));
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+        assert_eq!(deliveries[0].last_status, Some(204));
+        assert!(store.read_deliveries("deleted").await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_retries_then_dead_letter() {
+        let (url, received) = receiver(500);
+        let store = Arc::new(MemoryWebhookStore::default());
+        subscribe(store.as_ref(), "failing", &url, vec![WebhookEvent::Deleted]).await;
+        let dispatcher = Dispatcher::new(store.clone(), config());
+
+        dispatcher.enqueue(&change(ChangeKind::Deleted, 1)).await;
+        for attempt in 1..=3 {
+            assert_eq!(dispatcher.deliver_due().await, 1, "attempt {attempt}");
+        }
+        assert_eq!(dispatcher.deliver_due().await, 0);
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        let delivery = &store.read_deliveries("failing").await[0];
+        assert_eq!(delivery.status, DeliveryStatus::Dead);
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.last_status, Some(500));
+        // All attempts are the same delivery
+        let ids: Vec<String> = received.lock().unwrap().iter().map(|request| request.header("x-webhook-delivery").to_owned()).collect();
+        assert!(ids.iter().all(|id| id == &delivery.id));
+    }
+
+    #[actix_web::test]
+    async fn test_backoff() {
+        let config = WebhookConfig { initial_backoff_secs: 10, max_backoff_secs: 60, ..WebhookConfig::default() };
+        let waits: Vec<i64> = (1..=5).map(|attempts| config.backoff(attempts)).collect();
+        assert_eq!(waits, vec![10, 20, 40, 60, 60]);
+    }
+
+    #[actix_web::test]
+    async fn test_completed_change() {
+        let changes = TodoChanges::default();
+        let mut received = changes.subscribe();
+        let provider = TodoMemoryProvider { todo_list: vec![Todo { id: 1, value: String::from("some value"), checked: false }] };
+        let container = container! { repository => NotifyingProvider::new(provider, changes.clone()); singleton };
+        let repository = container.resolve::<dyn TodoRepository>("repository").unwrap();
+
+        let check = || TodoUpdateRequest { value: None, checked: Some(true) };
+        repository.update_one(1, check()).await.unwrap();
+        repository.update_one(1, check()).await.unwrap();
+        repository.update_one(1, TodoUpdateRequest { value: Some(String::from("other")), checked: None }).await.unwrap();
+
+        let kinds: Vec<ChangeKind> = (0..3).map(|_| received.try_recv().unwrap().kind).collect();
+        assert_eq!(kinds, vec![ChangeKind::Completed, ChangeKind::Updated, ChangeKind::Updated]);
+    }
+
+    #[actix_web::test]
+    async fn test_webhook_endpoints() {
+        let store: Arc<dyn WebhookStore> = Arc::new(MemoryWebhookStore::default());
+        let container = container! { repository => TodoMemoryProvider { todo_list: Vec::new() }; singleton };
+        let app = test::init_service(
+            App::new()
+                .app_data(container)
+                .app_data(web::Data::from(store.clone()))
+                .app_data(web::Data::new(config()))
+                .configure(configure())
+                .configure(webhooks::configure()),
+        ).await;
+
+        let req = test::TestRequest::post().uri("/webhooks")
+            .set_json(json!({"url": "not an url", "events": [], "secret": "short"}));
+        let resp: Value = test::call_and_read_body_json(&app, req.to_request()).await;
+        let mut fields: Vec<&str> = resp["errors"].as_array().unwrap().iter().map(|error| error["field"].as_str().unwrap()).collect();
+        fields.sort();
+        assert_eq!(fields, vec!["events", "secret", "url"]);
+
+        let req = test::TestRequest::post().uri("/webhooks")
+            .set_json(json!({"url": "https://example.com/hook", "events": ["todo.completed"], "secret": SECRET}));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 201);
+        let created: Value = test::read_body_json(resp).await;
+        assert!(created.get("secret").is_none());
+        let id = created["id"].as_str().unwrap();
+        assert_eq!(store.read_webhook(id).await.unwrap().secret, SECRET);
+
+        let req = test::TestRequest::get().uri("/webhooks");
+        let listed: Vec<Value> = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert_eq!(listed, vec![created.clone()]);
+
+        let req = test::TestRequest::get().uri(&format!("/webhooks/{id}/deliveries"));
+        let deliveries: Vec<Value> = test::call_and_read_body_json(&app, req.to_request()).await;
+        assert!(deliveries.is_empty());
+
+        let req = test::TestRequest::delete().uri(&format!("/webhooks/{id}"));
+        assert_eq!(test::call_service(&app, req.to_request()).await.status(), 200);
+        let req = test::TestRequest::get().uri(&format!("/webhooks/{id}")).insert_header(("Accept", "application/json"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body, json!({"WebhookNotFound": format!("id = {id}")}));
+
+        // Internal addresses, and schemes other than http, are refused
+        for url in ["http://169.254.169.254/latest/meta-data", "http://localhost:8080/todo", "http://[::1]/hook", "http://10.0.0.1/hook", "ftp://example.com/hook"] {
+            let req = test::TestRequest::post().uri("/webhooks")
+                .set_json(json!({"url": url, "events": ["todo.completed"], "secret": SECRET}));
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), 400, "{url}");
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(body["errors"][0]["field"], "url");
+        }
+        assert_eq!(store.read_webhooks().await.len(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_internal_url_not_delivered() {
+        let (url, received) = receiver(204);
+        let store = Arc::new(MemoryWebhookStore::default());
+        subscribe(store.as_ref(), "internal", &url, vec![WebhookEvent::Deleted]).await;
+        // Registered while allowed, delivered once no longer
+        let dispatcher = Dispatcher::new(store.clone(), WebhookConfig { allowed_hosts: Vec::new(), ..config() });
+
+        dispatcher.enqueue(&change(ChangeKind::Deleted, 1)).await;
+        assert_eq!(dispatcher.deliver_due().await, 1);
+        assert!(received.lock().unwrap().is_empty());
+        let delivery = &store.read_deliveries("internal").await[0];
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.last_error.as_deref(), Some("url must not resolve to an internal address"));
+    }
+}
@@ -0,0 +1,71 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::{
+    dev::Payload,
+    error::{InternalError, JsonPayloadError},
+    web::{JsonConfig, PayloadConfig},
+    Error, FromRequest, HttpRequest, ResponseError,
+};
+use serde::de::DeserializeOwned;
+use validator::Validate;
+
+use crate::negotiation::Negotiated;
+use crate::problem::error_response;
+use crate::schemas::{field_errors, ErrorResponse, FieldError};
+
+/// Largest accepted body, in bytes.
+pub const PAYLOAD_LIMIT: usize = 16 * 1024;
+
+/// Json extractor configuration, answering malformed and oversized bodies with a validation
+/// problem rather than actix's plain-text errors.
+pub fn json_config() -> JsonConfig {
+    JsonConfig::default()
+        .limit(PAYLOAD_LIMIT)
+        .error_handler(|err: JsonPayloadError, req: &HttpRequest| {
+            let error = ErrorResponse::Validation(vec![FieldError {
+                field: "body".to_owned(),
+                messages: vec![err.to_string()],
+            }]);
+            let response = error_response(req, err.status_code(), error);
+            InternalError::from_response(err, response).into()
+        })
+}
+
+/// Size limit of the MessagePack and CBOR bodies, which do not go through the Json extractor.
+pub fn payload_config() -> PayloadConfig {
+    PayloadConfig::new(PAYLOAD_LIMIT)
+}
+
+/// Body extractor rejecting bodies which break the `Validate` rules of `T`, with a 400
+/// validation problem listing the messages by field.
+///
+/// Bodies are decoded like `Negotiated` does, so JSON, MessagePack and CBOR are accepted.
+pub struct Validated<T>(pub T);
+
+impl<T> Validated<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for Validated<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let body = Negotiated::<T>::from_request(req, payload);
+        let req = req.clone();
+        Box::pin(async move {
+            let value = body.await?.into_inner();
+            match value.validate() {
+                Ok(()) => Ok(Validated(value)),
+                Err(errors) => {
+                    let error = ErrorResponse::Validation(field_errors(&errors));
+                    let response = error_response(&req, error.status(), error);
+                    Err(InternalError::from_response(errors, response).into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,494 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{
+    http::StatusCode,
+    rt,
+    web::{self, Data, Path, ServiceConfig},
+    Either, HttpResponse, Responder,
+};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::negotiation::Negotiated;
+use crate::schemas::{
+    ChangeKind, Delivery, DeliveryStatus, ErrorResponse, FieldError, TodoChange, Webhook,
+    WebhookEvent, WebhookRequest,
+};
+use crate::stores::changes::TodoChanges;
+use crate::stores::webhooks::{Subscription, WebhookStore};
+use crate::validation::Validated;
+
+/// Delivery of the webhooks, read from `webhooks.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    /// Attempts of a delivery before it is dead-lettered.
+    pub max_attempts: i32,
+    /// Wait before the first retry, doubled on each further one.
+    pub initial_backoff_secs: i64,
+    pub max_backoff_secs: i64,
+    /// Deadline of each attempt.
+    pub timeout_secs: u64,
+    /// How often the queue is checked for due deliveries.
+    pub poll_interval_ms: u64,
+    /// Deliveries attempted at once.
+    pub batch_size: i64,
+    /// Hosts delivered to even though they resolve to internal addresses, e.g. receivers in the
+    /// same network. Other urls must resolve to public addresses only.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff_secs: 10,
+            max_backoff_secs: 3600,
+            timeout_secs: 10,
+            poll_interval_ms: 1000,
+            batch_size: 20,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// Read the configuration from a yml file, falling back to the defaults when there is none.
+    pub fn from_file(path: &str) -> Self {
+        match std::fs::File::open(path) {
+            Ok(file) => serde_yaml::from_reader(file).expect("Could not read webhooks file."),
+            Err(_) => {
+                tracing::warn!(path, "no webhooks file, using the defaults");
+                Self::default()
+            }
+        }
+    }
+
+    /// Wait before the attempt following the `attempts`th one.
+    pub(crate) fn backoff(&self, attempts: i32) -> i64 {
+        let exponent = attempts.saturating_sub(1).clamp(0, 30) as u32;
+        self.initial_backoff_secs
+            .saturating_mul(2i64.saturating_pow(exponent))
+            .min(self.max_backoff_secs)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// `X-Webhook-Signature` of a delivery: the HMAC-SHA256 of `{timestamp}.{body}`, keyed by the
+/// webhook secret, so that receivers can check both the sender and the freshness.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Addresses of the instance itself, of its networks or of the metadata services of the clouds.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // This network, 0.0.0.0/8, and the shared address space, 100.64.0.0/10
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link local, fe80::/10
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+/// Check that webhooks may be delivered to `url`: over http or https, to one of `allowed_hosts`
+/// or to a host resolving to public addresses only.
+pub(crate) async fn check_url(url: &str, allowed_hosts: &[String]) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|_| String::from("must be an absolute url"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(String::from("must be an http or https url"));
+    }
+    let Some(host) = url.host_str() else {
+        return Err(String::from("must be an absolute url"));
+    };
+    if is_allowed(host, allowed_hosts) {
+        return Ok(());
+    }
+    // IPv6 hosts are bracketed in urls
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or_default();
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    if addrs.is_empty() {
+        return Err(String::from("must name a host which resolves"));
+    }
+    if addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(String::from("must not resolve to an internal address"));
+    }
+    Ok(())
+}
+
+/// Resolver of the webhook client, refusing internal addresses but for the allowed hosts, so that
+/// a host resolving differently once its url was checked is not delivered to either.
+struct PublicResolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = is_allowed(name.as_str(), &self.allowed_hosts);
+        Box::pin(async move {
+            // The port is set by the client
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if !allowed && addrs.iter().any(|addr| is_internal(addr.ip())) {
+                return Err(format!("{} resolves to an internal address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Queues the todo changes for the webhooks subscribed to them, and delivers the queue.
+#[derive(Clone)]
+pub struct Dispatcher {
+    store: Arc<dyn WebhookStore>,
+    config: Arc<WebhookConfig>,
+    client: reqwest::Client,
+}
+
+impl Dispatcher {
+    pub fn new(store: Arc<dyn WebhookStore>, config: WebhookConfig) -> Self {
+        let resolver = PublicResolver {
+            allowed_hosts: config.allowed_hosts.clone(),
+        };
+        // Redirects could lead anywhere, past the checks of the urls
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(resolver))
+            .build()
+            .expect("Could not build the webhook client.");
+        Self {
+            store,
+            config: Arc::new(config),
+            client,
+        }
+    }
+
+    /// Queue a delivery of `change` to every webhook subscribed to its event.
+    pub async fn enqueue(&self, change: &TodoChange) {
+        let event = match change.kind {
+            ChangeKind::Created => WebhookEvent::Created,
+            ChangeKind::Completed => WebhookEvent::Completed,
+            ChangeKind::Deleted => WebhookEvent::Deleted,
+            ChangeKind::Updated => return,
+        };
+        let created_at = now();
+        let payload = json!({
+            "event": event,
+            "todo_id": change.id,
+            "todo": change.todo,
+            "occurred_at": created_at,
+        });
+        for subscription in self.store.read_webhooks().await {
+            if !subscription.webhook.events.contains(&event) {
+                continue;
+            }
+            let delivery = Delivery {
+                id: Uuid::new_v4().to_string(),
+                webhook_id: subscription.webhook.id,
+                event,
+                payload: payload.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                next_attempt_at: created_at,
+                last_status: None,
+                last_error: None,
+                created_at,
+            };
+            self.store.enqueue(&delivery).await;
+        }
+    }
+
+    /// Attempt the deliveries due, returning how many there were.
+    pub async fn deliver_due(&self) -> usize {
+        let now = now();
+        // Left to another worker if this one did not finish them by then
+        let lease_until = now + 2 * self.config.timeout_secs as i64;
+        let due = self
+            .store
+            .claim_due(now, lease_until, self.config.batch_size)
+            .await;
+        let count = due.len();
+        futures::future::join_all(due.into_iter().map(|delivery| self.deliver(delivery))).await;
+        count
+    }
+
+    async fn deliver(&self, mut delivery: Delivery) {
+        // Deliveries of deleted webhooks are deleted along with them
+        let Ok(subscription) = self.store.read_webhook(&delivery.webhook_id).await else {
+            return;
+        };
+        let body = delivery.payload.to_string();
+        let timestamp = now();
+        let result = match check_url(&subscription.webhook.url, &self.config.allowed_hosts).await {
+            Err(reason) => Err(format!("url {reason}")),
+            Ok(()) => self
+                .client
+                .post(&subscription.webhook.url)
+                .header("Content-Type", "application/json")
+                .header("X-Webhook-Event", delivery.event.as_str())
+                .header("X-Webhook-Delivery", &delivery.id)
+                .header("X-Webhook-Timestamp", timestamp.to_string())
+                .header(
+                    "X-Webhook-Signature",
+                    sign(&subscription.secret, timestamp, body.as_bytes()),
+                )
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| err.to_string()),
+        };
+        delivery.attempts += 1;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.last_status = Some(i32::from(response.status().as_u16()));
+                delivery.last_error = None;
+            }
+            Ok(response) => {
+                delivery.last_status = Some(i32::from(response.status().as_u16()));
+                delivery.last_error = Some(format!("answered {}", response.status()));
+            }
+            Err(err) => {
+                delivery.last_status = None;
+                delivery.last_error = Some(err);
+            }
+        }
+        if delivery.status == DeliveryStatus::Pending {
+            if delivery.attempts >= self.config.max_attempts {
+                tracing::warn!(delivery = %delivery.id, webhook = %delivery.webhook_id, "webhook delivery dead-lettered");
+                delivery.status = DeliveryStatus::Dead;
+            } else {
+                delivery.next_attempt_at = now() + self.config.backoff(delivery.attempts);
+            }
+        }
+        self.store.update_delivery(&delivery).await;
+    }
+
+    /// Queue the changes published from now on, and deliver the queue in the background.
+    pub fn spawn(self, changes: &TodoChanges) {
+        let mut received = changes.subscribe();
+        let queue = self.clone();
+        rt::spawn(async move {
+            loop {
+                match received.recv().await {
+                    Ok(change) => queue.enqueue(&change).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "webhooks missed todo changes")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        rt::spawn(async move {
+            let interval = Duration::from_millis(self.config.poll_interval_ms);
+            loop {
+                if self.deliver_due().await == 0 {
+                    rt::time::sleep(interval).await;
+                }
+            }
+        });
+    }
+}
+
+pub(super) fn configure() -> impl FnOnce(&mut ServiceConfig) {
+    |config: &mut ServiceConfig| {
+        config.service(
+            web::scope("/webhooks")
+                .route("", web::get().to(get_webhooks))
+                .route("", web::post().to(create_webhook))
+                .route("/{id}", web::get().to(get_webhook))
+                .route("/{id}", web::delete().to(delete_webhook))
+                .route("/{id}/deliveries", web::get().to(get_deliveries)),
+        );
+    }
+}
+
+/// List webhooks.
+///
+/// List the urls subscribed to todo events, oldest first. Secrets are never returned.
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "List current webhooks", body = [Webhook])
+    )
+)]
+async fn get_webhooks(store: Data<dyn WebhookStore>) -> impl Responder {
+    let webhooks: Vec<Webhook> = store
+        .read_webhooks()
+        .await
+        .into_iter()
+        .map(|subscription| subscription.webhook)
+        .collect();
+    Negotiated(webhooks)
+}
+
+/// Subscribe an url to todo events.
+///
+/// The url is posted the events listed, as JSON, with the headers `X-Webhook-Event`,
+/// `X-Webhook-Delivery`, `X-Webhook-Timestamp` and `X-Webhook-Signature`, the latter being
+/// `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}` keyed by `secret`. Deliveries
+/// not answered with a 2xx are retried with an exponential backoff, then dead-lettered.
+/// Urls must be http or https, and resolve to public addresses unless their host is allowed in
+/// `webhooks.yml`. Retries sent with the `Idempotency-Key` of the first attempt get its response
+/// replayed.
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = WebhookRequest,
+    responses(
+        (status = 201, description = "Webhook created successfully", body = Webhook),
+        (status = 400, description = "Malformed or invalid WebhookRequest", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 409, description = "A request with the same Idempotency-Key is being processed", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 413, description = "Body larger than 16KiB", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 415, description = "Body in an encoding other than JSON, MessagePack or CBOR", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse))),
+        (status = 422, description = "Idempotency-Key already used for another request", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse)))
+    ),
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Key of the request, for its retries to get its response replayed rather than be processed again")
+    )
+)]
+async fn create_webhook(
+    request: Validated<WebhookRequest>,
+    store: Data<dyn WebhookStore>,
+    config: Data<WebhookConfig>,
+) -> impl Responder {
+    let request = request.into_inner();
+    if let Err(reason) = check_url(&request.url, &config.allowed_hosts).await {
+        return Either::Right(ErrorResponse::Validation(vec![FieldError {
+            field: String::from("url"),
+            messages: vec![reason],
+        }]));
+    }
+    let subscription = Subscription {
+        webhook: Webhook {
+            id: Uuid::new_v4().to_string(),
+            url: request.url,
+            events: request.events,
+            created_at: now(),
+        },
+        secret: request.secret,
+    };
+    store.create_webhook(&subscription).await;
+    Either::Left(
+        Negotiated(subscription.webhook)
+            .customize()
+            .with_status(StatusCode::CREATED),
+    )
+}
+
+/// Get webhook by given id.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Webhook found", body = Webhook),
+        (status = 404, description = "Webhook not found by id", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse)))
+    ),
+    params(
+        ("id", description = "Unique id of the webhook")
+    )
+)]
+async fn get_webhook(id: Path<String>, store: Data<dyn WebhookStore>) -> impl Responder {
+    match store.read_webhook(&id).await {
+        Ok(subscription) => Either::Left(Negotiated(subscription.webhook)),
+        Err(()) => Either::Right(ErrorResponse::WebhookNotFound(format!("id = {id}"))),
+    }
+}
+
+/// Delete webhook by given id.
+///
+/// Its pending deliveries are dropped, and its delivery log with them.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Webhook deleted successfully"),
+        (status = 404, description = "Webhook not found by id", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse)))
+    ),
+    params(
+        ("id", description = "Unique id of the webhook")
+    )
+)]
+async fn delete_webhook(id: Path<String>, store: Data<dyn WebhookStore>) -> impl Responder {
+    match store.delete_webhook(&id).await {
+        Ok(()) => Either::Left(HttpResponse::Ok().finish()),
+        Err(()) => Either::Right(ErrorResponse::WebhookNotFound(format!("id = {id}"))),
+    }
+}
+
+/// Delivery log of a webhook.
+///
+/// Every delivery of the webhook, latest first: pending ones with their next attempt, delivered
+/// ones, and dead-lettered ones with the error of their last attempt.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Deliveries of the webhook", body = [Delivery]),
+        (status = 404, description = "Webhook not found by id", content(("application/problem+json" = Problem), ("application/json" = ErrorResponse)))
+    ),
+    params(
+        ("id", description = "Unique id of the webhook")
+    )
+)]
+async fn get_deliveries(id: Path<String>, store: Data<dyn WebhookStore>) -> impl Responder {
+    if store.read_webhook(&id).await.is_err() {
+        return Either::Right(ErrorResponse::WebhookNotFound(format!("id = {id}")));
+    }
+    Either::Left(Negotiated(store.read_deliveries(&id).await))
+}
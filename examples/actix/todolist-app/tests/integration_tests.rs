@@ -1,7 +1,5 @@
-use example::schemas::Todo;
-use reqwest;
 use rstest::rstest;
-use serde_json;
+use todolist_client::{blocking::TodoClient, Todo};
 
 // Integration test(s)
 
@@ -14,49 +12,24 @@ use serde_json;
 #[rstest]
 fn test_bin() {
     // Perform a few queries and check results semantically.
-    let client = reqwest::blocking::Client::new();
-    let resp = client.get("http://localhost:8080/health").send().unwrap();
+    let client = TodoClient::builder("http://localhost:8080").build().unwrap();
+    client.health().unwrap();
 
-    assert_eq!(resp.status(), 200);
-
-    let resp = client.get("http://localhost:8080/todo").send().unwrap();
-    assert_eq!(resp.status(), 200);
-    assert_eq!(resp.text().unwrap(), "[]");
+    assert_eq!(client.list_todos().unwrap(), vec![]);
 
     let todo: Todo = Todo {
         id: 60,
         value: "test value".to_string(),
         checked: false,
     };
-    let resp = client
-        .post("http://localhost:8080/todo")
-        .body(serde_json::to_string(&todo).unwrap())
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .send()
-        .unwrap();
-    assert_eq!(resp.status(), 201);
-
-    let resp = client
-        .get("http://localhost:8080/todo/search")
-        .query(&[("value", "test")])
-        .send()
-        .unwrap();
-    assert_eq!(resp.status(), 200);
-    let res: Vec<Todo> = serde_json::from_slice::<Vec<Todo>>(&resp.bytes().unwrap()).unwrap();
+    assert_eq!(client.create_todo(&todo).unwrap(), todo);
+
+    let res = client.search_todos("test").unwrap();
 
     assert_eq!(res.len(), 1);
-    assert_eq!(res.get(0).unwrap(), &todo);
-
-    let resp = client.get("http://localhost:8080/todo/60").send().unwrap();
-    assert_eq!(resp.status(), 200);
-    assert_eq!(
-        &serde_json::from_slice::<Todo>(&resp.bytes().unwrap()).unwrap(),
-        &todo
-    );
-
-    let resp = client
-        .delete("http://localhost:8080/todo/60")
-        .send()
-        .unwrap();
-    assert_eq!(resp.status(), 200);
+    assert_eq!(res.first().unwrap(), &todo);
+
+    assert_eq!(client.get_todo(60).unwrap(), todo);
+
+    client.delete_todo(60).unwrap();
 }
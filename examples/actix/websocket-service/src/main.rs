@@ -1,11 +1,13 @@
 use actix::*;
 use actix_web::{
-    middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    http::StatusCode, middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer,
+    Responder,
 };
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+mod problem;
 mod server;
 mod session;
 
@@ -14,19 +16,24 @@ async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::NotifyServer>>,
-    user_id: web::Query<ConnectId>,
+    user_id: Option<web::Query<ConnectId>>,
 ) -> Result<HttpResponse, Error> {
-    /*
-    let opt_user_id: Option<&actix_web::http::header::HeaderValue> = req.headers().get("X-User");
-    if opt_user_id.is_none() {
-        return Err(actix_web::error::ErrorUnauthorized("Needs authentication"));
-    }
-    let user_id: uuid::Uuid =
-        uuid::Uuid::try_parse(opt_user_id.unwrap().to_str().unwrap()).unwrap();
-    */
+    // Behind the gateway, the user is the one of the session, sent in `X-User`
+    let gateway_user = req
+        .headers()
+        .get("X-User")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| uuid::Uuid::try_parse(value).ok());
+    let Some(id) = gateway_user.or(user_id.map(|query| query.into_inner().user_id)) else {
+        return Ok(problem::error_response(
+            &req,
+            StatusCode::UNAUTHORIZED,
+            "Needs authentication",
+        ));
+    };
     ws::start(
         session::WsNotifySession {
-            id: user_id.into_inner().user_id,
+            id,
             path: req.path().to_owned(),
             hb: Instant::now(),
             addr: srv.get_ref().clone(),
@@ -59,13 +66,21 @@ async fn push_route(
     post_param: web::Json<PostData>,
     req: HttpRequest,
 ) -> impl Responder {
-    let _ = srv
+    let sent = srv
         .send(PushData {
             post: post_param.0,
             path: req.path().to_owned(),
         })
         .await;
-    actix_web::HttpResponse::Ok()
+    match sent {
+        Ok(()) => HttpResponse::Ok().finish(),
+        // The notify server is stopping, or its mailbox is full
+        Err(err) => problem::error_response(
+            &req,
+            StatusCode::SERVICE_UNAVAILABLE,
+            &format!("Could not push the message: {err}"),
+        ),
+    }
 }
 
 #[actix_web::main]
@@ -77,15 +92,54 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("starting HTTP server at http://localhost:8080");
 
-    HttpServer::new(move || {
+    let notify_server = server.clone();
+    let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(server.clone()))
+            .app_data(problem::json_config())
             .route("/{tail:.*}", web::post().to(push_route))
             .route("/{tail:.*}", web::get().to(ws_route))
             .wrap(Logger::default())
     })
     .workers(2)
+    // Signals are handled below, to close the websockets before the server waits for them
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout().as_secs())
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    let handle = http_server.handle();
+    actix_web::rt::spawn(async move {
+        termination_signal().await;
+        log::info!("shutdown requested, closing websocket sessions");
+        let _ = notify_server.send(server::Shutdown).await;
+        handle.stop(true).await;
+    });
+    http_server.await
+}
+
+/// Deadline given to connections to close once the shutdown started, `SHUTDOWN_TIMEOUT_SECS`
+/// (30 by default).
+fn shutdown_timeout() -> Duration {
+    let seconds = std::env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+/// Resolve on SIGTERM, sent by docker and kubernetes, or on Ctrl-C.
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen to SIGTERM.");
+        futures_util::future::select(
+            Box::pin(sigterm.recv()),
+            Box::pin(actix_web::rt::signal::ctrl_c()),
+        )
+        .await;
+    }
+    #[cfg(not(unix))]
+    let _ = actix_web::rt::signal::ctrl_c().await;
 }
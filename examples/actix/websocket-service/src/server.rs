@@ -9,12 +9,18 @@ use crate::PushData;
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// Ask a session to close its websocket, with the given reason.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Close(pub String);
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub id: Uuid,
     pub path: String,
     pub addr: Recipient<Message>,
+    pub close: Recipient<Close>,
 }
 
 #[derive(Message)]
@@ -23,6 +29,11 @@ pub struct Disconnect {
     pub id: Uuid,
 }
 
+/// Close every session, before the HTTP server stops.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
 struct PathNode {
     path: String,
     clients: HashMap<Uuid, Recipient<Message>>,
@@ -116,6 +127,8 @@ impl PathNode {
 
 pub struct NotifyServer {
     sessions: PathNode,
+    // Flat view of the sessions, only needed to close them all on shutdown
+    closers: HashMap<Uuid, Recipient<Close>>,
 }
 
 impl NotifyServer {
@@ -126,6 +139,7 @@ impl NotifyServer {
                 clients: HashMap::new(),
                 children: Vec::new(),
             },
+            closers: HashMap::new(),
         }
     }
 }
@@ -139,6 +153,7 @@ impl Handler<Connect> for NotifyServer {
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
         self.sessions.add_node_to_path(&msg.path, msg.id, &msg.addr);
+        self.closers.insert(msg.id, msg.close);
     }
 }
 
@@ -147,6 +162,18 @@ impl Handler<Disconnect> for NotifyServer {
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
         self.sessions.remove_client(&msg.id);
+        self.closers.remove(&msg.id);
+    }
+}
+
+impl Handler<Shutdown> for NotifyServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, _: &mut Context<Self>) {
+        log::info!("closing {} websocket sessions", self.closers.len());
+        for close in self.closers.values() {
+            close.do_send(Close("server shutting down".to_owned()));
+        }
     }
 }
 
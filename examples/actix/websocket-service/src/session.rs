@@ -46,7 +46,8 @@ impl Actor for WsNotifySession {
             .send(server::Connect {
                 id: self.id,
                 path: self.path.clone(),
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
             })
             .into_actor(self)
             .then(|_res, act, ctx| {
@@ -75,6 +76,19 @@ impl Handler<server::Message> for WsNotifySession {
     }
 }
 
+impl Handler<server::Close> for WsNotifySession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Close, ctx: &mut Self::Context) {
+        // Going away tells clients they can reconnect, to another instance
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsNotifySession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
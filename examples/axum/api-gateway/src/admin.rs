@@ -0,0 +1,357 @@
+use axum::{
+    extract::{Path, State, TypedHeader},
+    headers::Cookie,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::authn::{self, credential, NewApiKey, NewClient};
+use crate::authz::valid_role;
+use crate::problem::error_response;
+use crate::rest::authenticate;
+use crate::stores::config::{get_config, reload_config};
+use crate::{DynCache, DynUserRepo};
+
+/// Error response unless the request is made by an admin, with a session, a bearer token or an
+/// API key scoped to `admin`.
+async fn require_admin(
+    state_repo: &DynUserRepo,
+    cache: &DynCache,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> Result<(), Response> {
+    let cookie = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get("session"));
+    let credential = credential(headers, cookie);
+    match authenticate(state_repo, cache, credential).await {
+        Some((user, _)) if user.is_admin() => Ok(()),
+        Some(_) => Err(error_response(
+            headers,
+            uri.path(),
+            StatusCode::FORBIDDEN,
+            "Insuficient permissions",
+        )),
+        None => Err(error_response(
+            headers,
+            uri.path(),
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+        )),
+    }
+}
+
+/// Configuration in use, along with its version.
+///
+/// ```text
+/// curl --cookie session=... localhost:8080/admin/config
+/// ```
+pub(crate) async fn active_config(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    Json(&*get_config()).into_response()
+}
+
+/// Reload the configuration file, answering the version in use afterwards, or why the file was
+/// rejected.
+pub(crate) async fn reload(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    match reload_config("admin") {
+        Ok(version) => Json(version).into_response(),
+        Err(err) => error_response(
+            &headers,
+            uri.path(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &err.to_string(),
+        ),
+    }
+}
+
+/// Roles of a user, the `admin` flag being set apart from them.
+#[derive(Serialize)]
+struct UserRoles {
+    id: String,
+    admin: bool,
+    roles: Vec<String>,
+}
+
+/// Roles of the user of `id`, along with the users repository, read again rather than from the
+/// cache for the roles to be up to date.
+async fn roles_response(
+    state_repo: &DynUserRepo,
+    cache: &DynCache,
+    headers: &HeaderMap,
+    uri: &Uri,
+    id: Uuid,
+) -> Response {
+    match state_repo.get_user(id).await {
+        Some(user) => {
+            // Other gateway instances see the change once their cache entry expires
+            let _res = cache.create_user(&user).await;
+            Json(UserRoles {
+                id: user.id.to_string(),
+                admin: user.admin,
+                roles: user.roles,
+            })
+            .into_response()
+        }
+        None => error_response(headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user"),
+    }
+}
+
+/// ```text
+/// curl --cookie session=... localhost:8080/admin/users/{id}/roles
+/// ```
+pub(crate) async fn user_roles(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path(id): Path<String>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    };
+    roles_response(&state_repo, &cache, &headers, &uri, id).await
+}
+
+/// Grant a role to a user, answering its roles.
+///
+/// ```text
+/// curl -X PUT --cookie session=... localhost:8080/admin/users/{id}/roles/editor
+/// ```
+pub(crate) async fn grant_role(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path((id, role)): Path<(String, String)>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    };
+    if !valid_role(&role) {
+        let detail = "Roles are 1 to 64 letters, digits or `_-.:`";
+        return error_response(&headers, uri.path(), StatusCode::BAD_REQUEST, detail);
+    }
+    if state_repo.get_user(id).await.is_none() {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    }
+    if state_repo.grant_role(id, &role).await.is_err() {
+        let detail = "Could not grant the role";
+        return error_response(
+            &headers,
+            uri.path(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            detail,
+        );
+    }
+    tracing::info!(user = %id, role, "role granted");
+    roles_response(&state_repo, &cache, &headers, &uri, id).await
+}
+
+/// Revoke a role of a user, answering its roles.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8080/admin/users/{id}/roles/editor
+/// ```
+pub(crate) async fn revoke_role(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path((id, role)): Path<(String, String)>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    };
+    if state_repo.revoke_role(id, &role).await.is_err() {
+        let detail = "Could not revoke the role";
+        return error_response(
+            &headers,
+            uri.path(),
+            StatusCode::SERVICE_UNAVAILABLE,
+            detail,
+        );
+    }
+    tracing::info!(user = %id, role, "role revoked");
+    roles_response(&state_repo, &cache, &headers, &uri, id).await
+}
+
+/// API keys of a user, without the keys.
+///
+/// ```text
+/// curl --cookie session=... localhost:8080/admin/users/{id}/api-keys
+/// ```
+pub(crate) async fn api_keys(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path(id): Path<String>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    };
+    match authn::list_api_keys(state_repo.as_ref(), id).await {
+        Ok(keys) => Json(keys).into_response(),
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Create an API key for a user, answering the key, which is not shown again.
+///
+/// ```text
+/// curl --cookie session=... localhost:8080/admin/users/{id}/api-keys \
+///     -H 'Content-Type: application/json' \
+///     -d '{"name": "backup script", "scopes": ["editor"], "expires_in_days": 90}'
+/// ```
+pub(crate) async fn create_api_key(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path(id): Path<String>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(request): Json<NewApiKey>,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let Ok(id) = Uuid::parse_str(&id) else {
+        return error_response(&headers, uri.path(), StatusCode::NOT_FOUND, "Unknown user");
+    };
+    match authn::create_api_key(state_repo.as_ref(), id, request).await {
+        Ok(key) => {
+            tracing::info!(user = %id, key = key.info.id, "API key created");
+            (StatusCode::CREATED, Json(key)).into_response()
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Delete an API key of a user, which stops working at once.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8080/admin/users/{id}/api-keys/{key}
+/// ```
+pub(crate) async fn delete_api_key(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path((id, key)): Path<(String, String)>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    let (Ok(id), Ok(key)) = (Uuid::parse_str(&id), Uuid::parse_str(&key)) else {
+        return error_response(
+            &headers,
+            uri.path(),
+            StatusCode::NOT_FOUND,
+            "Unknown API key",
+        );
+    };
+    match authn::delete_api_key(state_repo.as_ref(), id, key).await {
+        Ok(()) => {
+            tracing::info!(user = %id, %key, "API key deleted");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Create a machine client and its service principal, answering the client secret, which is not
+/// shown again.
+///
+/// ```text
+/// curl --cookie session=... localhost:8080/admin/clients -H 'Content-Type: application/json' \
+///     -d '{"name": "billing", "roles": ["invoicer"]}'
+/// ```
+pub(crate) async fn create_client(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(request): Json<NewClient>,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    match authn::create_client(state_repo.as_ref(), request).await {
+        Ok(client) => {
+            tracing::info!(
+                client = client.client_id,
+                user = client.user_id,
+                "client created"
+            );
+            (StatusCode::CREATED, Json(client)).into_response()
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Delete a machine client, revoking the tokens it was issued.
+///
+/// ```text
+/// curl -X DELETE --cookie session=... localhost:8080/admin/clients/{client_id}
+/// ```
+pub(crate) async fn delete_client(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    Path(client_id): Path<String>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    if let Err(response) = require_admin(&state_repo, &cache, cookie, &headers, &uri).await {
+        return response;
+    }
+    match authn::delete_client(state_repo.as_ref(), &client_id).await {
+        Ok(principal) => {
+            // Other gateway instances see the revocation once their cache entry expires
+            let _res = cache.create_user(&principal).await;
+            tracing::info!(client = %client_id, "client deleted");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
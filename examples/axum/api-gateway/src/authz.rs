@@ -0,0 +1,365 @@
+//! Authorization of the requests to the routes, on the roles of the users, the permissions the
+//! `roles` section of `routes.yml` grants to them and the paths they request.
+
+use std::collections::HashMap;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::authn::AuthMethod;
+use crate::routing::Captures;
+use crate::schemas::User;
+use crate::stores::config::Route;
+
+/// Role of the `roles` section of `routes.yml`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoleConfig {
+    /// Permissions granted to the users with the role, e.g. `todos:write`.
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+/// Whether users need one or every role of a rule.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleMatch {
+    #[default]
+    AnyOf,
+    AllOf,
+}
+
+/// What users need to be served by a route, its `require` entry in `routes.yml`. Admins are
+/// served by every route.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessRule {
+    /// Roles of the users served, any user when empty.
+    pub roles: Vec<String>,
+    #[serde(rename = "match")]
+    pub mode: RoleMatch,
+    /// Permissions the roles of the users need to grant, every one of them.
+    pub permissions: Vec<String>,
+    /// Conditions on the path, every one of them.
+    pub conditions: Vec<Condition>,
+}
+
+/// Condition on a value the route captures from the path, e.g. for users to only reach their
+/// own resources under `/users/{id}`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub capture: String,
+    pub equals: UserAttribute,
+}
+
+/// Attribute of the user a capture is compared with.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAttribute {
+    /// Id of the user.
+    UserId,
+    /// Any role of the user, e.g. teams given as roles.
+    Role,
+}
+
+impl AccessRule {
+    /// Check the rule against the names of the values the route captures.
+    pub fn check(&self, captures: &[String]) -> Result<(), String> {
+        if let Some(role) = self.roles.iter().find(|role| !valid_role(role)) {
+            return Err(format!("`{role}` is not a valid role"));
+        }
+        if self.mode == RoleMatch::AllOf && self.roles.is_empty() {
+            return Err("`match: all_of` requires listing `roles`".to_owned());
+        }
+        if let Some(condition) = self
+            .conditions
+            .iter()
+            .find(|condition| !captures.contains(&condition.capture))
+        {
+            return Err(format!(
+                "condition on `{}`, which the route does not capture",
+                condition.capture
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether `user` is served, `roles` granting the permissions and `captures` being the
+    /// values captured from the path.
+    pub fn allows(
+        &self,
+        user: &User,
+        roles: &HashMap<String, RoleConfig>,
+        captures: &Captures,
+    ) -> bool {
+        if user.is_admin() {
+            return true;
+        }
+        let has_roles = match self.mode {
+            _ if self.roles.is_empty() => true,
+            RoleMatch::AnyOf => self.roles.iter().any(|role| user.has_role(role)),
+            RoleMatch::AllOf => self.roles.iter().all(|role| user.has_role(role)),
+        };
+        let granted = |permission: &String| {
+            user.roles
+                .iter()
+                .filter_map(|role| roles.get(role))
+                .any(|role| role.permissions.contains(permission))
+        };
+        let conditions = self.conditions.iter().all(|condition| {
+            let Some((_, value)) = captures.iter().find(|(name, _)| *name == condition.capture)
+            else {
+                return false;
+            };
+            match condition.equals {
+                UserAttribute::UserId => *value == user.id.to_string(),
+                UserAttribute::Role => user.has_role(value),
+            }
+        });
+        has_roles && self.permissions.iter().all(granted) && conditions
+    }
+}
+
+impl Route {
+    /// Rule of the requests with `method`, the one of `require_by_method` when listed, `HEAD`
+    /// going with `GET`.
+    pub fn access_rule(&self, method: &str) -> Option<&AccessRule> {
+        let method = if method == "HEAD" && !self.require_by_method.contains_key("HEAD") {
+            "GET"
+        } else {
+            method
+        };
+        self.require_by_method.get(method).or(self.require.as_ref())
+    }
+
+    /// Whether `user` is served with `method`, `captures` being the values captured from the
+    /// path.
+    pub fn authorizes(
+        &self,
+        user: &User,
+        method: &str,
+        roles: &HashMap<String, RoleConfig>,
+        captures: &Captures,
+    ) -> bool {
+        if self.restrict_admin && !user.is_admin() {
+            return false;
+        }
+        self.access_rule(method)
+            .is_none_or(|rule| rule.allows(user, roles, captures))
+    }
+}
+
+/// Whether `role` may be granted: 1 to 64 letters, digits or `_-.:`.
+pub fn valid_role(role: &str) -> bool {
+    (1..=64).contains(&role.len())
+        && role
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+}
+
+/// Roles of the user sent to the services, comma separated.
+pub const ROLES_HEADER: &str = "x-user-roles";
+/// How the user authenticated, e.g. `session` or `api_key`.
+pub const AUTH_METHOD_HEADER: &str = "x-auth-method";
+/// `t={seconds since the epoch},v1={signature}`, the signature being the hex encoded HMAC-SHA256
+/// of `{t}.{X-User}.{X-User-Roles}.{X-Auth-Method}` with `UPSTREAM_SIGNING_KEY`.
+pub const SIGNATURE_HEADER: &str = "x-user-signature";
+
+lazy_static! {
+    // Shared with the services, unlike the key of the sessions
+    static ref SIGNING_KEY: Option<Vec<u8>> = env::var("UPSTREAM_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes);
+}
+
+/// Headers telling the services the roles of `user` and how it authenticated, signed when
+/// `UPSTREAM_SIGNING_KEY` is set for the services to check they come from the gateway.
+pub fn identity_headers(user: &User, method: AuthMethod) -> HeaderMap {
+    let mut roles: Vec<&str> = user.roles.iter().map(String::as_str).collect();
+    if user.admin && !user.has_role("admin") {
+        roles.push("admin");
+    }
+    let roles = roles.join(",");
+    let mut headers = HeaderMap::new();
+    // Roles are checked to be visible ASCII when granted
+    if let Ok(value) = HeaderValue::from_str(&roles) {
+        headers.insert(HeaderName::from_static(ROLES_HEADER), value);
+    }
+    headers.insert(
+        HeaderName::from_static(AUTH_METHOD_HEADER),
+        HeaderValue::from_static(method.as_str()),
+    );
+    if let Some(key) = SIGNING_KEY.as_ref() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        let signed = format!("{timestamp}.{}.{roles}.{}", user.id, method.as_str());
+        mac.update(signed.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        let value = HeaderValue::from_str(&format!("t={timestamp},v1={signature}")).unwrap();
+        headers.insert(HeaderName::from_static(SIGNATURE_HEADER), value);
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn user(admin: bool, roles: &[&str]) -> User {
+        User {
+            id: Uuid::new_v4(),
+            admin,
+            session_version: 0,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn rule(yaml: &str) -> AccessRule {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn route(yaml: &str) -> Route {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn roles() -> HashMap<String, RoleConfig> {
+        serde_yaml::from_str(
+            "editor: {permissions: ['todos:write']}\nviewer: {permissions: ['todos:read']}",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let captures = ["id".to_owned()];
+        assert!(rule("roles: [editor]").check(&captures).is_ok());
+        assert!(rule("roles: ['not a role']").check(&captures).is_err());
+        assert!(rule("match: all_of").check(&captures).is_err());
+        let conditions = "conditions: [{capture: id, equals: user_id}]";
+        assert!(rule(conditions).check(&captures).is_ok());
+        assert!(rule(conditions).check(&[]).is_err());
+        assert!(serde_yaml::from_str::<AccessRule>("role: [editor]").is_err());
+    }
+
+    #[test]
+    fn test_role_match() {
+        let any_of = rule("roles: [editor, viewer]");
+        let all_of = rule("roles: [editor, viewer]\nmatch: all_of");
+        let editor = user(false, &["editor"]);
+        let both = user(false, &["editor", "viewer"]);
+        let nobody = user(false, &[]);
+        assert!(any_of.allows(&editor, &roles(), &Vec::new()));
+        assert!(!any_of.allows(&nobody, &roles(), &Vec::new()));
+        assert!(!all_of.allows(&editor, &roles(), &Vec::new()));
+        assert!(all_of.allows(&both, &roles(), &Vec::new()));
+        // Any user without roles listed
+        assert!(rule("{}").allows(&nobody, &roles(), &Vec::new()));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let write = rule("permissions: ['todos:write']");
+        assert!(write.allows(&user(false, &["editor"]), &roles(), &Vec::new()));
+        assert!(!write.allows(&user(false, &["viewer"]), &roles(), &Vec::new()));
+        let both = rule("permissions: ['todos:read', 'todos:write']");
+        assert!(!both.allows(&user(false, &["editor"]), &roles(), &Vec::new()));
+        assert!(both.allows(&user(false, &["editor", "viewer"]), &roles(), &Vec::new()));
+        // Roles missing from the section grant nothing
+        assert!(!write.allows(&user(false, &["unknown"]), &roles(), &Vec::new()));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let own = rule("conditions: [{capture: id, equals: user_id}]");
+        let member = rule("conditions: [{capture: team, equals: role}]");
+        let alice = user(false, &["blue"]);
+        let captures = |name: &str, value: &str| vec![(name.to_owned(), value.to_owned())];
+        assert!(own.allows(&alice, &roles(), &captures("id", &alice.id.to_string())));
+        let other = Uuid::new_v4().to_string();
+        assert!(!own.allows(&alice, &roles(), &captures("id", &other)));
+        assert!(!own.allows(&alice, &roles(), &Vec::new()));
+        assert!(member.allows(&alice, &roles(), &captures("team", "blue")));
+        assert!(!member.allows(&alice, &roles(), &captures("team", "red")));
+    }
+
+    #[test]
+    fn test_admin_bypass() {
+        let strict = rule(
+            "roles: [editor]\npermissions: ['todos:write']\n\
+            conditions: [{capture: id, equals: user_id}]",
+        );
+        let captures = vec![("id".to_owned(), Uuid::new_v4().to_string())];
+        assert!(strict.allows(&user(true, &[]), &roles(), &captures));
+        assert!(strict.allows(&user(false, &["admin"]), &roles(), &captures));
+        assert!(!strict.allows(&user(false, &["editor"]), &roles(), &captures));
+    }
+
+    #[test]
+    fn test_rule_by_method() {
+        let route = route(
+            "methods: [GET, POST]\nservice: todos:80\nrequire: {roles: [viewer]}\n\
+            require_by_method: {POST: {roles: [editor]}}",
+        );
+        let viewer = user(false, &["viewer"]);
+        let editor = user(false, &["editor"]);
+        assert!(route.authorizes(&viewer, "GET", &roles(), &Vec::new()));
+        assert!(!route.authorizes(&viewer, "POST", &roles(), &Vec::new()));
+        assert!(route.authorizes(&editor, "POST", &roles(), &Vec::new()));
+        assert!(!route.authorizes(&editor, "GET", &roles(), &Vec::new()));
+        // `HEAD` goes with `GET`, unless listed
+        assert!(route.authorizes(&viewer, "HEAD", &roles(), &Vec::new()));
+        assert!(!route.authorizes(&editor, "HEAD", &roles(), &Vec::new()));
+        let route = Route {
+            require_by_method: HashMap::from([("HEAD".to_owned(), AccessRule::default())]),
+            ..route
+        };
+        assert!(route.authorizes(&editor, "HEAD", &roles(), &Vec::new()));
+    }
+
+    #[test]
+    fn test_restrict_admin() {
+        let route = route("methods: [GET]\nservice: admin:80\nrestrict_admin: true");
+        assert!(!route.authorizes(&user(false, &["editor"]), "GET", &roles(), &Vec::new()));
+        assert!(route.authorizes(&user(true, &[]), "GET", &roles(), &Vec::new()));
+        assert!(route.authorizes(&user(false, &["admin"]), "GET", &roles(), &Vec::new()));
+    }
+
+    #[test]
+    fn test_identity_headers() {
+        // Read once, before any header is signed
+        env::set_var("UPSTREAM_SIGNING_KEY", "shared with the services");
+        let admin = user(true, &["editor"]);
+        let headers = identity_headers(&admin, AuthMethod::ApiKey);
+        assert_eq!(headers.get(ROLES_HEADER).unwrap(), "editor,admin");
+        assert_eq!(headers.get(AUTH_METHOD_HEADER).unwrap(), "api_key");
+
+        // Verified as the services do
+        let signature = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap();
+        let (timestamp, signature) = signature.split_once(',').unwrap();
+        let timestamp = timestamp.strip_prefix("t=").unwrap();
+        let signature = hex::decode(signature.strip_prefix("v1=").unwrap()).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now - timestamp.parse::<u64>().unwrap() < 5);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"shared with the services").unwrap();
+        mac.update(format!("{timestamp}.{}.editor,admin.api_key", admin.id).as_bytes());
+        assert!(mac.verify_slice(&signature).is_ok());
+
+        let mut forged = Hmac::<Sha256>::new_from_slice(b"shared with the services").unwrap();
+        forged.update(format!("{timestamp}.{}.editor,admin.session", admin.id).as_bytes());
+        assert!(forged.verify_slice(&signature).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::task::{Context, Poll};
+
+use axum::{
+    http::{
+        header::{
+            HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        Method, Request, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::problem::error_response;
+use crate::routing::RequestInfo;
+use crate::stores::config::{get_config, Config};
+
+/// Cross-origin requests of browsers, in the `cors` section of `routes.yml`. No origin is allowed
+/// by default.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the gateway, e.g. `https://app.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Request headers allowed on top of the CORS-safelisted ones, or `*` for any.
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the scripts on top of the CORS-safelisted ones.
+    pub exposed_headers: Vec<String>,
+    /// Whether the session cookie is sent along with the requests.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.allowed_headers
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// Headers granting `origin` access to a response.
+fn set_headers(headers: &mut HeaderMap, config: &CorsConfig, origin: &HeaderValue) {
+    // Origins are echoed rather than `*`, which browsers refuse along with credentials
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+    if config.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if !config.exposed_headers.is_empty() {
+        headers.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            header_value(&config.exposed_headers.join(", ")),
+        );
+    }
+}
+
+/// Preflight response, the requested method and headers being checked against the
+/// routes matching the path and the configuration.
+fn preflight(config: &Config, headers: &HeaderMap, path: &str, requested_method: &str) -> Response {
+    let methods = config.allowed_methods(&RequestInfo::new("OPTIONS", path, "", headers));
+    let requested_headers = headers
+        .get(ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|headers| headers.to_str().ok())
+        .unwrap_or_default();
+    if !methods.iter().any(|method| method == requested_method) {
+        let detail = format!("{requested_method} is not allowed on this path");
+        return error_response(headers, path, StatusCode::FORBIDDEN, &detail);
+    }
+    if !config.cors.allows_headers(requested_headers) {
+        return error_response(
+            headers,
+            path,
+            StatusCode::FORBIDDEN,
+            "Request headers not allowed",
+        );
+    }
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        header_value(&methods.join(", ")),
+    );
+    if !requested_headers.is_empty() {
+        response_headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            header_value(requested_headers),
+        );
+    }
+    response_headers.insert(
+        ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from(config.cors.max_age_secs),
+    );
+    response
+}
+
+/// Layer answering the CORS preflight requests of the allowed origins, and granting them access
+/// to the responses of the gateway and of the proxied services.
+///
+/// Preflights are answered without authentication, browsers sending them without cookies, with
+/// the methods of the routes matching their path. Requests from other origins go through
+/// untouched, browsers refusing their responses to the scripts.
+#[derive(Clone)]
+pub struct CorsLayer;
+
+impl<S> Layer<S> for CorsLayer {
+    type Service = CorsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CorsService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CorsService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for CorsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // The service polled ready is the one to call, keep a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        // Kept along the request, for the preflight to see the origins it was allowed by
+        let config = get_config();
+        let origin = req
+            .headers()
+            .get(ORIGIN)
+            .filter(|origin| {
+                config
+                    .cors
+                    .allows_origin(origin.to_str().unwrap_or_default())
+            })
+            .cloned();
+
+        Box::pin(async move {
+            let Some(origin) = origin else {
+                return inner.call(req).await;
+            };
+            let requested_method = req
+                .headers()
+                .get(ACCESS_CONTROL_REQUEST_METHOD)
+                .and_then(|method| method.to_str().ok())
+                .filter(|_| req.method() == Method::OPTIONS);
+            let mut response = match requested_method {
+                Some(requested_method) => {
+                    let mut response =
+                        preflight(&config, req.headers(), req.uri().path(), requested_method);
+                    for vary in [
+                        "Access-Control-Request-Method",
+                        "Access-Control-Request-Headers",
+                    ] {
+                        response
+                            .headers_mut()
+                            .append(VARY, HeaderValue::from_static(vary));
+                    }
+                    response
+                }
+                None => inner.call(req).await?,
+            };
+            set_headers(response.headers_mut(), &config.cors, &origin);
+            Ok(response)
+        })
+    }
+}
@@ -1,18 +1,43 @@
 use std::{net::SocketAddr, sync::Arc};
 
+use tokio::sync::Notify;
+
+use crate::cors::CorsLayer;
+use crate::metrics::{MetricsLayer, PoolMetrics};
+use crate::ratelimit::{InMemoryBackend, RateLimitLayer};
+use crate::shutdown::{Readiness, ShutdownConfig};
+use crate::stores::config::watch_config;
 use crate::store_interface::{CacheRepository, Proxy, UserRepository};
 use stores::cache::InMemoryUser;
 use stores::http::RqClient;
 use stores::postgres::PostgresUser;
+use telemetry::RequestTracingLayer;
 
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+mod accounts;
+mod admin;
+mod authn;
+mod authz;
+mod balancer;
+mod cors;
+mod discovery;
+mod forwarding;
+mod metrics;
+mod oidc;
+mod problem;
+mod ratelimit;
+mod resilience;
 mod rest;
+mod routing;
 mod schemas;
+mod shutdown;
 mod store_interface;
+mod telemetry;
 mod stores {
     pub mod cache;
     pub mod config;
     pub mod http;
+    #[cfg(test)]
+    pub mod memory;
     pub mod postgres;
 }
 use crate::rest::configure;
@@ -29,6 +54,7 @@ pub struct AppState {
     user_repo: DynUserRepo,
     proxy: DynHttp,
     cache: DynCache,
+    readiness: Readiness,
 }
 
 // the api specific state
@@ -59,38 +85,83 @@ impl FromRef<AppState> for DynCache {
     }
 }
 
+impl FromRef<AppState> for Readiness {
+    fn from_ref(app_state: &AppState) -> Readiness {
+        app_state.readiness.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "example_error_handling_and_dependency_injection=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let tracer_provider = telemetry::init("api-gateway");
     // Local access to a dockerized postgres
 
     let postgres_user =
         PostgresUser::new("some_postgres", "postgres", "replacethisplease", "postgres").await;
     let _res = postgres_user.migrate().await.unwrap();
+    prometheus::register(Box::new(PoolMetrics::new(
+        "users",
+        postgres_user.pool.clone(),
+    )))
+    .expect("Could not register pool metrics.");
+    let pool = postgres_user.pool.clone();
+    // Reloaded as long as the watcher lives
+    let _watcher = watch_config()
+        .inspect_err(|err| tracing::warn!(%err, "could not watch the configuration file"))
+        .ok();
+    #[cfg(unix)]
+    tokio::spawn(stores::config::reload_on_hangup());
+    tokio::spawn(balancer::run_health_checks());
+    tokio::spawn(discovery::run_discovery());
 
     let state_repo = Arc::new(postgres_user) as DynUserRepo;
 
     let proxy = Arc::new(RqClient::new()) as DynHttp;
     let cache = InMemoryUser::new();
     let arc_cache = Arc::new(cache) as DynCache;
+    let readiness = Readiness::default();
+    let shutdown_config = ShutdownConfig::from_env();
 
     // Build our application with some routes
-    let app: Router = configure(Router::new()).with_state(AppState {
-        user_repo: state_repo,
-        proxy: proxy,
-        cache: arc_cache,
-    });
+    let app: Router = configure(Router::new())
+        .layer(RateLimitLayer::new(Arc::new(InMemoryBackend::default())))
+        // Outside of the rate limits, for their rejections to be readable by the scripts
+        .layer(CorsLayer)
+        .layer(MetricsLayer)
+        .layer(RequestTracingLayer)
+        .with_state(AppState {
+            user_repo: state_repo,
+            proxy,
+            cache: arc_cache,
+            readiness: readiness.clone(),
+        });
 
     // Run our application
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let draining = Arc::new(Notify::new());
+    let server = Server::bind(&addr)
+        // Peer addresses are the rate limiting key of anonymous clients
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown::graceful_shutdown(
+            readiness,
+            shutdown_config,
+            shutdown::termination_signal(),
+            draining.clone(),
+        ));
+    // Proxied calls in flight are drained like any other request, up to the deadline
+    tokio::select! {
+        res = server => res.unwrap(),
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(shutdown_config.drain_timeout).await;
+        } => tracing::warn!("drain deadline reached, dropping in-flight requests"),
+    }
+
+    // Nothing uses the database past this point
+    pool.close();
+
+    // Flush the spans not exported yet
+    if let Some(provider) = tracer_provider {
+        let _ = provider.shutdown();
+    }
 }
@@ -0,0 +1,217 @@
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::{
+    extract::MatchedPath,
+    http::{header::CONTENT_TYPE, Request, Response, StatusCode},
+    response::IntoResponse,
+};
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, TextEncoder,
+};
+use tower::{Layer, Service};
+
+// Metrics are registered once in the default prometheus registry, and shared by every task.
+lazy_static! {
+    pub static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests handled, by method, route pattern and status code.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency in seconds, by method and route pattern.",
+        &["method", "route"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_REQUEST_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "gateway_upstream_request_duration_seconds",
+        "Latency of proxied upstream calls in seconds, by route and upstream status code.",
+        &["route", "status"]
+    )
+    .unwrap();
+    pub static ref USER_CACHE_LOOKUPS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "gateway_user_cache_lookups_total",
+        "Number of user cache lookups, by result (hit or miss).",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref CONFIG_RELOADS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "gateway_config_reloads_total",
+        "Number of configuration reloads, by result (applied, unchanged or rejected).",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref CONFIG_VERSION: IntGauge = register_int_gauge!(
+        "gateway_config_version",
+        "Version of the configuration in use."
+    )
+    .unwrap();
+    pub static ref UPSTREAM_ENDPOINT_HEALTHY: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_upstream_endpoint_healthy",
+        "Whether an upstream endpoint passes its health checks, by upstream and endpoint.",
+        &["upstream", "endpoint"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_RETRIES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "gateway_upstream_retries_total",
+        "Number of upstream calls retried, by route.",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_CIRCUIT_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_upstream_circuit_state",
+        "Circuit breaker state of an upstream: 0 closed, 1 half-open, 2 open.",
+        &["upstream"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_EJECTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "gateway_upstream_ejections_total",
+        "Number of upstream endpoints ejected for failing requests, by upstream and endpoint.",
+        &["upstream", "endpoint"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_DISCOVERED_ENDPOINTS: IntGaugeVec = register_int_gauge_vec!(
+        "gateway_upstream_discovered_endpoints",
+        "Number of endpoints found by the discovery of an upstream, by upstream.",
+        &["upstream"]
+    )
+    .unwrap();
+}
+
+// Expose every registered metric in the Prometheus text format.
+pub async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, encoder.format_type().to_owned())],
+            buffer,
+        )
+            .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Tower layer counting requests and measuring their latency.
+///
+/// Requests are labelled with the matched route pattern (`/*path`) rather than the raw path,
+/// so that the number of series stays bounded whatever the clients send. Proxied calls are all
+/// matched by the same pattern; see `UPSTREAM_REQUEST_DURATION_SECONDS` for a per route breakdown.
+#[derive(Clone, Default)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let method = req.method().to_string();
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_owned())
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let start = Instant::now();
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            HTTP_REQUESTS_TOTAL
+                .with_label_values(&[&method, &route, res.status().as_str()])
+                .inc();
+            HTTP_REQUEST_DURATION_SECONDS
+                .with_label_values(&[&method, &route])
+                .observe(start.elapsed().as_secs_f64());
+            Ok(res)
+        })
+    }
+}
+
+/// Gauges reporting the state of a deadpool connection pool.
+///
+/// Values are read from the pool at scrape time, so there is nothing to update on the request path.
+pub struct PoolMetrics {
+    pool: deadpool_postgres::Pool,
+    max_size: IntGauge,
+    size: IntGauge,
+    available: IntGauge,
+    waiting: IntGauge,
+}
+
+impl PoolMetrics {
+    pub fn new(name: &str, pool: deadpool_postgres::Pool) -> Self {
+        let gauge = |metric: &str, help: &str| {
+            IntGauge::with_opts(Opts::new(metric, help).const_label("pool", name)).unwrap()
+        };
+        Self {
+            pool,
+            max_size: gauge(
+                "db_pool_max_size",
+                "Maximum number of connections of the pool.",
+            ),
+            size: gauge("db_pool_size", "Current number of connections of the pool."),
+            available: gauge("db_pool_available", "Idle connections of the pool."),
+            waiting: gauge(
+                "db_pool_waiting",
+                "Number of requests waiting for a connection of the pool.",
+            ),
+        }
+    }
+}
+
+impl Collector for PoolMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.max_size.desc(),
+            self.size.desc(),
+            self.available.desc(),
+            self.waiting.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let status = self.pool.status();
+        self.max_size.set(status.max_size as i64);
+        self.size.set(status.size as i64);
+        self.available.set(status.available as i64);
+        self.waiting.set(status.waiting as i64);
+        [
+            self.max_size.collect(),
+            self.size.collect(),
+            self.available.collect(),
+            self.waiting.collect(),
+        ]
+        .concat()
+    }
+}
@@ -0,0 +1,482 @@
+//! Login with the accounts of an OpenID Connect provider, following the authorization code flow
+//! with PKCE.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+
+use jwt_simple::prelude::{
+    Claims, JWTClaims, MACLike, NoCustomClaims, RS256PublicKey, RSAPublicKeyLike, Token,
+    VerificationOptions,
+};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use lazy_static::lazy_static;
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::stores::config::get_key;
+
+/// Provider of the `oidc` section of `routes.yml`, its client secret being read from the
+/// `OIDC_CLIENT_SECRET` variable. Without a secret, the gateway logs in as a public client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OidcConfig {
+    /// Issuer identifier, its metadata being read from `{issuer}/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    /// Callback of the gateway registered at the provider, ending in `/public/oidc/callback`.
+    pub redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// Where users land once logged in.
+    #[serde(default = "default_landing_path")]
+    pub landing_path: String,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_owned()]
+}
+
+fn default_landing_path() -> String {
+    "/".to_owned()
+}
+
+/// Cookie carrying the login in progress, from the redirection to the provider to the callback.
+pub const FLOW_COOKIE: &str = "oidc_flow";
+pub const FLOW_TTL_SECS: u64 = 600;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+}
+
+/// Why a login through the provider failed.
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider could not be reached, or answered unexpectedly.
+    Provider(String),
+    /// The callback does not belong to a login started by this browser, or came too late.
+    Flow,
+    /// The provider refused the login, or its ID token is invalid.
+    Rejected(String),
+}
+
+impl OidcError {
+    /// Status of the response the client gets.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Provider(_) => StatusCode::BAD_GATEWAY,
+            Self::Flow => StatusCode::BAD_REQUEST,
+            Self::Rejected(_) => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Detail of the problem the client gets.
+    pub fn detail(&self) -> &'static str {
+        match self {
+            Self::Provider(_) => "Identity provider unavailable",
+            Self::Flow => "Login expired or started elsewhere, please log in again",
+            Self::Rejected(_) => "Login refused by the identity provider",
+        }
+    }
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Provider(reason) => write!(f, "identity provider failed: {reason}"),
+            Self::Flow => write!(f, "unknown or expired login flow"),
+            Self::Rejected(reason) => write!(f, "login rejected: {reason}"),
+        }
+    }
+}
+
+fn provider_error(err: reqwest::Error) -> OidcError {
+    OidcError::Provider(err.to_string())
+}
+
+/// Endpoints of the provider, from its discovery document.
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+async fn provider_metadata(config: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    );
+    let metadata: ProviderMetadata = CLIENT
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)?;
+    if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+        return Err(OidcError::Provider(format!(
+            "metadata of issuer {}",
+            metadata.issuer
+        )));
+    }
+    Ok(metadata)
+}
+
+/// Secrets of a login in progress, signed by the gateway along with the nonce of the ID token.
+#[derive(Serialize, Deserialize)]
+struct LoginFlow {
+    state: String,
+    verifier: String,
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+fn code_challenge(verifier: &str) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(verifier.as_bytes())).unwrap()
+}
+
+/// Url of the provider to send the browser to, and the value of the `FLOW_COOKIE` to set along.
+pub async fn start_login(config: &OidcConfig) -> Result<(String, String), OidcError> {
+    let metadata = provider_metadata(config).await?;
+    let nonce = random_string(32);
+    let flow = LoginFlow {
+        state: random_string(32),
+        verifier: random_string(64),
+    };
+    let url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        [
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_uri),
+            ("scope", &config.scopes.join(" ")),
+            ("state", &flow.state),
+            ("nonce", &nonce),
+            ("code_challenge", &code_challenge(&flow.verifier)),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|err| OidcError::Provider(format!("authorization endpoint: {err}")))?;
+    let claims = Claims::with_custom_claims(
+        flow,
+        jwt_simple::prelude::Duration::from_secs(FLOW_TTL_SECS),
+    )
+    .with_nonce(nonce);
+    let cookie = get_key().authenticate(claims).unwrap();
+    Ok((url.into(), cookie))
+}
+
+/// Query of the provider redirecting the browser back to the gateway.
+#[derive(Deserialize)]
+pub struct Callback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// RSA key of the provider the ID token is signed with.
+async fn signing_key(
+    metadata: &ProviderMetadata,
+    key_id: Option<&str>,
+) -> Result<RS256PublicKey, OidcError> {
+    let jwks: Jwks = CLIENT
+        .get(&metadata.jwks_uri)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(provider_error)?
+        .json()
+        .await
+        .map_err(provider_error)?;
+    let jwk = jwks
+        .keys
+        .iter()
+        .filter(|jwk| jwk.kty == "RSA")
+        .find(|jwk| key_id.is_none() || jwk.kid.as_deref() == key_id)
+        .ok_or_else(|| OidcError::Rejected("ID token signed with an unknown key".to_owned()))?;
+    let decode = |component: &Option<String>| {
+        Base64UrlSafeNoPadding::decode_to_vec(component.as_deref().unwrap_or_default(), None)
+    };
+    let (Ok(n), Ok(e)) = (decode(&jwk.n), decode(&jwk.e)) else {
+        return Err(OidcError::Provider("malformed signing key".to_owned()));
+    };
+    RS256PublicKey::from_components(&n, &e)
+        .map_err(|err| OidcError::Provider(format!("signing key: {err}")))
+}
+
+/// Identity of a user at the provider.
+pub struct Identity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// Exchange the code of `callback` for the identity of the user, checking it belongs to the
+/// login of the `flow_cookie`.
+pub async fn finish_login(
+    config: &OidcConfig,
+    callback: &Callback,
+    flow_cookie: Option<&str>,
+) -> Result<Identity, OidcError> {
+    let flow: JWTClaims<LoginFlow> = flow_cookie
+        .and_then(|cookie| get_key().verify_token(cookie, None).ok())
+        .ok_or(OidcError::Flow)?;
+    let nonce = flow.nonce.ok_or(OidcError::Flow)?;
+    let flow = flow.custom;
+    if callback.state.as_deref() != Some(flow.state.as_str()) {
+        return Err(OidcError::Flow);
+    }
+    if let Some(error) = &callback.error {
+        return Err(OidcError::Rejected(error.clone()));
+    }
+    let code = callback
+        .code
+        .as_deref()
+        .ok_or_else(|| OidcError::Rejected("no code".to_owned()))?;
+
+    let metadata = provider_metadata(config).await?;
+    let mut request = CLIENT.post(&metadata.token_endpoint).form(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &config.redirect_uri),
+        ("client_id", &config.client_id),
+        ("code_verifier", &flow.verifier),
+    ]);
+    if let Some(secret) = std::env::var("OIDC_CLIENT_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+    {
+        request = request.basic_auth(&config.client_id, Some(secret));
+    }
+    let response = request.send().await.map_err(provider_error)?;
+    if !response.status().is_success() {
+        return Err(OidcError::Rejected(format!(
+            "token endpoint answered {}",
+            response.status()
+        )));
+    }
+    let tokens: TokenResponse = response.json().await.map_err(provider_error)?;
+
+    let header = Token::decode_metadata(&tokens.id_token)
+        .map_err(|err| OidcError::Rejected(format!("ID token: {err}")))?;
+    if header.algorithm() != "RS256" {
+        return Err(OidcError::Rejected(format!(
+            "ID token signed with {}",
+            header.algorithm()
+        )));
+    }
+    let key = signing_key(&metadata, header.key_id()).await?;
+    let options = VerificationOptions {
+        allowed_issuers: Some(HashSet::from([metadata.issuer.clone()])),
+        allowed_audiences: Some(HashSet::from([config.client_id.clone()])),
+        required_nonce: Some(nonce),
+        ..Default::default()
+    };
+    let claims: JWTClaims<NoCustomClaims> = key
+        .verify_token(&tokens.id_token, Some(options))
+        .map_err(|err| OidcError::Rejected(format!("ID token: {err}")))?;
+    let subject = claims
+        .subject
+        .ok_or_else(|| OidcError::Rejected("ID token without subject".to_owned()))?;
+    Ok(Identity {
+        issuer: metadata.issuer,
+        subject,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use jwt_simple::prelude::{RS256KeyPair, RSAKeyPairLike};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "gateway";
+
+    /// Provider answering the discovery, token and keys requests on a local port, the token
+    /// endpoint answering the ID token set in `id_token`.
+    fn mock_provider(key_pair: &RS256KeyPair, id_token: Arc<Mutex<String>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let components = key_pair.public_key().to_components();
+        let jwk = serde_json::json!({ "keys": [{
+            "kty": "RSA",
+            "kid": "test",
+            "n": Base64UrlSafeNoPadding::encode_to_string(components.n).unwrap(),
+            "e": Base64UrlSafeNoPadding::encode_to_string(components.e).unwrap(),
+        }]});
+        let metadata = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "jwks_uri": format!("{issuer}/jwks"),
+        });
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let body = match path {
+                    "/.well-known/openid-configuration" => metadata.to_string(),
+                    "/token" => {
+                        let id_token = id_token.lock().unwrap().clone();
+                        serde_json::json!({ "id_token": id_token }).to_string()
+                    }
+                    "/jwks" => jwk.to_string(),
+                    _ => String::new(),
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        issuer
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_owned(),
+            client_id: CLIENT_ID.to_owned(),
+            redirect_uri: "http://localhost/public/oidc/callback".to_owned(),
+            scopes: default_scopes(),
+            landing_path: default_landing_path(),
+        }
+    }
+
+    fn query_param(url: &str, name: &str) -> String {
+        let url = Url::parse(url).unwrap();
+        let (_, value) = url.query_pairs().find(|(key, _)| key == name).unwrap();
+        value.into_owned()
+    }
+
+    fn id_token(key_pair: &RS256KeyPair, issuer: &str, audience: &str, nonce: &str) -> String {
+        let claims = Claims::create(jwt_simple::prelude::Duration::from_mins(5))
+            .with_issuer(issuer)
+            .with_audience(audience)
+            .with_subject("alice")
+            .with_nonce(nonce);
+        key_pair.sign(claims).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bad_state() {
+        // Refused before the provider is called
+        let config = config("http://127.0.0.1:9");
+        let flow = LoginFlow {
+            state: "state".to_owned(),
+            verifier: "verifier".to_owned(),
+        };
+        let claims = Claims::with_custom_claims(flow, jwt_simple::prelude::Duration::from_mins(5))
+            .with_nonce("nonce");
+        let cookie = get_key().authenticate(claims).unwrap();
+        let callback = |state: &str| Callback {
+            code: Some("code".to_owned()),
+            state: Some(state.to_owned()),
+            error: None,
+        };
+        for (callback, cookie) in [
+            (callback("forged"), Some(cookie.as_str())),
+            (callback("state"), None),
+            (callback("state"), Some("not a flow")),
+        ] {
+            let refused = finish_login(&config, &callback, cookie)
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(refused, OidcError::Flow));
+            assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_id_token_checked() {
+        let key_pair = RS256KeyPair::generate(2048).unwrap().with_key_id("test");
+        let served = Arc::new(Mutex::new(String::new()));
+        let issuer = mock_provider(&key_pair, served.clone());
+        let config = config(&issuer);
+
+        for (audience, matching_nonce, accepted) in [
+            (CLIENT_ID, true, true),
+            (CLIENT_ID, false, false),
+            ("another client", true, false),
+        ] {
+            let (url, cookie) = start_login(&config).await.unwrap();
+            assert_eq!(query_param(&url, "code_challenge_method"), "S256");
+            let nonce = match matching_nonce {
+                true => query_param(&url, "nonce"),
+                false => "replayed".to_owned(),
+            };
+            *served.lock().unwrap() = id_token(&key_pair, &issuer, audience, &nonce);
+            let callback = Callback {
+                code: Some("code".to_owned()),
+                state: Some(query_param(&url, "state")),
+                error: None,
+            };
+            let identity = finish_login(&config, &callback, Some(&cookie)).await;
+            match identity {
+                Ok(identity) => {
+                    assert!(accepted);
+                    assert_eq!(identity.issuer, issuer);
+                    assert_eq!(identity.subject, "alice");
+                }
+                Err(refused) => {
+                    assert!(!accepted, "{refused}");
+                    assert!(matches!(refused, OidcError::Rejected(_)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use axum::{
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::telemetry::current_trace_id;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Error details following RFC 7807, served as `application/problem+json`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Problem {
+    /// URI reference identifying the kind of problem, `about:blank` when the status says it all.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Short summary of the kind of problem.
+    pub title: String,
+    /// HTTP status code of the response.
+    pub status: u16,
+    /// Explanation specific to this occurrence of the problem.
+    pub detail: String,
+    /// Path of the request which failed.
+    pub instance: String,
+    /// Id of the trace of the request, when traces are exported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Whether the client asked for the legacy plain-text errors, sending `Accept: text/plain`
+/// without listing `application/problem+json`.
+fn wants_legacy(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media| {
+            media
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .collect::<Vec<_>>();
+    accept.iter().any(|media| media == "text/plain")
+        && !accept.iter().any(|media| media == PROBLEM_JSON)
+}
+
+/// Render an error of the gateway itself as `application/problem+json`, or as plain text when
+/// the client asked for it. Errors of proxied services are forwarded as is.
+pub fn error_response(
+    headers: &HeaderMap,
+    instance: &str,
+    status: StatusCode,
+    detail: &str,
+) -> Response {
+    if wants_legacy(headers) {
+        return (status, detail.to_owned()).into_response();
+    }
+    let problem = Problem {
+        problem_type: "about:blank".to_owned(),
+        title: status.canonical_reason().unwrap_or_default().to_owned(),
+        status: status.as_u16(),
+        detail: detail.to_owned(),
+        instance: instance.to_owned(),
+        trace_id: current_trace_id(),
+    };
+    (status, [(CONTENT_TYPE, PROBLEM_JSON)], Json(problem)).into_response()
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::{
+    extract::ConnectInfo,
+    headers::{Cookie, HeaderMapExt},
+    http::{
+        header::{HeaderName, HeaderValue, RETRY_AFTER},
+        HeaderMap, Request, StatusCode,
+    },
+    response::Response,
+};
+use futures::future::BoxFuture;
+use jwt_simple::prelude::{JWTClaims, MACLike, NoCustomClaims};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::authn::{credential, Credential};
+use crate::problem::error_response;
+use crate::routing::RequestInfo;
+use crate::stores::config::{get_config, get_key};
+
+/// Token bucket parameters: `per_second` tokens are given back every second, up to `burst`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Rate limits of the gateway, in the `rate_limit` section of `routes.yml`. Routes may set
+/// their own limits, in a `rate_limit` entry.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Limits shared by every route without its own.
+    pub default: Quota,
+    /// Paths never limited, such as the probes.
+    #[serde(default)]
+    pub exempt: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: Quota {
+                per_second: 50.0,
+                burst: 100,
+            },
+            exempt: vec![
+                "/health".to_owned(),
+                "/ready".to_owned(),
+                "/metrics".to_owned(),
+            ],
+        }
+    }
+}
+
+/// Name and limits of the bucket a request goes to, `None` when the path is exempt.
+fn quota_for(request: &RequestInfo) -> Option<(String, Quota)> {
+    let config = get_config();
+    if config.rate_limit.exempt.iter().any(|exempt| exempt == request.path) {
+        return None;
+    }
+    // Routes are matched like the proxy does
+    let quota = config
+        .find_route(request)
+        .ok()
+        .and_then(|found| {
+            let name = format!("{} {}", request.method, found.route.label());
+            Some((name, found.route.rate_limit?))
+        })
+        .unwrap_or_else(|| ("default".to_owned(), config.rate_limit.default));
+    Some(quota)
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until a token is available, zero when the request was allowed.
+    pub retry_after: Duration,
+}
+
+/// Storage of the token buckets.
+///
+/// The in-memory backend limits each instance on its own; implement this trait over a shared
+/// store, e.g. redis, to enforce the limits across instances.
+#[async_trait]
+pub trait RateLimitBackend: Send + Sync {
+    /// Take a token from the bucket of `key`, created full on first use.
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+// Past this number of buckets, the full ones are dropped as they hold no information
+const MAX_BUCKETS: usize = 100_000;
+
+/// Token buckets kept in the memory of the instance.
+#[derive(Default)]
+pub struct InMemoryBackend {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+fn secs(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+#[async_trait]
+impl RateLimitBackend for InMemoryBackend {
+    async fn acquire(&self, key: &str, quota: Quota) -> Decision {
+        let now = Instant::now();
+        let burst = f64::from(quota.burst);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * quota.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = secs((burst - bucket.tokens) / quota.per_second);
+        bucket.full_at = now + reset;
+        Decision {
+            allowed,
+            limit: quota.burst,
+            remaining: bucket.tokens as u32,
+            reset,
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                secs((1.0 - bucket.tokens) / quota.per_second)
+            },
+        }
+    }
+}
+
+/// Id of the user of a valid session token, sent as a cookie or a bearer token.
+fn token_user(credential: &Credential) -> Option<String> {
+    let (Credential::Session(token) | Credential::Bearer(token)) = credential else {
+        return None;
+    };
+    let claims: JWTClaims<NoCustomClaims> = get_key().verify_token(token, None).ok()?;
+    claims.subject
+}
+
+/// Who the request is counted against: the user of its session or bearer token, verified, or its
+/// peer address. `X-User` and API keys are unverified at this point, and left to the address.
+fn client_key<B>(req: &Request<B>) -> String {
+    let cookie = req.headers().typed_get::<Cookie>();
+    let session = cookie.as_ref().and_then(|cookie| cookie.get("session"));
+    let credential = credential(req.headers(), session);
+    if let Some(user) = credential.as_ref().and_then(token_user) {
+        format!("user:{user}")
+    } else {
+        // Only known when served with `into_make_service_with_connect_info`
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        format!("ip:{}", peer.unwrap_or_default())
+    }
+}
+
+// Round up, so that clients waiting the advertised time do get a token
+fn header_secs(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs_f64().ceil() as u64)
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        header_secs(decision.reset),
+    );
+}
+
+/// Tower layer limiting the request rate of each client with token buckets.
+///
+/// Requests over the limit are answered with a 429 problem and a `Retry-After` header; every
+/// limited response carries the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl RateLimitLayer {
+    pub fn new(backend: Arc<dyn RateLimitBackend>) -> Self {
+        Self { backend }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            backend: self.backend.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    backend: Arc<dyn RateLimitBackend>,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // The service polled ready is the one to call, keep a fresh clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let backend = self.backend.clone();
+
+        Box::pin(async move {
+            let request = RequestInfo::new(
+                req.method().as_str(),
+                req.uri().path(),
+                req.uri().query().unwrap_or_default(),
+                req.headers(),
+            );
+            let Some((name, quota)) = quota_for(&request) else {
+                return inner.call(req).await;
+            };
+            let decision = backend
+                .acquire(&format!("{name}:{}", client_key(&req)), quota)
+                .await;
+            if !decision.allowed {
+                let detail = format!(
+                    "{} requests allowed per second, with bursts of {}",
+                    quota.per_second, quota.burst
+                );
+                let mut response = error_response(
+                    req.headers(),
+                    req.uri().path(),
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &detail,
+                );
+                set_headers(response.headers_mut(), &decision);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, header_secs(decision.retry_after));
+                return Ok(response);
+            }
+            let mut response = inner.call(req).await?;
+            set_headers(response.headers_mut(), &decision);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use jwt_simple::prelude::Claims;
+
+    use super::*;
+
+    fn request(header: (&str, &str)) -> Request<Body> {
+        let mut req = Request::builder()
+            .header(header.0, header.1)
+            .body(Body::empty())
+            .unwrap();
+        let peer: SocketAddr = "203.0.113.7:4000".parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    }
+
+    #[test]
+    fn test_client_key() {
+        // Whatever the client claims, unverified
+        for header in [
+            ("x-user", "alice"),
+            ("x-api-key", "gwk_random"),
+            ("authorization", "Bearer gwk_random"),
+            ("authorization", "Bearer forged"),
+        ] {
+            assert_eq!(client_key(&request(header)), "ip:203.0.113.7");
+        }
+
+        let claims =
+            Claims::create(jwt_simple::prelude::Duration::from_hours(1)).with_subject("alice");
+        let token = get_key().authenticate(claims).unwrap();
+        let cookie = format!("session={token}");
+        assert_eq!(client_key(&request(("cookie", &cookie))), "user:alice");
+        let bearer = format!("Bearer {token}");
+        assert_eq!(client_key(&request(("authorization", &bearer))), "user:alice");
+    }
+}
@@ -1,111 +1,332 @@
+use crate::accounts::{self, session_claims, Login, PasswordChange};
+use crate::admin;
+use crate::authn::{self, api_key_user, credential, AuthMethod, Credential, TokenRequest};
+use crate::authz::identity_headers;
+use crate::forwarding::{request_headers, response_headers, upgrade};
+use crate::metrics::metrics;
+use crate::oidc::{self, Callback, FLOW_COOKIE, FLOW_TTL_SECS};
+use crate::problem::error_response;
+use crate::routing::{NoRoute, RequestInfo};
+use crate::schemas::SessionClaims;
+use crate::shutdown::ready;
 use crate::stores::cache::User;
+use crate::resilience::{call_service, ServiceCall};
+use crate::store_interface::{UpstreamBody, UpstreamError};
 use crate::stores::config::{get_config, get_key};
+use crate::telemetry::RequestId;
 use crate::{AppState, DynCache, DynHttp, DynUserRepo};
 use axum::headers::Cookie;
-use axum::routing::{delete, get, post, put};
+use axum::routing::{any, delete, get, post, put};
 use axum::{
     self,
-    body::Body,
-    extract::{State, TypedHeader},
-    http::header::SET_COOKIE,
+    body::{Body, StreamBody},
+    extract::{ConnectInfo, Query, State, TypedHeader},
+    http::header::{
+        ALLOW, AUTHORIZATION, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, LOCATION, RETRY_AFTER,
+        SET_COOKIE, TRANSFER_ENCODING, UPGRADE,
+    },
     http::status::StatusCode,
-    http::Request,
-    response::{AppendHeaders, IntoResponse},
-    Router,
+    http::{HeaderMap, HeaderValue, Method, Request, Uri},
+    response::{AppendHeaders, IntoResponse, Response},
+    Form, Json, Router,
 };
-use jwt_simple::prelude::{Claims, Duration, JWTClaims, MACLike, NoCustomClaims};
+use hyper::upgrade::OnUpgrade;
+use jwt_simple::prelude::{Claims, Duration, MACLike};
+use std::net::SocketAddr;
 use uuid::Uuid;
 
 pub fn configure(router: Router<AppState>) -> Router<AppState> {
     router
         .route("/public/sign-up", get(sign_up))
+        .route("/public/register", post(register))
+        .route("/public/login", post(login))
+        .route("/public/oidc/login", get(oidc_login))
+        .route("/public/oidc/callback", get(oidc_callback))
+        .route("/public/oauth/token", post(token))
+        // Reserved to sessions
+        .route("/account/logout", post(logout))
+        .route("/account/password", post(change_password))
         .route("/health", get(health))
-        .route("/*path", get(req_proxy))
-        .route("/*path", post(req_proxy))
-        .route("/*path", put(req_proxy))
-        .route("/*path", delete(req_proxy))
+        .route("/ready", get(ready))
+        // Keep it unreachable from outside in production,
+        // e.g. by only exposing it on the internal network your Prometheus scrapes from
+        .route("/metrics", get(metrics))
+        // Reserved to admin sessions
+        .route("/admin/config", get(admin::active_config))
+        .route("/admin/config/reload", post(admin::reload))
+        .route("/admin/users/:id/roles", get(admin::user_roles))
+        .route(
+            "/admin/users/:id/roles/:role",
+            put(admin::grant_role).delete(admin::revoke_role),
+        )
+        .route(
+            "/admin/users/:id/api-keys",
+            get(admin::api_keys).post(admin::create_api_key),
+        )
+        .route(
+            "/admin/users/:id/api-keys/:key",
+            delete(admin::delete_api_key),
+        )
+        .route("/admin/clients", post(admin::create_client))
+        .route("/admin/clients/:client_id", delete(admin::delete_client))
+        // Routes are configuration driven, whatever the method
+        .route("/*path", any(req_proxy))
 }
 
 async fn health() -> impl IntoResponse {
     "OK"
 }
 
-async fn gen_session_token(user_id: Uuid) -> String {
-    let mut claims = Claims::create(Duration::from_days(1));
-    claims.subject = Some(user_id.to_string());
+async fn gen_session_token(user: &User) -> String {
+    let session = SessionClaims {
+        session_version: user.session_version,
+        client_id: None,
+    };
+    let mut claims = Claims::with_custom_claims(session, Duration::from_days(1));
+    claims.subject = Some(user.id.to_string());
     let key = get_key();
     key.authenticate(claims).unwrap()
 }
 
+fn session_cookie(token: &str) -> String {
+    format!("session={token}; Max-Age=86400; Path=/; SameSite=Lax; Secure")
+}
+
+const SESSION_REMOVAL: &str = "session=; Max-Age=0; Path=/; SameSite=Lax; Secure";
+
+// Only sent back to the callback, within the time given to log in at the provider
+fn flow_cookie(value: &str, max_age: u64) -> String {
+    format!(
+        "{FLOW_COOKIE}={value}; Max-Age={max_age}; Path=/public/oidc; SameSite=Lax; Secure; \
+        HttpOnly"
+    )
+}
+
+/// Body of the client request, streamed to the service. Requests without a body get none.
+fn request_body(req: Request<Body>) -> reqwest::Body {
+    let has_body = req.headers().contains_key(TRANSFER_ENCODING)
+        || req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .is_some_and(|length| length != "0");
+    if !has_body {
+        return reqwest::Body::from(Vec::new());
+    }
+    reqwest::Body::wrap_stream(req.into_body())
+}
+
+/// Pipe the client connection, once switched to another protocol, to the service one.
+async fn tunnel(client: OnUpgrade, mut upgraded: reqwest::Upgraded) {
+    match client.await {
+        Ok(mut client) => {
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upgraded).await;
+        }
+        Err(err) => tracing::warn!(%err, "could not upgrade the client connection"),
+    }
+}
+
+/// `Allow` header listing the methods the routes serving `request` accept.
+fn allow_header(request: &RequestInfo) -> HeaderValue {
+    let methods = get_config().allowed_methods(request).join(", ");
+    HeaderValue::from_str(&methods).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// User of a session cookie, as long as it exists.
+pub(crate) async fn session_user(
+    state_repo: &DynUserRepo,
+    cache: &DynCache,
+    cookie: Option<&str>,
+) -> Option<User> {
+    // Check token signature
+    // In real life, keys should be taken from env, and injected in your production via CI/CD tools
+    let (user_id, claims) = session_claims(cookie?)?;
+    // We also verify the user exists. This enable for permission check
+    // Users cached before their sessions were revoked are read again
+    let mut opt_user = cache
+        .get_user(user_id)
+        .await
+        .filter(|user| user.session_version == claims.session_version);
+    if opt_user.is_none() {
+        opt_user = state_repo.get_user(user_id).await;
+    }
+    // Sessions opened before a logout or a password change are revoked
+    let user = opt_user.filter(|user| user.session_version == claims.session_version)?;
+    let _ret = cache.create_user(&user).await;
+    Some(user)
+}
+
+/// User of the credential of a request, and how it authenticated.
+pub(crate) async fn authenticate(
+    state_repo: &DynUserRepo,
+    cache: &DynCache,
+    credential: Option<Credential>,
+) -> Option<(User, AuthMethod)> {
+    match credential? {
+        Credential::Session(token) => {
+            // Machine tokens are only bearer tokens, never refreshed as sessions are
+            if session_claims(&token).is_some_and(|(_, claims)| claims.client_id.is_some()) {
+                return None;
+            }
+            let user = session_user(state_repo, cache, Some(&token)).await?;
+            Some((user, AuthMethod::Session))
+        }
+        Credential::Bearer(token) => {
+            let method = match session_claims(&token) {
+                Some((_, claims)) if claims.client_id.is_some() => AuthMethod::ClientCredentials,
+                _ => AuthMethod::Bearer,
+            };
+            let user = session_user(state_repo, cache, Some(&token)).await?;
+            Some((user, method))
+        }
+        // Looked up every time, for revoked keys to stop working at once
+        Credential::ApiKey(key) => api_key_user(state_repo.as_ref(), &key)
+            .await
+            .map(|user| (user, AuthMethod::ApiKey)),
+    }
+}
+
 async fn req_proxy(
     State(state_repo): State<DynUserRepo>,
     State(proxy): State<DynHttp>,
     State(cache): State<DynCache>,
-    TypedHeader(cookie): TypedHeader<Cookie>,
-    req: Request<Body>,
-) -> impl IntoResponse {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    cookie: Option<TypedHeader<Cookie>>,
+    mut req: Request<Body>,
+) -> Response {
+    // Answered by the gateway unless a route leaves them to its service
+    let config = get_config();
+    let target = RequestInfo::new(
+        req.method().as_str(),
+        req.uri().path(),
+        req.uri().query().unwrap_or_default(),
+        req.headers(),
+    );
+    if req.method() == Method::OPTIONS
+        && config.find_route(&target).is_err()
+        && !config.allowed_methods(&target).is_empty()
+    {
+        return (StatusCode::NO_CONTENT, [(ALLOW, allow_header(&target))]).into_response();
+    }
     // For the purpose of browser testing, we use a session cookie.
-    let sess_cookie = cookie.get("session");
-    let cookie_val = match sess_cookie {
-        Some(cookie_value) => cookie_value.to_owned(),
+    let sess_cookie = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get("session"));
+    let credential = credential(req.headers(), sess_cookie);
+    let Some((user, auth_method)) = authenticate(&state_repo, &cache, credential).await else {
         // Not authenticated - Could redirect to a front signup page
-        None => return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+        return error_response(
+            req.headers(),
+            req.uri().path(),
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+        );
     };
-
-    // Check token signature
-    // In real life, keys should be taken from env, and injected in your production via CI/CD tools
-    let key = get_key();
-
-    let try_claims: Result<JWTClaims<NoCustomClaims>, jwt_simple::Error> =
-        key.verify_token(cookie_val.as_str(), None);
-    if try_claims.is_err() {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-    }
-    let claims = try_claims.unwrap();
-    let try_user_id = claims.subject;
-    if try_user_id.is_none() {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-    }
-    let user_id_str = try_user_id.unwrap();
-    let user_id = Uuid::parse_str(&user_id_str).unwrap();
-    // We also verify the user exists. This enable for permission check
-    let mut opt_user = cache.get_user(user_id).await;
-    if opt_user.is_none() {
-        opt_user = state_repo.get_user(user_id.clone()).await;
-        if opt_user.is_none() {
-            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let query = req.uri().query().unwrap_or_default().to_owned();
+    let target = RequestInfo::new(method.as_str(), &path, &query, req.headers());
+    let found = match config.find_route(&target) {
+        Ok(found) => found,
+        Err(NoRoute::MethodNotAllowed) => {
+            let mut response = error_response(
+                req.headers(),
+                &path,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method not allowed",
+            );
+            response.headers_mut().insert(ALLOW, allow_header(&target));
+            return response;
+        }
+        Err(NoRoute::NotFound) => {
+            return error_response(req.headers(), &path, StatusCode::NOT_FOUND, "Not found")
         }
+    };
+    let route = found.route;
+    if !route.authorizes(&user, method.as_str(), &config.roles, &found.captures) {
+        return error_response(
+            req.headers(),
+            &path,
+            StatusCode::FORBIDDEN,
+            "Insuficient permissions",
+        );
     }
-    let user = opt_user.unwrap();
-    let _ret = cache.create_user(&user);
-    let route_config = get_config();
-    let method = req.method().as_str();
-    let path = req.uri().path();
-    for route in &route_config.routes {
-        if route.methods.contains(&method.to_owned()) && path.starts_with(&route.prefix) {
-            if route.restrict_admin && !user.admin {
-                return (StatusCode::FORBIDDEN, "Insuficient permissions").into_response();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone())
+        .unwrap_or_default();
+    let client_headers = req.headers().clone();
+    // The connection of an upgrade is tunneled once the service switched
+    let (body, on_upgrade) = match upgrade(&client_headers) {
+        Some(_) => (
+            reqwest::Body::from(Vec::new()),
+            Some(hyper::upgrade::on(&mut req)),
+        ),
+        None => (request_body(req), None),
+    };
+    let mut headers = request_headers(&client_headers, Some(peer.ip()), "http");
+    // Services may authorize in turn on the roles of the user
+    headers.extend(identity_headers(&user, auth_method));
+    let call = ServiceCall {
+        method: &method,
+        query: &query,
+        headers,
+        body,
+        user_id: &user.id,
+        request_id: &request_id,
+    };
+    let upstream = match call_service(proxy.as_ref(), &config, &found, call).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            let mut response =
+                error_response(&client_headers, &path, err.status(), err.detail());
+            if let UpstreamError::CircuitOpen(remaining) = err {
+                // Rounded up, for clients waiting the advertised time to find it closed
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(seconds));
             }
-            let (code, body) = proxy.make_request(method, path, &user.id).await;
-
-            let token = gen_session_token(user_id).await;
-            return (
-                StatusCode::from_u16(code).unwrap(),
-                AppendHeaders([(
-                    SET_COOKIE,
-                    format!(
-                        "session={}; Max-Age=86400; Path=/; SameSite=Lax; Secure",
-                        token
-                    ),
-                )]),
-                String::from_utf8(body.to_vec()).unwrap(),
-            )
-                .into_response();
+            return response;
+        }
+    };
+
+    let mut response = match (upstream.body, on_upgrade) {
+        (UpstreamBody::Stream(body), _) => StreamBody::new(body).into_response(),
+        (UpstreamBody::Upgraded(upgraded), Some(on_upgrade)) => {
+            tokio::spawn(tunnel(on_upgrade, upgraded));
+            ().into_response()
+        }
+        (UpstreamBody::Upgraded(_), None) => {
+            return error_response(
+                &client_headers,
+                &path,
+                StatusCode::BAD_GATEWAY,
+                "Unexpected protocol switch",
+            );
+        }
+    };
+    *response.status_mut() = upstream.status;
+    *response.headers_mut() = response_headers(&upstream.headers);
+    if let Some(protocol) = upstream
+        .headers
+        .get(UPGRADE)
+        .filter(|_| upstream.status == StatusCode::SWITCHING_PROTOCOLS)
+    {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        response.headers_mut().insert(UPGRADE, protocol.clone());
+    }
+    // Refresh token, to avoid cutting session during browsing
+    if auth_method == AuthMethod::Session {
+        let token = gen_session_token(&user).await;
+        if let Ok(cookie) = HeaderValue::from_str(&session_cookie(&token)) {
+            response.headers_mut().append(SET_COOKIE, cookie);
         }
     }
-    return (StatusCode::NOT_FOUND, "Not found").into_response();
+    response
 }
-
 //Our extremely simplified signup. Get the url to automatically register a new user and get a cookie
 
 async fn sign_up(
@@ -115,20 +336,286 @@ async fn sign_up(
     let u: User = User {
         id: Uuid::new_v4(),
         admin: false,
+        session_version: 0,
+        roles: Vec::new(),
     };
     state_repo.create_user(&u).await.unwrap();
     cache.create_user(&u).await.unwrap();
-    let token_str = gen_session_token(u.id).await;
+    let token_str = gen_session_token(&u).await;
 
     (
         StatusCode::OK,
-        AppendHeaders([(
-            SET_COOKIE,
-            format!(
-                "session={}; Max-Age=86400; Path=/; SameSite=Lax; Secure",
-                token_str.as_str()
-            ),
-        )]),
+        AppendHeaders([(SET_COOKIE, session_cookie(&token_str))]),
         "Signed up ! Check http://localhost:8080/hello/",
     )
 }
+
+/// Response opening a session for `user`.
+async fn signed_in(status: StatusCode, user: &User) -> Response {
+    let token = gen_session_token(user).await;
+    (
+        status,
+        AppendHeaders([(SET_COOKIE, session_cookie(&token))]),
+        Json(serde_json::json!({ "id": user.id.to_string() })),
+    )
+        .into_response()
+}
+
+/// User of the session, or the response refusing the request.
+async fn require_session(
+    state_repo: &DynUserRepo,
+    cache: &DynCache,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> Result<User, Response> {
+    let cookie = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get("session"));
+    let credential = cookie.map(|cookie| Credential::Session(cookie.to_owned()));
+    match authenticate(state_repo, cache, credential).await {
+        Some((user, _)) => Ok(user),
+        None => Err(error_response(
+            headers,
+            uri.path(),
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized",
+        )),
+    }
+}
+
+/// Create an account, and open its first session.
+///
+/// ```text
+/// curl -i localhost:8080/public/register -H 'Content-Type: application/json' \
+///     -d '{"username": "alice", "password": "correct horse"}'
+/// ```
+async fn register(
+    State(state_repo): State<DynUserRepo>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(login): Json<Login>,
+) -> Response {
+    match accounts::register(state_repo.as_ref(), login).await {
+        Ok(user) => signed_in(StatusCode::CREATED, &user).await,
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Open a session with the username and password of an account.
+async fn login(
+    State(state_repo): State<DynUserRepo>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(login): Json<Login>,
+) -> Response {
+    match accounts::login(state_repo.as_ref(), login).await {
+        Ok(user) => signed_in(StatusCode::OK, &user).await,
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Revoke every session of the user, and remove the session cookie.
+async fn logout(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let user = match require_session(&state_repo, &cache, cookie, &headers, &uri).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    match accounts::logout(state_repo.as_ref(), &user).await {
+        Ok(user) => {
+            // Forgotten at once by this instance, by the others when their cache entry expires
+            let _ret = cache.create_user(&user).await;
+            (
+                StatusCode::NO_CONTENT,
+                AppendHeaders([(SET_COOKIE, SESSION_REMOVAL)]),
+            )
+                .into_response()
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Change the password of the user, revoking its other sessions.
+async fn change_password(
+    State(state_repo): State<DynUserRepo>,
+    State(cache): State<DynCache>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(change): Json<PasswordChange>,
+) -> Response {
+    let user = match require_session(&state_repo, &cache, cookie, &headers, &uri).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    match accounts::change_password(state_repo.as_ref(), &user, change).await {
+        Ok(user) => {
+            let _ret = cache.create_user(&user).await;
+            signed_in(StatusCode::OK, &user).await
+        }
+        Err(refused) => error_response(&headers, uri.path(), refused.status, &refused.detail),
+    }
+}
+
+/// Redirect the browser to the OpenID Connect provider to log in.
+async fn oidc_login(headers: HeaderMap, uri: Uri) -> Response {
+    let config = get_config();
+    let Some(oidc) = &config.oidc else {
+        return error_response(
+            &headers,
+            uri.path(),
+            StatusCode::NOT_FOUND,
+            "OpenID Connect login not configured",
+        );
+    };
+    match oidc::start_login(oidc).await {
+        Ok((url, flow)) => (
+            StatusCode::FOUND,
+            AppendHeaders([
+                (LOCATION, url),
+                (SET_COOKIE, flow_cookie(&flow, FLOW_TTL_SECS)),
+            ]),
+        )
+            .into_response(),
+        Err(err) => {
+            tracing::warn!(%err, "could not start an OpenID Connect login");
+            error_response(&headers, uri.path(), err.status(), err.detail())
+        }
+    }
+}
+
+/// Open a session for the user the provider redirected back, creating its account on its first
+/// login.
+async fn oidc_callback(
+    State(state_repo): State<DynUserRepo>,
+    cookie: Option<TypedHeader<Cookie>>,
+    headers: HeaderMap,
+    uri: Uri,
+    Query(callback): Query<Callback>,
+) -> Response {
+    let config = get_config();
+    let Some(oidc) = &config.oidc else {
+        return error_response(
+            &headers,
+            uri.path(),
+            StatusCode::NOT_FOUND,
+            "OpenID Connect login not configured",
+        );
+    };
+    let flow = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get(FLOW_COOKIE));
+    let identity = match oidc::finish_login(oidc, &callback, flow).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            tracing::warn!(%err, "OpenID Connect login failed");
+            return error_response(&headers, uri.path(), err.status(), err.detail());
+        }
+    };
+    let user = match accounts::identity_user(state_repo.as_ref(), &identity).await {
+        Ok(user) => user,
+        Err(refused) => {
+            return error_response(&headers, uri.path(), refused.status, &refused.detail)
+        }
+    };
+    let token = gen_session_token(&user).await;
+    (
+        StatusCode::SEE_OTHER,
+        AppendHeaders([
+            (LOCATION, oidc.landing_path.clone()),
+            (SET_COOKIE, session_cookie(&token)),
+            (SET_COOKIE, flow_cookie("", 0)),
+        ]),
+    )
+        .into_response()
+}
+
+/// Token of a machine client, with the client credentials grant.
+///
+/// ```text
+/// curl localhost:8080/public/oauth/token -u "$CLIENT_ID:$CLIENT_SECRET" \
+///     -d grant_type=client_credentials
+/// ```
+async fn token(
+    State(state_repo): State<DynUserRepo>,
+    headers: HeaderMap,
+    Form(request): Form<TokenRequest>,
+) -> Response {
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match authn::client_credentials(state_repo.as_ref(), authorization, request).await {
+        Ok(token) => ([(CACHE_CONTROL, "no-store")], Json(token)).into_response(),
+        Err(err) => (
+            err.status(),
+            [(CACHE_CONTROL, "no-store")],
+            Json(serde_json::json!({ "error": err.code() })),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::authn::{client_credentials, create_client, NewClient};
+    use crate::stores::cache::InMemoryUser;
+    use crate::stores::memory::InMemoryUsers;
+
+    #[tokio::test]
+    async fn test_authenticate_methods() {
+        let user = User {
+            id: Uuid::new_v4(),
+            admin: false,
+            session_version: 0,
+            roles: Vec::new(),
+        };
+        let state_repo: DynUserRepo = Arc::new(InMemoryUsers::with_users([user.clone()]));
+        let cache: DynCache = Arc::new(InMemoryUser::new());
+        let token = gen_session_token(&user).await;
+
+        let session = Some(Credential::Session(token.clone()));
+        let (_, method) = authenticate(&state_repo, &cache, session).await.unwrap();
+        assert_eq!(method, AuthMethod::Session);
+        let bearer = Some(Credential::Bearer(token));
+        let (_, method) = authenticate(&state_repo, &cache, bearer).await.unwrap();
+        assert_eq!(method, AuthMethod::Bearer);
+        assert!(authenticate(&state_repo, &cache, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_tokens_are_no_sessions() {
+        let state_repo: DynUserRepo = Arc::new(InMemoryUsers::default());
+        let cache: DynCache = Arc::new(InMemoryUser::new());
+        let request = NewClient {
+            name: "billing".to_owned(),
+            roles: Vec::new(),
+        };
+        let client = create_client(state_repo.as_ref(), request).await.unwrap();
+        let request = TokenRequest {
+            grant_type: "client_credentials".to_owned(),
+            client_id: Some(client.client_id),
+            client_secret: Some(client.client_secret),
+        };
+        let token = client_credentials(state_repo.as_ref(), None, request)
+            .await
+            .unwrap()
+            .access_token;
+
+        let bearer = Some(Credential::Bearer(token.clone()));
+        let (principal, method) = authenticate(&state_repo, &cache, bearer).await.unwrap();
+        assert_eq!(principal.id.to_string(), client.user_id);
+        assert_eq!(method, AuthMethod::ClientCredentials);
+        // Sessions get refreshed, which would keep machine tokens alive for good
+        let session = Some(Credential::Session(token));
+        assert!(authenticate(&state_repo, &cache, session).await.is_none());
+    }
+}
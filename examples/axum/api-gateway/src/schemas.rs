@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::time::Instant;
 use uuid::Uuid;
 
@@ -7,9 +8,74 @@ use uuid::Uuid;
 pub struct User {
     pub id: Uuid,
     pub admin: bool,
+    /// Sessions carry the version they were opened with, revoking them increments it.
+    pub session_version: i32,
+    /// Roles granted by the admins, the permissions they give being set in `routes.yml`.
+    pub roles: Vec<String>,
+}
+
+impl User {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|granted| granted == role)
+    }
+
+    /// Admins have the `admin` flag or role, and access to everything.
+    pub fn is_admin(&self) -> bool {
+        self.admin || self.has_role("admin")
+    }
 }
 
 pub struct CacheEntry {
     pub admin: bool,
+    pub session_version: i32,
+    pub roles: Vec<String>,
     pub timestamp: Instant,
 }
+
+/// Login of an account with a password.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user_id: Uuid,
+    pub username: String,
+    /// PHC string of the argon2 hash.
+    pub password_hash: String,
+}
+
+/// Claims of the session tokens on top of the registered ones, the user id being its subject.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SessionClaims {
+    // Sessions opened before the versions were introduced are on the first one
+    #[serde(default, rename = "ver")]
+    pub session_version: i32,
+    /// Machine client the token was issued to, `None` for the sessions of users.
+    #[serde(default, rename = "cid", skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+/// Long-lived key a user authenticates with, e.g. from scripts.
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    /// Start of the key, for users to tell their keys apart.
+    pub prefix: String,
+    /// SHA-256 of the key, hex encoded.
+    pub key_hash: String,
+    /// Roles of the user the key may use.
+    pub scopes: Vec<String>,
+    /// Seconds since the epoch, `None` for keys never expiring.
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+
+/// Machine client authenticating with the client credentials grant, as its service principal.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub client_id: String,
+    /// User the requests of the client are made as.
+    pub user_id: Uuid,
+    pub name: String,
+    /// SHA-256 of the client secret, hex encoded.
+    pub secret_hash: String,
+}
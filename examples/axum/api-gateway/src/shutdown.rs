@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use tokio::sync::Notify;
+
+/// Readiness of the gateway to take new traffic, shared by every task.
+///
+/// It goes unhealthy as soon as a shutdown is requested, while `/health` keeps reporting the
+/// process as alive until it exits.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_not_ready(&self) {
+        self.0.store(false, Ordering::SeqCst)
+    }
+}
+
+pub async fn ready(State(readiness): State<Readiness>) -> impl IntoResponse {
+    if readiness.is_ready() {
+        (StatusCode::OK, "READY")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "SHUTTING DOWN")
+    }
+}
+
+/// Timings of the graceful shutdown.
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownConfig {
+    /// Time left to load balancers to notice readiness went down, before new connections are refused.
+    pub grace_period: Duration,
+    /// Deadline given to in-flight requests to complete once connections are refused.
+    pub drain_timeout: Duration,
+}
+
+impl ShutdownConfig {
+    /// Read `SHUTDOWN_GRACE_PERIOD_SECS` (5 by default) and `SHUTDOWN_TIMEOUT_SECS` (30 by default).
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            grace_period: Duration::from_secs(seconds("SHUTDOWN_GRACE_PERIOD_SECS", 5)),
+            drain_timeout: Duration::from_secs(seconds("SHUTDOWN_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+/// Resolve on SIGTERM, sent by docker and kubernetes, or on Ctrl-C.
+pub async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not listen to SIGTERM.");
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Shutdown future for `Server::with_graceful_shutdown`, resolving once `signal` did.
+///
+/// Readiness goes down first, then after the grace period the future resolves, so the server
+/// stops accepting connections and drains in-flight requests. `draining` is notified at that
+/// point, for the caller to enforce the drain deadline, which hyper does not.
+pub async fn graceful_shutdown(
+    readiness: Readiness,
+    config: ShutdownConfig,
+    signal: impl Future<Output = ()>,
+    draining: Arc<Notify>,
+) {
+    signal.await;
+    tracing::info!(?config, "shutdown requested, draining in-flight requests");
+    readiness.set_not_ready();
+    tokio::time::sleep(config.grace_period).await;
+    draining.notify_one();
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::sync::RwLock;
 
+use crate::metrics::USER_CACHE_LOOKUPS_TOTAL;
 use crate::schemas::CacheEntry;
 pub use crate::schemas::User;
 pub use crate::store_interface::CacheRepository;
@@ -35,13 +36,18 @@ impl CacheRepository for InMemoryUser {
             {
                 let mut w_cache = self.users.write().unwrap();
                 w_cache.remove(&id);
+                USER_CACHE_LOOKUPS_TOTAL.with_label_values(&["miss"]).inc();
                 return None;
             }
+            USER_CACHE_LOOKUPS_TOTAL.with_label_values(&["hit"]).inc();
             return Some(User {
                 id: id,
                 admin: user.unwrap().admin,
+                session_version: user.unwrap().session_version,
+                roles: user.unwrap().roles.clone(),
             });
         }
+        USER_CACHE_LOOKUPS_TOTAL.with_label_values(&["miss"]).inc();
         None
     }
 
@@ -51,6 +57,8 @@ impl CacheRepository for InMemoryUser {
             u.id,
             CacheEntry {
                 admin: u.admin,
+                session_version: u.session_version,
+                roles: u.roles.clone(),
                 timestamp: std::time::Instant::now(),
             },
         );
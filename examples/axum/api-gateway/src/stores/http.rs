@@ -1,12 +1,22 @@
 pub use crate::store_interface::Proxy;
+use crate::store_interface::{UpstreamBody, UpstreamError, UpstreamRequest, UpstreamResponse};
+use crate::telemetry::{HeaderInjector, REQUEST_ID_HEADER};
 use async_trait::async_trait;
-use bytes::Bytes;
+use futures::StreamExt;
+use opentelemetry::global;
 pub use reqwest;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{field::Empty, instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
 pub struct RqClient {
-    pub inner: reqwest::Client,
+    /// Clients by connect timeout, which reqwest only sets per client.
+    clients: Mutex<HashMap<Duration, reqwest::Client>>,
 }
 
 // Our cache implementation is a little naive and grow indefinitely in memory.
@@ -17,32 +27,85 @@ pub struct RqClient {
 impl RqClient {
     pub fn new() -> Self {
         Self {
-            inner: reqwest::Client::new(),
+            clients: Mutex::default(),
         }
     }
+
+    fn client(&self, connect_timeout: Duration) -> reqwest::Client {
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(connect_timeout).or_insert_with(|| {
+            reqwest::Client::builder()
+                // Redirects are for the client to follow, along with the rest of the response
+                .redirect(reqwest::redirect::Policy::none())
+                .connect_timeout(connect_timeout)
+                .build()
+                .unwrap()
+        });
+        client.clone()
+    }
+}
+
+fn upstream_error(err: reqwest::Error) -> UpstreamError {
+    if err.is_timeout() {
+        UpstreamError::Timeout
+    } else {
+        UpstreamError::Unreachable(err)
+    }
 }
 
 #[async_trait]
 impl Proxy for RqClient {
-    async fn make_request(&self, method: &str, url: &str, user_id: &Uuid) -> (u16, Bytes) {
-        let mut headers = reqwest::header::HeaderMap::new();
+    #[instrument(
+        name = "upstream request",
+        skip_all,
+        fields(otel.kind = "client", http.method = %request.method, http.status_code = Empty)
+    )]
+    async fn make_request(
+        &self,
+        request: UpstreamRequest,
+        user_id: &Uuid,
+        request_id: &str,
+    ) -> Result<UpstreamResponse, UpstreamError> {
+        let mut headers = request.headers;
         // Transmit all necessary user info through HTTP headers; its agnostic of query methods and simplifies handling for services
         let header_name = reqwest::header::HeaderName::from_str("X-User").unwrap();
         let header_value =
             reqwest::header::HeaderValue::from_str(user_id.to_string().as_str()).unwrap();
         headers.insert(header_name, header_value);
+        // Same goes for tracing: the upstream service joins our trace and logs the same request id
+        if let Ok(header_value) = reqwest::header::HeaderValue::from_str(request_id) {
+            headers.insert(REQUEST_ID_HEADER, header_value);
+        }
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(
+                &Span::current().context(),
+                &mut HeaderInjector(&mut headers),
+            )
+        });
 
-        let request = match method {
-            "POST" => self.inner.post(url).headers(headers),
-            "PUT" => self.inner.put(url).headers(headers),
-            "DELETE" => self.inner.delete(url).headers(headers),
-            _str => self.inner.get(url).headers(headers),
+        let send = self
+            .client(request.connect_timeout)
+            .request(request.method, request.url)
+            .headers(headers)
+            .body(request.body)
+            .send();
+        // Not a reqwest timeout, which would cover streaming the body as well
+        let response = timeout(request.timeout, send)
+            .await
+            .map_err(|_| UpstreamError::Timeout)?
+            .map_err(upstream_error)?;
+        Span::current().record("http.status_code", response.status().as_u16());
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = if status == reqwest::StatusCode::SWITCHING_PROTOCOLS {
+            UpstreamBody::Upgraded(response.upgrade().await.map_err(upstream_error)?)
+        } else {
+            UpstreamBody::Stream(response.bytes_stream().boxed())
         };
-        let result = request.send().await;
-        if result.is_err() {
-            return (500_u16, Bytes::default());
-        }
-        let response = result.unwrap();
-        (response.status().as_u16(), response.bytes().await.unwrap())
+        Ok(UpstreamResponse {
+            status,
+            headers,
+            body,
+        })
     }
 }
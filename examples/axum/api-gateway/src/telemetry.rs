@@ -0,0 +1,205 @@
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::{
+    extract::MatchedPath,
+    http::{
+        header::{HeaderName, HeaderValue},
+        HeaderMap, Request, Response,
+    },
+};
+use futures::future::BoxFuture;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tower::{Layer, Service};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer as _};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Install the global tracing subscriber.
+///
+/// Logs are written to stdout, filtered by `RUST_LOG` (`info` by default), as JSON lines when
+/// `LOG_FORMAT=json`. Spans are also exported over OTLP/gRPC when `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set, e.g. `http://localhost:4317` for a local collector.
+///
+/// Returns the tracer provider to shut down on exit, so buffered spans get flushed.
+pub fn init(service_name: &'static str) -> Option<TracerProvider> {
+    // Incoming and outgoing trace context use the W3C `traceparent` header
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let fmt_layer = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+
+    let provider = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().map(|_| {
+        // The exporter reads the endpoint from the environment itself
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .build()
+            .expect("Could not build the OTLP exporter.");
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+            .build()
+    });
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+    provider
+}
+
+/// Id of the trace the current span belongs to, only known when spans are exported.
+pub fn current_trace_id() -> Option<String> {
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Id of the request, taken from the `X-Request-Id` header or generated when missing.
+///
+/// Available from the request extensions once `RequestTracingLayer` ran, to forward it upstream.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// Read-only view of the request headers for the trace context propagator
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Write view of outgoing request headers for the trace context propagator
+pub struct HeaderInjector<'a>(pub &'a mut reqwest::header::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Tower layer opening a span per request, and logging its outcome.
+///
+/// The span continues the trace of the caller when a `traceparent` header is present, and carries
+/// the request id, taken from the `X-Request-Id` header or generated when missing. The id is
+/// echoed back in the `X-Request-Id` response header.
+#[derive(Clone, Default)]
+pub struct RequestTracingLayer;
+
+impl<S> Layer<S> for RequestTracingLayer {
+    type Service = RequestTracingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestTracingService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestTracingService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestTracingService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // Only trust reasonably sized ids, they end up in every log line
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= 128)
+            .map(str::to_owned)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_owned())
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let span = tracing::info_span!(
+            "HTTP request",
+            otel.name = format!("{} {}", req.method(), route),
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.method = %req.method(),
+            http.route = %route,
+            http.target = %req.uri(),
+            http.status_code = Empty,
+            request_id = %request_id,
+        );
+        let parent_context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        span.set_parent(parent_context);
+
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+        let start = Instant::now();
+        let fut = span.in_scope(|| self.inner.call(req));
+
+        Box::pin(
+            async move {
+                let mut response = fut.await?;
+                let status = response.status();
+                let span = tracing::Span::current();
+                span.record("http.status_code", status.as_u16());
+                if status.is_server_error() {
+                    span.record("otel.status_code", "ERROR");
+                }
+                tracing::info!(
+                    status = status.as_u16(),
+                    elapsed_ms = start.elapsed().as_millis() as u64,
+                    "request completed"
+                );
+
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}